@@ -1,17 +1,210 @@
-use anyhow::Result;
-use log::{debug, info};
-use metashrew_runtime::{BatchLike, KeyValueStoreLike};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use metashrew_runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
 use redis::Commands;
-use std::sync::{Arc, Mutex};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const TIP_HEIGHT_KEY: &'static str = "/__INTERNAL/tip-height";
+const LABELS_KEY: &'static str = "/__INTERNAL/labels";
+
+// The label registry spans labels, so only the chain id goes in front of it
+fn labels_key() -> Vec<u8> {
+    let mut key: Vec<u8> = get_chain_id().into_iter().collect();
+    key.extend(LABELS_KEY.as_bytes());
+    stored_key(key)
+}
+
+/// Every label that has committed a block to this store, sorted, with the tip stored under each
+/// (None if it has since been deleted). `write` adds the active label to a set at
+/// `/__INTERNAL/labels`, behind the chain id but ahead of any label, so listing them never scans
+/// the keyspace; a label that has not written since the registry was introduced is missing.
+pub fn list_labels(connection: &mut redis::Connection) -> Result<Vec<(String, Option<u32>)>> {
+    let mut labels: Vec<String> = connection.smembers(labels_key())?;
+    labels.sort();
+    labels
+        .into_iter()
+        .map(|label| {
+            let mut height_key: Vec<u8> = get_chain_id().into_iter().collect();
+            height_key.extend(format!("{}://{}", label, TIP_HEIGHT_KEY).into_bytes());
+            let tip = match connection.get::<_, Option<Vec<u8>>>(stored_key(height_key.clone()))? {
+                Some(v) => Some(u32::from_le_bytes(
+                    open_value(&height_key, v)?
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| anyhow!("tip height of label {} is not 4 bytes", label))?,
+                )),
+                None => None,
+            };
+            Ok((label, tip))
+        })
+        .collect()
+}
 
 pub struct RedisRuntimeAdapter(pub String, pub Arc<Mutex<redis::Connection>>, pub u32);
 
 static mut _LABEL: Option<String> = None;
+static mut _CHAIN_ID: Option<u8> = None;
+static mut _WRITE_RETRIES: u32 = 3;
+// (replicas, timeout in ms, every K-th block) for the WAIT issued after a block write
+static mut _WAIT_REPLICAS: Option<(u32, u64, u32)> = None;
 
 const TIMEOUT: u64 = 1500;
 
+// The keys set_encryption_key derives, one per purpose, so the value cipher and the key HMAC
+// never share key material
+struct Encryption {
+    values: LessSafeKey,
+    keys: Option<hmac::Key>,
+    random: SystemRandom,
+}
+
+static ENCRYPTION: OnceLock<Encryption> = OnceLock::new();
+
+impl Encryption {
+    fn derive(key: [u8; 32], hash_keys: bool) -> Result<Self> {
+        let master = hmac::Key::new(hmac::HMAC_SHA256, &key);
+        let values = UnboundKey::new(
+            &CHACHA20_POLY1305,
+            hmac::sign(&master, b"metashrew-keydb values").as_ref(),
+        )
+        .map_err(|_| anyhow!("failed to derive the value key"))?;
+        let keys = hash_keys.then(|| {
+            hmac::Key::new(
+                hmac::HMAC_SHA256,
+                hmac::sign(&master, b"metashrew-keydb keys").as_ref(),
+            )
+        });
+        Ok(Self {
+            values: LessSafeKey::new(values),
+            keys,
+            random: SystemRandom::new(),
+        })
+    }
+
+    // Frames `value` as a nonce and sealed ciphertext bound to the labeled key
+    fn seal(&self, labeled: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random
+            .fill(&mut nonce)
+            .expect("system random source failed");
+        let mut sealed = value.to_vec();
+        // Only fails for values longer than the cipher allows, far beyond any KeyDB value
+        self.values
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(labeled),
+                &mut sealed,
+            )
+            .expect("value too long to encrypt");
+        let mut framed = nonce.to_vec();
+        framed.extend(sealed);
+        framed
+    }
+
+    fn open(&self, labeled: &[u8], stored: Vec<u8>) -> Result<Vec<u8>, redis::RedisError> {
+        if stored.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(decrypt_error());
+        }
+        let nonce =
+            Nonce::try_assume_unique_for_key(&stored[..NONCE_LEN]).map_err(|_| decrypt_error())?;
+        let mut sealed = stored[NONCE_LEN..].to_vec();
+        let length = self
+            .values
+            .open_in_place(nonce, Aad::from(labeled), &mut sealed)
+            .map_err(|_| decrypt_error())?
+            .len();
+        sealed.truncate(length);
+        Ok(sealed)
+    }
+}
+
+/// Store operations counted per attempt, including each retry, and per failed attempt.
+#[derive(Clone, Copy, Debug)]
+pub enum StoreOp {
+    Get,
+    Put,
+    Delete,
+    Write,
+}
+
+impl StoreOp {
+    const ALL: [StoreOp; 4] = [StoreOp::Get, StoreOp::Put, StoreOp::Delete, StoreOp::Write];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StoreOp::Get => "get",
+            StoreOp::Put => "put",
+            StoreOp::Delete => "delete",
+            StoreOp::Write => "write",
+        }
+    }
+}
+
+static STORE_OPS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static STORE_ERRORS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+// When any adapter in this process last made a store operation, in milliseconds since the
+// Unix epoch; the keepalive only pings once this is old enough
+static LAST_USED_MS: AtomicU64 = AtomicU64::new(0);
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Counts one attempt of `op` and, if it failed, one error
+// How long the connection has gone unused at `now`, if that is long enough for a keepalive PING
+fn keepalive_idle(last_used: u64, now: u64, every: Duration) -> Option<u64> {
+    let idle = now.saturating_sub(last_used);
+    (idle >= every.as_millis() as u64).then_some(idle)
+}
+
+fn record_attempt<T>(op: StoreOp, result: &Result<T, redis::RedisError>) {
+    LAST_USED_MS.store(now_ms(), Ordering::Relaxed);
+    STORE_OPS[op as usize].fetch_add(1, Ordering::Relaxed);
+    if result.is_err() {
+        STORE_ERRORS[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Attempts and failed attempts of `op` made by every adapter in this process.
+pub fn store_op_counts(op: StoreOp) -> (u64, u64) {
+    (
+        STORE_OPS[op as usize].load(Ordering::Relaxed),
+        STORE_ERRORS[op as usize].load(Ordering::Relaxed),
+    )
+}
+
+/// Prometheus text for `metashrew_store_ops_total` and `metashrew_store_errors_total`.
+pub fn render_store_metrics() -> String {
+    let mut ops = String::from("# TYPE metashrew_store_ops_total counter\n");
+    let mut errors = String::from("# TYPE metashrew_store_errors_total counter\n");
+    for op in StoreOp::ALL {
+        let (attempts, failures) = store_op_counts(op);
+        ops += &format!("metashrew_store_ops_total{{op=\"{}\"}} {}\n", op.name(), attempts);
+        errors += &format!("metashrew_store_errors_total{{op=\"{}\"}} {}\n", op.name(), failures);
+    }
+    ops + &errors
+}
+
 use std::{thread, time};
 
 pub fn wait_timeout() {
@@ -24,6 +217,47 @@ pub fn set_label(s: String) -> () {
     }
 }
 
+/// Sets how many times `write` reconnects and retries before returning the error.
+pub fn set_write_retries(n: u32) -> () {
+    unsafe {
+        _WRITE_RETRIES = n;
+    }
+}
+
+pub fn get_write_retries() -> u32 {
+    unsafe { _WRITE_RETRIES }
+}
+
+/// After every `every`-th block write, blocks on `WAIT replicas timeout_ms` so the commit has
+/// reached that many replicas before indexing moves on.
+pub fn set_wait_replicas(replicas: u32, timeout_ms: u64, every: u32) -> () {
+    unsafe {
+        _WAIT_REPLICAS = Some((replicas, timeout_ms, every.max(1)));
+    }
+}
+
+pub fn get_wait_replicas() -> Option<(u32, u64, u32)> {
+    unsafe { _WAIT_REPLICAS }
+}
+
+// Whether the write of the block at `height` is one of the every-th that waits for replicas,
+// counting from block 0 so `every` 1 waits after each block
+fn waits_after(height: u32, every: u32) -> bool {
+    (height + 1) % every == 0
+}
+
+/// Puts every key, internal ones included, behind this byte, ahead of any label, so indexers for
+/// different chains sharing one KeyDB never see each other's keys.
+pub fn set_chain_id(chain_id: u8) -> () {
+    unsafe {
+        _CHAIN_ID = Some(chain_id);
+    }
+}
+
+pub fn get_chain_id() -> Option<u8> {
+    unsafe { _CHAIN_ID }
+}
+
 pub fn get_label() -> &'static String {
     unsafe { _LABEL.as_ref().unwrap() }
 }
@@ -33,9 +267,12 @@ pub fn has_label() -> bool {
 }
 
 pub fn to_labeled_key(key: &Vec<u8>) -> Vec<u8> {
-    if has_label() {
+    if has_label() || get_chain_id().is_some() {
         let mut result: Vec<u8> = vec![];
-        result.extend(get_label().as_str().as_bytes());
+        result.extend(get_chain_id());
+        if has_label() {
+            result.extend(get_label().as_str().as_bytes());
+        }
         result.extend(key);
         result
     } else {
@@ -43,10 +280,70 @@ pub fn to_labeled_key(key: &Vec<u8>) -> Vec<u8> {
     }
 }
 
+/// Encrypts every value with ChaCha20-Poly1305 under a key derived from `key`. Each stored value
+/// is a fresh random nonce followed by the ciphertext and tag, sealed to the key it is stored
+/// under so it cannot be moved to another. With `hash_keys`, keys are stored as their
+/// HMAC-SHA256 instead of their bytes; that hides them but leaves prefixes unmatchable, so
+/// `scan_prefix` fails.
+///
+/// Random nonces are safe for about 2^32 values under one key. Can only be set once, before the
+/// first store operation.
+pub fn set_encryption_key(key: [u8; 32], hash_keys: bool) -> Result<()> {
+    ENCRYPTION
+        .set(Encryption::derive(key, hash_keys)?)
+        .map_err(|_| anyhow!("the encryption key is already set"))
+}
+
+/// Parses a 32-byte encryption key from 64 hex digits.
+pub fn parse_encryption_key(s: &str) -> Result<[u8; 32]> {
+    hex::decode(s.trim().trim_start_matches("0x"))?
+        .try_into()
+        .map_err(|_| anyhow!("the encryption key must be 32 bytes"))
+}
+
+/// Reads a 32-byte encryption key from a file holding either the raw bytes or 64 hex digits.
+pub fn read_encryption_key<P: AsRef<Path>>(path: P) -> Result<[u8; 32]> {
+    let bytes = std::fs::read(path)?;
+    match <[u8; 32]>::try_from(bytes.as_slice()) {
+        Ok(key) => Ok(key),
+        Err(_) => parse_encryption_key(std::str::from_utf8(&bytes)?),
+    }
+}
+
+fn decrypt_error() -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::ClientError,
+        "stored value failed to decrypt; wrong encryption key or not encrypted",
+    ))
+}
+
+// The bytes a labeled key is stored under
+fn stored_key(labeled: Vec<u8>) -> Vec<u8> {
+    match ENCRYPTION.get().and_then(|encryption| encryption.keys.as_ref()) {
+        Some(keys) => hmac::sign(keys, &labeled).as_ref().to_vec(),
+        None => labeled,
+    }
+}
+
+fn seal_value(labeled: &[u8], value: &[u8]) -> Vec<u8> {
+    match ENCRYPTION.get() {
+        Some(encryption) => encryption.seal(labeled, value),
+        None => value.to_vec(),
+    }
+}
+
+fn open_value(labeled: &[u8], stored: Vec<u8>) -> Result<Vec<u8>, redis::RedisError> {
+    match ENCRYPTION.get() {
+        Some(encryption) => encryption.open(labeled, stored),
+        None => Ok(stored),
+    }
+}
+
 pub async fn query_height(connection: &mut redis::Connection, start_block: u32) -> Result<u32> {
-    let height_key = TIP_HEIGHT_KEY.as_bytes().to_vec();
-    let bytes: Vec<u8> = match connection.get(&to_labeled_key(&height_key)) {
-        Ok(v) => v,
+    let height_key = to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec());
+    let bytes: Vec<u8> = match connection.get::<Vec<u8>, Option<Vec<u8>>>(stored_key(height_key.clone())) {
+        Ok(Some(v)) => open_value(&height_key, v)?,
+        Ok(None) => vec![],
         Err(_) => {
             return Ok(start_block);
         }
@@ -85,27 +382,67 @@ impl RedisRuntimeAdapter {
             }
         }
     }
+    /// Opens a connection and checks it answers PING. PING needs no key, so a store without a
+    /// POLL key, or holding another type under it, still reads as healthy.
+    pub fn connect_checked(&self) -> Result<redis::Connection> {
+        let mut connection = self.connect()?;
+        redis::cmd("PING").query::<String>(&mut connection)?;
+        Ok(connection)
+    }
+    // Swaps the shared connection in place, so every clone, the keepalive thread's included,
+    // moves to the new one; a mutex poisoned by a panic mid-command is cleared along the way
+    fn replace_connection(&self, connection: redis::Connection) {
+        *self.1.lock().unwrap_or_else(|e| e.into_inner()) = connection;
+        self.1.clear_poison();
+    }
+    pub fn try_reset_connection(&mut self) -> Result<(), redis::RedisError> {
+        let connection = redis::Client::open(self.0.clone())?.get_connection()?;
+        self.replace_connection(connection);
+        Ok(())
+    }
     pub fn reset_connection(&mut self) {
         debug!("KeyDB reset -- wait 1.5s");
         wait_timeout();
-        self.1 = Arc::new(Mutex::new(self.connect().unwrap()));
+        self.replace_connection(self.connect().unwrap());
+    }
+    /// Spawns a thread that sends `PING` on the shared connection once no store operation has
+    /// used it for `every`, so a connection left idle at the tip is not closed by a proxy or the
+    /// server's `timeout`. A failed PING reconnects right away rather than on the next block.
+    pub fn start_keepalive(&self, every: Duration) -> thread::JoinHandle<()> {
+        let mut adapter = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(every);
+            let idle = match keepalive_idle(LAST_USED_MS.load(Ordering::Relaxed), now_ms(), every) {
+                Some(idle) => idle,
+                None => continue,
+            };
+            let result = match adapter.1.lock() {
+                Ok(mut connection) => redis::cmd("PING").query::<String>(&mut *connection),
+                Err(_) => continue,
+            };
+            match result {
+                Ok(_) => debug!("KeyDB keepalive PING after {}ms idle", idle),
+                Err(e) => {
+                    debug!("KeyDB keepalive PING failed: {:?}", e);
+                    if let Err(e) = adapter.try_reset_connection() {
+                        debug!("KeyDB reconnect failed: {:?}", e);
+                    }
+                }
+            }
+        })
     }
 }
 
 pub struct RedisBatch(pub redis::Pipeline);
 
 fn to_redis_key<T: AsRef<[u8]>>(v: T) -> Vec<Vec<u8>> {
-    if has_label() {
-        let mut data: Vec<u8> = get_label().as_str().as_bytes().to_vec();
-        let key: &[u8] = v.as_ref().try_into().unwrap();
-        data.extend(key);
-        vec![data]
-    } else {
-        to_redis_args(v)
-    }
+    vec![stored_key(to_labeled_key(&v.as_ref().to_vec()))]
 }
-fn to_redis_args<T: AsRef<[u8]>>(v: T) -> Vec<Vec<u8>> {
-    return vec![v.as_ref().try_into().unwrap()];
+fn to_redis_args<K: AsRef<[u8]>, V: AsRef<[u8]>>(k: K, v: V) -> Vec<Vec<u8>> {
+    vec![seal_value(&to_labeled_key(&k.as_ref().to_vec()), v.as_ref())]
+}
+fn from_redis_value<K: AsRef<[u8]>>(k: K, v: Vec<u8>) -> Result<Vec<u8>, redis::RedisError> {
+    open_value(&to_labeled_key(&k.as_ref().to_vec()), v)
 }
 
 impl BatchLike for RedisBatch {
@@ -115,8 +452,8 @@ impl BatchLike for RedisBatch {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, k: K, v: V) {
         self.0
             .cmd("SET")
-            .arg(to_redis_key(k))
-            .arg(to_redis_args(v))
+            .arg(to_redis_key(k.as_ref()))
+            .arg(to_redis_args(k.as_ref(), v))
             .ignore();
     }
 }
@@ -127,42 +464,92 @@ impl Clone for RedisRuntimeAdapter {
     }
 }
 
+impl RedisRuntimeAdapter {
+    // Issues the configured WAIT once the block at self.2 is written; a short acknowledgement
+    // only warns, since the write itself has already succeeded on the primary
+    fn wait_for_replicas(&self) {
+        let (replicas, timeout_ms, every) = match get_wait_replicas() {
+            Some(v) => v,
+            None => return,
+        };
+        if !waits_after(self.2, every) {
+            return;
+        }
+        let acknowledged = match self.1.lock() {
+            Ok(mut connection) => redis::cmd("WAIT")
+                .arg(replicas)
+                .arg(timeout_ms)
+                .query::<u32>(&mut *connection),
+            Err(_) => return,
+        };
+        match acknowledged {
+            Ok(n) if n < replicas => warn!(
+                "block {} reached {} of {} replicas within {}ms",
+                self.2, n, replicas, timeout_ms
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("WAIT after block {} failed: {:?}", self.2, e),
+        }
+    }
+}
+
 impl KeyValueStoreLike for RedisRuntimeAdapter {
     type Batch = RedisBatch;
     type Error = redis::RedisError;
     fn write(&mut self, mut batch: RedisBatch) -> Result<(), Self::Error> {
         let key_bytes: Vec<u8> = TIP_HEIGHT_KEY.as_bytes().to_vec();
         let height_bytes: Vec<u8> = (self.2 + 1).to_le_bytes().to_vec();
-        /*
-        let _ok: bool = connection
-            .set(to_redis_args(&key_bytes), to_redis_args(&height_bytes))
-            .unwrap();
-            */
+        // The tip rides in the same pipeline as the block's writes on the persistent connection
         batch.put(&key_bytes, &height_bytes);
+        if has_label() {
+            batch
+                .0
+                .cmd("SADD")
+                .arg(labels_key())
+                .arg(get_label().trim_end_matches("://"))
+                .ignore();
+        }
+        // The pipeline holds only SETs and the registry's SADD, so resending it after an attempt
+        // that was partly applied writes the same values again; anything non-idempotent added to
+        // RedisBatch breaks this
+        let mut attempt: u32 = 0;
         loop {
-            {
-                match batch.0.query::<()>(&mut self.1.lock().unwrap()) {
-                    Ok(_) => {
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        debug!("{:?}", e);
+            let result = match self.1.lock() {
+                Ok(mut connection) => batch.0.query::<()>(&mut *connection),
+                Err(_) => Err(redis::RedisError::from((
+                    redis::ErrorKind::ClientError,
+                    "connection mutex poisoned",
+                ))),
+            };
+            record_attempt(StoreOp::Write, &result);
+            match result {
+                Ok(_) => {
+                    self.wait_for_replicas();
+                    return Ok(());
+                }
+                Err(e) if attempt < get_write_retries() => {
+                    debug!("{:?}", e);
+                    attempt += 1;
+                    wait_timeout();
+                    if let Err(e) = self.try_reset_connection() {
+                        debug!("KeyDB reconnect failed: {:?}", e);
                     }
                 }
+                Err(e) => return Err(e),
             }
-            self.reset_connection();
         }
     }
     fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
         loop {
             {
-                match self
+                let result = self
                     .1
                     .lock()
                     .unwrap()
-                    .get::<Vec<Vec<u8>>, Option<Vec<u8>>>(to_redis_args(key.as_ref()))
-                {
-                    Ok(v) => return Ok(v),
+                    .get::<Vec<Vec<u8>>, Option<Vec<u8>>>(to_redis_key(key.as_ref()));
+                record_attempt(StoreOp::Get, &result);
+                match result {
+                    Ok(v) => return v.map(|v| from_redis_value(key.as_ref(), v)).transpose(),
                     Err(e) => {
                         debug!("{:?}", e);
                     }
@@ -174,12 +561,13 @@ impl KeyValueStoreLike for RedisRuntimeAdapter {
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
         loop {
             {
-                match self
+                let result = self
                     .1
                     .lock()
                     .unwrap()
-                    .del::<Vec<Vec<u8>>, ()>(to_redis_args(key.as_ref()))
-                {
+                    .del::<Vec<Vec<u8>>, ()>(to_redis_key(key.as_ref()));
+                record_attempt(StoreOp::Delete, &result);
+                match result {
                     Ok(_) => {
                         return Ok(());
                     }
@@ -194,14 +582,16 @@ impl KeyValueStoreLike for RedisRuntimeAdapter {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), Self::Error> {
         loop {
             {
-                match self
+                let result = self
                     .1
                     .lock()
                     .unwrap()
                     .set::<Vec<Vec<u8>>, Vec<Vec<u8>>, ()>(
                         to_redis_key(key.as_ref()),
-                        to_redis_args(value.as_ref()),
-                    ) {
+                        to_redis_args(key.as_ref(), value.as_ref()),
+                    );
+                record_attempt(StoreOp::Put, &result);
+                match result {
                     Ok(v) => {
                         return Ok(());
                     }
@@ -213,4 +603,318 @@ impl KeyValueStoreLike for RedisRuntimeAdapter {
             self.reset_connection();
         }
     }
+    // WATCH makes the MULTI/EXEC abort if the key changes after it is read, in which case
+    // redis::transaction runs the check again
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let plain_key = key.as_ref().to_vec();
+        let key = to_redis_key(key.as_ref());
+        loop {
+            {
+                let result = redis::transaction(
+                    &mut *self.1.lock().unwrap(),
+                    &key,
+                    |connection, pipe| {
+                        // A value that does not decrypt is not the expected one
+                        let current: Option<Vec<u8>> = connection
+                            .get::<_, Option<Vec<u8>>>(&key)?
+                            .and_then(|v| from_redis_value(&plain_key, v).ok());
+                        if current.as_deref() != Some(expected.as_ref()) {
+                            return Ok(Some(false));
+                        }
+                        pipe.del(&key)
+                            .query::<Option<(u32,)>>(connection)
+                            .map(|deleted| deleted.map(|(n,)| n > 0))
+                    },
+                );
+                record_attempt(StoreOp::Delete, &result);
+                match result {
+                    Ok(deleted) => return Ok(deleted),
+                    Err(e) => {
+                        debug!("{:?}", e);
+                    }
+                }
+            }
+            self.reset_connection();
+        }
+    }
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        if ENCRYPTION.get().is_some_and(|encryption| encryption.keys.is_some()) {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "prefix scans are unavailable with hashed keys",
+            )));
+        }
+        let labeled_prefix = to_labeled_key(&prefix.as_ref().to_vec());
+        let label_len = labeled_prefix.len() - prefix.as_ref().len();
+        let mut pattern: Vec<u8> = vec![];
+        for byte in &labeled_prefix {
+            if b"*?[]\\".contains(byte) {
+                pattern.push(b'\\');
+            }
+            pattern.push(*byte);
+        }
+        pattern.push(b'*');
+        loop {
+            {
+                let mut connection = self.1.lock().unwrap();
+                let scanned: Result<KeyValuePairs, Self::Error> = (|| {
+                    let mut keys: Vec<Vec<u8>> = connection
+                        .scan_match::<Vec<u8>, Vec<u8>>(pattern.clone())?
+                        .collect();
+                    // SCAN order is unspecified, so sort to keep iteration deterministic
+                    keys.sort();
+                    let mut result: KeyValuePairs = vec![];
+                    for key in keys {
+                        if let Some(value) = connection.get::<Vec<u8>, Option<Vec<u8>>>(key.clone())? {
+                            result.push((key[label_len..].to_vec(), value));
+                        }
+                    }
+                    Ok(result)
+                })();
+                match scanned {
+                    Ok(v) => {
+                        return v
+                            .into_iter()
+                            .map(|(key, value)| Ok((key.clone(), from_redis_value(key, value)?)))
+                            .collect()
+                    }
+                    Err(e) => {
+                        debug!("{:?}", e);
+                    }
+                }
+            }
+            self.reset_connection();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_after_every_kth_block() {
+        assert!((0..10).all(|height| waits_after(height, 1)));
+        let waited: Vec<u32> = (0..10).filter(|height| waits_after(*height, 4)).collect();
+        assert_eq!(waited, vec![3, 7]);
+    }
+
+    #[test]
+    fn wait_every_zero_clamps_to_each_block() {
+        set_wait_replicas(2, 500, 0);
+        assert_eq!(get_wait_replicas(), Some((2, 500, 1)));
+    }
+
+    #[test]
+    fn keepalive_pings_only_once_idle_for_the_interval() {
+        let every = Duration::from_secs(30);
+        assert_eq!(keepalive_idle(100_000, 110_000, every), None);
+        assert_eq!(keepalive_idle(100_000, 130_000, every), Some(30_000));
+        assert_eq!(keepalive_idle(100_000, 190_000, every), Some(90_000));
+        // A store operation recorded after the check read the clock is not idle time
+        assert_eq!(keepalive_idle(130_000, 120_000, every), None);
+    }
+
+    #[test]
+    fn sealed_values_open_under_the_same_key_and_label() {
+        let encryption = Encryption::derive([7u8; 32], false).unwrap();
+        let sealed = encryption.seal(b"mainnet://height", b"840000");
+        assert_ne!(&sealed[NONCE_LEN..], b"840000");
+        assert_ne!(sealed, encryption.seal(b"mainnet://height", b"840000"));
+        assert_eq!(
+            encryption.open(b"mainnet://height", sealed).unwrap(),
+            b"840000".to_vec()
+        );
+    }
+
+    #[test]
+    fn opening_fails_under_a_wrong_key_or_label() {
+        let encryption = Encryption::derive([7u8; 32], false).unwrap();
+        let sealed = encryption.seal(b"mainnet://height", b"840000");
+        let other = Encryption::derive([8u8; 32], false).unwrap();
+        assert!(other.open(b"mainnet://height", sealed.clone()).is_err());
+        assert!(encryption.open(b"testnet://height", sealed).is_err());
+        assert!(encryption
+            .open(b"mainnet://height", b"840000".to_vec())
+            .is_err());
+    }
+
+    // Answers GET, SET, DEL, HSET and PING from an in-memory map and anything else with +OK,
+    // except that the first connection is closed on its first command
+    fn serve_fake_redis() -> String {
+        serve_fake_redis_dropping_after(0)
+    }
+
+    // serve_fake_redis, with the first connection applying `applied` commands before it is closed
+    fn serve_fake_redis_dropping_after(applied: usize) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let map: Arc<Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>> = Arc::default();
+        // Keys holding a hash, which GET refuses
+        let hashes: Arc<Mutex<std::collections::HashSet<Vec<u8>>>> = Arc::default();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let map = map.clone();
+                let hashes = hashes.clone();
+                thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    let mut handled = 0;
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 || (index == 0 && handled == applied) {
+                            return;
+                        }
+                        handled += 1;
+                        let count: usize = line.trim_start_matches('*').trim().parse().unwrap();
+                        let mut args: Vec<Vec<u8>> = vec![];
+                        for _ in 0..count {
+                            line.clear();
+                            reader.read_line(&mut line).unwrap();
+                            let len: usize = line.trim_start_matches('$').trim().parse().unwrap();
+                            let mut arg = vec![0; len + 2];
+                            std::io::Read::read_exact(&mut reader, &mut arg).unwrap();
+                            arg.truncate(len);
+                            args.push(arg);
+                        }
+                        let mut map = map.lock().unwrap();
+                        let reply = match args[0].to_ascii_uppercase().as_slice() {
+                            b"PING" => b"+PONG\r\n".to_vec(),
+                            b"HSET" => {
+                                hashes.lock().unwrap().insert(args[1].clone());
+                                b":1\r\n".to_vec()
+                            }
+                            b"GET" if hashes.lock().unwrap().contains(&args[1]) => {
+                                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_vec()
+                            }
+                            b"GET" => match map.get(&args[1]) {
+                                Some(value) => {
+                                    let head = format!("${}\r\n", value.len());
+                                    [head.as_bytes(), value, b"\r\n"].concat()
+                                }
+                                None => b"$-1\r\n".to_vec(),
+                            },
+                            b"SET" => {
+                                map.insert(args[1].clone(), args[2].clone());
+                                b"+OK\r\n".to_vec()
+                            }
+                            b"DEL" => {
+                                let removed = map.remove(&args[1]).is_some() as u8;
+                                format!(":{}\r\n", removed).into_bytes()
+                            }
+                            _ => b"+OK\r\n".to_vec(),
+                        };
+                        stream.write_all(&reply).unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn store_counters_track_attempts_and_failures() {
+        let url = serve_fake_redis();
+        let connection = redis::Client::open(url.clone()).unwrap().get_connection().unwrap();
+        let mut adapter = RedisRuntimeAdapter(url, Arc::new(Mutex::new(connection)), 0);
+        let before: Vec<(u64, u64)> = [StoreOp::Get, StoreOp::Put, StoreOp::Delete]
+            .into_iter()
+            .map(store_op_counts)
+            .collect();
+
+        // The first attempt is dropped, so the put reconnects and succeeds on its second
+        adapter.put(b"key", b"value").unwrap();
+        assert_eq!(adapter.get(b"key").unwrap(), Some(b"value".to_vec()));
+        adapter.delete(b"key").unwrap();
+        assert_eq!(adapter.get(b"key").unwrap(), None);
+
+        let delta = |op: StoreOp, index: usize| {
+            let (attempts, errors) = store_op_counts(op);
+            (attempts - before[index].0, errors - before[index].1)
+        };
+        assert_eq!(delta(StoreOp::Get, 0), (2, 0));
+        assert_eq!(delta(StoreOp::Put, 1), (2, 1));
+        assert_eq!(delta(StoreOp::Delete, 2), (1, 0));
+        let (attempts, errors) = store_op_counts(StoreOp::Put);
+        let metrics = render_store_metrics();
+        assert!(metrics.contains(&format!("metashrew_store_ops_total{{op=\"put\"}} {}\n", attempts)));
+        assert!(metrics.contains(&format!("metashrew_store_errors_total{{op=\"put\"}} {}\n", errors)));
+    }
+
+    #[test]
+    fn connect_checked_accepts_a_store_whose_poll_key_is_not_a_string() {
+        let url = serve_fake_redis();
+        let connection = redis::Client::open(url.clone()).unwrap().get_connection().unwrap();
+        let adapter = RedisRuntimeAdapter(url.clone(), Arc::new(Mutex::new(connection)), 0);
+
+        let started = std::time::Instant::now();
+        let mut probed = adapter.connect_checked().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Reading POLL as a string, the old probe, fails on this store; PING does not
+        redis::cmd("HSET").arg("POLL").arg("field").arg("value").query::<u32>(&mut probed).unwrap();
+        assert!(redis::cmd("GET").arg("POLL").query::<Vec<u8>>(&mut probed).is_err());
+        let started = std::time::Instant::now();
+        adapter.connect_checked().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_retried_write_applies_an_append_once() {
+        use metashrew_runtime::{db_make_length_key, db_make_list_key};
+        // The first attempt lands the list entry, then the connection drops before the length
+        let url = serve_fake_redis_dropping_after(1);
+        let connection = redis::Client::open(url.clone()).unwrap().get_connection().unwrap();
+        let mut adapter = RedisRuntimeAdapter(url, Arc::new(Mutex::new(connection)), 0);
+        let key = b"/list".to_vec();
+        let mut batch = RedisBatch::default();
+        batch.put(db_make_list_key(&key, 0).unwrap(), b"value");
+        batch.put(db_make_length_key(&key).unwrap(), 1u32.to_le_bytes());
+
+        // The first attempt never gets its replies, so this only succeeds through the retry
+        set_write_retries(1);
+        adapter.write(batch).unwrap();
+        assert_eq!(adapter.get(db_make_length_key(&key).unwrap()).unwrap(), Some(1u32.to_le_bytes().to_vec()));
+        assert_eq!(adapter.get(db_make_list_key(&key, 0).unwrap()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(adapter.get(db_make_list_key(&key, 1).unwrap()).unwrap(), None);
+        assert_eq!(adapter.get(TIP_HEIGHT_KEY).unwrap(), Some(1u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn a_dropped_connection_fails_the_write_after_its_retries() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        // Accepts connections and closes them before answering anything
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicU32::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+
+        set_write_retries(1);
+        let connection = redis::Client::open(url.clone()).unwrap().get_connection().unwrap();
+        let mut adapter = RedisRuntimeAdapter(url, Arc::new(Mutex::new(connection)), 0);
+        let mut batch = RedisBatch::default();
+        batch.put(b"key", b"value");
+        assert!(adapter.write(batch).is_err());
+        // The first attempt's connection plus the one reopened for the retry, which the listener
+        // may not have accepted yet
+        for _ in 0..100 {
+            if accepted.load(Ordering::SeqCst) >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
 }
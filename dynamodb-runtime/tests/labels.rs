@@ -0,0 +1,103 @@
+// The label is process-wide, so this runs in its own test binary rather than alongside the
+// library's tests, which would see their keys move under it.
+
+use metashrew_keydb_runtime::{list_labels, set_label, RedisBatch, RedisRuntimeAdapter};
+use metashrew_runtime::{BatchLike, KeyValueStoreLike};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex};
+
+type Sets = Mutex<HashMap<Vec<u8>, BTreeSet<Vec<u8>>>>;
+
+// Answers GET, SET, SADD and SMEMBERS from in-memory maps and anything else with +OK
+fn serve_fake_redis() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    let strings: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>> = Arc::default();
+    let sets: Arc<Sets> = Arc::default();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let strings = strings.clone();
+            let sets = sets.clone();
+            std::thread::spawn(move || {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let count: usize = line.trim_start_matches('*').trim().parse().unwrap();
+                    let mut args: Vec<Vec<u8>> = vec![];
+                    for _ in 0..count {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        let len: usize = line.trim_start_matches('$').trim().parse().unwrap();
+                        let mut arg = vec![0; len + 2];
+                        reader.read_exact(&mut arg).unwrap();
+                        arg.truncate(len);
+                        args.push(arg);
+                    }
+                    let bulk = |value: &[u8]| {
+                        [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat()
+                    };
+                    let reply = match args[0].to_ascii_uppercase().as_slice() {
+                        b"GET" => match strings.lock().unwrap().get(&args[1]) {
+                            Some(value) => bulk(value),
+                            None => b"$-1\r\n".to_vec(),
+                        },
+                        b"SET" => {
+                            strings.lock().unwrap().insert(args[1].clone(), args[2].clone());
+                            b"+OK\r\n".to_vec()
+                        }
+                        b"SADD" => {
+                            let mut sets = sets.lock().unwrap();
+                            let set = sets.entry(args[1].clone()).or_default();
+                            let added = args[2..].iter().filter(|member| set.insert(member.to_vec())).count();
+                            format!(":{}\r\n", added).into_bytes()
+                        }
+                        b"SMEMBERS" => {
+                            let sets = sets.lock().unwrap();
+                            let members = sets.get(&args[1]).cloned().unwrap_or_default();
+                            let mut reply = format!("*{}\r\n", members.len()).into_bytes();
+                            for member in members {
+                                reply.extend(bulk(&member));
+                            }
+                            reply
+                        }
+                        _ => b"+OK\r\n".to_vec(),
+                    };
+                    stream.write_all(&reply).unwrap();
+                }
+            });
+        }
+    });
+    url
+}
+
+#[test]
+fn labels_are_listed_with_the_tips_they_wrote() {
+    let url = serve_fake_redis();
+    let connection = Arc::new(Mutex::new(
+        redis::Client::open(url.clone()).unwrap().get_connection().unwrap(),
+    ));
+    // Each label commits the block before its tip, "mainnet" twice
+    for (label, height) in [("testnet", 11), ("mainnet", 3), ("signet", 2), ("mainnet", 4)] {
+        set_label(label.to_string());
+        let mut adapter = RedisRuntimeAdapter(url.clone(), connection.clone(), height);
+        let mut batch = RedisBatch::default();
+        batch.put(b"/key", label.as_bytes());
+        adapter.write(batch).unwrap();
+    }
+
+    let labels = list_labels(&mut connection.lock().unwrap()).unwrap();
+    assert_eq!(
+        labels,
+        vec![
+            ("mainnet".to_string(), Some(5)),
+            ("signet".to_string(), Some(3)),
+            ("testnet".to_string(), Some(12)),
+        ]
+    );
+}
@@ -1,9 +1,12 @@
 use actix_cors::Cors;
 use actix_web::error;
 use actix_web::http::{header::ContentType, StatusCode};
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder, Result};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, Result};
 //use itertools::Itertools;
-use metashrew_keydb_runtime::{query_height, set_label, RedisRuntimeAdapter};
+use metashrew_keydb_runtime::{
+    query_height, read_encryption_key, render_store_metrics, set_chain_id, set_encryption_key,
+    set_label, RedisRuntimeAdapter,
+};
 use metashrew_runtime::MetashrewRuntime;
 use std::fmt;
 //use rlp::Rlp;
@@ -157,6 +160,14 @@ async fn view(
     }
 }
 
+// Attempt and error counts of this process's KeyDB operations, retries included
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render_store_metrics())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
@@ -179,9 +190,17 @@ async fn main() -> std::io::Result<()> {
     hasher.finalize(&mut output);
     info!("program hash: 0x{}", hex::encode(output));
     let path_clone: PathBuf = path.into();
+    if let Ok(chain_id) = env::var("REDIS_CHAIN_ID") {
+        set_chain_id(chain_id.parse::<u8>().expect("REDIS_CHAIN_ID must be a byte"));
+    }
     if let Ok(label) = env::var("REDIS_LABEL") {
         set_label(label.clone());
     }
+    if let Ok(key_file) = env::var("REDIS_ENCRYPTION_KEY_FILE") {
+        let key = read_encryption_key(key_file)
+            .expect("REDIS_ENCRYPTION_KEY_FILE must hold a 32-byte key");
+        set_encryption_key(key, env::var("REDIS_HASH_KEYS").is_ok()).unwrap();
+    }
     let redis_uri: String = match env::var("REDIS_URI") {
         Ok(v) => v,
         Err(_) => "redis://localhost:7777".into(),
@@ -215,6 +234,7 @@ async fn main() -> std::io::Result<()> {
                 .unwrap(),
             }))
             .service(view)
+            .service(metrics)
     })
     .bind((
         match env::var("HOST") {
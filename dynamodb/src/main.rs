@@ -2,12 +2,15 @@ use anyhow::{anyhow, Result};
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_dynamodb::{Client, Region};
 use aws_sdk_dynamodb::model::{AttributeValue, PutRequest, WriteRequest};
-use clap::{command, Parser};
+use clap::{command, Parser, Subcommand};
 use env_logger;
 use hex;
 use itertools::Itertools;
 use log::debug;
-use metashrew_dynamodb_runtime::{query_height, set_label, DynamoDBRuntimeAdapter}; 
+use metashrew_dynamodb_runtime::{
+    list_labels, parse_encryption_key, query_height, read_encryption_key, set_encryption_key,
+    set_label, set_wait_replicas, set_write_retries, DynamoDBRuntimeAdapter,
+};
 use metashrew_runtime::KeyValueStoreLike;
 use metashrew_runtime::MetashrewRuntime;
 use reqwest::{Response, Url};
@@ -36,6 +39,38 @@ struct Args {
     auth: Option<String>,
     #[arg(long)]
     label: Option<String>,
+    /// Times a failed block write is retried on a fresh connection before the error is returned
+    #[arg(long, default_value_t = 3)]
+    write_retries: u32,
+    /// After each block write, wait until this many replicas acknowledge it (KeyDB WAIT)
+    #[arg(long)]
+    wait_replicas: Option<u32>,
+    /// Milliseconds each WAIT may block before indexing continues with a warning
+    #[arg(long, default_value_t = 1000)]
+    wait_timeout_ms: u64,
+    /// Only WAIT after every N-th block
+    #[arg(long, default_value_t = 1)]
+    wait_every: u32,
+    /// Send PING on the KeyDB connection whenever it has been idle this many seconds
+    #[arg(long)]
+    keepalive_secs: Option<u64>,
+    /// Encrypt stored values with this 32-byte key, given as 64 hex digits
+    #[arg(long, group = "encryption")]
+    encryption_key: Option<String>,
+    /// Read the encryption key from a file holding the raw 32 bytes or 64 hex digits
+    #[arg(long, group = "encryption")]
+    encryption_key_file: Option<PathBuf>,
+    /// Store keys as their HMAC as well; disables prefix scans
+    #[arg(long, requires = "encryption")]
+    hash_keys: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List every label that has written to the store, with its tip height
+    Labels,
 }
 
 const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
@@ -180,20 +215,13 @@ impl MetashrewKeyDBSync {
     pub async fn poll_connection(&self) -> redis::Connection {
         loop {
             let connected: Option<redis::Connection> =
-                match self.runtime.context.lock().unwrap().db.connect() {
+                match self.runtime.context.lock().unwrap().db.connect_checked() {
                     Err(_) => {
                         debug!("KeyDB connection failure -- retrying in 3s ...");
                         sleep(Duration::from_millis(3000)).await;
                         None
                     }
-                    Ok(mut v) => match v.get::<Vec<u8>, Vec<u8>>("POLL".into()) {
-                        Ok(_) => Some(v),
-                        Err(_) => {
-                            debug!("KeyDB connection failure -- retrying in 3s ...");
-                            sleep(Duration::from_millis(3000)).await;
-                            None
-                        }
-                    },
+                    Ok(v) => Some(v),
                 };
 
             if let Some(v) = connected {
@@ -376,12 +404,19 @@ impl MetashrewKeyDBSync {
                 Ok(v) => v,
                 Err(_) => i,
             };
-            self.runtime.context.lock().unwrap().block = self.pull_block(best).await.unwrap();
-            self.runtime.context.lock().unwrap().height = best;
+            let block = self.pull_block(best).await.unwrap();
+            self.runtime.context.lock().unwrap().begin_block(best, block);
             self.runtime.context.lock().unwrap().db.2 = best;
-            if let Err(_) = self.runtime.run() {
+            if let Err(e) = self.runtime.run() {
                 debug!("respawn cache");
-                self.runtime.refresh_memory();
+                self.runtime.refresh_memory().map_err(|r| {
+                    anyhow!(
+                        "block {} failed with {} and the instance could not be recreated: {:#}",
+                        best,
+                        e,
+                        r
+                    )
+                })?;
                 if let Err(e) = self.runtime.run() {
                     panic!("runtime run failed after retry: {}", e);
                 }
@@ -401,12 +436,33 @@ async fn main() {
     if let Some(ref label) = args.label {
         set_label(label.clone());
     }
+    set_write_retries(args.write_retries);
+    if let Some(ref key) = args.encryption_key {
+        set_encryption_key(parse_encryption_key(key).unwrap(), args.hash_keys).unwrap();
+    } else if let Some(ref path) = args.encryption_key_file {
+        set_encryption_key(read_encryption_key(path).unwrap(), args.hash_keys).unwrap();
+    }
+    if let Some(replicas) = args.wait_replicas {
+        set_wait_replicas(replicas, args.wait_timeout_ms, args.wait_every);
+    }
     let start_block = args.start_block.unwrap_or_else(|| 0);
     let indexer: PathBuf = args.indexer.clone().into();
     let redis_uri: String = args.redis.clone();
+    let adapter = RedisRuntimeAdapter::open(redis_uri).unwrap();
+    if let Some(Command::Labels) = args.command {
+        for (label, tip) in list_labels(&mut adapter.1.lock().unwrap()).unwrap() {
+            match tip {
+                Some(tip) => println!("{} {}", label, tip),
+                None => println!("{} (no tip)", label),
+            }
+        }
+        return;
+    }
+    if let Some(secs) = args.keepalive_secs {
+        adapter.start_keepalive(Duration::from_secs(secs));
+    }
     let mut sync = MetashrewKeyDBSync {
-        runtime: MetashrewRuntime::load(indexer, RedisRuntimeAdapter::open(redis_uri).unwrap())
-            .unwrap(),
+        runtime: MetashrewRuntime::load(indexer, adapter).unwrap(),
         args,
         start_block,
     };
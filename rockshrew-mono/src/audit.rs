@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::sync::atomic::Ordering;
+
+use super::IndexerState;
+
+/// The outcome of checking the blocks in `[from, to)` against the node.
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    from: u32,
+    to: u32,
+    // Heights below the tip with no stored block hash
+    missing: Vec<u32>,
+    // Heights whose stored block hash is not the node's
+    mismatched: Vec<u32>,
+}
+
+impl AuditReport {
+    pub fn passed(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl std::fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "audit of blocks {} through {}: {} checked, {} missing hashes, {} mismatched hashes: {}",
+            self.from,
+            self.to.saturating_sub(1),
+            self.to.saturating_sub(self.from),
+            self.missing.len(),
+            self.mismatched.len(),
+            if self.passed() { "PASS" } else { "FAIL" }
+        )
+    }
+}
+
+impl IndexerState {
+    // Compares the stored hash of each of the last --audit-depth blocks below `tip` with the
+    // node's. Every block below the tip was indexed after its hash was stored, so a missing
+    // hash is as much a failure as a different one.
+    pub async fn audit(&self, tip: u32) -> Result<AuditReport> {
+        let mut report = AuditReport {
+            from: tip.saturating_sub(self.args.audit_depth).max(self.start_block),
+            to: tip,
+            ..Default::default()
+        };
+        for height in report.from..report.to {
+            let stored = match self.get_blockhash(height).await {
+                Some(stored) => stored,
+                None => {
+                    log::error!("block {} is below the tip {} but has no stored hash", height, tip);
+                    report.missing.push(height);
+                    continue;
+                }
+            };
+            let expected = self.fetch_blockhash(height).await?;
+            if stored != expected {
+                log::error!(
+                    "block {} is stored as 0x{} but the node has 0x{}",
+                    height,
+                    hex::encode(&stored),
+                    hex::encode(&expected)
+                );
+                report.mismatched.push(height);
+            }
+        }
+        Ok(report)
+    }
+
+    // Runs the --audit-on-tip audit once, the first time `height` is past what the node has.
+    // The last polled count settles most calls without asking the node again.
+    pub async fn audit_at_tip(&self, height: u32, audited: &mut bool) -> Result<()> {
+        if !self.args.audit_on_tip || *audited {
+            return Ok(());
+        }
+        let needed = height.saturating_add(self.args.min_confirmations);
+        let polled = self.metrics.node_tip.load(Ordering::Relaxed);
+        if (polled > 0 && needed <= polled) || needed <= self.fetch_blockcount().await? {
+            return Ok(());
+        }
+        *audited = true;
+        let report = self.audit(height).await?;
+        if !report.passed() {
+            return Err(anyhow!("{}", report));
+        }
+        info!("{}", report);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{open_adapter, serve_fake_node, test_args, test_state, FakeNode};
+    use metashrew_runtime::KeyValueStoreLike;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn audit_on_tip_fails_on_an_inconsistent_store() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let url = serve_fake_node(node.clone()).await;
+        let args = test_args(&dir, &url, &["--audit-on-tip", "--audit-depth=5"]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 5;
+            for height in 0..=5 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+            }
+        }
+        for height in [0, 1, 4, 5] {
+            adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+        }
+        adapter.put(args.hash_key_format.key(2), vec![0xee; 32]).unwrap();
+        let state = test_state(args, adapter);
+
+        let report = state.audit(6).await.unwrap();
+        assert!(!report.passed());
+        assert_eq!((report.from, report.to), (1, 6));
+        assert_eq!((report.missing, report.mismatched), (vec![3], vec![2]));
+
+        // Nothing is audited until sync has indexed the node's tip, then only once
+        let mut audited = false;
+        state.audit_at_tip(5, &mut audited).await.unwrap();
+        assert!(!audited);
+        let error = state.audit_at_tip(6, &mut audited).await.unwrap_err();
+        assert!(audited);
+        assert!(error.to_string().ends_with("1 missing hashes, 1 mismatched hashes: FAIL"), "{}", error);
+        state.audit_at_tip(6, &mut audited).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn audit_passes_a_consistent_store() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let url = serve_fake_node(node.clone()).await;
+        let args = test_args(&dir, &url, &["--audit-on-tip"]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 2;
+            for height in 0..=2 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+            }
+        }
+        let state = test_state(args, adapter);
+        let mut audited = false;
+        state.audit_at_tip(3, &mut audited).await.unwrap();
+        assert!(audited);
+        let report = state.audit(3).await.unwrap();
+        assert!(report.passed());
+        assert_eq!(report.to.saturating_sub(report.from), 3);
+    }
+}
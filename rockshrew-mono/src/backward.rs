@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use metashrew_runtime::{supports_reverse, KeyValueStoreLike, REVERSE_EXPORT};
+use rockshrew_runtime::TIP_HEIGHT_KEY;
+
+use super::IndexerState;
+
+impl IndexerState {
+    // Feeds each height from `from` down to `to` through `execute`, since `run` would roll back
+    // the higher heights already scanned, and drops the tip every commit writes: a descending
+    // pass has no canonical tip to resume from
+    pub async fn backward_scan(&self, from: u32, to: u32) -> Result<()> {
+        if !supports_reverse(&self.runtime.lock().await.module) {
+            return Err(anyhow!(
+                "indexer does not export {} and so has not opted into reverse delivery",
+                REVERSE_EXPORT
+            ));
+        }
+        for height in (to..=from).rev() {
+            let blockhash = self.fetch_blockhash(height).await?;
+            let block = self.fetch_block_with_retry(&blockhash).await?;
+            let mut runtime = self.runtime.lock().await;
+            {
+                let mut context = runtime.context.lock().unwrap();
+                context.begin_block(height, block);
+                context.db.set_height(height);
+            }
+            runtime
+                .execute()
+                .map_err(|e| anyhow!("block {} failed with {}", height, e))?;
+            runtime
+                .context
+                .lock()
+                .unwrap()
+                .db
+                .delete(TIP_HEIGHT_KEY.as_bytes())?;
+            debug!("scanned block {} in reverse", height);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{
+        open_adapter, serve_fake_node, test_args, test_state, FakeNode, TRAP_AT_ONE,
+    };
+    use metashrew_runtime::{db_make_length_key, db_make_list_key, KeyValueStoreLike};
+    use rockshrew_runtime::TIP_HEIGHT_KEY;
+    use std::sync::Arc;
+
+    // Opts into reverse delivery and appends the little-endian height of each block it is given
+    // to the key "/o"
+    const RECORD_ORDER: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 196) "\0a\00\00\00\0a\02/o\0a\04")
+      (func (export "__reverse"))
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store (i32.const 206) (i32.load (i32.const 1000)))
+        (call $flush (i32.const 200))))"#;
+
+    #[tokio::test]
+    async fn backward_scan_delivers_blocks_in_descending_order() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 5;
+            for height in 0..=5 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![0; 80]);
+            }
+        }
+        std::fs::write(&args.indexer, TRAP_AT_ONE).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        let error = state.backward_scan(4, 1).await.unwrap_err();
+        assert!(error.to_string().contains("__reverse"), "{}", error);
+        drop(state);
+
+        std::fs::write(&args.indexer, RECORD_ORDER).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.backward_scan(4, 1).await.unwrap();
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        let key = b"/o".to_vec();
+        assert_eq!(db.get(db_make_length_key(&key).unwrap()).unwrap(), Some(4u32.to_le_bytes().to_vec()));
+        let delivered: Vec<u32> = (0..4)
+            .map(|index| {
+                let entry = db.get(db_make_list_key(&key, index).unwrap()).unwrap().unwrap();
+                u32::from_le_bytes(entry[..4].try_into().unwrap())
+            })
+            .collect();
+        assert_eq!(delivered, vec![4, 3, 2, 1]);
+        assert_eq!(db.get(TIP_HEIGHT_KEY.as_bytes()).unwrap(), None);
+    }
+}
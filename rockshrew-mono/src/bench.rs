@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use metashrew_runtime::bench::{run_write_bench, BenchConfig, BenchReport};
+use metashrew_runtime::MemStoreAdapter;
+use rocksdb::Options;
+use rockshrew_runtime::RocksDBRuntimeAdapter;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::IndexerState;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum BenchStore {
+    Memory,
+    Rocksdb,
+}
+
+pub fn print_bench_report(report: &BenchReport) {
+    println!(
+        "{} blocks, {} writes in {:.2}s: {:.1} blocks/s, {:.1} writes/s, p50 {:.3}ms, p99 {:.3}ms",
+        report.blocks,
+        report.writes,
+        report.elapsed.as_secs_f64(),
+        report.blocks_per_sec(),
+        report.writes_per_sec(),
+        report.p50.as_secs_f64() * 1000.0,
+        report.p99.as_secs_f64() * 1000.0,
+    );
+}
+
+// Runs the write bench against `store`, a RocksDB one at the scratch `path` that must not be
+// the live `db_path`
+pub fn run_bench(
+    store: BenchStore,
+    path: Option<&PathBuf>,
+    db_path: &str,
+    config: &BenchConfig,
+) -> Result<BenchReport> {
+    match store {
+        BenchStore::Memory => run_write_bench(MemStoreAdapter::new(), config),
+        BenchStore::Rocksdb => {
+            let path = path.ok_or_else(|| anyhow!("--path is required for the rocksdb bench store"))?;
+            if path == &PathBuf::from(db_path) {
+                return Err(anyhow!("refusing to benchmark against the live --db-path"));
+            }
+            let mut bench_opts = Options::default();
+            bench_opts.create_if_missing(true);
+            let adapter = RocksDBRuntimeAdapter::open(path.to_string_lossy().to_string(), bench_opts)?;
+            run_write_bench(adapter, config)
+        }
+    }
+}
+
+// Fetch throughput measured by benchmark-rpc-only
+pub struct RpcBenchReport {
+    blocks: u32,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl RpcBenchReport {
+    fn blocks_per_sec(&self) -> f64 {
+        self.blocks as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1_000_000.0 / self.elapsed.as_secs_f64()
+    }
+}
+
+pub fn print_rpc_bench_report(report: &RpcBenchReport) {
+    println!(
+        "{} blocks, {:.2} MB fetched in {:.2}s: {:.1} blocks/s, {:.2} MB/s",
+        report.blocks,
+        report.bytes as f64 / 1_000_000.0,
+        report.elapsed.as_secs_f64(),
+        report.blocks_per_sec(),
+        report.mb_per_sec(),
+    );
+}
+
+impl IndexerState {
+    // Fetches each block in [from, to] the way pull_block does, minus storing its hash, and
+    // discards it; neither the runtime nor the store is touched
+    pub async fn benchmark_rpc(&self, from: u32, to: u32) -> Result<RpcBenchReport> {
+        let started = Instant::now();
+        let mut bytes: u64 = 0;
+        for height in from..=to {
+            let blockhash = self.fetch_blockhash(height).await?;
+            bytes += self.fetch_block_with_retry(&blockhash).await?.len() as u64;
+        }
+        Ok(RpcBenchReport {
+            blocks: to - from + 1,
+            bytes,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{open_adapter, serve_fake_node, test_args, test_state, FakeNode};
+    use metashrew_runtime::KeyValueStoreLike;
+    use rockshrew_runtime::TIP_HEIGHT_KEY;
+    use std::sync::Arc;
+
+    #[test]
+    fn rocksdb_bench_needs_a_scratch_path() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let live = dir.path().join("db");
+        let config = BenchConfig {
+            blocks: 5,
+            writes_per_block: 4,
+            value_size: 8,
+        };
+        assert!(run_bench(BenchStore::Rocksdb, None, &live.to_string_lossy(), &config).is_err());
+        assert!(run_bench(BenchStore::Rocksdb, Some(&live), &live.to_string_lossy(), &config).is_err());
+        assert!(!live.exists());
+
+        let scratch = dir.path().join("bench");
+        let report = run_bench(BenchStore::Rocksdb, Some(&scratch), &live.to_string_lossy(), &config).unwrap();
+        assert_eq!((report.blocks, report.writes), (5, 20));
+        let report = run_bench(BenchStore::Memory, None, &live.to_string_lossy(), &config).unwrap();
+        assert_eq!((report.blocks, report.writes), (5, 20));
+    }
+
+    #[tokio::test]
+    async fn benchmark_rpc_only_fetches_without_indexing() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        // Traps on any block, so a block reaching the runtime would fail the run
+        std::fs::write(&args.indexer, r#"(module (memory (export "memory") 1) (func (export "_start") unreachable))"#).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 9;
+            for height in 0..=9 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![height as u8; 1000]);
+            }
+        }
+        let state = test_state(args.clone(), open_adapter(&args));
+        let report = state.benchmark_rpc(2, 4).await.unwrap();
+        assert_eq!((report.blocks, report.bytes), (3, 3000));
+        assert!(report.blocks_per_sec() > 0.0 && report.mb_per_sec() > 0.0);
+        {
+            let node = node.lock().unwrap();
+            assert_eq!((node.calls["getblockhash"], node.calls["getblock"]), (3, 3));
+        }
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        assert_eq!(db.get(TIP_HEIGHT_KEY.as_bytes()).unwrap(), None);
+        assert!((2..=4).all(|height| db.get(args.hash_key_format.key(height)).unwrap().is_none()));
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::Result;
+use log::info;
+use metashrew_runtime::MetashrewRuntime;
+use rockshrew_runtime::history::{downsample_page, HistoryResolution};
+use rockshrew_runtime::{query_height, RocksDBRuntimeAdapter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// How long --history-resolution waits between compaction passes, and how many keys it rewrites
+// per hold of the runtime lock
+const HISTORY_COMPACT_INTERVAL: Duration = Duration::from_secs(600);
+const HISTORY_PAGE_SIZE: usize = 256;
+
+// Periodically walks the keyspace applying --history-resolution
+pub async fn compact_history(
+    runtime: Arc<Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
+    resolution: HistoryResolution,
+    start_block: u32,
+) -> Result<()> {
+    loop {
+        tokio::time::sleep(HISTORY_COMPACT_INTERVAL).await;
+        let dropped = compact_pass(&runtime, resolution, start_block).await?;
+        info!("history compaction dropped {} versions", dropped);
+    }
+}
+
+// One walk over the keyspace, returning the number of versions dropped. The runtime lock is
+// held for one page of keys at a time, so blocks are never committed while a key is being
+// rewritten
+async fn compact_pass(
+    runtime: &Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>,
+    resolution: HistoryResolution,
+    start_block: u32,
+) -> Result<u64> {
+    let mut after: Option<Vec<u8>> = None;
+    let mut dropped: u64 = 0;
+    loop {
+        let runtime = runtime.lock().await;
+        let db = runtime.context.lock().unwrap().db.clone();
+        let tip = query_height(db.db.clone(), start_block).await?;
+        let (count, next) =
+            downsample_page(&db, after.as_deref(), HISTORY_PAGE_SIZE, resolution, tip)?;
+        dropped += count;
+        match next {
+            Some(key) => after = Some(key),
+            None => return Ok(dropped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{open_adapter, test_args};
+    use metashrew_runtime::{db_make_length_key, db_make_list_key, KeyValueStoreLike};
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn compact_pass_downsamples_every_page() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let mut adapter = open_adapter(&args);
+        let heights = [1u32, 5, 9, 12, 18, 25, 31, 35];
+        let keys: Vec<Vec<u8>> = (0..HISTORY_PAGE_SIZE + 44)
+            .map(|n| format!("/history/{:04}", n).into_bytes())
+            .collect();
+        for key in &keys {
+            for (index, height) in heights.iter().enumerate() {
+                let mut value = vec![index as u8];
+                value.extend(height.to_le_bytes());
+                adapter.put(db_make_list_key(key, index as u32).unwrap(), value).unwrap();
+            }
+            adapter
+                .put(db_make_length_key(key).unwrap(), (heights.len() as u32).to_le_bytes())
+                .unwrap();
+        }
+        adapter.write_tip_height(35).unwrap();
+        let runtime = Mutex::new(MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap());
+
+        // Cutoff is 35 - 10 = 25: 9 and 18 close their buckets, so 1, 5 and 12 go
+        let resolution = HistoryResolution { recent: 10, every: 10 };
+        assert_eq!(compact_pass(&runtime, resolution, 0).await.unwrap(), 3 * keys.len() as u64);
+        let runtime = runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        for key in &keys {
+            assert_eq!(db.get(db_make_length_key(key).unwrap()).unwrap(), Some(5u32.to_le_bytes().to_vec()));
+            let first = db.get(db_make_list_key(key, 0).unwrap()).unwrap().unwrap();
+            assert_eq!(first[1..], 9u32.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use itertools::Itertools;
+use log::info;
+use metashrew_runtime::KeyValueStoreLike;
+use rockshrew_runtime::RocksDBRuntimeAdapter;
+use serde::{Deserialize, Serialize};
+
+use super::{HashKeyFormat, IndexerState, DEAD_LETTERS, QUARANTINE};
+
+#[derive(Subcommand, Debug)]
+pub enum DlqCommand {
+    /// Print every dead-lettered block with its hash, attempt count and last error
+    List,
+    /// Index a dead-lettered block again, dropping it from the queue and the quarantine list once it succeeds
+    Retry {
+        #[arg(long)]
+        height: u32,
+        /// Confirm the indexer's state for a block does not depend on the blocks after it
+        #[arg(long)]
+        gap_safe: bool,
+    },
+}
+
+// The quarantine list is a flat run of entries, each a little-endian u32 height followed by
+// the 32-byte block hash, zeroed when the hash was never stored.
+const QUARANTINE_ENTRY_LEN: usize = 4 + 32;
+
+pub fn quarantine_block(
+    db: &mut RocksDBRuntimeAdapter,
+    height: u32,
+    format: HashKeyFormat,
+) -> Result<u32> {
+    let mut blockhash = db.get(format.key(height))?.unwrap_or_default();
+    blockhash.resize(QUARANTINE_ENTRY_LEN - 4, 0);
+    let mut list = db.get(QUARANTINE.as_bytes())?.unwrap_or_default();
+    let height_bytes = height.to_le_bytes();
+    let already_listed = list
+        .chunks(QUARANTINE_ENTRY_LEN)
+        .any(|entry| entry.starts_with(&height_bytes));
+    if !already_listed {
+        list.extend(height_bytes);
+        list.extend(&blockhash);
+        db.put(QUARANTINE.as_bytes(), &list)?;
+    }
+    Ok((list.len() / QUARANTINE_ENTRY_LEN) as u32)
+}
+
+// Drops `height` from the quarantine list, returning whether it was listed
+pub fn unquarantine_block(db: &mut RocksDBRuntimeAdapter, height: u32) -> Result<bool> {
+    let list = match db.get(QUARANTINE.as_bytes())? {
+        Some(list) => list,
+        None => return Ok(false),
+    };
+    let height_bytes = height.to_le_bytes();
+    let kept: Vec<u8> = list
+        .chunks(QUARANTINE_ENTRY_LEN)
+        .filter(|entry| !entry.starts_with(&height_bytes))
+        .flatten()
+        .copied()
+        .collect();
+    if kept.len() == list.len() {
+        return Ok(false);
+    }
+    db.put(QUARANTINE.as_bytes(), &kept)?;
+    Ok(true)
+}
+
+/// A block that failed deterministically and was skipped, kept until it is retried successfully.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub height: u32,
+    pub blockhash: String,
+    pub error: String,
+    pub attempts: u32,
+}
+
+// Dead letters are keyed by big-endian height so a scan visits them in height order
+fn dead_letter_key(height: u32) -> Vec<u8> {
+    let mut key = DEAD_LETTERS.as_bytes().to_vec();
+    key.extend(height.to_be_bytes());
+    key
+}
+
+// Records a failure of `height`, counting it as one more attempt if the block is already queued
+pub fn record_dead_letter(
+    db: &mut RocksDBRuntimeAdapter,
+    height: u32,
+    format: HashKeyFormat,
+    error: &str,
+) -> Result<DeadLetter> {
+    let key = dead_letter_key(height);
+    let attempts = match db.get(&key)? {
+        Some(value) => serde_json::from_slice::<DeadLetter>(&value)?.attempts,
+        None => 0,
+    };
+    let letter = DeadLetter {
+        height,
+        blockhash: hex::encode(db.get(format.key(height))?.unwrap_or_else(|| vec![0; 32])),
+        error: error.to_string(),
+        attempts: attempts + 1,
+    };
+    db.put(&key, serde_json::to_vec(&letter)?)?;
+    Ok(letter)
+}
+
+pub fn dead_letters(db: &mut RocksDBRuntimeAdapter) -> Result<Vec<DeadLetter>> {
+    let mut letters: Vec<DeadLetter> = vec![];
+    for (_, value) in db.scan_prefix(DEAD_LETTERS.as_bytes())? {
+        letters.push(serde_json::from_slice(&value)?);
+    }
+    Ok(letters)
+}
+
+impl IndexerState {
+    pub async fn run_dlq(&self, action: &DlqCommand) -> Result<()> {
+        match action {
+            DlqCommand::List => {
+                let runtime = self.runtime.lock().await;
+                let letters = dead_letters(&mut runtime.context.lock().unwrap().db)?;
+                for letter in &letters {
+                    println!(
+                        "{} {} attempts={} {}",
+                        letter.height,
+                        letter.blockhash,
+                        letter.attempts,
+                        letter.error.split_whitespace().join(" ")
+                    );
+                }
+                info!("{} blocks in the dead-letter queue", letters.len());
+            }
+            DlqCommand::Retry { height, gap_safe } => {
+                self.retry_dead_letter(*height, *gap_safe).await?;
+                info!("block {} indexed and removed from the dead-letter queue", height);
+            }
+        }
+        Ok(())
+    }
+
+    async fn retry_dead_letter(&self, height: u32, gap_safe: bool) -> Result<()> {
+        let queued = {
+            let runtime = self.runtime.lock().await;
+            let mut context = runtime.context.lock().unwrap();
+            context.db.get(dead_letter_key(height))?.is_some()
+        };
+        if !queued {
+            return Err(anyhow!("block {} is not in the dead-letter queue", height));
+        }
+        if !gap_safe {
+            return Err(anyhow!(
+                "block {} is behind the tip, so retrying it appends its values after later blocks'; refusing without --gap-safe",
+                height
+            ));
+        }
+        if let Err(e) = self.fill_gaps(&[height]).await {
+            let runtime = self.runtime.lock().await;
+            let letter = record_dead_letter(
+                &mut runtime.context.lock().unwrap().db,
+                height,
+                self.args.hash_key_format,
+                &format!("{:#}", e),
+            )?;
+            return Err(anyhow!("retry {} of block {} failed: {:#}", letter.attempts, height, e));
+        }
+        let runtime = self.runtime.lock().await;
+        let mut context = runtime.context.lock().unwrap();
+        context.db.delete(dead_letter_key(height))?;
+        unquarantine_block(&mut context.db, height)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        open_adapter, serve_fake_node, test_args, test_state, FakeNode, TRAP_AT_ONE,
+    };
+    use metashrew_runtime::MetashrewRuntime;
+    use std::sync::Arc;
+
+    // TRAP_AT_ONE with the trap fixed: flushes nothing at every height
+    const FLUSH_NOTHING: &str = r#"(module
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\00\00\00\00")
+      (func (export "_start")
+        (call $flush (i32.const 100))))"#;
+
+    #[tokio::test]
+    async fn quarantine_skips_a_failing_block_and_keeps_syncing() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--quarantine-bad-blocks"]);
+        std::fs::write(&args.indexer, TRAP_AT_ONE).unwrap();
+        let mut adapter = open_adapter(&args);
+        adapter.put(args.hash_key_format.key(1), [0xab; 32]).unwrap();
+        let state = test_state(args.clone(), adapter);
+        for height in 0..3 {
+            state.index_block(height, vec![0; 80]).await.unwrap();
+        }
+
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        let entry = |height: u32, hash: u8| [&height.to_le_bytes()[..], &[hash; 32]].concat();
+        assert_eq!(db.get(QUARANTINE.as_bytes()).unwrap(), Some(entry(1, 0xab)));
+        let letters = dead_letters(db).unwrap();
+        assert_eq!((letters.len(), letters[0].height, letters[0].attempts), (1, 1, 1));
+        assert_eq!(db.get(args.hash_key_format.key(2)).unwrap(), None);
+
+        // A block with no stored hash is listed with a zeroed one, and each height only once
+        assert_eq!(quarantine_block(db, 2, args.hash_key_format).unwrap(), 2);
+        assert_eq!(quarantine_block(db, 1, args.hash_key_format).unwrap(), 2);
+        assert!(unquarantine_block(db, 1).unwrap());
+        assert!(!unquarantine_block(db, 1).unwrap());
+        assert_eq!(db.get(QUARANTINE.as_bytes()).unwrap(), Some(entry(2, 0)));
+    }
+
+    #[tokio::test]
+    async fn retry_reindexes_a_dead_letter_once_the_indexer_is_fixed() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--quarantine-bad-blocks"]);
+        std::fs::write(&args.indexer, TRAP_AT_ONE).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 2;
+            node.hashes.insert(1, vec![0xab; 32]);
+            node.blocks.insert(vec![0xab; 32], vec![0; 80]);
+        }
+        let mut adapter = open_adapter(&args);
+        adapter.put(args.hash_key_format.key(1), [0xab; 32]).unwrap();
+        let state = test_state(args.clone(), adapter);
+        for height in 0..3 {
+            state.index_block(height, vec![0; 80]).await.unwrap();
+        }
+        let attempts = || async {
+            let runtime = state.runtime.lock().await;
+            let letters = dead_letters(&mut runtime.context.lock().unwrap().db).unwrap();
+            letters.iter().map(|letter| letter.attempts).collect::<Vec<u32>>()
+        };
+
+        assert!(state.retry_dead_letter(2, true).await.is_err());
+        assert!(state.retry_dead_letter(1, false).await.is_err());
+        assert_eq!(attempts().await, vec![1]);
+
+        // Still trapping, the retry counts as another attempt
+        assert!(state.retry_dead_letter(1, true).await.is_err());
+        assert_eq!(attempts().await, vec![2]);
+
+        let fixed = dir.path().join("fixed.wat");
+        std::fs::write(&fixed, FLUSH_NOTHING).unwrap();
+        {
+            let mut runtime = state.runtime.lock().await;
+            let db = runtime.context.lock().unwrap().db.clone();
+            *runtime = MetashrewRuntime::load(fixed, db).unwrap();
+        }
+        state.retry_dead_letter(1, true).await.unwrap();
+        assert_eq!(attempts().await, Vec::<u32>::new());
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        assert_eq!(db.get(QUARANTINE.as_bytes()).unwrap(), Some(vec![]));
+    }
+}
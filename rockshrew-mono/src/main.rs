@@ -1,27 +1,110 @@
 use actix_cors::Cors;
 use actix_web::error;
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder, Result as ActixResult};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, Result as ActixResult};
 use anyhow::{anyhow, Result};
-use clap::{Parser};
+use clap::{Parser, Subcommand};
 use env_logger;
 use hex;
 use itertools::Itertools;
 use log::{debug, info};
-use rockshrew_runtime::{query_height, set_label, RocksDBRuntimeAdapter};
-use metashrew_runtime::{KeyValueStoreLike, MetashrewRuntime};
+use rockshrew_runtime::codec::KeyCodec;
+use rockshrew_runtime::history::HistoryResolution;
+use rockshrew_runtime::{
+    get_chain_id, get_label, has_label, normalize_label, query_height, set_chain_id, set_label,
+    snapshot, RocksDBRuntimeAdapter, TIP_HEIGHT_KEY,
+};
+use metashrew_runtime::bench::BenchConfig;
+use metashrew_runtime::sandbox::{Sandbox, SandboxWorker};
+use metashrew_runtime::{
+    db_make_length_key, db_make_list_key, u32_to_vec, KeyValueStoreLike, MemStoreAdapter,
+    MetashrewRuntime, OverlayAdapter, RuntimeError, ORPHANS_PREFIX,
+};
+#[cfg(feature = "block-parser")]
+use metashrew_runtime::TxFilter;
 use rocksdb::{Options};
 use reqwest::{Response, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Number, Value};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio;
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use tiny_keccak::{Hasher, Sha3};
+use tokio::sync::{watch, Mutex};
+use tracing::Instrument;
+
+mod audit;
+mod backward;
+mod bench;
+mod compact;
+mod dlq;
+mod rest;
+mod stream;
+mod telemetry;
+mod watchdog;
+
+use bench::{print_bench_report, print_rpc_bench_report, run_bench, BenchStore};
+use compact::compact_history;
+use dlq::{quarantine_block, record_dead_letter, DlqCommand};
+use rest::{handle_rest_height, handle_rest_key, handle_rest_view, handle_scan};
+use stream::{BlockSource, DirBlockSource, StreamBlockSource};
+use watchdog::{spawn_loop_watchdog, Liveness, WatchdogAction};
 
 const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
+const HEIGHT_TO_HASH_BINARY: &str = "/__INTERNAL/height-to-hash-be/";
+const BLOCK_CHECKSUM: &'static str = "/__INTERNAL/block-checksum/";
+const QUARANTINE: &'static str = "/__INTERNAL/quarantine";
+const DEAD_LETTERS: &str = "/__INTERNAL/dlq/";
+const REORG_LOG: &str = "/__INTERNAL/reorg-log/";
+// How many of the latest reorgs metashrew_reorgs returns when not given a count
+const DEFAULT_REORGS: usize = 100;
+const MEMPOOL_PREFIX: &'static str = "/__MEMPOOL/";
+const ASSERT_PREFIX: &str = "/__ASSERT/";
+const STATE_ROOT: &'static str = "/__INTERNAL/stateroot/";
+const SHUTDOWN_KEY: &str = "/__INTERNAL/shutdown/";
+// How often --watch-shutdown-key reads the shutdown key
+const SHUTDOWN_KEY_POLL: Duration = Duration::from_secs(5);
+// How far below the node's tip best_height compares block hashes to detect a reorg
+const REORG_WINDOW: u32 = 6;
+// Set in a --sandbox worker's environment; the worker is this binary spawned with the same args
+const SANDBOX_WORKER_ENV: &str = "ROCKSHREW_SANDBOX_WORKER";
+// Log target for --trace-rpc, so RUST_LOG=rpc=debug shows only node traffic
+const TRACE_TARGET: &str = "rpc";
+const TRACE_PREVIEW_LEN: usize = 256;
 static mut _HEIGHT: u32 = 0;
 
+// The next height to index, which the query server treats as "latest"
+fn current_height() -> u32 {
+    unsafe { _HEIGHT }
+}
+
+// What metashrew_info reports for an indexer running under `label` and `chain_id`. The key
+// prefix is the one to_labeled_key puts in front of every key
+fn server_info(label: Option<&str>, chain_id: Option<u8>, indexer_hash: &[u8], height: u32) -> Value {
+    let mut key_prefix: Vec<u8> = chain_id.into_iter().collect();
+    if let Some(label) = label {
+        key_prefix.extend(format!("{}://", label).into_bytes());
+    }
+    serde_json::json!({
+        "label": label,
+        "chain_id": chain_id,
+        "internal_prefix": "/__INTERNAL/",
+        "key_prefix": format!("0x{}", hex::encode(key_prefix)),
+        "indexer_hash": format!("0x{}", hex::encode(indexer_hash)),
+        "version": env!("CARGO_PKG_VERSION"),
+        "height": height,
+    })
+}
+
+fn set_current_height(h: u32) {
+    unsafe {
+        _HEIGHT = h;
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -37,18 +120,944 @@ struct Args {
     auth: Option<String>,
     #[arg(long)]
     label: Option<String>,
+    /// Lowercase --label so differently-cased spellings share a namespace
+    #[arg(long)]
+    lowercase_label: bool,
+    /// Prefix every key, internal ones included, with this chain id byte ahead of any label
+    #[arg(long)]
+    chain_id: Option<u8>,
     #[arg(long)]
     exit_at: Option<u32>,
+    /// The first time sync catches up with the node, audit the last --audit-depth blocks and stop if the audit fails
+    #[arg(long, conflicts_with = "no_reorg_tracking")]
+    audit_on_tip: bool,
+    /// Number of blocks below the tip whose stored hashes an audit checks against the node
+    #[arg(long, default_value_t = 100)]
+    audit_depth: u32,
+    /// Treat this height as the chain tip: never fetch past it, and exit once it is indexed
+    #[arg(long)]
+    pin_tip: Option<u32>,
+    /// Only index a block once the node's tip is at least this many blocks past it
+    #[arg(long, default_value_t = 0)]
+    min_confirmations: u32,
+    /// Log every JSON-RPC call to the node and a preview of its response at the rpc target
+    #[arg(long)]
+    trace_rpc: bool,
     // JSON-RPC server args
     #[arg(long, env = "HOST", default_value = "127.0.0.1")]
     host: String,
     #[arg(long, env = "PORT", default_value_t = 8080)]
     port: u16,
+    /// Store a SHA3-256 checksum of each indexed block's bytes for later verification
+    #[arg(long)]
+    store_block_checksums: bool,
+    /// Maximum number of JSON-RPC requests per second sent to the node
+    #[arg(long)]
+    rpc_max_rps: Option<u32>,
+    /// Only persist the tip height every N blocks; up to N-1 blocks are re-indexed after a crash
+    #[arg(long, default_value_t = 1)]
+    commit_tip_every: u32,
+    /// Hold back up to this many consecutive blocks that write nothing and commit them, tip
+    /// included, in one write; they are re-indexed after a crash
+    #[arg(long, conflicts_with = "sandbox")]
+    coalesce_empty_blocks: Option<u32>,
+    /// Log each block's batch to a write-ahead log in this directory and fsync it before the
+    /// store commit; a log left by a crash is replayed on startup
+    #[arg(long)]
+    wal_dir: Option<PathBuf>,
+    /// Downsample old history as <recent>:<every>: keep every version from the last <recent>
+    /// blocks and only the last version in each <every>-block bucket below that
+    #[arg(long)]
+    history_resolution: Option<HistoryResolution>,
+    /// Compact the whole database before indexing starts
+    #[arg(long)]
+    compact_on_start: bool,
+    /// Serve metashrew_internal, which returns raw /__INTERNAL/ bookkeeping values
+    #[arg(long)]
+    expose_internal: bool,
+    /// Serve metashrew_reload, which swaps in a new indexer module between blocks
+    #[arg(long)]
+    allow_reload: bool,
+    /// Also serve GET /v1/key/{hex}, /v1/height and /v1/view/{name} next to JSON-RPC
+    #[arg(long)]
+    rest: bool,
+    /// Largest raw result, in bytes, returned by metashrew_view or metashrew_internal
+    #[arg(long)]
+    max_response_bytes: Option<usize>,
+    /// What to do with a result over --max-response-bytes
+    #[arg(long, value_enum, default_value_t = ResponseOverflow::Error)]
+    response_overflow: ResponseOverflow,
+    /// Refuse views, scans and key lookups at heights more than this many blocks below the tip
+    #[arg(long)]
+    max_history_depth: Option<u32>,
+    /// Layout of the per-height block hash keys; switch an existing database with migrate-hash-keys
+    #[arg(long, value_enum, default_value_t = HashKeyFormat::String)]
+    hash_key_format: HashKeyFormat,
+    /// On a reorg, copy the orphaned blocks' hashes and reverted values under /__INTERNAL/orphans/
+    #[arg(long, conflicts_with = "no_reorg_tracking")]
+    keep_orphans: bool,
+    /// Also append each reorg recorded under /__INTERNAL/reorg-log/ to this file as a JSON line
+    #[arg(long, conflicts_with = "no_reorg_tracking")]
+    reorg_log: Option<PathBuf>,
+    /// Never record block hashes or check for reorgs; only for chains that cannot reorg
+    #[arg(long)]
+    no_reorg_tracking: bool,
+    /// Skip reorg detection, and the node round trips it costs, while indexing below this height
+    #[arg(long)]
+    trusted_height: Option<u32>,
+    /// Interrupt a block whose indexer runs longer than this, then retry it on fresh memory
+    #[arg(long)]
+    block_timeout_secs: Option<u64>,
+    /// Treat the sync loop as stuck when it reports no progress for this many seconds, whether
+    /// it is waiting on the node, the store lock or a block; must exceed the slowest block
+    #[arg(long, conflicts_with = "block_stream")]
+    loop_watchdog_secs: Option<u64>,
+    /// What --loop-watchdog-secs does once the sync loop is stuck
+    #[arg(long, value_enum, default_value_t = WatchdogAction::Abort, requires = "loop_watchdog_secs")]
+    loop_watchdog_action: WatchdogAction,
+    /// Trap any block whose indexer makes more than this many __get/__get_len store reads
+    #[arg(long)]
+    max_reads_per_block: Option<u32>,
+    /// Only let the indexer write keys starting with this hex prefix; may be repeated. A block
+    /// that flushes any other key is rejected
+    #[arg(long)]
+    write_prefix: Vec<String>,
+    /// Trap any block whose indexer flushes more than this many key and value bytes
+    #[arg(long)]
+    max_write_bytes_per_block: Option<u64>,
+    /// Grow the indexer's memory to this many MiB and touch every page of it on each new instance
+    #[arg(long)]
+    prealloc_memory_mb: Option<u64>,
+    /// Only expose transactions matching this predicate (op-return or taproot) through the
+    /// block parser imports
+    #[cfg(feature = "block-parser")]
+    #[arg(long)]
+    tx_filter: Option<TxFilter>,
+    /// Run each block's indexer in a worker process that is restarted if it dies
+    #[arg(long, conflicts_with = "allow_reload")]
+    sandbox: bool,
+    /// Record blocks that fail deterministically under /__INTERNAL/quarantine and keep syncing
+    #[arg(long)]
+    quarantine_bad_blocks: bool,
+    /// Whenever a block fails, write it to <height>.blk in this directory and its height, label,
+    /// error and staged writes to <height>.json
+    #[arg(long)]
+    crash_dump_dir: Option<PathBuf>,
+    /// Exit with an error once more than this many blocks have been quarantined
+    #[arg(long, default_value_t = 10)]
+    quarantine_max: u32,
+    /// After each block, store a root committing to its changed keys under /__INTERNAL/stateroot/<height>
+    #[arg(long)]
+    compute_state_root: bool,
+    /// File of `<height> <hex state root>` lines; abort if the computed root at a listed height differs
+    #[arg(long, requires = "compute_state_root")]
+    checkpoints: Option<PathBuf>,
+    /// How many times to retry downloading a block body before giving up on the iteration
+    #[arg(long, default_value_t = 5)]
+    block_fetch_retries: u32,
+    /// Initial delay between getblockcount polls once caught up to the node's tip
+    #[arg(long, default_value_t = 3000)]
+    poll_interval_ms: u64,
+    /// Cap the poll delay grows to while no new block appears
+    #[arg(long, default_value_t = 30000)]
+    poll_interval_max_ms: u64,
+    /// Also index unconfirmed transactions into a separate namespace, served via height "pending"
+    #[arg(long)]
+    index_mempool: bool,
+    /// Seconds between mempool polls
+    #[arg(long, default_value_t = 10)]
+    mempool_interval: u64,
+    /// Seconds after the last successful poll before mempool state is considered expired
+    #[arg(long, default_value_t = 60)]
+    mempool_ttl: u64,
+    /// Snapshot to load at startup if the store has no recorded tip yet
+    #[arg(long)]
+    bootstrap_snapshot: Option<PathBuf>,
+    /// Resume sync at this height instead of the stored tip; requires --i-know-what-im-doing
+    #[arg(long)]
+    resume_height: Option<u32>,
+    /// If the stored tip is missing or malformed, rebuild it from the stored block hashes
+    #[arg(long, conflicts_with_all = ["resume_height", "no_reorg_tracking"])]
+    recover_tip: bool,
+    /// Confirm overrides that can leave the store inconsistent, such as --resume-height
+    #[arg(long)]
+    i_know_what_im_doing: bool,
+    /// Read blocks as binary frames from this host:port instead of fetching them over JSON-RPC
+    #[arg(long)]
+    block_stream: Option<String>,
+    /// Shut down gracefully once /__INTERNAL/shutdown/<label> is set to a truthy value
+    #[arg(long)]
+    watch_shutdown_key: bool,
+    /// Read blocks from files in this directory instead of the node, without any RPC
+    #[arg(long, conflicts_with = "block_stream")]
+    block_dir: Option<PathBuf>,
+    /// What --block-dir does on reaching a height it has no file for
+    #[arg(long, value_enum, default_value_t = MissingBlock::Stop, requires = "block_dir")]
+    on_missing_block: MissingBlock,
+    /// What to do when the node reports a tip below the last indexed block, e.g. after being
+    /// swapped for a freshly started or pruned node
+    #[arg(long, value_enum, default_value_t = NodeBehind::Wait)]
+    on_node_behind: NodeBehind,
+    /// Extra HTTP header sent with every node RPC request, as "Name: Value"; may be repeated
+    #[arg(long)]
+    rpc_header: Vec<String>,
+    /// JSON-RPC method that returns the node's block count, for nodes that rename it
+    #[arg(long, default_value = "getblockcount")]
+    rpc_method_getblockcount: String,
+    /// JSON-RPC method that returns the hash of the block at a height
+    #[arg(long, default_value = "getblockhash")]
+    rpc_method_getblockhash: String,
+    /// JSON-RPC method that returns a raw block, given its hash and verbosity 0
+    #[arg(long, default_value = "getblock")]
+    rpc_method_getblock: String,
+    /// Write a snapshot to --dump-dir every N committed blocks
+    #[arg(long, requires = "dump_dir")]
+    dump_interval: Option<u32>,
+    /// Directory for periodic snapshots
+    #[arg(long)]
+    dump_dir: Option<PathBuf>,
+    /// Number of periodic snapshots to retain
+    #[arg(long, default_value_t = 3)]
+    dump_keep: usize,
+    /// Write the process id here at startup, refusing to start if the file already exists. On
+    /// Linux a file naming a process that is gone is reclaimed instead
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+    /// Table names for tooling output, as comma-separated `<hex byte>=<name>` pairs (e.g. 01=utxo)
+    #[arg(long)]
+    key_codec: Option<String>,
+    /// Export fetch/execute/commit spans for every block to this OTLP/gRPC collector
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Warn when a block's header time is more than this many seconds from the previous block's
+    #[arg(long)]
+    max_clock_skew: Option<u32>,
+    /// Fail instead of warning when --max-clock-skew is exceeded
+    #[arg(long, requires = "max_clock_skew")]
+    reject_clock_skew: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Refetch indexed blocks from the node and compare them against their stored checksums
+    VerifyBlocks {
+        #[arg(long)]
+        from: Option<u32>,
+        #[arg(long)]
+        to: Option<u32>,
+    },
+    /// Index a block range twice into scratch in-memory stores and compare the resulting keyspaces
+    VerifyDeterminism {
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+    },
+    /// Index only the heights in a range that have no stored blockhash, leaving the rest alone
+    FillGaps {
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+        /// Confirm the indexer's state for a block does not depend on the blocks after it
+        #[arg(long)]
+        gap_safe: bool,
+    },
+    /// Feed blocks from --from down to --to to a reverse-capable indexer under label --into, for analytics
+    BackwardScan {
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+        #[arg(long)]
+        into: String,
+    },
+    /// Write the label's full keyspace to a snapshot file
+    Snapshot {
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Stream the label's keyspace as newline-delimited JSON with hex-encoded keys and values
+    ExportJsonl {
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Write the pairs under a table prefix to a Parquet file with key, value and table columns
+    #[cfg(feature = "parquet")]
+    ExportParquet {
+        #[arg(long)]
+        out: PathBuf,
+        /// Hex-encoded key prefix, relative to the label
+        #[arg(long, default_value = "")]
+        table: String,
+    },
+    /// Print key/value pairs under a prefix, with keys rendered through --key-codec
+    Dump {
+        /// Hex-encoded key prefix, relative to the label
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long)]
+        limit: Option<u64>,
+    },
+    /// Report key count and value sizes per table-id byte, named through --key-codec
+    Stats,
+    /// Rewrite every stored block hash key from the other layout into --hash-key-format
+    MigrateHashKeys,
+    /// Compare the keyspaces of two labels in the same database
+    DiffLabels {
+        #[arg(long)]
+        a: String,
+        #[arg(long)]
+        b: String,
+        /// Also print every differing key with its values
+        #[arg(long)]
+        detail: bool,
+    },
+    /// Run a block file through the indexer in a scratch overlay and compare its writes against a golden JSONL file
+    Assert {
+        #[arg(long)]
+        block: PathBuf,
+        #[arg(long)]
+        height: u32,
+        #[arg(long)]
+        expect: PathBuf,
+        /// Overwrite the golden file with this run's writes instead of comparing
+        #[arg(long)]
+        update: bool,
+    },
+    /// Inspect or reprocess quarantined blocks in the dead-letter queue
+    Dlq {
+        #[command(subcommand)]
+        action: DlqCommand,
+    },
+    /// Check the stored hashes of the last --audit-depth blocks against the node and report pass or fail
+    Audit,
+    /// Report which RPC methods and optional interfaces the node supports, and flags that suit it
+    Probe,
+    /// Measure commit throughput with synthetic blocks against a scratch store
+    Bench {
+        #[arg(long, value_enum, default_value_t = BenchStore::Memory)]
+        store: BenchStore,
+        /// Scratch RocksDB directory, required with --store rocksdb; never the live --db-path
+        #[arg(long)]
+        path: Option<PathBuf>,
+        #[arg(long, default_value_t = 1000)]
+        blocks: u32,
+        #[arg(long, default_value_t = 1000)]
+        writes_per_block: u32,
+        #[arg(long, default_value_t = 32)]
+        value_size: usize,
+    },
+    /// Fetch a block range through the normal RPC path without indexing or storing it, and
+    /// report fetch throughput
+    BenchmarkRpcOnly {
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum HashKeyFormat {
+    /// HEIGHT_TO_HASH followed by the decimal height
+    String,
+    /// HEIGHT_TO_HASH_BINARY followed by the big-endian u32 height, so keys sort by height
+    Binary,
+}
+
+impl HashKeyFormat {
+    fn prefix(self) -> &'static str {
+        match self {
+            HashKeyFormat::String => HEIGHT_TO_HASH,
+            HashKeyFormat::Binary => HEIGHT_TO_HASH_BINARY,
+        }
+    }
+
+    fn key(self, height: u32) -> Vec<u8> {
+        let mut key = self.prefix().as_bytes().to_vec();
+        match self {
+            HashKeyFormat::String => key.extend(height.to_string().into_bytes()),
+            HashKeyFormat::Binary => key.extend(height.to_be_bytes()),
+        }
+        key
+    }
+
+    // Inverse of key(); None for keys outside this layout
+    fn height(self, key: &[u8]) -> Option<u32> {
+        let suffix = key.strip_prefix(self.prefix().as_bytes())?;
+        match self {
+            HashKeyFormat::String => std::str::from_utf8(suffix).ok()?.parse().ok(),
+            HashKeyFormat::Binary => Some(u32::from_be_bytes(suffix.try_into().ok()?)),
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            HashKeyFormat::String => HashKeyFormat::Binary,
+            HashKeyFormat::Binary => HashKeyFormat::String,
+        }
+    }
+}
+
+// Copies each hash to its key in `to` before deleting the old one, so an interrupted run
+// loses nothing and can simply be repeated.
+fn migrate_hash_keys(db: &mut RocksDBRuntimeAdapter, to: HashKeyFormat) -> Result<u64> {
+    let from = to.other();
+    let mut count: u64 = 0;
+    for (key, blockhash) in db.scan_prefix(from.prefix().as_bytes())? {
+        let height = from
+            .height(&key)
+            .ok_or_else(|| anyhow!("malformed block hash key {}", hex::encode(&key)))?;
+        db.put(to.key(height), &blockhash)?;
+        db.delete(&key)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Under --compact-on-start, compacts the store and returns the SST bytes before and after
+fn compact_on_start(args: &Args, adapter: &RocksDBRuntimeAdapter) -> Result<Option<(u64, u64)>> {
+    if !args.compact_on_start {
+        return Ok(None);
+    }
+    let started = Instant::now();
+    let (before, after) = adapter.compact()?;
+    info!(
+        "compacted database in {:?}: {} -> {} bytes of SST files ({} reclaimed)",
+        started.elapsed(),
+        before,
+        after,
+        before.saturating_sub(after)
+    );
+    Ok(Some((before, after)))
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum MissingBlock {
+    /// Treat the first missing height as the end of the archive and exit cleanly
+    Stop,
+    /// Exit with an error naming the missing height
+    Error,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum NodeBehind {
+    /// Assume the node is still syncing and wait for it to pass the indexed tip
+    Wait,
+    /// Treat it as a deep reorg: re-index from the last block whose hash the node agrees with
+    Rollback,
+}
+
+/// A reorg that reaches the block before `--start-block`: the node's chain does not contain the
+/// start, so no rollback above it can recover.
+#[derive(Debug)]
+struct OrphanedStart(u32);
+
+impl std::fmt::Display for OrphanedStart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the node's chain diverges at or below block {}, the anchor for --start-block {}; the configured start is on an orphaned chain",
+            self.0 - 1,
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for OrphanedStart {}
+
+/// What `probe` found the node to support.
+#[derive(Debug, Default)]
+struct ProbeReport {
+    // getblockchaininfo's result, null if the node does not have it
+    chain_info: Value,
+    // Each method probed, in order, and whether the node has it
+    methods: Vec<(String, bool)>,
+    // Methods this configuration calls that the node does not have
+    missing: Vec<String>,
+    batch: bool,
+    rest: bool,
+    // The type and address of each ZMQ notification the node publishes
+    zmq: Vec<(String, String)>,
+    suggestions: Vec<String>,
+}
+
+impl ProbeReport {
+    fn passed(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        if !self.chain_info.is_null() {
+            writeln!(
+                f,
+                "chain: {}, blocks: {}, headers: {}, pruned: {}",
+                self.chain_info["chain"].as_str().unwrap_or("unknown"),
+                self.chain_info["blocks"],
+                self.chain_info["headers"],
+                self.chain_info["pruned"]
+            )?;
+        }
+        for (method, present) in self.methods.iter() {
+            writeln!(f, "{}: {}", method, yes_no(*present))?;
+        }
+        writeln!(f, "batch requests: {}", yes_no(self.batch))?;
+        writeln!(f, "rest interface: {}", yes_no(self.rest))?;
+        if self.zmq.is_empty() {
+            writeln!(f, "zmq notifications: none")?;
+        }
+        for (kind, address) in self.zmq.iter() {
+            writeln!(f, "zmq notification: {} at {}", kind, address)?;
+        }
+        for suggestion in self.suggestions.iter() {
+            writeln!(f, "suggestion: {}", suggestion)?;
+        }
+        write!(f, "probe: {}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+
+/// A rewind the indexer made because the node's chain no longer matched its own.
+///
+/// `old_hash` and `new_hash` are the stored and the node's hash of the first block above the
+/// common ancestor, `new_hash` null when the node does not have that block yet.
+#[derive(Serialize, Deserialize, Debug)]
+struct ReorgEvent {
+    timestamp: u64,
+    old_tip: u32,
+    new_common_ancestor: Option<u32>,
+    old_hash: Option<String>,
+    new_hash: Option<String>,
+}
+
+// Reorgs are keyed by big-endian timestamp and old tip so a scan visits them in the order they
+// happened; the log is only ever appended to
+fn record_reorg(db: &mut RocksDBRuntimeAdapter, event: &ReorgEvent) -> Result<()> {
+    let mut key = REORG_LOG.as_bytes().to_vec();
+    key.extend(event.timestamp.to_be_bytes());
+    key.extend(event.old_tip.to_be_bytes());
+    db.put(&key, serde_json::to_vec(event)?)?;
+    Ok(())
+}
+
+// Returns the last `count` reorgs, oldest first
+fn reorg_events(db: &mut RocksDBRuntimeAdapter, count: usize) -> Result<Vec<ReorgEvent>> {
+    let entries = db.scan_prefix(REORG_LOG.as_bytes())?;
+    let skip = entries.len().saturating_sub(count);
+    entries
+        .into_iter()
+        .skip(skip)
+        .map(|(_, value)| Ok(serde_json::from_slice(&value)?))
+        .collect()
+}
+
+fn write_varint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend((n as u16).to_le_bytes());
+        }
+        0x10000..=0xffffffff => {
+            out.push(0xfe);
+            out.extend((n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend(n.to_le_bytes());
+        }
+    }
+}
+
+// Wraps mempool transactions in a block with an all-zero header and an empty coinbase, so
+// indexers that skip the first transaction as the coinbase see every mempool transaction.
+fn mempool_block(txs: &[Vec<u8>]) -> Vec<u8> {
+    let mut block = vec![0u8; 80];
+    write_varint(&mut block, txs.len() as u64 + 1);
+    block.extend(1i32.to_le_bytes());
+    block.push(1);
+    block.extend([0u8; 32]);
+    block.extend(u32::MAX.to_le_bytes());
+    block.push(0);
+    block.extend(u32::MAX.to_le_bytes());
+    block.push(1);
+    block.extend(0u64.to_le_bytes());
+    block.push(0);
+    block.extend(0u32.to_le_bytes());
+    for tx in txs {
+        block.extend(tx);
+    }
+    block
+}
+
+// One checkpoint per non-empty line, ignoring anything after a '#'
+fn load_checkpoints(path: &PathBuf) -> Result<BTreeMap<u32, Vec<u8>>> {
+    let mut checkpoints = BTreeMap::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (height, root) = line
+            .split_whitespace()
+            .collect_tuple()
+            .ok_or_else(|| anyhow!("{}:{}: expected <height> <state root>", path.display(), number + 1))?;
+        let root = hex::decode(root.trim_start_matches("0x"))?;
+        if root.len() != 32 {
+            return Err(anyhow!("{}:{}: state root must be 32 bytes", path.display(), number + 1));
+        }
+        checkpoints.insert(height.parse::<u32>()?, root);
+    }
+    Ok(checkpoints)
+}
+
+fn rpc_client(headers: &[String]) -> Result<reqwest::Client> {
+    let mut map = reqwest::header::HeaderMap::new();
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("--rpc-header {:?} must look like \"Name: Value\"", header))?;
+        map.append(
+            reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())?,
+            reqwest::header::HeaderValue::from_str(value.trim())?,
+        );
+    }
+    Ok(reqwest::Client::builder().default_headers(map).build()?)
+}
+
+// Cuts a --trace-rpc preview to TRACE_PREVIEW_LEN characters
+fn truncate_preview(text: &str) -> String {
+    match text.char_indices().nth(TRACE_PREVIEW_LEN) {
+        Some((end, _)) => format!("{}... ({} more bytes)", &text[..end], text.len() - end),
+        None => text.to_string(),
+    }
+}
+
+// Double SHA-256 of the 80-byte header, byte-reversed to match getblockhash
+fn header_blockhash(block: &[u8]) -> Result<Vec<u8>> {
+    if block.len() < 80 {
+        return Err(anyhow!("block is only {} bytes, shorter than a header", block.len()));
+    }
+    let mut hash = Sha256::digest(Sha256::digest(&block[..80])).to_vec();
+    hash.reverse();
+    Ok(hash)
+}
+
+// Names this process's indexer in metrics: the --label if set, else the module's file stem
+fn indexer_label(args: &Args) -> String {
+    match args.label {
+        Some(ref label) => label.clone(),
+        None => std::path::Path::new(&args.indexer)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+// Decodes --write-prefix into the runtime's allowlist, None when the flag is not given
+fn write_prefixes(args: &Args) -> Result<Option<Vec<Vec<u8>>>> {
+    if args.write_prefix.is_empty() {
+        return Ok(None);
+    }
+    let mut prefixes: Vec<Vec<u8>> = vec![];
+    for prefix in &args.write_prefix {
+        prefixes.push(hex::decode(prefix.trim_start_matches("0x"))?);
+    }
+    Ok(Some(prefixes))
+}
+
+fn prealloc_bytes(args: &Args) -> Option<u64> {
+    args.prealloc_memory_mb.map(|mb| mb * 1024 * 1024)
+}
+
+fn block_timeout(args: &Args) -> Option<Duration> {
+    args.block_timeout_secs.map(Duration::from_secs)
+}
+
+// Loads `indexer` against `store` under the limits from `args`
+fn load_runtime<T>(indexer: PathBuf, store: T, args: &Args) -> Result<MetashrewRuntime<T>>
+where
+    T: KeyValueStoreLike + Clone + Send + Sync + 'static,
+{
+    let mut runtime = MetashrewRuntime::load_with_timeout(indexer, store, block_timeout(args))?;
+    apply_runtime_limits(&mut runtime, args)?;
+    Ok(runtime)
+}
+
+// Applies the per-block limits every runtime loaded from --indexer runs under, so the sync
+// loop, the mempool, the sandbox worker and the checks all enforce the same ones
+fn apply_runtime_limits<T>(runtime: &mut MetashrewRuntime<T>, args: &Args) -> Result<()>
+where
+    T: KeyValueStoreLike + Clone + Send + Sync + 'static,
+{
+    runtime.max_reads_per_block = args.max_reads_per_block;
+    runtime.write_prefixes = write_prefixes(args)?;
+    runtime.max_write_bytes_per_block = args.max_write_bytes_per_block;
+    #[cfg(feature = "block-parser")]
+    {
+        runtime.tx_filter = args.tx_filter;
+    }
+    runtime.block_timeout = block_timeout(args);
+    runtime.set_prealloc_memory(prealloc_bytes(args))
+}
+
+// Runs `block` at `height` against an overlay on the live store, so the indexer reads the
+// indexed state but writes land in a namespace that is cleared again, and returns those writes
+// relative to the namespace
+fn assert_block(
+    args: &Args,
+    db: RocksDBRuntimeAdapter,
+    height: u32,
+    block: Vec<u8>,
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut overlay = OverlayAdapter::new(db, ASSERT_PREFIX.as_bytes().to_vec());
+    overlay.clear()?;
+    let mut runtime = load_runtime(PathBuf::from(&args.indexer), overlay.clone(), args)?;
+    runtime.context.lock().unwrap().begin_block(height, block);
+    let result = runtime.run();
+    let pairs = overlay.base.scan_prefix(ASSERT_PREFIX.as_bytes())?;
+    overlay.clear()?;
+    result.map_err(|e| anyhow!("block {} failed with {}", height, e))?;
+    Ok(pairs
+        .into_iter()
+        .map(|(key, value)| (key[ASSERT_PREFIX.len()..].to_vec(), value))
+        .collect())
+}
+
+// Diffs the writes of the block at `height` against the golden file `expect` onto `out`,
+// failing on any difference and otherwise returning the number of pairs matched
+fn compare_golden<W: std::io::Write>(
+    height: u32,
+    actual: &BTreeMap<Vec<u8>, Vec<u8>>,
+    expect: &Path,
+    out: &mut W,
+    codec: &KeyCodec,
+) -> Result<u64> {
+    let expected = snapshot::read_jsonl(std::io::BufReader::new(std::fs::File::open(expect)?))?;
+    let diff = snapshot::diff_pairs(&expected, actual, Some(out), codec)?;
+    if !diff.is_empty() {
+        return Err(anyhow!(
+            "block {} wrote {} unexpected, {} missing and {} differing pairs against {}",
+            height,
+            diff.only_b,
+            diff.only_a,
+            diff.changed,
+            expect.display()
+        ));
+    }
+    Ok(diff.same)
+}
+
+fn header_time(block: &[u8]) -> Result<u32> {
+    if block.len() < 80 {
+        return Err(anyhow!("block is only {} bytes, shorter than a header", block.len()));
+    }
+    Ok(u32::from_le_bytes(block[68..72].try_into()?))
+}
+
+// The first key whose presence or value differs between two keyspaces
+fn first_divergent_key(
+    first: &BTreeMap<Vec<u8>, Vec<u8>>,
+    second: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Option<Vec<u8>> {
+    let mut left = first.iter();
+    let mut right = second.iter();
+    loop {
+        match (left.next(), right.next()) {
+            (None, None) => return None,
+            (Some((a, va)), Some((b, vb))) if a == b && va == vb => {}
+            (Some((a, _)), Some((b, _))) => return Some(std::cmp::min(a, b).clone()),
+            (Some((key, _)), None) | (None, Some((key, _))) => return Some(key.clone()),
+        }
+    }
+}
+
+// best_height only walks back from `height` to re-index across a reorg; moving past it would
+// leave blocks unindexed
+fn check_next_height(best: u32, height: u32) -> Result<()> {
+    if best > height {
+        return Err(anyhow!(
+            "next block to index is {} but the tip is {}; refusing to skip blocks",
+            best,
+            height
+        ));
+    }
+    Ok(())
+}
+
+fn block_checksum(block: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; 32];
+    hasher.update(block);
+    hasher.finalize(&mut output);
+    output.to_vec()
+}
+
+struct MempoolIndex {
+    runtime: MetashrewRuntime<OverlayAdapter<RocksDBRuntimeAdapter>>,
+    args: Arc<Args>,
+    ttl: Duration,
+    refreshed: Option<Instant>,
+}
+
+impl MempoolIndex {
+    // Rebuilds the mempool namespace from scratch on a fresh instance, so nothing from the
+    // previous poll survives
+    fn refresh(&mut self, block: Vec<u8>, height: u32) -> Result<()> {
+        {
+            let mut context = self.runtime.context.lock().unwrap();
+            context.db.clear()?;
+            context.begin_block(height, block);
+        }
+        self.refreshed = None;
+        self.runtime.refresh_memory()?;
+        self.runtime.run()?;
+        self.refreshed = Some(Instant::now());
+        Ok(())
+    }
+
+    // The mempool namespace is rebuilt on every poll, so a fresh runtime on the same store loses
+    // nothing; until the next poll its views are unavailable
+    fn reload(&mut self, indexer: PathBuf) -> Result<()> {
+        let db = self.runtime.context.lock().unwrap().db.clone();
+        self.runtime = load_runtime(indexer, db, &self.args)?;
+        self.refreshed = None;
+        Ok(())
+    }
+
+    fn view(&self, symbol: String, input: &Vec<u8>) -> Result<Vec<u8>> {
+        match self.refreshed {
+            Some(at) if at.elapsed() <= self.ttl => {}
+            _ => return Err(anyhow!("mempool state is unavailable or expired")),
+        }
+        let height = self.runtime.context.lock().unwrap().height;
+        self.runtime.view(symbol, input, height)
+    }
+}
+
+fn sha3_concat(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; 32];
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize(&mut output);
+    output
+}
+
+// The root for a block hashes the previous block's root with the Merkle root of the keys it
+// changed, so it commits to the whole history of writes. Leaves are sorted by key and an odd
+// node at any level is carried up unchanged.
+fn compute_state_root(db: &mut RocksDBRuntimeAdapter, height: u32) -> Result<Vec<u8>> {
+    let context = Arc::new(std::sync::Mutex::new(
+        metashrew_runtime::MetashrewRuntimeContext::new(db.clone(), height, vec![]),
+    ));
+    let updated_key = u32_to_vec(height)?;
+    let length = MetashrewRuntime::<RocksDBRuntimeAdapter>::db_length_at_key(
+        context.clone(),
+        &db_make_length_key(&updated_key)?,
+    )?;
+    let mut keys: Vec<Vec<u8>> = vec![];
+    for i in 0..length {
+        if let Some(key) = db.get(db_make_list_key(&updated_key, i)?)? {
+            keys.push(key);
+        }
+    }
+    keys.sort();
+    keys.dedup();
+
+    let mut level: Vec<[u8; 32]> = vec![];
+    for key in &keys {
+        let value = MetashrewRuntime::<RocksDBRuntimeAdapter>::db_value_at_block(
+            context.clone(),
+            key,
+            height,
+        )?;
+        level.push(sha3_concat(&[&(key.len() as u32).to_le_bytes(), key, &value]));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => sha3_concat(&[left, right]),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    let changes = level.first().copied().unwrap_or([0; 32]);
+
+    let previous = match height.checked_sub(1) {
+        Some(h) => db
+            .get((String::from(STATE_ROOT) + &h.to_string()).into_bytes())?
+            .unwrap_or_else(|| vec![0; 32]),
+        None => vec![0; 32],
+    };
+    let root = sha3_concat(&[&previous, &changes]).to_vec();
+    db.put((String::from(STATE_ROOT) + &height.to_string()).into_bytes(), &root)?;
+    Ok(root)
 }
 
 #[derive(Clone)]
 struct AppState {
     runtime: Arc<Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
+    mempool: Option<Arc<Mutex<MempoolIndex>>>,
+    metrics: Arc<Metrics>,
+    expose_internal: bool,
+    allow_reload: bool,
+    max_response_bytes: Option<usize>,
+    response_overflow: ResponseOverflow,
+    max_history_depth: Option<u32>,
+    hash_key_format: HashKeyFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ResponseOverflow {
+    Error,
+    Truncate,
+}
+
+impl AppState {
+    // Applies --max-response-bytes to a raw result before it is hex-encoded
+    fn limit_response(&self, mut result: Vec<u8>) -> Result<Vec<u8>, JsonRpcErrorObject> {
+        match self.max_response_bytes {
+            Some(max) if result.len() > max => match self.response_overflow {
+                ResponseOverflow::Truncate => {
+                    result.truncate(max);
+                    Ok(result)
+                }
+                ResponseOverflow::Error => Err(JsonRpcErrorObject {
+                    code: -32001,
+                    message: format!(
+                        "response of {} bytes exceeds --max-response-bytes {}",
+                        result.len(),
+                        max
+                    ),
+                    data: None,
+                }),
+            },
+            _ => Ok(result),
+        }
+    }
+
+    // Applies --max-history-depth: values at old heights sit further back in their version
+    // lists, so queries are only served within `depth` blocks of the indexed tip
+    fn check_history_depth(&self, height: u32) -> Result<(), String> {
+        let tip = current_height().saturating_sub(1);
+        match self.max_history_depth {
+            Some(depth) if height < tip.saturating_sub(depth) => Err(format!(
+                "height {} is more than --max-history-depth {} blocks below the tip {}",
+                height, depth, tip
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)] 
@@ -108,73 +1117,367 @@ impl error::ResponseError for IndexerError {
         })
     }
 }
-struct IndexerState {
-    runtime: Arc<Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
-    args: Arc<Args>,
-    start_block: u32,
+/// Token bucket allowing bursts of up to one second's worth of requests.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
 }
 
-impl IndexerState {
-    async fn post_once(&self, body: String) -> Result<Response, reqwest::Error> {
-        let response = reqwest::Client::new()
-            .post(match self.args.auth.clone() {
-                Some(v) => {
-                    let mut url = Url::parse(self.args.daemon_rpc_url.as_str()).unwrap();
-                    let (username, password) = v.split(":").next_tuple().unwrap();
-                    url.set_username(username).unwrap();
-                    url.set_password(Some(password)).unwrap();
-                    url
-                }
-                None => Url::parse(self.args.daemon_rpc_url.as_str()).unwrap(),
-            })
-            .header("Content-Type", "application/json")
-            .body(body)
-            .send()
-            .await;
-        return response;
+impl RateLimiter {
+    fn new(rps: u32) -> Self {
+        let rate = std::cmp::max(rps, 1) as f64;
+        RateLimiter {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
     }
 
-    #[allow(unused_assignments)]
-    async fn post(&self, body: String) -> Result<Response> {
-        let mut count = 0;
-        let mut response: Option<Response> = None;
+    /// Takes a token, returning how long the caller has to wait before it may be used.
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// Lengthens the wait between tip polls while the chain is quiet, with up to 20% jitter so
+// several indexers against one node don't poll in lockstep.
+struct PollBackoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl PollBackoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            current: min,
+            max: std::cmp::max(min, max),
+        }
+    }
+
+    fn next_wait(&mut self) -> Duration {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = self.current.mul_f64((nanos % 1000) as f64 / 5000.0);
+        let wait = std::cmp::min(self.current + jitter, self.max);
+        self.current = std::cmp::min(self.current * 2, self.max);
+        wait
+    }
+}
+
+// Holds the --pid-file for the life of the process and removes it when dropped
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    // The file is created with create_new, so of two processes starting at once only one gets
+    // it. A stale file is removed and the create retried once.
+    fn acquire(path: PathBuf) -> Result<Self> {
+        use std::io::Write;
+        let mut reclaimed = false;
         loop {
-            match self.post_once(body.clone()).await {
-                Ok(v) => {
-                    response = Some(v);
-                    break;
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", std::process::id())?;
+                    return Ok(Self { path });
                 }
-                Err(e) => {
-                    if count > 10 {
-                        return Err(e.into());
-                    } else {
-                        count += 1;
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && !reclaimed => {
+                    let contents = std::fs::read_to_string(&path).unwrap_or_default();
+                    if !pid_is_stale(contents.trim()) {
+                        return Err(anyhow!(
+                            "pid file {} already exists and names process {:?}",
+                            path.display(),
+                            contents.trim()
+                        ));
                     }
-                    debug!("err: retrying POST");
-                    tokio::time::sleep(Duration::from_millis(3000)).await;
+                    log::warn!("reclaiming stale pid file {}", path.display());
+                    std::fs::remove_file(&path)?;
+                    reclaimed = true;
                 }
+                Err(e) => return Err(anyhow!("failed to create pid file {}: {}", path.display(), e)),
             }
         }
-        Ok(response.unwrap())
     }
+}
 
-    async fn fetch_blockcount(&self) -> Result<u32> {
-        let response = self
-            .post(serde_json::to_string(&JsonRpcRequest {
-                id: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)?
-                    .as_secs()
-                    .try_into()?,
-                jsonrpc: String::from("2.0"),
-                method: String::from("getblockcount"),
-                params: vec![],
-            })?)
-            .await?;
-
-        let result: Value = response.json().await?;
-        Ok(result["result"]
-            .as_u64()
-            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))? as u32)
+// A pid with no /proc entry belongs to a process that is gone
+#[cfg(target_os = "linux")]
+fn pid_is_stale(pid: &str) -> bool {
+    match pid.parse::<u32>() {
+        Ok(pid) => !PathBuf::from(format!("/proc/{}", pid)).exists(),
+        Err(_) => true,
+    }
+}
+
+// Without /proc there is no portable liveness check, so an existing file is never reclaimed
+#[cfg(not(target_os = "linux"))]
+fn pid_is_stale(_pid: &str) -> bool {
+    false
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Upper bounds, in seconds, of the metashrew_block_duration_seconds histogram buckets
+const BLOCK_DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// Gauges shared between the sync loop and the /metrics endpoint
+struct Metrics {
+    last_commit: std::sync::Mutex<Instant>,
+    node_tip: AtomicU32,
+    // Value of the `indexer` label on per-indexer series
+    indexer: String,
+    // Cumulative count per BLOCK_DURATION_BUCKETS entry, plus the overall count and sum
+    block_buckets: Vec<AtomicU64>,
+    blocks: AtomicU64,
+    block_micros: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl Metrics {
+    fn new(indexer: String) -> Self {
+        Self {
+            last_commit: std::sync::Mutex::new(Instant::now()),
+            node_tip: AtomicU32::new(0),
+            indexer,
+            block_buckets: BLOCK_DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            blocks: AtomicU64::new(0),
+            block_micros: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    fn record_commit(&self) {
+        *self.last_commit.lock().unwrap() = Instant::now();
+    }
+
+    fn record_block(&self, elapsed: Duration, writes: u64) {
+        let seconds = elapsed.as_secs_f64();
+        for (bound, count) in BLOCK_DURATION_BUCKETS.iter().zip(self.block_buckets.iter()) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        self.block_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.writes.fetch_add(writes, Ordering::Relaxed);
+    }
+
+    // Prometheus text for the series labelled with this indexer
+    fn render_indexer(&self) -> String {
+        let label = format!(
+            "indexer=\"{}\"",
+            self.indexer
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        );
+        let mut out = String::from("# TYPE metashrew_block_duration_seconds histogram\n");
+        for (bound, count) in BLOCK_DURATION_BUCKETS.iter().zip(self.block_buckets.iter()) {
+            out += &format!(
+                "metashrew_block_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                label,
+                bound,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let blocks = self.blocks.load(Ordering::Relaxed);
+        out += &format!(
+            "metashrew_block_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n\
+             metashrew_block_duration_seconds_sum{{{}}} {:.6}\n\
+             metashrew_block_duration_seconds_count{{{}}} {}\n\
+             # TYPE metashrew_writes_total counter\n\
+             metashrew_writes_total{{{}}} {}\n",
+            label,
+            blocks,
+            label,
+            self.block_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            label,
+            blocks,
+            label,
+            self.writes.load(Ordering::Relaxed),
+        );
+        out
+    }
+
+    fn seconds_since_last_block(&self) -> f64 {
+        self.seconds_since_last_block_at(Instant::now())
+    }
+
+    fn seconds_since_last_block_at(&self, now: Instant) -> f64 {
+        now.saturating_duration_since(*self.last_commit.lock().unwrap())
+            .as_secs_f64()
+    }
+}
+
+// A node's tip height and the hash of the block at it
+type NodeTip = (u32, Vec<u8>);
+
+#[derive(Clone)]
+struct IndexerState {
+    runtime: Arc<Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
+    args: Arc<Args>,
+    start_block: u32,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    metrics: Arc<Metrics>,
+    dump_running: Arc<AtomicBool>,
+    client: reqwest::Client,
+    // Header time of the last block indexed by this process, 0 before the first
+    last_block_time: Arc<AtomicU32>,
+    // Flips to true on SIGINT/SIGTERM; the sync loop stops between blocks
+    shutdown: watch::Receiver<bool>,
+    // Expected state roots by height, from --checkpoints
+    checkpoints: Arc<BTreeMap<u32, Vec<u8>>>,
+    // Worker process that runs blocks under --sandbox
+    sandbox: Option<Arc<std::sync::Mutex<Sandbox>>>,
+    // The node's tip as of the last reorg walk that completed
+    checked_tip: Arc<std::sync::Mutex<Option<NodeTip>>>,
+    // Where the sync loop last reported itself, for --loop-watchdog-secs
+    liveness: Arc<Liveness>,
+}
+
+impl IndexerState {
+    async fn throttle(&self) {
+        if let Some(ref limiter) = self.rate_limiter {
+            let wait = limiter.lock().await.reserve();
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    fn rpc_url(&self) -> Result<Url> {
+        let mut url = Url::parse(self.args.daemon_rpc_url.as_str())?;
+        if let Some(ref auth) = self.args.auth {
+            let (username, password) = auth
+                .split(":")
+                .next_tuple()
+                .ok_or_else(|| anyhow!("--auth must be formatted as username:password"))?;
+            url.set_username(username)
+                .map_err(|_| anyhow!("invalid RPC username"))?;
+            url.set_password(Some(password))
+                .map_err(|_| anyhow!("invalid RPC password"))?;
+        }
+        Ok(url)
+    }
+
+    async fn post_once(&self, url: Url, body: String) -> Result<Response, reqwest::Error> {
+        self.throttle().await;
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await;
+        return response;
+    }
+
+    #[allow(unused_assignments)]
+    async fn post(&self, body: String) -> Result<Response> {
+        let url = self.rpc_url()?;
+        if self.args.trace_rpc {
+            self.trace_request(&url, &body);
+        }
+        let mut count = 0;
+        let mut response: Option<Response> = None;
+        loop {
+            match self.post_once(url.clone(), body.clone()).await {
+                Ok(v) => {
+                    response = Some(v);
+                    break;
+                }
+                Err(e) => {
+                    if count > 10 {
+                        return Err(e.into());
+                    } else {
+                        count += 1;
+                    }
+                    debug!("err: retrying POST");
+                    tokio::time::sleep(Duration::from_millis(3000)).await;
+                }
+            }
+        }
+        let response = response.unwrap();
+        if self.args.trace_rpc {
+            return self.trace_response(response).await;
+        }
+        Ok(response)
+    }
+
+    fn trace_request(&self, url: &Url, body: &str) {
+        // --auth is carried in the URL, so never log its credentials
+        let mut url = url.clone();
+        if url.password().is_some() {
+            let _ = url.set_password(Some("redacted"));
+        }
+        let request: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+        debug!(
+            target: TRACE_TARGET,
+            "-> {} {} params={}",
+            url,
+            request["method"].as_str().unwrap_or("?"),
+            truncate_preview(&request["params"].to_string())
+        );
+    }
+
+    // Reads the body to log it, then hands callers an equivalent response to parse
+    async fn trace_response(&self, response: Response) -> Result<Response> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = response.bytes().await?;
+        debug!(
+            target: TRACE_TARGET,
+            "<- {} {} bytes {}",
+            status,
+            bytes.len(),
+            truncate_preview(&String::from_utf8_lossy(&bytes))
+        );
+        let mut rebuilt = http::Response::new(bytes);
+        *rebuilt.status_mut() = status;
+        *rebuilt.headers_mut() = headers;
+        Ok(Response::from(rebuilt))
+    }
+
+    async fn fetch_blockcount(&self) -> Result<u32> {
+        let response = self
+            .post(serde_json::to_string(&JsonRpcRequest {
+                id: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs()
+                    .try_into()?,
+                jsonrpc: String::from("2.0"),
+                method: self.args.rpc_method_getblockcount.clone(),
+                params: vec![],
+            })?)
+            .await?;
+
+        let result: Value = response.json().await?;
+        let count = result["result"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))? as u32;
+        Ok(match self.args.pin_tip {
+            Some(pin) => count.min(pin),
+            None => count,
+        })
     }
 
     async fn query_height(&self) -> Result<u32> {
@@ -187,31 +1490,133 @@ impl IndexerState {
     }
 
     async fn best_height(&self, block_number: u32) -> Result<u32> {
+        // Blocks below --trusted-height, or held back by --min-confirmations outside the
+        // window, are assumed buried too deep to reorg
+        if self.args.no_reorg_tracking
+            || self.args.min_confirmations >= REORG_WINDOW
+            || self
+                .args
+                .trusted_height
+                .is_some_and(|trusted| block_number < trusted)
+        {
+            return Ok(block_number);
+        }
         let mut best: u32 = block_number;
         let tip = self.fetch_blockcount().await?;
+        if tip.saturating_add(1) < block_number {
+            return match self.args.on_node_behind {
+                // pull_block waits for the node to reach block_number
+                NodeBehind::Wait => {
+                    log::warn!(
+                        "node tip {} is below indexed height {}, waiting for it to catch up",
+                        tip,
+                        block_number - 1
+                    );
+                    Ok(block_number)
+                }
+                NodeBehind::Rollback => {
+                    let ancestor = self.common_ancestor(tip).await?;
+                    log::warn!(
+                        "node tip {} is below indexed height {}, last common block is {:?}",
+                        tip,
+                        block_number - 1,
+                        ancestor
+                    );
+                    Ok(ancestor.map_or(0, |height| height + 1))
+                }
+            };
+        }
         
-        if best >= tip - std::cmp::min(6, tip) {
-            loop {
-                if best == 0 {
-                    break;
+        // Only hashes are compared while walking back, so no block body is downloaded until
+        // the caller pulls the first block past the common ancestor. The walk starts at the last
+        // indexed block and goes no lower than the anchor below --start-block.
+        if block_number > 0 && best >= tip - std::cmp::min(REORG_WINDOW, tip) {
+            // A reorg changes the node's tip hash, so while the tip is the block the last walk
+            // saw, the blocks indexed since came from the chain it verified. A reorg that is
+            // undone again between two checks goes unnoticed. The count is compared first, so
+            // the tip hash is only fetched when it can match.
+            let cached_height = self.checked_tip.lock().unwrap().as_ref().map(|(height, _)| *height);
+            let mut tip_hash: Option<Vec<u8>> = None;
+            if cached_height == Some(tip) {
+                let hash = self.fetch_blockhash(tip).await?;
+                if self.checked_tip.lock().unwrap().as_ref().map(|(_, cached)| cached) == Some(&hash) {
+                    return Ok(best);
                 }
+                tip_hash = Some(hash);
+            }
+            let floor = self.start_block.saturating_sub(1);
+            let mut height = block_number - 1;
+            loop {
                 let blockhash = self
-                    .get_blockhash(best)
+                    .get_blockhash(height)
                     .await
                     .ok_or_else(|| anyhow!("failed to retrieve blockhash"))?;
-                let remote_blockhash = self.fetch_blockhash(best).await?;
-                if blockhash == remote_blockhash {
+                let remote = self.fetch_blockhash(height).await?;
+                if height == tip {
+                    tip_hash = Some(remote.clone());
+                }
+                if blockhash == remote {
+                    best = height + 1;
                     break;
-                } else {
-                    best = best - 1;
                 }
+                if height == floor {
+                    if self.start_block > 0 {
+                        return Err(OrphanedStart(self.start_block).into());
+                    }
+                    best = 0;
+                    break;
+                }
+                height -= 1;
             }
+            // Below the tip the walk never saw its hash; the next check walks again instead
+            *self.checked_tip.lock().unwrap() = tip_hash.map(|hash| (tip, hash));
         }
         Ok(best)
     }
 
+    // Walks down from `from` to the highest height whose stored hash the node agrees with, with
+    // no limit on depth other than the anchor below --start-block
+    async fn common_ancestor(&self, from: u32) -> Result<Option<u32>> {
+        let floor = self.start_block.saturating_sub(1);
+        let mut height = from;
+        loop {
+            if let Some(local) = self.get_blockhash(height).await {
+                if local == self.fetch_blockhash(height).await? {
+                    return Ok(Some(height));
+                }
+            }
+            if height <= floor {
+                if self.start_block > 0 {
+                    return Err(OrphanedStart(self.start_block).into());
+                }
+                return Ok(None);
+            }
+            height -= 1;
+        }
+    }
+
+    // Stores the node's hash of the block before --start-block, which is never indexed, as the
+    // anchor the reorg walk stops at. A database that already has one keeps it.
+    async fn store_start_anchor(&self) -> Result<()> {
+        if self.args.no_reorg_tracking || self.start_block == 0 {
+            return Ok(());
+        }
+        let anchor = self.start_block - 1;
+        if self.get_blockhash(anchor).await.is_some() {
+            return Ok(());
+        }
+        let blockhash = self.fetch_blockhash(anchor).await?;
+        info!("anchoring reorg checks at block {} (0x{})", anchor, hex::encode(&blockhash));
+        let runtime = self.runtime.lock().await;
+        let mut context = runtime.context.lock().unwrap();
+        context
+            .db
+            .put(self.args.hash_key_format.key(anchor), &blockhash)?;
+        Ok(())
+    }
+
     async fn get_blockhash(&self, block_number: u32) -> Option<Vec<u8>> {
-        let key = (String::from(HEIGHT_TO_HASH) + &block_number.to_string()).into_bytes();
+        let key = self.args.hash_key_format.key(block_number);
         let runtime = self.runtime.lock().await;
         let mut context = runtime.context.lock().unwrap();
         context.db.get(&key).unwrap()
@@ -225,7 +1630,7 @@ impl IndexerState {
                     .as_secs()
                     .try_into()?,
                 jsonrpc: String::from("2.0"),
-                method: String::from("getblockhash"),
+                method: self.args.rpc_method_getblockhash.clone(),
                 params: vec![Value::Number(Number::from(block_number))],
             })?)
             .await?;
@@ -237,23 +1642,7 @@ impl IndexerState {
         Ok(hex::decode(blockhash)?)
     }
 
-    async fn pull_block(&self, block_number: u32) -> Result<Vec<u8>> {
-        loop {
-            let count = self.fetch_blockcount().await?;
-            if block_number > count {
-                tokio::time::sleep(Duration::from_millis(3000)).await;
-            } else {
-                break;
-            }
-        }
-        let blockhash = self.fetch_blockhash(block_number).await?;
-        
-        let runtime = self.runtime.lock().await;
-        runtime.context.lock().unwrap().db.put(
-            &(String::from(HEIGHT_TO_HASH) + &block_number.to_string()).into_bytes(),
-            &blockhash,
-        )?;
-
+    async fn fetch_block(&self, blockhash: &Vec<u8>) -> Result<Vec<u8>> {
         let response = self
             .post(serde_json::to_string(&JsonRpcRequest {
                 id: SystemTime::now()
@@ -261,9 +1650,9 @@ impl IndexerState {
                     .as_secs()
                     .try_into()?,
                 jsonrpc: String::from("2.0"),
-                method: String::from("getblock"),
+                method: self.args.rpc_method_getblock.clone(),
                 params: vec![
-                    Value::String(hex::encode(&blockhash)),
+                    Value::String(hex::encode(blockhash)),
                     Value::Number(Number::from(0)),
                 ],
             })?)
@@ -276,112 +1665,1015 @@ impl IndexerState {
         Ok(hex::decode(block_hex)?)
     }
 
-    async fn run(&mut self) -> Result<()> {
-        let mut height: u32 = self.query_height().await?;
-        
+    async fn fetch_rpc_result(&self, method: &str, params: Vec<Value>) -> Result<Value> {
+        let response = self
+            .post(serde_json::to_string(&JsonRpcRequest {
+                id: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs()
+                    .try_into()?,
+                jsonrpc: String::from("2.0"),
+                method: String::from(method),
+                params,
+            })?)
+            .await?;
+
+        let mut result: Value = response.json().await?;
+        Ok(result["result"].take())
+    }
+
+    // Calls `method`, returning None if the node answers that it has no such method. Nodes reply
+    // to an unknown method with JSON-RPC error -32601, bitcoind over an HTTP 404.
+    async fn probe_method(&self, method: &str, params: Vec<Value>) -> Result<Option<Value>> {
+        let response = self
+            .post(serde_json::to_string(&JsonRpcRequest {
+                id: 0,
+                jsonrpc: String::from("2.0"),
+                method: String::from(method),
+                params,
+            })?)
+            .await?;
+        let mut result: Value = response.json().await?;
+        if result["error"]["code"].as_i64() == Some(-32601) {
+            return Ok(None);
+        }
+        Ok(Some(result["result"].take()))
+    }
+
+    async fn probe_batch(&self) -> Result<bool> {
+        let request = |id| JsonRpcRequest {
+            id,
+            jsonrpc: String::from("2.0"),
+            method: self.args.rpc_method_getblockcount.clone(),
+            params: vec![],
+        };
+        let response = self
+            .post(serde_json::to_string(&[request(0), request(1)])?)
+            .await?;
+        Ok(response
+            .json::<Value>()
+            .await
+            .is_ok_and(|results| results.as_array().is_some_and(|results| results.len() == 2)))
+    }
+
+    // The REST interface is served unauthenticated from the RPC port when the node enables it
+    async fn probe_rest(&self) -> Result<bool> {
+        let mut url = Url::parse(self.args.daemon_rpc_url.as_str())?;
+        url.set_path("/rest/chaininfo.json");
+        Ok(match self.client.get(url).send().await {
+            Ok(response) if response.status().is_success() => {
+                response.json::<Value>().await.is_ok()
+            }
+            _ => false,
+        })
+    }
+
+    // Reports what the node supports. Lookups of a hash or txid the node does not have still
+    // show that the method exists, so only genesis is ever fetched.
+    async fn probe(&self) -> Result<ProbeReport> {
+        let mut report = ProbeReport {
+            chain_info: self
+                .probe_method("getblockchaininfo", vec![])
+                .await?
+                .unwrap_or(Value::Null),
+            ..Default::default()
+        };
+        let genesis = self
+            .probe_method(&self.args.rpc_method_getblockhash, vec![Value::from(0)])
+            .await?;
+        let genesis_hash = genesis
+            .as_ref()
+            .and_then(|hash| hash.as_str().map(String::from))
+            .unwrap_or_else(|| "00".repeat(32));
+        let probes = [
+            (&self.args.rpc_method_getblockcount, "--rpc-method-getblockcount", vec![]),
+            (&self.args.rpc_method_getblockhash, "--rpc-method-getblockhash", vec![Value::from(0)]),
+            (
+                &self.args.rpc_method_getblock,
+                "--rpc-method-getblock",
+                vec![Value::String(genesis_hash), Value::from(0)],
+            ),
+        ];
+        for (method, flag, params) in probes {
+            let present = self.probe_method(method, params).await?.is_some();
+            if !present {
+                report.missing.push(method.clone());
+                report.suggestions.push(format!(
+                    "the node has no {}; pass {} with the node's name for it",
+                    method, flag
+                ));
+            }
+            report.methods.push((method.clone(), present));
+        }
+        let mempool_probes = [
+            ("getrawmempool", vec![Value::Bool(false)]),
+            ("getrawtransaction", vec![Value::String("00".repeat(32))]),
+        ];
+        for (method, params) in mempool_probes {
+            let present = self.probe_method(method, params).await?.is_some();
+            if !present && self.args.index_mempool {
+                report.missing.push(String::from(method));
+                report.suggestions.push(format!(
+                    "--index-mempool needs {}, which the node does not have",
+                    method
+                ));
+            }
+            report.methods.push((String::from(method), present));
+        }
+        if report.chain_info["pruned"].as_bool() == Some(true) {
+            let pruned_below = report.chain_info["pruneheight"].as_u64().unwrap_or(0);
+            if self.start_block < pruned_below as u32 {
+                report.suggestions.push(format!(
+                    "the node has pruned blocks below {}; pass --start-block {} or use an unpruned node",
+                    pruned_below, pruned_below
+                ));
+            }
+        }
+        report.batch = self.probe_batch().await?;
+        report.rest = self.probe_rest().await?;
+        if let Some(Value::Array(notifications)) =
+            self.probe_method("getzmqnotifications", vec![]).await?
+        {
+            report.zmq = notifications
+                .iter()
+                .map(|notification| {
+                    (
+                        notification["type"].as_str().unwrap_or("").to_string(),
+                        notification["address"].as_str().unwrap_or("").to_string(),
+                    )
+                })
+                .collect();
+        }
+        Ok(report)
+    }
+
+    async fn fetch_mempool_block(&self) -> Result<Vec<u8>> {
+        let txids = self
+            .fetch_rpc_result("getrawmempool", vec![Value::Bool(false)])
+            .await?;
+        let mut txs: Vec<Vec<u8>> = vec![];
+        for txid in txids
+            .as_array()
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))?
+        {
+            // Transactions can leave the mempool between the two calls
+            if let Some(tx_hex) = self
+                .fetch_rpc_result("getrawtransaction", vec![txid.clone()])
+                .await?
+                .as_str()
+            {
+                txs.push(hex::decode(tx_hex)?);
+            }
+        }
+        Ok(mempool_block(&txs))
+    }
+
+    async fn run_mempool(&self, mempool: Arc<Mutex<MempoolIndex>>) -> Result<()> {
         loop {
-            if let Some(exit_at) = self.args.exit_at {
-                if height >= exit_at {
-                    info!("Reached exit-at block {}, shutting down gracefully", exit_at);
-                    return Ok(());
+            match self.fetch_mempool_block().await {
+                Ok(block) => {
+                    let height = self.query_height().await?;
+                    if let Err(e) = mempool.lock().await.refresh(block, height) {
+                        log::warn!("mempool indexing failed: {}", e);
+                    }
                 }
+                Err(e) => debug!("failed to fetch mempool: {}", e),
             }
+            tokio::time::sleep(Duration::from_secs(self.args.mempool_interval)).await;
+        }
+    }
 
-            let best: u32 = self.best_height(height).await.unwrap_or(height);
-            let block_data = self.pull_block(best).await?;
-            
-            let mut runtime = self.runtime.lock().await;
-            runtime.context.lock().unwrap().block = block_data;
-            runtime.context.lock().unwrap().height = best;
-            runtime.context.lock().unwrap().db.set_height(best);
-            
-            if let Err(_) = runtime.run() {
-                debug!("respawn cache");
-                runtime.refresh_memory()?;
-                runtime.run()?;
-            }
-            
-            height = best + 1;
-            unsafe {
-                _HEIGHT = height;
+    // Retries just the block body download and parse, doubling the wait after each failure
+    async fn fetch_block_with_retry(&self, blockhash: &Vec<u8>) -> Result<Vec<u8>> {
+        let mut attempt: u32 = 0;
+        loop {
+            match self.fetch_block(blockhash).await {
+                Ok(block) => return Ok(block),
+                Err(e) if attempt < self.args.block_fetch_retries => {
+                    let wait = Duration::from_millis(500 << attempt.min(10));
+                    log::warn!(
+                        "getblock {} failed with {}, retrying in {:?}",
+                        hex::encode(blockhash),
+                        e,
+                        wait
+                    );
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
             }
         }
     }
-}
 
-#[post("/")]
-async fn handle_jsonrpc(
-    body: web::Json<JsonRpcRequest>,
-    state: web::Data<AppState>,
-) -> ActixResult<impl Responder> {
-    debug!("RPC request: {}", serde_json::to_string(&body).unwrap());
+    async fn pull_block(&self, block_number: u32) -> Result<Vec<u8>> {
+        // Each call starts from the short interval, so polling snaps back right after a new block
+        let mut backoff = PollBackoff::new(
+            Duration::from_millis(self.args.poll_interval_ms),
+            Duration::from_millis(self.args.poll_interval_max_ms),
+        );
+        loop {
+            let count = self.fetch_blockcount().await?;
+            self.metrics.node_tip.store(count, Ordering::Relaxed);
+            if block_number.saturating_add(self.args.min_confirmations) > count {
+                self.liveness
+                    .beat(format!("waiting for block {} (node at {})", block_number, count));
+                tokio::time::sleep(backoff.next_wait()).await;
+            } else {
+                break;
+            }
+        }
+        let blockhash = self.fetch_blockhash(block_number).await?;
+        let span = tracing::Span::current();
+        if !span.is_disabled() {
+            span.record("blockhash", hex::encode(&blockhash));
+        }
 
-    let runtime = state.runtime.lock().await;
+        if !self.args.no_reorg_tracking {
+            let runtime = self.runtime.lock().await;
+            runtime.context.lock().unwrap().db.put(
+                &self.args.hash_key_format.key(block_number),
+                &blockhash,
+            )?;
+        }
 
-    if body.method == "metashrew_view" {
-        if body.params.len() < 3 {
-            return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [view_name, input_data, height]".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            }));
+        let block = self.fetch_block_with_retry(&blockhash).await?;
+        if self.args.store_block_checksums {
+            let runtime = self.runtime.lock().await;
+            runtime.context.lock().unwrap().db.put(
+                &(String::from(BLOCK_CHECKSUM) + &block_number.to_string()).into_bytes(),
+                &block_checksum(&block),
+            )?;
         }
+        Ok(block)
+    }
 
-        let view_name = match body.params[0].as_str() {
-            Some(s) => s.to_string(),
-            None => return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: view_name must be a string".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
-        };
+    // Indexes [from, to] twice into fresh in-memory stores and returns the first key whose
+    // presence or value differs between the two runs, if any
+    async fn verify_determinism(&self, from: u32, to: u32) -> Result<Option<Vec<u8>>> {
+        let mut blocks: Vec<Vec<u8>> = vec![];
+        for height in from..=to {
+            let blockhash = self.fetch_blockhash(height).await?;
+            blocks.push(self.fetch_block_with_retry(&blockhash).await?);
+        }
 
-        let input_hex = match body.params[1].as_str() {
-            Some(s) => s.to_string(),
-            None => return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: input_data must be a hex string".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
-        };
+        let mut stores: Vec<MemStoreAdapter> = vec![];
+        for _ in 0..2 {
+            let store = MemStoreAdapter::new();
+            let mut runtime = load_runtime(PathBuf::from(&self.args.indexer), store.clone(), &self.args)?;
+            for (height, block) in (from..=to).zip(blocks.iter()) {
+                {
+                    let mut context = runtime.context.lock().unwrap();
+                    context.begin_block(height, block.clone());
+                }
+                runtime
+                    .run()
+                    .map_err(|e| anyhow!("block {} failed with {}", height, e))?;
+            }
+            stores.push(store);
+        }
 
-        let height = match &body.params[2] {
-            Value::String(s) if s == "latest" => unsafe { _HEIGHT },
-            Value::Number(n) => n.as_u64().unwrap_or(0) as u32,
-            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: height must be a number or 'latest'".to_string(),
+        let first = stores[0].map.lock().unwrap();
+        let second = stores[1].map.lock().unwrap();
+        Ok(first_divergent_key(&first, &second))
+    }
+
+    async fn verify_blocks(&self, from: u32, to: u32) -> Result<u32> {
+        let mut failures: u32 = 0;
+        for height in from..=to {
+            let (checksum, blockhash) = {
+                let runtime = self.runtime.lock().await;
+                let mut context = runtime.context.lock().unwrap();
+                (
+                    context.db.get(&(String::from(BLOCK_CHECKSUM) + &height.to_string()).into_bytes())?,
+                    context.db.get(&self.args.hash_key_format.key(height))?,
+                )
+            };
+            let checksum = match checksum {
+                Some(v) => v,
+                None => {
+                    debug!("no checksum stored for block {}, skipping", height);
+                    continue;
+                }
+            };
+            let blockhash = match blockhash {
+                Some(v) => v,
+                None => self.fetch_blockhash(height).await?,
+            };
+            let block = self.fetch_block_with_retry(&blockhash).await?;
+            if block_checksum(&block) != checksum {
+                log::error!("checksum mismatch for block {} (0x{})", height, hex::encode(&blockhash));
+                failures += 1;
+            }
+        }
+        Ok(failures)
+    }
+
+    // Heights in [from, to] with no stored blockhash, i.e. never indexed
+    async fn find_gaps(&self, from: u32, to: u32) -> Result<Vec<u32>> {
+        let mut gaps: Vec<u32> = vec![];
+        for height in from..=to {
+            if self.get_blockhash(height).await.is_none() {
+                gaps.push(height);
+            }
+        }
+        Ok(gaps)
+    }
+
+    // Indexes each missing height in ascending order without reorg handling, so indexed blocks
+    // on either side are left untouched, and puts the stored tip back after every block
+    async fn fill_gaps(&self, gaps: &[u32]) -> Result<()> {
+        let tip = self.query_height().await?;
+        for &height in gaps {
+            let blockhash = self.fetch_blockhash(height).await?;
+            let block = self.fetch_block_with_retry(&blockhash).await?;
+            let checksum = block_checksum(&block);
+            let mut runtime = self.runtime.lock().await;
+            {
+                let mut context = runtime.context.lock().unwrap();
+                context.begin_block(height, block);
+                context.db.set_height(height);
+            }
+            runtime
+                .execute()
+                .map_err(|e| anyhow!("block {} failed with {}", height, e))?;
+            let mut context = runtime.context.lock().unwrap();
+            context.db.put(
+                &self.args.hash_key_format.key(height),
+                &blockhash,
+            )?;
+            if self.args.store_block_checksums {
+                context.db.put(
+                    &(String::from(BLOCK_CHECKSUM) + &height.to_string()).into_bytes(),
+                    &checksum,
+                )?;
+            }
+            context.db.write_tip_height(tip)?;
+            info!("filled gap at block {}", height);
+        }
+        Ok(())
+    }
+
+    // Block hashes are stored before a block is indexed, so the highest one in the unbroken run
+    // from start_block may belong to a block that never committed; the tip is set to re-index it
+    async fn recover_tip(&self) -> Result<()> {
+        let stored = {
+            let runtime = self.runtime.lock().await;
+            let mut context = runtime.context.lock().unwrap();
+            context.db.get(TIP_HEIGHT_KEY.as_bytes())?
+        };
+        if stored.is_some_and(|tip| tip.len() == 4) {
+            return Ok(());
+        }
+        let mut next = self.start_block;
+        while self.get_blockhash(next).await.is_some() {
+            next += 1;
+        }
+        match next.checked_sub(1) {
+            Some(highest) if next > self.start_block => {
+                log::warn!(
+                    "tip height missing or malformed; recovered blocks {} through {} from stored block hashes, resuming at {}",
+                    self.start_block,
+                    highest,
+                    highest
+                );
+                let runtime = self.runtime.lock().await;
+                runtime.context.lock().unwrap().db.write_tip_height(highest)?;
+            }
+            _ => info!("tip height missing and no block hashes stored from {}; nothing to recover", self.start_block),
+        }
+        Ok(())
+    }
+
+    // Checks that the block just below an operator-supplied resume height is still the one the
+    // node has at that height before syncing from it
+    async fn verified_resume_height(&self, resume_height: u32) -> Result<u32> {
+        let stored_tip = self.query_height().await?;
+        log::warn!(
+            "--resume-height {} overrides the stored tip {}; blocks in between will not be reconsidered",
+            resume_height,
+            stored_tip
+        );
+        if let Some(previous) = resume_height.checked_sub(1) {
+            let stored = self
+                .get_blockhash(previous)
+                .await
+                .ok_or_else(|| anyhow!("no stored blockhash for block {} to verify --resume-height against", previous))?;
+            let remote = self.fetch_blockhash(previous).await?;
+            if stored != remote {
+                return Err(anyhow!(
+                    "stored blockhash for block {} does not match the node, refusing to resume at {}",
+                    previous,
+                    resume_height
+                ));
+            }
+        }
+        Ok(resume_height)
+    }
+
+    // --resume-height, once verified, takes precedence over the stored tip
+    async fn start_height(&self) -> Result<u32> {
+        match self.args.resume_height {
+            Some(resume_height) => self.verified_resume_height(resume_height).await,
+            None => self.query_height().await,
+        }
+    }
+
+    // Starts a background snapshot when `height` lands on --dump-interval, skipping the interval
+    // if the previous dump is still being written
+    async fn maybe_dump(&self, height: u32) {
+        let (interval, dir) = match (self.args.dump_interval, self.args.dump_dir.clone()) {
+            (Some(interval), Some(dir)) if interval > 0 => (interval, dir),
+            _ => return,
+        };
+        if !height.is_multiple_of(interval) {
+            return;
+        }
+        if self.dump_running.swap(true, Ordering::SeqCst) {
+            log::warn!("previous snapshot still running, skipping dump at block {}", height);
+            return;
+        }
+        let db = self.runtime.lock().await.context.lock().unwrap().db.db.clone();
+        let keep = self.args.dump_keep;
+        let running = self.dump_running.clone();
+        tokio::task::spawn_blocking(move || {
+            match snapshot::dump_snapshot(&db, &dir, height, keep) {
+                Ok(path) => info!("wrote snapshot {} at block {}", path.display(), height),
+                Err(e) => log::error!("snapshot at block {} failed: {}", height, e),
+            }
+            running.store(false, Ordering::SeqCst);
+        });
+    }
+
+    // Compares a block's header time with the previous block's and warns, or fails with
+    // --reject-clock-skew, when they are further apart than --max-clock-skew
+    fn check_clock_skew(&self, height: u32, block: &[u8]) -> Result<()> {
+        let max_skew = match self.args.max_clock_skew {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let time = header_time(block)?;
+        let previous = self.last_block_time.swap(time, Ordering::Relaxed);
+        if previous != 0 && time.abs_diff(previous) > max_skew {
+            let message = format!(
+                "block {} header time {} is {}s from the previous block's {}, beyond --max-clock-skew {}",
+                height,
+                time,
+                time.abs_diff(previous),
+                previous,
+                max_skew
+            );
+            if self.args.reject_clock_skew {
+                return Err(anyhow!(message));
+            }
+            log::warn!("{}", message);
+        }
+        Ok(())
+    }
+
+    // Replaces the instance after `cause` failed block `height`. If the instance cannot be
+    // recreated, sync stops with both errors rather than running the block on a broken one.
+    fn respawn(
+        runtime: &mut MetashrewRuntime<RocksDBRuntimeAdapter>,
+        height: u32,
+        cause: &RuntimeError,
+    ) -> Result<()> {
+        runtime.refresh_memory().map_err(|e| {
+            anyhow!(
+                "block {} failed with {} and the instance could not be recreated: {:#}",
+                height,
+                cause,
+                e
+            )
+        })
+    }
+
+    // Writes the failed block as <height>.blk, the --block-dir layout, so a dump directory can be
+    // replayed as is, next to <height>.json describing the failure. Staged writes are only known
+    // for blocks run in-process.
+    fn write_crash_dump(
+        dir: &Path,
+        runtime: &MetashrewRuntime<RocksDBRuntimeAdapter>,
+        error: &RuntimeError,
+    ) -> Result<PathBuf> {
+        let (height, block) = {
+            let context = runtime.context.lock().unwrap();
+            (context.height, context.block.clone())
+        };
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(format!("{}.blk", height)), &block)?;
+        let dump = serde_json::json!({
+            "height": height,
+            "label": has_label().then(|| get_label().trim_end_matches("://")),
+            "blockhash": header_blockhash(&block).ok().map(hex::encode),
+            "indexer_hash": format!("0x{}", hex::encode(runtime.indexer_hash)),
+            "error": format!("{:#}", error),
+            "deterministic": error.is_deterministic(),
+            "block": format!("{}.blk", height),
+            "staged": runtime.staged_pairs().map(|pairs| {
+                pairs
+                    .iter()
+                    .map(|(key, value)| {
+                        serde_json::json!({ "key": hex::encode(key), "value": hex::encode(value) })
+                    })
+                    .collect::<Vec<_>>()
+            }),
+        });
+        let path = dir.join(format!("{}.json", height));
+        std::fs::write(&path, serde_json::to_vec_pretty(&dump)?)?;
+        Ok(path)
+    }
+
+    // A dump that cannot be written is logged rather than replacing the block's own error
+    fn dump_crash(&self, runtime: &MetashrewRuntime<RocksDBRuntimeAdapter>, error: &RuntimeError) {
+        if let Some(ref dir) = self.args.crash_dump_dir {
+            match Self::write_crash_dump(dir, runtime, error) {
+                Ok(path) => log::warn!("wrote crash dump {}", path.display()),
+                Err(e) => log::warn!("failed to write crash dump to {}: {:#}", dir.display(), e),
+            }
+        }
+    }
+
+    // Runs the loaded block in-process, or in the worker under --sandbox, returning the number
+    // of pairs it flushed. Reorg rollback always happens here, against the real store.
+    fn run_block(
+        &self,
+        runtime: &mut MetashrewRuntime<RocksDBRuntimeAdapter>,
+    ) -> Result<u64, RuntimeError> {
+        let sandbox = match self.sandbox {
+            Some(ref sandbox) => sandbox,
+            None => {
+                runtime.run()?;
+                return Ok(runtime.flushed_pairs());
+            }
+        };
+        runtime.handle_reorg()?;
+        let mut context = runtime.context.lock().unwrap();
+        let (height, block) = (context.height, context.block.clone());
+        let mut sandbox = sandbox.lock().unwrap();
+        sandbox.execute(&mut context.db, height, &block)?;
+        Ok(sandbox.flushed_pairs)
+    }
+
+    // Runs one block through the indexer and applies the per-block bookkeeping
+    async fn index_block(&self, best: u32, block_data: Vec<u8>) -> Result<()> {
+        self.check_clock_skew(best, &block_data)?;
+        let span = tracing::info_span!("execute", height = best, blockhash = tracing::field::Empty);
+        if !span.is_disabled() {
+            if let Ok(blockhash) = header_blockhash(&block_data) {
+                span.record("blockhash", hex::encode(blockhash));
+            }
+        }
+        let mut runtime = self.runtime.lock().await;
+        let _entered = span.enter();
+        runtime.context.lock().unwrap().begin_block(best, block_data);
+        runtime.context.lock().unwrap().db.set_height(best);
+        
+        let started = Instant::now();
+        match self.run_block(&mut runtime) {
+            Ok(pairs) => {
+                self.metrics.record_commit();
+                self.metrics.record_block(started.elapsed(), pairs);
+            }
+            Err(e) => {
+                self.dump_crash(&runtime, &e);
+                if e.is_deterministic() {
+                    if !self.args.quarantine_bad_blocks {
+                        return Err(anyhow!("block {} failed with {}", best, e));
+                    }
+                    let count = {
+                        let db = &mut runtime.context.lock().unwrap().db;
+                        record_dead_letter(db, best, self.args.hash_key_format, &format!("{:#}", e))?;
+                        quarantine_block(db, best, self.args.hash_key_format)?
+                    };
+                    log::warn!("block {} failed with {}, quarantined ({} total)", best, e, count);
+                    if count > self.args.quarantine_max {
+                        return Err(anyhow!(
+                            "{} blocks quarantined, exceeding --quarantine-max {}",
+                            count,
+                            self.args.quarantine_max
+                        ));
+                    }
+                    Self::respawn(&mut runtime, best, &e)?;
+                } else {
+                    log::warn!("block {} failed with {}, respawning cache", best, e);
+                    Self::respawn(&mut runtime, best, &e)?;
+                    let pairs = self
+                        .run_block(&mut runtime)
+                        .inspect_err(|e| self.dump_crash(&runtime, e))?;
+                    self.metrics.record_commit();
+                    self.metrics.record_block(started.elapsed(), pairs);
+                }
+            }
+        }
+        
+        if self.args.compute_state_root {
+            let root = compute_state_root(&mut runtime.context.lock().unwrap().db, best)?;
+            if let Some(expected) = self.checkpoints.get(&best) {
+                if &root != expected {
+                    return Err(anyhow!(
+                        "state root at block {} is {} but the checkpoint expects {}",
+                        best,
+                        hex::encode(&root),
+                        hex::encode(expected)
+                    ));
+                }
+                info!("block {} matches its checkpoint state root", best);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs before pull_block overwrites the stored hashes; the runtime keeps the values
+    async fn preserve_orphan_hashes(&self, from: u32, to: u32) -> Result<()> {
+        let runtime = self.runtime.lock().await;
+        let mut context = runtime.context.lock().unwrap();
+        for height in from..to {
+            if let Some(blockhash) = context.db.get(self.args.hash_key_format.key(height))? {
+                context.db.put(
+                    format!("{}{}/blockhash", ORPHANS_PREFIX, height).into_bytes(),
+                    &blockhash,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Records the rewind from `height` to `best` in the reorg log, before the orphaned hashes are
+    // forgotten. The node may be rewinding too and not have a block at `best` yet, so a failed
+    // lookup is logged as a null hash rather than failing the reorg.
+    async fn log_reorg(&self, best: u32, height: u32) -> Result<()> {
+        let event = ReorgEvent {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            old_tip: height - 1,
+            new_common_ancestor: best.checked_sub(1),
+            old_hash: self.get_blockhash(best).await.map(hex::encode),
+            new_hash: self.fetch_blockhash(best).await.ok().map(hex::encode),
+        };
+        {
+            let runtime = self.runtime.lock().await;
+            let mut context = runtime.context.lock().unwrap();
+            record_reorg(&mut context.db, &event)?;
+        }
+        if let Some(ref path) = self.args.reorg_log {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&event)?)?;
+        }
+        Ok(())
+    }
+
+    // Deletes the stored hashes of blocks above `from` up to `to`, which belong to the branch a
+    // reorg is abandoning, so later reorg checks do not compare against them
+    async fn forget_blockhashes(&self, from: u32, to: u32) -> Result<()> {
+        let runtime = self.runtime.lock().await;
+        let mut context = runtime.context.lock().unwrap();
+        for height in (from + 1)..to {
+            context.db.delete(self.args.hash_key_format.key(height))?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.clone();
+        // With the sender gone, as for a state built outside main, no shutdown can be requested
+        if shutdown.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // Records `height` as the tip, so blocks committed under --commit-tip-every are not
+    // re-indexed on restart
+    async fn stop_at(&self, height: u32) -> Result<()> {
+        info!("shutdown requested, stopping before block {}", height);
+        let mut runtime = self.runtime.lock().await;
+        runtime.flush_empty_blocks()?;
+        runtime.context.lock().unwrap().db.write_tip_height(height)?;
+        Ok(())
+    }
+
+    async fn exit_at_reached(&self, height: u32) -> Result<bool> {
+        if let Some(pin) = self.args.pin_tip {
+            if height > pin {
+                info!("Indexed through pinned tip {}, shutting down gracefully", pin);
+                let mut runtime = self.runtime.lock().await;
+                runtime.flush_empty_blocks()?;
+                runtime.context.lock().unwrap().db.write_tip_height(height)?;
+                return Ok(true);
+            }
+        }
+        if let Some(exit_at) = self.args.exit_at {
+            if height >= exit_at {
+                info!("Reached exit-at block {}, shutting down gracefully", exit_at);
+                let mut runtime = self.runtime.lock().await;
+                runtime.flush_empty_blocks()?;
+                runtime.context.lock().unwrap().db.write_tip_height(height)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        if self.args.recover_tip {
+            self.recover_tip().await?;
+        }
+        let mut height: u32 = self.start_height().await?;
+
+        if let Some(ref addr) = self.args.block_stream {
+            return self.run_stream(addr.clone(), height).await;
+        }
+        if let Some(ref dir) = self.args.block_dir {
+            return self.run_dir(dir.clone(), height).await;
+        }
+        
+        self.store_start_anchor().await?;
+        let mut audited = false;
+        loop {
+            if self.exit_at_reached(height).await? {
+                return Ok(());
+            }
+            self.audit_at_tip(height, &mut audited).await?;
+
+            self.liveness.beat(format!("checking for a reorg below block {}", height));
+            let best: u32 = match self.best_height(height).await {
+                // There is nothing indexed below the start to rewind to
+                Err(e) if e.is::<OrphanedStart>() => return Err(e),
+                result => result.unwrap_or(height),
+            };
+            check_next_height(best, height)?;
+            if best < height {
+                log::warn!("reorg detected, re-indexing from block {} (tip was {})", best, height);
+                self.log_reorg(best, height).await?;
+                if self.args.keep_orphans {
+                    self.preserve_orphan_hashes(best, height).await?;
+                }
+                self.forget_blockhashes(best, height).await?;
+            }
+            self.liveness.beat(format!("fetching block {}", best));
+            // Only the fetch is abandoned on shutdown; a block that has started indexing is
+            // always committed first
+            let block_data = tokio::select! {
+                block = self
+                    .pull_block(best)
+                    .instrument(tracing::info_span!("fetch", height = best, blockhash = tracing::field::Empty)) => block?,
+                _ = self.shutdown_requested() => return self.stop_at(best).await,
+            };
+            self.liveness.beat(format!("indexing block {}", best));
+            self.index_block(best, block_data).await?;
+            // Held-back empty blocks would otherwise wait for the next block to be stored
+            if best >= self.metrics.node_tip.load(Ordering::Relaxed) {
+                self.runtime.lock().await.flush_empty_blocks()?;
+            }
+
+            height = best + 1;
+            set_current_height(height);
+            self.maybe_dump(height).await;
+        }
+    }
+
+    // Stores the hash and checksum pull_block would have for a block that did not come from the
+    // node
+    async fn record_block(&self, height: u32, block: &[u8]) -> Result<()> {
+        let runtime = self.runtime.lock().await;
+        let mut context = runtime.context.lock().unwrap();
+        if !self.args.no_reorg_tracking {
+            context.db.put(
+                &self.args.hash_key_format.key(height),
+                &header_blockhash(block)?,
+            )?;
+        }
+        if self.args.store_block_checksums {
+            context.db.put(
+                &(String::from(BLOCK_CHECKSUM) + &height.to_string()).into_bytes(),
+                &block_checksum(block),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Indexes an on-disk block archive from `height` in order. The archive is taken as the
+    // canonical chain, so there is no reorg detection and the node is never contacted.
+    async fn run_dir(&self, dir: PathBuf, mut height: u32) -> Result<()> {
+        let mut source = DirBlockSource::open(dir.clone(), height).await?;
+        loop {
+            if self.exit_at_reached(height).await? {
+                return Ok(());
+            }
+            if *self.shutdown.borrow() {
+                return self.stop_at(height).await;
+            }
+            if !source.has_next().await? {
+                match self.args.on_missing_block {
+                    MissingBlock::Stop => {
+                        info!("{} has no block {}, indexing finished", dir.display(), height);
+                        let mut runtime = self.runtime.lock().await;
+                        runtime.flush_empty_blocks()?;
+                        runtime.context.lock().unwrap().db.write_tip_height(height)?;
+                        return Ok(());
+                    }
+                    MissingBlock::Error => {
+                        return Err(anyhow!("{} has no block {}", dir.display(), height));
+                    }
+                }
+            }
+            self.liveness.beat(format!("reading block {} from {}", height, dir.display()));
+            let (best, block) = source
+                .next_block()
+                .instrument(tracing::info_span!("fetch", height))
+                .await?;
+            self.record_block(best, &block).await?;
+            self.liveness.beat(format!("indexing block {}", best));
+            self.index_block(best, block).await?;
+
+            height = best + 1;
+            set_current_height(height);
+            self.maybe_dump(height).await;
+        }
+    }
+
+    // Indexes blocks pushed by a --block-stream server. The stream is trusted to deliver the
+    // canonical chain, so reorg detection against the node is skipped; dropped connections are
+    // retried from the next height.
+    async fn run_stream(&self, addr: String, mut height: u32) -> Result<()> {
+        loop {
+            let mut source = match StreamBlockSource::connect(&addr, height).await {
+                Ok(source) => source,
+                Err(e) => {
+                    log::warn!("failed to connect to block stream {}: {}", addr, e);
+                    tokio::time::sleep(Duration::from_millis(3000)).await;
+                    continue;
+                }
+            };
+            loop {
+                if self.exit_at_reached(height).await? {
+                    return Ok(());
+                }
+                let frame = tokio::select! {
+                    frame = source.next_block().instrument(tracing::info_span!("fetch", height)) => frame,
+                    _ = self.shutdown_requested() => return self.stop_at(height).await,
+                };
+                let (best, block) = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        log::warn!("block stream {} failed at height {}: {}", addr, height, e);
+                        break;
+                    }
+                };
+                self.record_block(best, &block).await?;
+                self.index_block(best, block).await?;
+
+                height = best + 1;
+                set_current_height(height);
+                self.maybe_dump(height).await;
+            }
+            tokio::time::sleep(Duration::from_millis(3000)).await;
+        }
+    }
+}
+
+// Accepts a single request or a JSON-RPC 2.0 batch. Batch entries run one after another, in
+// order, each holding the runtime lock only for its own call, and their responses come back
+// as an array in the same order.
+#[post("/")]
+async fn handle_jsonrpc(
+    body: web::Json<Value>,
+    state: web::Data<AppState>,
+) -> ActixResult<HttpResponse> {
+    let entries = match body.into_inner() {
+        Value::Array(entries) => entries,
+        single => {
+            let request: JsonRpcRequest =
+                serde_json::from_value(single).map_err(error::ErrorBadRequest)?;
+            return handle_request(request, &state).await;
+        }
+    };
+    if entries.is_empty() {
+        return Ok(HttpResponse::Ok().json(invalid_request()));
+    }
+    let mut responses: Vec<Value> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let request: JsonRpcRequest = match serde_json::from_value(entry) {
+            Ok(request) => request,
+            Err(_) => {
+                responses.push(invalid_request());
+                continue;
+            }
+        };
+        let response = handle_request(request, &state).await?;
+        let bytes = actix_web::body::to_bytes(response.into_body())
+            .await
+            .map_err(|_| error::ErrorInternalServerError("failed to buffer batch entry"))?;
+        responses.push(serde_json::from_slice(&bytes)?);
+    }
+    Ok(HttpResponse::Ok().json(responses))
+}
+
+fn invalid_request() -> Value {
+    serde_json::json!({
+        "id": null,
+        "error": { "code": -32600, "message": "Invalid Request", "data": null },
+        "jsonrpc": "2.0",
+    })
+}
+
+async fn handle_request(
+    body: JsonRpcRequest,
+    state: &web::Data<AppState>,
+) -> ActixResult<HttpResponse> {
+    debug!("RPC request: {}", serde_json::to_string(&body).unwrap());
+
+    let mut runtime = state.runtime.lock().await;
+
+    if body.method == "metashrew_view" {
+        if body.params.len() < 3 {
+            return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: requires [view_name, input_data, height]".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            }));
+        }
+
+        let view_name = match body.params[0].as_str() {
+            Some(s) => s.to_string(),
+            None => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: view_name must be a string".to_string(),
                     data: None,
                 },
                 jsonrpc: "2.0".to_string(),
             })),
         };
 
-        match runtime.view(
-            view_name,
-            &hex::decode(input_hex.trim_start_matches("0x"))
-                .map_err(|e| error::ErrorBadRequest(format!("Invalid hex input: {}", e)))?,
-            height,
-        ) {
-            Ok(result) => Ok(HttpResponse::Ok().json(JsonRpcResult {
+        let input_hex = match body.params[1].as_str() {
+            Some(s) => s.to_string(),
+            None => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: input_data must be a hex string".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+
+        let input = hex::decode(input_hex.trim_start_matches("0x"))
+            .map_err(|e| error::ErrorBadRequest(format!("Invalid hex input: {}", e)))?;
+
+        let result = match &body.params[2] {
+            Value::String(s) if s == "pending" => match state.mempool {
+                Some(ref mempool) => mempool.lock().await.view(view_name, &input),
+                None => Err(anyhow!("mempool indexing is not enabled")),
+            },
+            Value::String(s) if s == "latest" => runtime.view(view_name, &input, current_height()),
+            Value::Number(n) => {
+                let height = n.as_u64().unwrap_or(0) as u32;
+                if let Err(message) = state.check_history_depth(height) {
+                    return Ok(HttpResponse::Ok().json(JsonRpcError {
+                        id: body.id,
+                        error: JsonRpcErrorObject {
+                            code: -32002,
+                            message,
+                            data: None,
+                        },
+                        jsonrpc: "2.0".to_string(),
+                    }));
+                }
+                runtime.view(view_name, &input, height)
+            }
+            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
                 id: body.id,
-                result: format!("0x{}", hex::encode(result)),
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: height must be a number, 'latest' or 'pending'".to_string(),
+                    data: None,
+                },
                 jsonrpc: "2.0".to_string(),
             })),
+        };
+
+        match result {
+            Ok(result) => match state.limit_response(result) {
+                Ok(result) => Ok(HttpResponse::Ok().json(JsonRpcResult {
+                    id: body.id,
+                    result: format!("0x{}", hex::encode(result)),
+                    jsonrpc: "2.0".to_string(),
+                })),
+                Err(error) => Ok(HttpResponse::Ok().json(JsonRpcError {
+                    id: body.id,
+                    error,
+                    jsonrpc: "2.0".to_string(),
+                })),
+            },
             Err(err) => Ok(HttpResponse::Ok().json(JsonRpcError {
                 id: body.id,
                 error: JsonRpcErrorObject {
@@ -395,9 +2687,63 @@ async fn handle_jsonrpc(
     } else if body.method == "metashrew_height" {
         Ok(HttpResponse::Ok().json(JsonRpcResult {
             id: body.id,
-            result: unsafe { _HEIGHT }.to_string(),
+            result: current_height().to_string(),
             jsonrpc: "2.0".to_string(),
         }))
+    } else if body.method == "metashrew_info" {
+        let label = has_label().then(|| get_label().trim_end_matches("://"));
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "id": body.id,
+            "result": server_info(label, get_chain_id(), &runtime.indexer_hash, current_height()),
+            "jsonrpc": "2.0",
+        })))
+    } else if body.method == "metashrew_reorgs" {
+        let count = match body.params.first() {
+            None => DEFAULT_REORGS,
+            Some(Value::Number(n)) if body.params.len() == 1 && n.as_u64().is_some() => {
+                n.as_u64().unwrap() as usize
+            }
+            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: takes [] or [count]".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+        let events = reorg_events(&mut runtime.context.lock().unwrap().db, count)
+            .map_err(|_| <anyhow::Error as Into<IndexerError>>::into(anyhow!("DB connection error while reading the reorg log")))?;
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "id": body.id,
+            "result": events,
+            "jsonrpc": "2.0",
+        })))
+    } else if body.method == "metashrew_lastmodified" {
+        let key = match body.params.first() {
+            Some(Value::String(s)) if body.params.len() == 1 => hex::decode(s.trim_start_matches("0x")).ok(),
+            _ => None,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: requires [key] as hex".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+        let height = MetashrewRuntime::<RocksDBRuntimeAdapter>::db_last_modified(runtime.context.clone(), &key)
+            .map_err(|_| <anyhow::Error as Into<IndexerError>>::into(anyhow!("DB connection error while fetching last modified height")))?;
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "id": body.id,
+            "result": height,
+            "jsonrpc": "2.0",
+        })))
     } else if body.method == "metashrew_getblockhash" {
         if body.params.len() != 1 {
             return Ok(HttpResponse::Ok().json(JsonRpcError {
@@ -424,7 +2770,7 @@ async fn handle_jsonrpc(
             })),
         };
 
-        let key = (String::from(HEIGHT_TO_HASH) + &height.to_string()).into_bytes();
+        let key = state.hash_key_format.key(height);
         match runtime.context.lock().unwrap().db.get(&key).map_err(|_| <anyhow::Error as Into<IndexerError>>::into(anyhow!("DB connection error while fetching blockhash")))? {
             Some(hash) => Ok(HttpResponse::Ok().json(JsonRpcResult {
                 id: body.id,
@@ -441,27 +2787,305 @@ async fn handle_jsonrpc(
                 jsonrpc: "2.0".to_string(),
             })),
         }
-    } else {
-        Ok(HttpResponse::Ok().json(JsonRpcError {
-            id: body.id,
-            error: JsonRpcErrorObject {
-                code: -32601,
-                message: format!("Method '{}' not found", body.method),
-                data: None,
-            },
-            jsonrpc: "2.0".to_string(),
-        }))
-    }
-}
-
-#[allow(deprecated)]
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-    let args = Arc::new(Args::parse());
-
-    if let Some(ref label) = args.label {
-        set_label(label.clone());
+    } else if body.method == "metashrew_stateroot" {
+        if body.params.len() != 1 {
+            return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: requires [height]".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            }));
+        }
+
+        let height = match &body.params[0] {
+            Value::String(s) if s == "latest" => current_height().saturating_sub(1),
+            Value::Number(n) => n.as_u64().unwrap_or(0) as u32,
+            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: height must be a number or 'latest'".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+
+        let key = (String::from(STATE_ROOT) + &height.to_string()).into_bytes();
+        match runtime.context.lock().unwrap().db.get(&key).map_err(|_| <anyhow::Error as Into<IndexerError>>::into(anyhow!("DB connection error while fetching state root")))? {
+            Some(root) => Ok(HttpResponse::Ok().json(JsonRpcResult {
+                id: body.id,
+                result: format!("0x{}", hex::encode(root)),
+                jsonrpc: "2.0".to_string(),
+            })),
+            None => Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32000,
+                    message: "State root not found".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        }
+    } else if body.method == "metashrew_internal" {
+        if !state.expose_internal {
+            return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32601,
+                    message: "metashrew_internal is disabled; start with --expose-internal".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            }));
+        }
+        let suffix = match body.params.first() {
+            Some(Value::String(s)) if body.params.len() == 1 => s.clone(),
+            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: requires [key_suffix]".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+
+        let key = (String::from("/__INTERNAL/") + &suffix).into_bytes();
+        match runtime.context.lock().unwrap().db.get(&key).map_err(|_| <anyhow::Error as Into<IndexerError>>::into(anyhow!("DB connection error while fetching internal key")))? {
+            Some(value) => match state.limit_response(value) {
+                Ok(value) => Ok(HttpResponse::Ok().json(JsonRpcResult {
+                    id: body.id,
+                    result: format!("0x{}", hex::encode(value)),
+                    jsonrpc: "2.0".to_string(),
+                })),
+                Err(error) => Ok(HttpResponse::Ok().json(JsonRpcError {
+                    id: body.id,
+                    error,
+                    jsonrpc: "2.0".to_string(),
+                })),
+            },
+            None => Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32000,
+                    message: "Internal key not found".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        }
+    } else if body.method == "metashrew_reload" {
+        if !state.allow_reload {
+            return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32601,
+                    message: "metashrew_reload is disabled; start with --allow-reload".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            }));
+        }
+        let path = match body.params.first() {
+            Some(Value::String(s)) if body.params.len() == 1 => PathBuf::from(s),
+            _ => return Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32602,
+                    message: "Invalid params: requires [indexer_path]".to_string(),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        };
+
+        // Holding the runtime lock keeps the sync loop between blocks for the whole swap
+        let mut reloaded = runtime.reload(path.clone());
+        if let (Ok(true), Some(ref mempool)) = (&reloaded, &state.mempool) {
+            if let Err(e) = mempool.lock().await.reload(path.clone()) {
+                reloaded = Err(e.context("indexer reloaded but the mempool index was not"));
+            }
+        }
+        match reloaded {
+            Ok(reloaded) => {
+                let hash = hex::encode(runtime.indexer_hash);
+                if reloaded {
+                    info!("reloaded indexer from {} ({})", path.display(), hash);
+                }
+                Ok(HttpResponse::Ok().json(JsonRpcResult {
+                    id: body.id,
+                    result: format!("0x{}", hash),
+                    jsonrpc: "2.0".to_string(),
+                }))
+            }
+            Err(e) => Ok(HttpResponse::Ok().json(JsonRpcError {
+                id: body.id,
+                error: JsonRpcErrorObject {
+                    code: -32000,
+                    message: format!("reload failed: {}", e),
+                    data: None,
+                },
+                jsonrpc: "2.0".to_string(),
+            })),
+        }
+    } else {
+        Ok(HttpResponse::Ok().json(JsonRpcError {
+            id: body.id,
+            error: JsonRpcErrorObject {
+                code: -32601,
+                message: format!("Method '{}' not found", body.method),
+                data: None,
+            },
+            jsonrpc: "2.0".to_string(),
+        }))
+    }
+}
+
+#[get("/metrics")]
+async fn handle_metrics(state: web::Data<AppState>) -> impl Responder {
+    let body = format!(
+        "# TYPE metashrew_seconds_since_last_block gauge\n\
+         metashrew_seconds_since_last_block {:.3}\n\
+         # TYPE metashrew_height gauge\n\
+         metashrew_height {}\n\
+         # TYPE metashrew_node_tip_height gauge\n\
+         metashrew_node_tip_height {}\n\
+         {}",
+        state.metrics.seconds_since_last_block(),
+        current_height(),
+        state.metrics.node_tip.load(Ordering::Relaxed),
+        state.metrics.render_indexer(),
+    );
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+// Resolves on the first SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            log::warn!("failed to install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// "1", "true" and "yes" in any case, ignoring surrounding whitespace
+fn is_truthy(value: &[u8]) -> bool {
+    matches!(
+        String::from_utf8_lossy(value).trim().to_lowercase().as_str(),
+        "1" | "true" | "yes"
+    )
+}
+
+// Resolves once `key` reads as truthy, deleting it so a restarted process does not stop again
+async fn watch_shutdown_key(mut db: RocksDBRuntimeAdapter, key: Vec<u8>, poll: Duration) {
+    loop {
+        tokio::time::sleep(poll).await;
+        match db.get(&key) {
+            Ok(Some(value)) if is_truthy(&value) => {
+                info!("{} is set, shutting down", String::from_utf8_lossy(&key));
+                if let Err(e) = db.delete(&key) {
+                    log::warn!("failed to clear {}: {}", String::from_utf8_lossy(&key), e);
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("failed to read {}: {}", String::from_utf8_lossy(&key), e),
+        }
+    }
+}
+
+// Resolves on SIGINT/SIGTERM or, under --watch-shutdown-key, once the shutdown key is set
+async fn shutdown_requested(shutdown_key: Option<(RocksDBRuntimeAdapter, Vec<u8>)>) {
+    match shutdown_key {
+        Some((db, key)) => tokio::select! {
+            _ = shutdown_signal() => {}
+            _ = watch_shutdown_key(db, key, SHUTDOWN_KEY_POLL) => {}
+        },
+        None => shutdown_signal().await,
+    }
+}
+
+// The --sandbox worker: runs blocks sent over stdin with the same limits as the supervisor,
+// never touching the database itself
+fn serve_sandbox(args: &Args) -> Result<()> {
+    let mut worker = SandboxWorker::new(
+        PathBuf::from(&args.indexer),
+        std::io::stdin(),
+        std::io::stdout(),
+        block_timeout(args),
+    )?;
+    apply_runtime_limits(&mut worker.runtime, args)?;
+    worker.serve()
+}
+
+// Imports the snapshot at `path` into an empty store, returning the number of entries restored,
+// or None when the store already has a tip height
+fn bootstrap_snapshot(adapter: &RocksDBRuntimeAdapter, path: &Path) -> Result<Option<u64>> {
+    if !snapshot::is_empty(&adapter.db)? {
+        return Ok(None);
+    }
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    Ok(Some(snapshot::import_snapshot(&adapter.db, &mut reader)?))
+}
+
+#[allow(deprecated)]
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let args = Arc::new(Args::parse());
+    if std::env::var_os(SANDBOX_WORKER_ENV).is_some() {
+        return serve_sandbox(&args);
+    }
+    let _tracing = match args.otlp_endpoint {
+        Some(ref endpoint) => Some(telemetry::init_otlp(endpoint)?),
+        None => None,
+    };
+
+    if let Some(secs) = args.loop_watchdog_secs {
+        if secs.saturating_mul(1000) <= args.poll_interval_max_ms {
+            return Err(anyhow!(
+                "--loop-watchdog-secs must exceed --poll-interval-max-ms, or an idle wait at the tip looks like a stall"
+            ));
+        }
+    }
+    if args.resume_height.is_some() && !args.i_know_what_im_doing {
+        return Err(anyhow!("--resume-height requires --i-know-what-im-doing"));
+    }
+    if let Some(resolution) = args.history_resolution {
+        // Reorg rollback needs every version it might remove
+        if resolution.recent < REORG_WINDOW {
+            return Err(anyhow!(
+                "--history-resolution must keep at least the last {} blocks",
+                REORG_WINDOW
+            ));
+        }
+    }
+
+    let _pid_file = match args.pid_file {
+        Some(ref path) => Some(PidFile::acquire(path.clone())?),
+        None => None,
+    };
+
+    if let Some(chain_id) = args.chain_id {
+        set_chain_id(chain_id);
+    }
+    if let Some(ref label) = args.label {
+        set_label(normalize_label(label, args.lowercase_label)?);
     }
 
     let start_block = args.start_block.unwrap_or(0);
@@ -485,33 +3109,362 @@ async fn main() -> Result<()> {
     opts.set_max_background_compactions(4);
     opts.set_disable_auto_compactions(false);
 
+    let mut adapter = RocksDBRuntimeAdapter::open(args.db_path.clone(), opts)?;
+    adapter.set_commit_tip_every(args.commit_tip_every);
+    let mempool_adapter = OverlayAdapter::new(adapter.clone(), MEMPOOL_PREFIX.as_bytes().to_vec());
+    // Attached after the mempool overlay takes its clone: the log holds one commit at a time,
+    // and mempool state is rebuilt from the node anyway
+    if let Some(ref dir) = args.wal_dir {
+        adapter.set_wal_dir(dir)?;
+        if adapter.replay_wal()? {
+            info!("replayed an interrupted commit from {}", dir.display());
+        }
+    }
+    compact_on_start(&args, &adapter)?;
+    let shutdown_key = args.watch_shutdown_key.then(|| {
+        (
+            adapter.clone(),
+            format!("{}{}", SHUTDOWN_KEY, indexer_label(&args)).into_bytes(),
+        )
+    });
+
+    if let Some(ref path) = args.bootstrap_snapshot {
+        match bootstrap_snapshot(&adapter, path)? {
+            Some(count) => info!("bootstrapped {} entries from snapshot {}", count, path.display()),
+            None => info!("store already has a tip height, skipping bootstrap snapshot"),
+        }
+    }
+
     // Create runtime with RocksDB adapter
-    let runtime = Arc::new(Mutex::new(MetashrewRuntime::load(
-        PathBuf::from(&args.indexer),
-        RocksDBRuntimeAdapter::open(args.db_path.clone(), opts)?,
-    )?));
+    let mut runtime = load_runtime(PathBuf::from(&args.indexer), adapter, &args)?;
+    runtime.capture_flush = args.crash_dump_dir.is_some();
+    // Subcommands such as fill-gaps set and restore the tip around each block themselves
+    if args.command.is_none() {
+        runtime.coalesce_empty_blocks = args.coalesce_empty_blocks;
+    }
+    runtime.keep_orphans = args.keep_orphans;
+    let runtime = Arc::new(Mutex::new(runtime));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let sandbox = if args.sandbox {
+        let exe = std::env::current_exe()?;
+        let argv: Vec<_> = std::env::args_os().skip(1).collect();
+        Some(Arc::new(std::sync::Mutex::new(Sandbox::new(move || {
+            let mut command = std::process::Command::new(&exe);
+            command.args(&argv).env(SANDBOX_WORKER_ENV, "1");
+            command
+        }))))
+    } else {
+        None
+    };
 
     // Create indexer state
     let mut indexer = IndexerState {
         runtime: runtime.clone(),
         args: args.clone(),
         start_block,
+        rate_limiter: args
+            .rpc_max_rps
+            .map(|rps| Arc::new(Mutex::new(RateLimiter::new(rps)))),
+        metrics: Arc::new(Metrics::new(indexer_label(&args))),
+        dump_running: Arc::new(AtomicBool::new(false)),
+        client: rpc_client(&args.rpc_header)?,
+        last_block_time: Arc::new(AtomicU32::new(0)),
+        shutdown: shutdown_rx,
+        checkpoints: Arc::new(match args.checkpoints {
+            Some(ref path) => load_checkpoints(path)?,
+            None => BTreeMap::new(),
+        }),
+        sandbox,
+        checked_tip: Arc::new(std::sync::Mutex::new(None)),
+        liveness: Arc::new(Liveness::new()),
+    };
+
+    if let Some(ref command) = args.command {
+        let codec = match args.key_codec {
+            Some(ref spec) => KeyCodec::parse(spec)?,
+            None => KeyCodec::new(),
+        };
+        match command {
+            Command::VerifyBlocks { from, to } => {
+                let from = from.unwrap_or(start_block);
+                let to = match to {
+                    Some(v) => *v,
+                    None => indexer.query_height().await?.saturating_sub(1),
+                };
+                let failures = indexer.verify_blocks(from, to).await?;
+                if failures > 0 {
+                    return Err(anyhow!("{} blocks failed checksum verification", failures));
+                }
+                info!("verified blocks {} through {}", from, to);
+            }
+            Command::VerifyDeterminism { from, to } => {
+                if let Some(key) = indexer.verify_determinism(*from, *to).await? {
+                    return Err(anyhow!(
+                        "indexer is nondeterministic over blocks {} through {}: first divergent key {}",
+                        from,
+                        to,
+                        codec.display(&key)
+                    ));
+                }
+                info!("blocks {} through {} indexed identically twice", from, to);
+            }
+            Command::FillGaps { from, to, gap_safe } => {
+                if args.no_reorg_tracking {
+                    return Err(anyhow!(
+                        "fill-gaps finds gaps through stored block hashes, which --no-reorg-tracking does not record"
+                    ));
+                }
+                let gaps = indexer.find_gaps(*from, *to).await?;
+                if gaps.is_empty() {
+                    info!("no gaps between blocks {} and {}", from, to);
+                } else if !*gap_safe {
+                    return Err(anyhow!(
+                        "{} blocks missing between {} and {} (first {}); refusing to index them out of order without --gap-safe",
+                        gaps.len(),
+                        from,
+                        to,
+                        gaps[0]
+                    ));
+                } else {
+                    indexer.fill_gaps(&gaps).await?;
+                    info!("filled {} gaps between blocks {} and {}", gaps.len(), from, to);
+                }
+            }
+            Command::BackwardScan { from, to, into } => {
+                if from < to {
+                    return Err(anyhow!("backward-scan runs from a higher --from down to --to"));
+                }
+                let into = normalize_label(into, args.lowercase_label)?;
+                if let Some(ref label) = args.label {
+                    if normalize_label(label, args.lowercase_label)? == into {
+                        return Err(anyhow!(
+                            "refusing to backward-scan into the live label {}",
+                            into
+                        ));
+                    }
+                }
+                set_label(into.clone());
+                indexer.backward_scan(*from, *to).await?;
+                info!("scanned blocks {} down to {} into label {}", from, to, into);
+            }
+            Command::Snapshot { out } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                let mut writer = std::io::BufWriter::new(std::fs::File::create(out)?);
+                let count = snapshot::export_snapshot(&db, &mut writer)?;
+                info!("wrote {} entries to snapshot {}", count, out.display());
+            }
+            Command::ExportJsonl { out } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                let mut writer = std::io::BufWriter::new(std::fs::File::create(out)?);
+                let count = snapshot::export_jsonl(&db, &mut writer, &codec)?;
+                info!("wrote {} entries to {}", count, out.display());
+            }
+            #[cfg(feature = "parquet")]
+            Command::ExportParquet { out, table } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                let prefix = hex::decode(table.trim_start_matches("0x"))?;
+                let writer = std::io::BufWriter::new(std::fs::File::create(out)?);
+                let count = snapshot::export_parquet(&db, writer, &prefix, &codec)?;
+                info!("wrote {} rows to {}", count, out.display());
+            }
+            Command::Dump { prefix, limit } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                let prefix = hex::decode(prefix.trim_start_matches("0x"))?;
+                let mut stdout = std::io::stdout().lock();
+                snapshot::dump(&db, &mut stdout, &prefix, *limit, &codec)?;
+            }
+            Command::MigrateHashKeys => {
+                let runtime = runtime.lock().await;
+                let count =
+                    migrate_hash_keys(&mut runtime.context.lock().unwrap().db, args.hash_key_format)?;
+                info!("migrated {} block hash keys to the {:?} layout", count, args.hash_key_format);
+            }
+            Command::DiffLabels { a, b, detail } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                let a = normalize_label(a, args.lowercase_label)?;
+                let b = normalize_label(b, args.lowercase_label)?;
+                let mut stdout = std::io::stdout().lock();
+                let diff = snapshot::diff_labels(
+                    &db,
+                    &a,
+                    &b,
+                    if *detail { Some(&mut stdout) } else { None },
+                    &codec,
+                )?;
+                println!(
+                    "{} only in {}, {} only in {}, {} differing, {} identical",
+                    diff.only_a, a, diff.only_b, b, diff.changed, diff.same
+                );
+                if !diff.is_empty() {
+                    return Err(anyhow!("labels {} and {} differ", a, b));
+                }
+            }
+            Command::Stats => {
+                let db = runtime.lock().await.context.lock().unwrap().db.db.clone();
+                for (table, stats) in snapshot::table_stats(&db)? {
+                    let name = match table {
+                        Some(table) => codec
+                            .name(&[table])
+                            .map(|name| name.to_string())
+                            .unwrap_or_else(|| format!("{:02x}", table)),
+                        None => String::from("(empty key)"),
+                    };
+                    println!(
+                        "{} count={} total={} avg={:.1}",
+                        name,
+                        stats.count,
+                        stats.total_value_bytes,
+                        stats.avg_value_bytes()
+                    );
+                }
+            }
+            Command::Assert {
+                block,
+                height,
+                expect,
+                update,
+            } => {
+                let db = runtime.lock().await.context.lock().unwrap().db.clone();
+                let actual = assert_block(&args, db, *height, std::fs::read(block)?)?;
+                if *update {
+                    let mut writer = std::io::BufWriter::new(std::fs::File::create(expect)?);
+                    let count = snapshot::write_jsonl(&actual, &mut writer, &codec)?;
+                    info!("wrote {} pairs to {}", count, expect.display());
+                } else {
+                    let mut stdout = std::io::stdout().lock();
+                    let same = compare_golden(*height, &actual, expect, &mut stdout, &codec)?;
+                    info!("block {} matched all {} pairs in {}", height, same, expect.display());
+                }
+            }
+            Command::Dlq { action } => indexer.run_dlq(action).await?,
+            Command::Audit => {
+                if args.no_reorg_tracking {
+                    return Err(anyhow!(
+                        "audit checks stored block hashes, which --no-reorg-tracking does not record"
+                    ));
+                }
+                let report = indexer.audit(indexer.query_height().await?).await?;
+                println!("{}", report);
+                if !report.passed() {
+                    return Err(anyhow!("audit failed"));
+                }
+            }
+            Command::Probe => {
+                let report = indexer.probe().await?;
+                println!("{}", report);
+                if !report.passed() {
+                    return Err(anyhow!("the node is missing methods this configuration calls"));
+                }
+            }
+            Command::Bench {
+                store,
+                path,
+                blocks,
+                writes_per_block,
+                value_size,
+            } => {
+                let config = BenchConfig {
+                    blocks: *blocks,
+                    writes_per_block: *writes_per_block,
+                    value_size: *value_size,
+                };
+                print_bench_report(&run_bench(*store, path.as_ref(), &args.db_path, &config)?);
+            }
+            Command::BenchmarkRpcOnly { from, to } => {
+                if from > to {
+                    return Err(anyhow!("--from {} is above --to {}", from, to));
+                }
+                print_rpc_bench_report(&indexer.benchmark_rpc(*from, *to).await?);
+            }
+        }
+        return Ok(());
+    }
+
+    let mempool = if args.index_mempool {
+        let mempool_runtime = load_runtime(PathBuf::from(&args.indexer), mempool_adapter, &args)?;
+        Some(Arc::new(Mutex::new(MempoolIndex {
+            runtime: mempool_runtime,
+            args: args.clone(),
+            ttl: Duration::from_secs(args.mempool_ttl),
+            refreshed: None,
+        })))
+    } else {
+        None
     };
 
+    if let Some(ref mempool) = mempool {
+        let poller = IndexerState {
+            runtime: runtime.clone(),
+            args: args.clone(),
+            start_block,
+            rate_limiter: indexer.rate_limiter.clone(),
+            metrics: indexer.metrics.clone(),
+            dump_running: indexer.dump_running.clone(),
+            client: indexer.client.clone(),
+            last_block_time: indexer.last_block_time.clone(),
+            shutdown: indexer.shutdown.clone(),
+            checkpoints: indexer.checkpoints.clone(),
+            sandbox: indexer.sandbox.clone(),
+            checked_tip: indexer.checked_tip.clone(),
+            liveness: indexer.liveness.clone(),
+        };
+        let mempool = mempool.clone();
+        tokio::spawn(async move {
+            if let Err(e) = poller.run_mempool(mempool).await {
+                log::error!("Mempool indexer error: {}", e);
+            }
+        });
+    }
+
+    if let Some(resolution) = args.history_resolution {
+        let runtime = runtime.clone();
+        tokio::spawn(async move {
+            if let Err(e) = compact_history(runtime, resolution, start_block).await {
+                log::error!("History compaction error: {}", e);
+            }
+        });
+    }
+
     // Create app state for JSON-RPC server
     let app_state = web::Data::new(AppState {
         runtime: runtime.clone(),
+        mempool,
+        metrics: indexer.metrics.clone(),
+        expose_internal: args.expose_internal,
+        allow_reload: args.allow_reload,
+        max_response_bytes: args.max_response_bytes,
+        response_overflow: args.response_overflow,
+        max_history_depth: args.max_history_depth,
+        hash_key_format: args.hash_key_format,
     });
 
+    // Under --loop-watchdog-action recover the watchdog asks for the sync loop to be restarted
+    // from this copy of its state
+    let (restart_tx, mut restart_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+    let restart = indexer.clone();
+    if let Some(secs) = args.loop_watchdog_secs {
+        spawn_loop_watchdog(
+            indexer.liveness.clone(),
+            Duration::from_secs(secs),
+            args.loop_watchdog_action,
+            runtime.clone(),
+            indexer.metrics.clone(),
+            restart_tx,
+        );
+    }
+
     // Start the indexer in a separate task
-    let indexer_handle = tokio::spawn(async move {
+    let mut indexer_handle = tokio::spawn(async move {
         if let Err(e) = indexer.run().await {
             log::error!("Indexer error: {}", e);
         }
     });
 
     // Start the JSON-RPC server
-    let server_handle = tokio::spawn(
+    let rest = args.rest;
+    let mut server_handle = tokio::spawn(
         HttpServer::new(move || {
             App::new()
                 .wrap(Cors::default()
@@ -524,7 +3477,17 @@ async fn main() -> Result<()> {
                     }))
                 .app_data(app_state.clone())
                 .service(handle_jsonrpc)
+                .service(handle_metrics)
+                .service(handle_scan)
+                .configure(|cfg| {
+                    if rest {
+                        cfg.service(handle_rest_key)
+                            .service(handle_rest_height)
+                            .service(handle_rest_view);
+                    }
+                })
         })
+        .disable_signals()
         .bind((args.host.as_str(), args.port))?
         .run()
     );
@@ -532,19 +3495,1753 @@ async fn main() -> Result<()> {
     info!("Server running at http://{}:{}", args.host, args.port);
     
     // Wait for either component to finish (or fail)
-    tokio::select! {
-        result = indexer_handle => {
-            if let Err(e) = result {
-                log::error!("Indexer task failed: {}", e);
+    let shutdown = shutdown_requested(shutdown_key);
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = &mut indexer_handle => {
+                if let Err(e) = result {
+                    log::error!("Indexer task failed: {}", e);
+                }
             }
-        }
-        result = server_handle => {
-            if let Err(e) = result {
-                log::error!("Server task failed: {}", e);
+            result = &mut server_handle => {
+                if let Err(e) = result {
+                    log::error!("Server task failed: {}", e);
+                }
+            }
+            _ = &mut shutdown => {
+                let _ = shutdown_tx.send(true);
+                if let Err(e) = indexer_handle.await {
+                    log::error!("Indexer task failed: {}", e);
+                }
+            }
+            Some(()) = restart_rx.recv() => {
+                // A task stuck outside an await point keeps running; the watchdog aborts the
+                // process if the new loop cannot make progress past it
+                log::warn!("restarting the stalled sync loop from the stored tip");
+                indexer_handle.abort();
+                let mut indexer = restart.clone();
+                indexer_handle = tokio::spawn(async move {
+                    if let Err(e) = indexer.run().await {
+                        log::error!("Indexer error: {}", e);
+                    }
+                });
+                continue;
             }
         }
+        break;
     }
 
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_key_formats_round_trip() {
+        for format in [HashKeyFormat::String, HashKeyFormat::Binary] {
+            for height in [0, 9, 10, 840_000, u32::MAX] {
+                assert_eq!(format.height(&format.key(height)), Some(height));
+                assert_eq!(format.other().height(&format.key(height)), None);
+            }
+        }
+        assert_eq!(HashKeyFormat::String.key(12), b"/__INTERNAL/height-to-hash/12".to_vec());
+        assert!(HashKeyFormat::Binary.key(1) < HashKeyFormat::Binary.key(256));
+        assert_eq!(HashKeyFormat::Binary.height(b"/__INTERNAL/height-to-hash-be/\x01"), None);
+    }
+
+    #[test]
+    fn pid_file_is_exclusive() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let path = dir.path().join("mono.pid");
+        let held = PidFile::acquire(path.clone()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        assert!(PidFile::acquire(path.clone()).is_err());
+        drop(held);
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pid_file_naming_a_dead_process_is_reclaimed() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let path = dir.path().join("mono.pid");
+        std::fs::write(&path, format!("{}\n", u32::MAX)).unwrap();
+        let _held = PidFile::acquire(path.clone()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+    }
+
+    #[test]
+    fn rate_limiter_allows_one_second_burst() {
+        let mut limiter = RateLimiter::new(5);
+        for _ in 0..5 {
+            assert_eq!(limiter.reserve(), Duration::ZERO);
+        }
+        let wait = limiter.reserve();
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200), "{:?}", wait);
+        // Refill never exceeds one second's worth
+        limiter.last -= Duration::from_secs(10);
+        limiter.reserve();
+        assert!(limiter.tokens <= 4.0);
+    }
+
+    #[test]
+    fn poll_backoff_doubles_up_to_max() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_millis(500);
+        let mut backoff = PollBackoff::new(min, max);
+        let mut floor = min;
+        for _ in 0..6 {
+            let wait = backoff.next_wait();
+            assert!(wait >= floor && wait <= std::cmp::min(floor.mul_f64(1.2), max), "{:?}", wait);
+            floor = std::cmp::min(floor * 2, max);
+        }
+        assert_eq!(backoff.current, max);
+        assert_eq!(PollBackoff::new(max, min).max, max);
+    }
+
+    pub(crate) const INDEXER: &str = r#"(module (memory (export "memory") 1) (func (export "_start")))"#;
+
+    // Parses the required flags pointing into `dir`, plus `extra`
+    pub(crate) fn test_args(dir: &tempdir::TempDir, rpc_url: &str, extra: &[&str]) -> Arc<Args> {
+        let indexer = dir.path().join("indexer.wat");
+        std::fs::write(&indexer, INDEXER).unwrap();
+        let mut argv = vec![
+            "rockshrew-mono".to_string(),
+            format!("--daemon-rpc-url={}", rpc_url),
+            format!("--indexer={}", indexer.display()),
+            format!("--db-path={}", dir.path().join("db").display()),
+        ];
+        argv.extend(extra.iter().map(|arg| arg.to_string()));
+        Arc::new(Args::try_parse_from(argv).unwrap())
+    }
+
+    pub(crate) fn open_adapter(args: &Args) -> RocksDBRuntimeAdapter {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        RocksDBRuntimeAdapter::open(args.db_path.clone(), opts).unwrap()
+    }
+
+    #[test]
+    fn mempool_reload_keeps_runtime_limits() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            "http://127.0.0.1:0",
+            &[
+                "--max-reads-per-block=7",
+                "--write-prefix=0xab",
+                "--max-write-bytes-per-block=64",
+                "--block-timeout-secs=3",
+                "--prealloc-memory-mb=1",
+            ],
+        );
+        let indexer = PathBuf::from(&args.indexer);
+        let overlay = OverlayAdapter::new(open_adapter(&args), MEMPOOL_PREFIX.as_bytes().to_vec());
+        let mut mempool = MempoolIndex {
+            runtime: MetashrewRuntime::load(indexer.clone(), overlay).unwrap(),
+            args: args.clone(),
+            ttl: Duration::from_secs(1),
+            refreshed: None,
+        };
+        mempool.reload(indexer).unwrap();
+
+        let runtime = &mut mempool.runtime;
+        assert_eq!(runtime.max_reads_per_block, Some(7));
+        assert_eq!(runtime.write_prefixes, Some(vec![vec![0xab]]));
+        assert_eq!(runtime.max_write_bytes_per_block, Some(64));
+        assert_eq!(runtime.block_timeout, Some(Duration::from_secs(3)));
+        let memory = runtime.instance.get_memory(&mut runtime.wasmstore, "memory").unwrap();
+        assert!(memory.data_size(&runtime.wasmstore) >= 1024 * 1024);
+    }
+
+    // Flushes the block's transaction count to /o, and answers the "greet" view with "hi"
+    const COUNT_TXS: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 100) "\02\00\00\00hi")
+      (data (i32.const 196) "\0a\00\00\00\0a\02/o\0a\04")
+      (func (export "greet") (result i32) (i32.const 104))
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store (i32.const 206) (i32.load8_u (i32.const 1084)))
+        (call $flush (i32.const 200))))"#;
+
+    #[tokio::test]
+    async fn mempool_namespace_populates_from_the_node_and_expires() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        std::fs::write(&args.indexer, COUNT_TXS).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.mempool.insert("aa".repeat(32), vec![0xaa; 10]);
+            node.mempool.insert("bb".repeat(32), vec![0xbb; 10]);
+        }
+        let mut base = open_adapter(&args);
+        let overlay = OverlayAdapter::new(base.clone(), MEMPOOL_PREFIX.as_bytes().to_vec());
+        let mut mempool = MempoolIndex {
+            runtime: MetashrewRuntime::load(PathBuf::from(&args.indexer), overlay).unwrap(),
+            args: args.clone(),
+            ttl: Duration::from_millis(200),
+            refreshed: None,
+        };
+        let state = test_state(args, base.clone());
+        let key = b"/o".to_vec();
+        let pending = [MEMPOOL_PREFIX.as_bytes(), &db_make_list_key(&key, 0).unwrap()].concat();
+        let pending_count = |base: &mut RocksDBRuntimeAdapter| {
+            let entry = base.get(&pending).unwrap()?;
+            Some(u32::from_le_bytes(entry[..4].try_into().unwrap()))
+        };
+        assert!(mempool.view("greet".to_string(), &vec![]).is_err());
+
+        // Both transactions follow the empty coinbase, and confirmed state is left alone
+        mempool.refresh(state.fetch_mempool_block().await.unwrap(), 0).unwrap();
+        assert_eq!(pending_count(&mut base), Some(3));
+        assert_eq!(base.get(db_make_length_key(&key).unwrap()).unwrap(), None);
+        assert_eq!(mempool.view("greet".to_string(), &vec![]).unwrap(), b"hi".to_vec());
+
+        // Each poll starts from an empty namespace rather than appending to the last one
+        node.lock().unwrap().mempool.remove(&"aa".repeat(32));
+        mempool.refresh(state.fetch_mempool_block().await.unwrap(), 0).unwrap();
+        assert_eq!(pending_count(&mut base), Some(2));
+        let length_key = [MEMPOOL_PREFIX.as_bytes(), &db_make_length_key(&key).unwrap()].concat();
+        assert_eq!(base.get(&length_key).unwrap(), Some(1u32.to_le_bytes().to_vec()));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(mempool.view("greet".to_string(), &vec![]).is_err());
+    }
+
+    // Flushes the block's first four bytes to /o
+    const RECORD_HEADER: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 196) "\0a\00\00\00\0a\02/o\0a\04")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store (i32.const 206) (i32.load (i32.const 1004)))
+        (call $flush (i32.const 200))))"#;
+
+    #[actix_web::test]
+    async fn state_roots_follow_the_state_and_match_across_replays() {
+        let roots = |blocks: [u8; 2]| async move {
+            let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+            let args = test_args(&dir, "http://127.0.0.1:0", &["--compute-state-root"]);
+            std::fs::write(&args.indexer, RECORD_HEADER).unwrap();
+            let state = test_state(args.clone(), open_adapter(&args));
+            for (height, first) in blocks.into_iter().enumerate() {
+                state.index_block(height as u32, vec![first; 80]).await.unwrap();
+            }
+            let runtime = state.runtime.lock().await;
+            let db = &mut runtime.context.lock().unwrap().db;
+            let stored = (0..2)
+                .map(|height| db.get(format!("{}{}", STATE_ROOT, height)).unwrap().unwrap())
+                .collect::<Vec<_>>();
+            (dir, args, stored)
+        };
+        let (dir, args, first) = roots([1, 2]).await;
+        assert_ne!(first[0], first[1]);
+        assert_eq!(roots([1, 2]).await.2, first);
+        let changed = roots([1, 3]).await.2;
+        assert_eq!(changed[0], first[0]);
+        assert_ne!(changed[1], first[1]);
+
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), open_adapter(&args)).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().app_data(web::Data::new(test_app_state(&args, runtime))).service(handle_jsonrpc),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::post()
+            .uri("/")
+            .set_json(serde_json::json!({
+                "id": 1,
+                "jsonrpc": "2.0",
+                "method": "metashrew_stateroot",
+                "params": [1],
+            }))
+            .to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["result"], format!("0x{}", hex::encode(&first[1])));
+        drop(dir);
+    }
+
+    // Answers one JSON-RPC request against the store behind `adapter`
+    async fn call_jsonrpc(args: &Args, adapter: RocksDBRuntimeAdapter, method: &str, params: Value) -> Value {
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().app_data(web::Data::new(test_app_state(args, runtime))).service(handle_jsonrpc),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::post()
+            .uri("/")
+            .set_json(serde_json::json!({"id": 1, "jsonrpc": "2.0", "method": method, "params": params}))
+            .to_request();
+        actix_web::test::call_and_read_body_json(&app, request).await
+    }
+
+    #[tokio::test]
+    async fn checkpoints_pass_a_matching_root_and_abort_on_a_wrong_one() {
+        let blocks = [vec![1; 80], vec![2; 80]];
+        let reference_dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&reference_dir, "http://127.0.0.1:0", &["--compute-state-root"]);
+        std::fs::write(&args.indexer, RECORD_HEADER).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        for (height, block) in blocks.iter().enumerate() {
+            state.index_block(height as u32, block.clone()).await.unwrap();
+        }
+        let roots: Vec<Vec<u8>> = {
+            let runtime = state.runtime.lock().await;
+            let db = &mut runtime.context.lock().unwrap().db;
+            (0..2).map(|height| db.get(format!("{}{}", STATE_ROOT, height)).unwrap().unwrap()).collect()
+        };
+
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let checkpoints = dir.path().join("checkpoints");
+        std::fs::write(
+            &checkpoints,
+            format!("# known good\n0 0x{}\n1 {}  # wrong\n", hex::encode(&roots[0]), "ee".repeat(32)),
+        )
+        .unwrap();
+        let flag = format!("--checkpoints={}", checkpoints.display());
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--compute-state-root", &flag]);
+        std::fs::write(&args.indexer, RECORD_HEADER).unwrap();
+        let mut state = test_state(args.clone(), open_adapter(&args));
+        state.checkpoints = Arc::new(load_checkpoints(args.checkpoints.as_ref().unwrap()).unwrap());
+        state.index_block(0, blocks[0].clone()).await.unwrap();
+        assert_eq!(
+            state.index_block(1, blocks[1].clone()).await.unwrap_err().to_string(),
+            format!(
+                "state root at block 1 is {} but the checkpoint expects {}",
+                hex::encode(&roots[1]),
+                "ee".repeat(32)
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn internal_keys_are_served_only_with_expose_internal() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--expose-internal"]);
+        let adapter = open_adapter(&args);
+        adapter.write_tip_height(7).unwrap();
+        let params = serde_json::json!(["tip-height"]);
+        let served = call_jsonrpc(&args, adapter.clone(), "metashrew_internal", params.clone()).await;
+        assert_eq!(served["result"], "0x07000000");
+
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let rejected = call_jsonrpc(&args, adapter, "metashrew_internal", params).await;
+        assert_eq!(rejected["error"]["code"], -32601);
+        assert!(rejected.get("result").is_none());
+    }
+
+    #[actix_web::test]
+    async fn oversized_results_are_rejected_or_truncated() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let mut adapter = open_adapter(&test_args(&dir, "http://127.0.0.1:0", &[]));
+        adapter.put(b"/__INTERNAL/big", [0xab; 16]).unwrap();
+        let params = serde_json::json!(["big"]);
+        let limited = |extra: &[&str]| {
+            let mut flags = vec!["--expose-internal", "--max-response-bytes=8"];
+            flags.extend(extra);
+            test_args(&dir, "http://127.0.0.1:0", &flags)
+        };
+
+        let rejected = call_jsonrpc(&limited(&[]), adapter.clone(), "metashrew_internal", params.clone()).await;
+        assert_eq!(rejected["error"]["code"], -32001);
+        assert_eq!(rejected["error"]["message"], "response of 16 bytes exceeds --max-response-bytes 8");
+        let truncate = limited(&["--response-overflow=truncate"]);
+        let truncated = call_jsonrpc(&truncate, adapter.clone(), "metashrew_internal", params.clone()).await;
+        assert_eq!(truncated["result"], format!("0x{}", "ab".repeat(8)));
+        let unlimited = test_args(&dir, "http://127.0.0.1:0", &["--expose-internal"]);
+        let whole = call_jsonrpc(&unlimited, adapter, "metashrew_internal", params).await;
+        assert_eq!(whole["result"], format!("0x{}", "ab".repeat(16)));
+    }
+
+    // RECORD_HEADER, flushing the wall-clock time instead of the block's bytes
+    const RECORD_CLOCK: &str = r#"(module
+      (import "wasi_snapshot_preview1" "clock_time_get" (func $clock (param i32 i64 i32) (result i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 196) "\0a\00\00\00\0a\02/o\0a\04")
+      (func (export "_start")
+        (drop (call $clock (i32.const 0) (i64.const 1) (i32.const 1000)))
+        (i32.store (i32.const 206) (i32.load (i32.const 1000)))
+        (call $flush (i32.const 200))))"#;
+
+    #[test]
+    fn a_nonmonotonic_next_height_aborts() {
+        let err = check_next_height(8, 7).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "next block to index is 8 but the tip is 7; refusing to skip blocks"
+        );
+        // Staying put or rewinding across a reorg is allowed
+        check_next_height(7, 7).unwrap();
+        check_next_height(3, 7).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_determinism_passes_a_pure_indexer_and_fails_a_clock_reading_one() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        {
+            let mut node = node.lock().unwrap();
+            for height in 0..3 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![height as u8 + 1; 80]);
+            }
+        }
+        std::fs::write(&args.indexer, RECORD_HEADER).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        assert_eq!(state.verify_determinism(0, 2).await.unwrap(), None);
+
+        // The runtime offers no clock, so an indexer that reads one fails verification outright
+        std::fs::write(&args.indexer, RECORD_CLOCK).unwrap();
+        let error = state.verify_determinism(0, 2).await.unwrap_err();
+        assert!(error.to_string().starts_with("block 0 failed"), "{}", error);
+
+        let pairs = |entries: &[(&[u8], &[u8])]| -> BTreeMap<Vec<u8>, Vec<u8>> {
+            entries.iter().map(|(key, value)| (key.to_vec(), value.to_vec())).collect()
+        };
+        let replay = pairs(&[(b"a", b"1"), (b"b", b"2"), (b"d", b"4")]);
+        assert_eq!(first_divergent_key(&replay, &replay.clone()), None);
+        let changed = pairs(&[(b"a", b"1"), (b"b", b"3"), (b"d", b"4")]);
+        assert_eq!(first_divergent_key(&replay, &changed), Some(b"b".to_vec()));
+        let extra = pairs(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3"), (b"d", b"4")]);
+        assert_eq!(first_divergent_key(&replay, &extra), Some(b"c".to_vec()));
+        assert_eq!(first_divergent_key(&extra, &pairs(&[(b"a", b"1")])), Some(b"b".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn fill_gaps_reindexes_only_the_missing_heights() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        std::fs::write(&args.indexer, RECORD_HEADER).unwrap();
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            for height in 0..6 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![height as u8 + 1; 80]);
+                if height != 2 && height != 4 {
+                    adapter.put(args.hash_key_format.key(height), [height as u8; 32]).unwrap();
+                }
+            }
+        }
+        adapter.write_tip_height(6).unwrap();
+        let state = test_state(args.clone(), adapter);
+        let gaps = state.find_gaps(0, 5).await.unwrap();
+        assert_eq!(gaps, vec![2, 4]);
+        state.fill_gaps(&gaps).await.unwrap();
+
+        assert_eq!(node.lock().unwrap().calls["getblock"], 2);
+        assert_eq!(state.find_gaps(0, 5).await.unwrap(), Vec::<u32>::new());
+        assert_eq!(state.query_height().await.unwrap(), 6);
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        let key = b"/o".to_vec();
+        let indexed: Vec<u8> = (0..2)
+            .map(|index| db.get(db_make_list_key(&key, index).unwrap()).unwrap().unwrap()[0])
+            .collect();
+        assert_eq!(indexed, vec![3, 5]);
+        assert_eq!(db.get(db_make_list_key(&key, 2).unwrap()).unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn history_depth_refuses_heights_too_far_below_the_tip() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--max-history-depth=10"]);
+        std::fs::write(&args.indexer, GREETER).unwrap();
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), open_adapter(&args)).unwrap();
+        let state = test_app_state(&args, runtime);
+        set_current_height(101);
+        assert!(state.check_history_depth(100).is_ok());
+        assert!(state.check_history_depth(90).is_ok());
+        assert_eq!(
+            state.check_history_depth(89).unwrap_err(),
+            "height 89 is more than --max-history-depth 10 blocks below the tip 100"
+        );
+        let mut unlimited = state.clone();
+        unlimited.max_history_depth = None;
+        assert!(unlimited.check_history_depth(0).is_ok());
+
+        let app = actix_web::test::init_service(
+            App::new().app_data(web::Data::new(state)).service(handle_jsonrpc),
+        )
+        .await;
+        let view = |height: Value| {
+            actix_web::test::TestRequest::post()
+                .uri("/")
+                .set_json(serde_json::json!({
+                    "id": 1,
+                    "jsonrpc": "2.0",
+                    "method": "metashrew_view",
+                    "params": ["greet", "0x", height],
+                }))
+                .to_request()
+        };
+        let body: Value = actix_web::test::call_and_read_body_json(&app, view(Value::from(90))).await;
+        assert_eq!(body["result"], format!("0x{}", hex::encode("hi")));
+        let body: Value = actix_web::test::call_and_read_body_json(&app, view(Value::from(89))).await;
+        assert_eq!(body["error"]["code"], -32002);
+        let body: Value = actix_web::test::call_and_read_body_json(&app, view(Value::from("latest"))).await;
+        assert_eq!(body["result"], format!("0x{}", hex::encode("hi")));
+    }
+
+    #[actix_web::test]
+    async fn batch_requests_answer_each_entry_in_order() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--expose-internal"]);
+        std::fs::write(&args.indexer, GREETER).unwrap();
+        let adapter = open_adapter(&args);
+        adapter.write_tip_height(7).unwrap();
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().app_data(web::Data::new(test_app_state(&args, runtime))).service(handle_jsonrpc),
+        )
+        .await;
+        set_current_height(7);
+        let request = |body: Value| actix_web::test::TestRequest::post().uri("/").set_json(body).to_request();
+
+        let batch = serde_json::json!([
+            {"id": 1, "jsonrpc": "2.0", "method": "metashrew_internal", "params": ["tip-height"]},
+            {"id": 2, "jsonrpc": "2.0", "method": "metashrew_view", "params": ["greet", "0x", "latest"]},
+            {"not": "a request"},
+            {"id": 3, "jsonrpc": "2.0", "method": "metashrew_height", "params": []},
+        ]);
+        let body: Value = actix_web::test::call_and_read_body_json(&app, request(batch)).await;
+        let responses = body.as_array().unwrap();
+        assert_eq!(responses.len(), 4);
+        assert_eq!((&responses[0]["id"], &responses[0]["result"]), (&Value::from(1), &Value::from("0x07000000")));
+        assert_eq!(responses[1]["result"], format!("0x{}", hex::encode("hi")));
+        assert_eq!(responses[2]["error"]["code"], -32600);
+        assert_eq!((&responses[3]["id"], &responses[3]["result"]), (&Value::from(3), &Value::from("7")));
+
+        let body: Value = actix_web::test::call_and_read_body_json(&app, request(serde_json::json!([]))).await;
+        assert_eq!(body["error"]["code"], -32600);
+    }
+
+    #[actix_web::test]
+    async fn metashrew_info_reports_the_label_and_current_tip() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        std::fs::write(&args.indexer, GREETER).unwrap();
+        set_current_height(4);
+        let info = call_jsonrpc(&args, open_adapter(&args), "metashrew_info", serde_json::json!([])).await;
+        let hash = metashrew_runtime::indexer_hash(&std::fs::read(&args.indexer).unwrap());
+        assert_eq!(info["result"], server_info(None, None, &hash, 4));
+        assert_eq!((&info["result"]["label"], &info["result"]["key_prefix"]), (&Value::Null, &Value::from("0x")));
+        assert_eq!(info["result"]["indexer_hash"], format!("0x{}", hex::encode(hash)));
+        assert_eq!(info["result"]["height"], 4);
+
+        let info = server_info(Some("tenant"), Some(7), &[0xab; 32], 9);
+        assert_eq!((&info["label"], &info["chain_id"]), (&Value::from("tenant"), &Value::from(7)));
+        assert_eq!(info["key_prefix"], format!("0x07{}", hex::encode("tenant://")));
+    }
+
+    #[tokio::test]
+    async fn lastmodified_returns_the_height_a_key_last_changed() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let adapter = open_adapter(&args);
+        let state = test_state(args.clone(), adapter.clone());
+        for height in [5, 12] {
+            state.index_block(height, vec![0; 80]).await.unwrap();
+        }
+        let last_modified = |key: &str| call_jsonrpc(&args, adapter.clone(), "metashrew_lastmodified", serde_json::json!([key]));
+        assert_eq!(last_modified(&format!("0x{}", hex::encode("/o"))).await["result"], 12);
+        assert_eq!(last_modified(&hex::encode("/p")).await["result"], Value::Null);
+        assert_eq!(last_modified("not hex").await["error"]["code"], -32602);
+    }
+
+    // Keeps the messages logged at the --trace-rpc target
+    struct RpcTraces(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for RpcTraces {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == TRACE_TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static RPC_TRACES: RpcTraces = RpcTraces(std::sync::Mutex::new(vec![]));
+
+    #[tokio::test]
+    async fn trace_rpc_logs_each_request_and_response() {
+        log::set_logger(&RPC_TRACES).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        node.lock().unwrap().tip = 42;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let url = serve_fake_node(node.clone()).await;
+        let args = test_args(&dir, &url, &["--trace-rpc", "--auth=user:secret"]);
+        let state = test_state(args.clone(), open_adapter(&args));
+        assert_eq!(state.fetch_blockcount().await.unwrap(), 42);
+
+        let traces = RPC_TRACES.0.lock().unwrap().clone();
+        assert_eq!(traces.len(), 2, "{:?}", traces);
+        assert!(traces[0].starts_with("-> ") && traces[0].contains(" getblockcount params="), "{}", traces[0]);
+        assert!(traces[0].contains("user:redacted@") && !traces[0].contains("secret"), "{}", traces[0]);
+        assert!(traces[1].starts_with("<- 200 OK ") && traces[1].contains("\"result\":42"), "{}", traces[1]);
+        assert_eq!(
+            truncate_preview(&"x".repeat(TRACE_PREVIEW_LEN + 3)),
+            format!("{}... (3 more bytes)", "x".repeat(TRACE_PREVIEW_LEN))
+        );
+    }
+
+    #[tokio::test]
+    async fn pull_block_retries_a_failed_block_download() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--block-fetch-retries=1", "--no-reorg-tracking"],
+        );
+        {
+            let mut node = node.lock().unwrap();
+            node.hashes.insert(0, vec![0xab; 32]);
+            node.blocks.insert(vec![0xab; 32], vec![7; 80]);
+            node.failing_getblocks = 1;
+        }
+        let state = test_state(args.clone(), open_adapter(&args));
+        assert_eq!(state.pull_block(0).await.unwrap(), vec![7; 80]);
+        assert_eq!(node.lock().unwrap().calls["getblock"], 2);
+
+        // Past the retry budget the failure is returned rather than panicking
+        node.lock().unwrap().failing_getblocks = 2;
+        assert!(state.pull_block(0).await.is_err());
+        assert_eq!(node.lock().unwrap().calls["getblock"], 4);
+    }
+
+    #[tokio::test]
+    async fn shutdown_commits_indexed_blocks_and_abandons_the_pending_fetch() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--poll-interval-ms=10", "--poll-interval-max-ms=10"],
+        );
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 1;
+            for height in 0..=1u32 {
+                let block = vec![height as u8; 80];
+                let blockhash = header_blockhash(&block).unwrap();
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash, block);
+            }
+        }
+        let mut adapter = open_adapter(&args);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut state = test_state(args.clone(), adapter.clone());
+        state.shutdown = shutdown_rx;
+        let indexer = tokio::spawn(async move { state.run().await });
+
+        // Blocks 0 and 1 are committed while block 2 waits for the node to produce it
+        while query_height(adapter.db.clone(), 0).await.unwrap() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), indexer)
+            .await
+            .expect("the pending fetch held up the shutdown")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(node.lock().unwrap().calls["getblock"], 2);
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 2);
+        let key = b"/o".to_vec();
+        assert_eq!(adapter.get(db_make_length_key(&key).unwrap()).unwrap(), Some(2u32.to_le_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn a_set_shutdown_key_stops_the_indexer_after_committed_blocks() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--watch-shutdown-key", "--poll-interval-ms=10", "--poll-interval-max-ms=10"],
+        );
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 1;
+            for height in 0..=1u32 {
+                let block = vec![height as u8; 80];
+                let blockhash = header_blockhash(&block).unwrap();
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash, block);
+            }
+        }
+        let mut adapter = open_adapter(&args);
+        let key = format!("{}{}", SHUTDOWN_KEY, indexer_label(&args)).into_bytes();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let watcher = adapter.clone();
+        let watched = key.clone();
+        tokio::spawn(async move {
+            watch_shutdown_key(watcher, watched, Duration::from_millis(10)).await;
+            let _ = shutdown_tx.send(true);
+        });
+        let mut state = test_state(args.clone(), adapter.clone());
+        state.shutdown = shutdown_rx;
+        let indexer = tokio::spawn(async move { state.run().await });
+
+        while query_height(adapter.db.clone(), 0).await.unwrap() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // A value that is not truthy leaves the indexer running
+        adapter.put(&key, b"no").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!indexer.is_finished());
+
+        adapter.put(&key, b" True\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), indexer)
+            .await
+            .expect("the shutdown key was not noticed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(adapter.get(&key).unwrap(), None);
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 2);
+        let output = b"/o".to_vec();
+        assert_eq!(adapter.get(db_make_length_key(&output).unwrap()).unwrap(), Some(2u32.to_le_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn pin_tip_stops_at_the_pinned_height_below_the_node_tip() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--pin-tip=100"]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 110;
+            for height in 0..=110u32 {
+                let mut block = vec![0; 80];
+                block[..4].copy_from_slice(&height.to_le_bytes());
+                let blockhash = header_blockhash(&block).unwrap();
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash, block);
+            }
+        }
+        // Resumes just below the pin, so only a few blocks are indexed
+        let mut adapter = open_adapter(&args);
+        adapter.write_tip_height(96).unwrap();
+        let mut state = test_state(args.clone(), adapter.clone());
+        assert_eq!(state.fetch_blockcount().await.unwrap(), 100);
+        state.run().await.unwrap();
+
+        assert_eq!(node.lock().unwrap().calls["getblock"], 5);
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 101);
+        let key = b"/o".to_vec();
+        assert_eq!(adapter.get(db_make_length_key(&key).unwrap()).unwrap(), Some(5u32.to_le_bytes().to_vec()));
+        let last = adapter.get(db_make_list_key(&key, 4).unwrap()).unwrap().unwrap();
+        assert_eq!(last[..4], 100u32.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn no_reorg_tracking_syncs_without_storing_block_hashes() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--no-reorg-tracking", "--exit-at=3"],
+        );
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 3;
+            for height in 0..=3 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![height as u8; 80]);
+            }
+        }
+        let mut adapter = open_adapter(&args);
+        let mut state = test_state(args.clone(), adapter.clone());
+        state.run().await.unwrap();
+
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 3);
+        let key = b"/o".to_vec();
+        assert_eq!(adapter.get(db_make_length_key(&key).unwrap()).unwrap(), Some(3u32.to_le_bytes().to_vec()));
+        for height in 0..3 {
+            assert_eq!(adapter.get(args.hash_key_format.key(height)).unwrap(), None);
+        }
+        assert!(adapter.scan_prefix(HEIGHT_TO_HASH.as_bytes()).unwrap().is_empty());
+        // One hash lookup per block fetched, none walking back for reorgs
+        assert_eq!(node.lock().unwrap().calls["getblockhash"], 3);
+    }
+
+    #[tokio::test]
+    async fn min_confirmations_keeps_the_indexer_behind_the_tip() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--min-confirmations=3", "--poll-interval-ms=10", "--poll-interval-max-ms=10"],
+        );
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 5;
+            for height in 0..=6u32 {
+                let block = vec![height as u8; 80];
+                let blockhash = header_blockhash(&block).unwrap();
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash, block);
+            }
+        }
+        let adapter = open_adapter(&args);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut state = test_state(args.clone(), adapter.clone());
+        state.shutdown = shutdown_rx;
+        let indexer = tokio::spawn(async move { state.run().await });
+        let indexed_through = |height: u32| {
+            let db = adapter.db.clone();
+            async move {
+                while query_height(db.clone(), 0).await.unwrap() < height + 1 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        // Block 3 has only 2 confirmations at a tip of 5
+        indexed_through(2).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(node.lock().unwrap().calls["getblock"], 3);
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 3);
+
+        node.lock().unwrap().tip = 6;
+        indexed_through(3).await;
+        shutdown_tx.send(true).unwrap();
+        indexer.await.unwrap().unwrap();
+        assert_eq!(node.lock().unwrap().calls["getblock"], 4);
+    }
+
+    #[tokio::test]
+    async fn resume_height_overrides_the_stored_tip_once_verified() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let url = serve_fake_node(node.clone()).await;
+        node.lock().unwrap().hashes.insert(4, vec![0xab; 32]);
+        let args = test_args(&dir, &url, &[]);
+        let mut adapter = open_adapter(&args);
+        adapter.write_tip_height(10).unwrap();
+        adapter.put(args.hash_key_format.key(4), [0xab; 32]).unwrap();
+        assert_eq!(test_state(args, adapter.clone()).start_height().await.unwrap(), 10);
+
+        let args = test_args(&dir, &url, &["--resume-height=5", "--i-know-what-im-doing"]);
+        let state = test_state(args.clone(), adapter.clone());
+        assert_eq!(state.start_height().await.unwrap(), 5);
+
+        // The node has since replaced block 4, so the store no longer lines up with it
+        node.lock().unwrap().hashes.insert(4, vec![0xcd; 32]);
+        assert!(state.start_height().await.is_err());
+    }
+
+    #[test]
+    fn seconds_since_last_block_counts_from_the_last_commit() {
+        let metrics = Metrics::new("test".to_string());
+        metrics.record_commit();
+        let committed = *metrics.last_commit.lock().unwrap();
+        assert_eq!(metrics.seconds_since_last_block_at(committed), 0.0);
+        assert_eq!(metrics.seconds_since_last_block_at(committed + Duration::from_secs(90)), 90.0);
+
+        // A block committing later resets the gauge, and a clock reading before it never goes negative
+        *metrics.last_commit.lock().unwrap() = committed + Duration::from_secs(60);
+        assert_eq!(metrics.seconds_since_last_block_at(committed + Duration::from_secs(90)), 30.0);
+        assert_eq!(metrics.seconds_since_last_block_at(committed), 0.0);
+    }
+
+    #[tokio::test]
+    async fn block_metrics_are_attributed_to_each_indexer_label() {
+        let writer_dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let writer_args = test_args(&writer_dir, "http://127.0.0.1:0", &["--label=writer"]);
+        std::fs::write(&writer_args.indexer, RECORD_HEIGHT).unwrap();
+        let writer = test_state(writer_args.clone(), open_adapter(&writer_args));
+        let idle_dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let idle_args = test_args(&idle_dir, "http://127.0.0.1:0", &["--label=idle"]);
+        // Flushes an empty batch
+        std::fs::write(
+            &idle_args.indexer,
+            r#"(module
+              (import "env" "__flush" (func $flush (param i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 96) "\00\00\00\00")
+              (func (export "_start")
+                (call $flush (i32.const 100))))"#,
+        )
+        .unwrap();
+        let idle = test_state(idle_args.clone(), open_adapter(&idle_args));
+        for height in 0..3 {
+            writer.index_block(height, vec![0; 80]).await.unwrap();
+        }
+        idle.index_block(0, vec![0; 80]).await.unwrap();
+
+        let writer_metrics = writer.metrics.render_indexer();
+        assert!(writer_metrics.contains("metashrew_writes_total{indexer=\"writer\"} 3\n"));
+        assert!(writer_metrics.contains("metashrew_block_duration_seconds_count{indexer=\"writer\"} 3\n"));
+        assert!(writer_metrics.contains("metashrew_block_duration_seconds_bucket{indexer=\"writer\",le=\"+Inf\"} 3\n"));
+        let idle_metrics = idle.metrics.render_indexer();
+        assert!(idle_metrics.contains("metashrew_writes_total{indexer=\"idle\"} 0\n"));
+        assert!(idle_metrics.contains("metashrew_block_duration_seconds_count{indexer=\"idle\"} 1\n"));
+        assert!(!idle_metrics.contains("writer"));
+    }
+
+    #[tokio::test]
+    async fn dumps_follow_the_interval_and_keep_only_the_latest() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let dumps = dir.path().join("dumps");
+        let args = test_args(
+            &dir,
+            "http://127.0.0.1:0",
+            &["--dump-interval=2", &format!("--dump-dir={}", dumps.display()), "--dump-keep=2"],
+        );
+        let state = test_state(args.clone(), open_adapter(&args));
+        let dumped = || {
+            let mut heights: Vec<u32> = std::fs::read_dir(&dumps)
+                .map(|entries| {
+                    entries
+                        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                        .map(|name| name.split('-').nth(1).unwrap().parse().unwrap())
+                        .collect()
+                })
+                .unwrap_or_default();
+            heights.sort();
+            heights
+        };
+        let mut after = vec![];
+        for height in 1..=7 {
+            state.maybe_dump(height).await;
+            while state.dump_running.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            after.push(dumped());
+        }
+        assert_eq!(
+            after,
+            vec![vec![], vec![2], vec![2], vec![2, 4], vec![2, 4], vec![4, 6], vec![4, 6]]
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_headers_are_sent_on_every_request() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &["--rpc-header=Authorization: Bearer secret", "--rpc-header", "X-Api-Key:abc"],
+        );
+        node.lock().unwrap().tip = 7;
+        let state = test_state(args.clone(), open_adapter(&args));
+        for _ in 0..2 {
+            assert_eq!(state.fetch_blockcount().await.unwrap(), 7);
+            let headers = node.lock().unwrap().headers.clone();
+            assert!(headers.contains(&"authorization: bearer secret".to_string()), "{:?}", headers);
+            assert!(headers.contains(&"x-api-key: abc".to_string()), "{:?}", headers);
+        }
+        assert!(rpc_client(&["no separator".to_string()]).is_err());
+    }
+
+    #[test]
+    fn clock_skew_guard_fires_on_an_anomalous_header_time() {
+        let block = |time: u32| {
+            let mut block = vec![0u8; 80];
+            block[68..72].copy_from_slice(&time.to_le_bytes());
+            block
+        };
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--max-clock-skew=3600", "--reject-clock-skew"]);
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.check_clock_skew(0, &block(1_000_000)).unwrap();
+        state.check_clock_skew(1, &block(1_000_600)).unwrap();
+        let error = state.check_clock_skew(2, &block(1_010_000)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "block 2 header time 1010000 is 9400s from the previous block's 1000600, beyond --max-clock-skew 3600"
+        );
+        // Jumping back just as far fires too
+        assert!(state.check_clock_skew(3, &block(1_000_000)).is_err());
+        drop(state);
+
+        // Without --reject-clock-skew the guard only warns
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--max-clock-skew=3600"]);
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.check_clock_skew(0, &block(1_000_000)).unwrap();
+        state.check_clock_skew(1, &block(1_010_000)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_blocks_flags_a_corrupted_refetch() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--store-block-checksums"]);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 3;
+            for height in 0..=3 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                node.blocks.insert(vec![height as u8; 32], vec![height as u8; 80]);
+            }
+        }
+        let adapter = open_adapter(&args);
+        let state = test_state(args, adapter);
+        for height in 0..=2 {
+            state.pull_block(height).await.unwrap();
+        }
+        assert_eq!(state.verify_blocks(0, 3).await.unwrap(), 0);
+
+        node.lock().unwrap().blocks.get_mut(&vec![1; 32]).unwrap()[40] ^= 1;
+        assert_eq!(state.verify_blocks(0, 3).await.unwrap(), 1);
+        assert_eq!(state.verify_blocks(2, 3).await.unwrap(), 0);
+    }
+
+    // A JSON-RPC node answering the methods the indexer calls, counting calls by method
+    #[derive(Default)]
+    pub(crate) struct FakeNode {
+        pub(crate) tip: u32,
+        pub(crate) hashes: BTreeMap<u32, Vec<u8>>,
+        pub(crate) blocks: BTreeMap<Vec<u8>, Vec<u8>>,
+        pub(crate) calls: BTreeMap<String, u32>,
+        // Raw transactions by txid
+        pub(crate) mempool: BTreeMap<String, Vec<u8>>,
+        // Number of upcoming getblock calls answered without a result
+        pub(crate) failing_getblocks: u32,
+        // Header lines of the last request, lowercased
+        pub(crate) headers: Vec<String>,
+        // Method names answered as the Bitcoin Core method they stand for
+        pub(crate) aliases: BTreeMap<String, String>,
+        // Methods answered with JSON-RPC error -32601, as a node without them would
+        pub(crate) unknown: std::collections::BTreeSet<String>,
+        pub(crate) chain_info: Value,
+        pub(crate) zmq_notifications: Value,
+        // Whether a batch is answered as one, and GET /rest/chaininfo.json served
+        pub(crate) batch: bool,
+        pub(crate) rest: bool,
+    }
+
+    fn answer_fake_rpc(node: &mut FakeNode, request: &Value) -> Value {
+        let method = request["method"].as_str().unwrap().to_string();
+        *node.calls.entry(method.clone()).or_insert(0) += 1;
+        if node.unknown.contains(&method) {
+            return serde_json::json!({
+                "id": request["id"],
+                "result": null,
+                "error": {"code": -32601, "message": "Method not found"}
+            });
+        }
+        let method = node.aliases.get(&method).cloned().unwrap_or(method);
+        let result = match method.as_str() {
+            "getblockcount" => Value::from(node.tip),
+            "getblockhash" => {
+                let height = request["params"][0].as_u64().unwrap() as u32;
+                Value::from(hex::encode(&node.hashes[&height]))
+            }
+            "getblock" if node.failing_getblocks > 0 => {
+                node.failing_getblocks -= 1;
+                Value::Null
+            }
+            "getblock" => {
+                let blockhash = hex::decode(request["params"][0].as_str().unwrap()).unwrap();
+                Value::from(hex::encode(&node.blocks[&blockhash]))
+            }
+            "getrawmempool" => Value::from(node.mempool.keys().cloned().collect::<Vec<_>>()),
+            "getrawtransaction" => match node.mempool.get(request["params"][0].as_str().unwrap()) {
+                Some(tx) => Value::from(hex::encode(tx)),
+                None => Value::Null,
+            },
+            "getblockchaininfo" => node.chain_info.clone(),
+            "getzmqnotifications" => node.zmq_notifications.clone(),
+            _ => Value::Null,
+        };
+        serde_json::json!({"id": request["id"], "result": result})
+    }
+
+    pub(crate) async fn serve_fake_node(node: Arc<std::sync::Mutex<FakeNode>>) -> String {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let node = node.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut length = 0;
+                        let mut headers = vec![];
+                        loop {
+                            let mut line = String::new();
+                            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            let line = line.trim_end().to_ascii_lowercase();
+                            if line.is_empty() {
+                                break;
+                            }
+                            if let Some(value) = line.strip_prefix("content-length:") {
+                                length = value.trim().parse().unwrap();
+                            }
+                            headers.push(line);
+                        }
+                        let mut body = vec![0; length];
+                        stream.read_exact(&mut body).await.unwrap();
+                        let (status, response) = {
+                            let mut node = node.lock().unwrap();
+                            let is_get = headers.first().is_some_and(|line| line.starts_with("get "));
+                            node.headers = headers;
+                            if is_get {
+                                match node.rest {
+                                    true => ("200 OK", node.chain_info.to_string()),
+                                    false => ("404 Not Found", String::new()),
+                                }
+                            } else {
+                                let request: Value = serde_json::from_slice(&body).unwrap();
+                                let response = match request.as_array() {
+                                    Some(requests) if node.batch => Value::Array(
+                                        requests.iter().map(|request| answer_fake_rpc(&mut node, request)).collect(),
+                                    ),
+                                    Some(_) => serde_json::json!({
+                                        "id": null,
+                                        "result": null,
+                                        "error": {"code": -32600, "message": "Invalid Request"}
+                                    }),
+                                    None => answer_fake_rpc(&mut node, &request),
+                                };
+                                ("200 OK", response.to_string())
+                            }
+                        };
+                        let head = format!(
+                            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                            status,
+                            response.len()
+                        );
+                        let stream = stream.get_mut();
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    pub(crate) fn test_state(args: Arc<Args>, adapter: RocksDBRuntimeAdapter) -> IndexerState {
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap();
+        IndexerState {
+            runtime: Arc::new(Mutex::new(runtime)),
+            args: args.clone(),
+            start_block: 0,
+            rate_limiter: None,
+            metrics: Arc::new(Metrics::new(indexer_label(&args))),
+            dump_running: Arc::new(AtomicBool::new(false)),
+            client: rpc_client(&args.rpc_header).unwrap(),
+            last_block_time: Arc::new(AtomicU32::new(0)),
+            shutdown: watch::channel(false).1,
+            checkpoints: Arc::new(BTreeMap::new()),
+            sandbox: None,
+            checked_tip: Arc::new(std::sync::Mutex::new(None)),
+            liveness: Arc::new(Liveness::new()),
+        }
+    }
+
+    // Held by tests that set or read the global current height
+    pub(crate) static CURRENT_HEIGHT: Mutex<()> = Mutex::const_new(());
+
+    pub(crate) fn test_app_state(
+        args: &Args,
+        runtime: MetashrewRuntime<RocksDBRuntimeAdapter>,
+    ) -> AppState {
+        AppState {
+            runtime: Arc::new(Mutex::new(runtime)),
+            mempool: None,
+            metrics: Arc::new(Metrics::new(indexer_label(args))),
+            expose_internal: args.expose_internal,
+            allow_reload: args.allow_reload,
+            max_response_bytes: args.max_response_bytes,
+            response_overflow: args.response_overflow,
+            max_history_depth: args.max_history_depth,
+            hash_key_format: args.hash_key_format,
+        }
+    }
+
+    // A view "greet" answering "hi" whatever its input
+    pub(crate) const GREETER: &str = r#"(module
+      (memory (export "memory") 1)
+      (data (i32.const 100) "\02\00\00\00hi")
+      (func (export "_start"))
+      (func (export "greet") (result i32) (i32.const 104)))"#;
+
+    // Flushes the block's height to /o
+    pub(crate) const RECORD_HEIGHT: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 196) "\0a\00\00\00\0a\02/o\0a\04")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store (i32.const 206) (i32.load (i32.const 1000)))
+        (call $flush (i32.const 200))))"#;
+
+    // Flushes nothing, except at height 1 where it traps
+    pub(crate) const TRAP_AT_ONE: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\00\00\00\00")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (if (i32.eq (i32.load (i32.const 1000)) (i32.const 1))
+          (then (unreachable)))
+        (call $flush (i32.const 100))))"#;
+
+    #[tokio::test]
+    async fn failing_block_stops_sync_without_quarantine() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        std::fs::write(&args.indexer, TRAP_AT_ONE).unwrap();
+        let adapter = open_adapter(&args);
+        let state = test_state(args, adapter);
+        state.index_block(0, vec![0; 80]).await.unwrap();
+        assert!(state.index_block(1, vec![0; 80]).await.is_err());
+        let runtime = state.runtime.lock().await;
+        assert_eq!(runtime.context.lock().unwrap().db.get(QUARANTINE.as_bytes()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_failed_block_writes_a_crash_dump_with_its_bytes_and_height() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let dumps = dir.path().join("dumps");
+        let args = test_args(&dir, "http://127.0.0.1:0", &[&format!("--crash-dump-dir={}", dumps.display())]);
+        // RECORD_HEIGHT, trapping once it has flushed
+        let trap_after_flush = RECORD_HEIGHT.replace("(call $flush (i32.const 200))))", "(call $flush (i32.const 200)) (unreachable)))");
+        std::fs::write(&args.indexer, trap_after_flush).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.runtime.lock().await.capture_flush = true;
+        let block: Vec<u8> = (0..80).collect();
+        assert!(state.index_block(3, block.clone()).await.is_err());
+
+        assert_eq!(std::fs::read(dumps.join("3.blk")).unwrap(), block);
+        let dump: Value = serde_json::from_slice(&std::fs::read(dumps.join("3.json")).unwrap()).unwrap();
+        assert_eq!(dump["height"], 3);
+        assert_eq!(dump["block"], "3.blk");
+        assert_eq!(dump["blockhash"], hex::encode(header_blockhash(&block).unwrap()));
+        assert_eq!(dump["deterministic"], true);
+        assert!(dump["error"].as_str().unwrap().contains("unreachable"), "{}", dump);
+        assert_eq!(
+            dump["staged"],
+            serde_json::json!([{"key": hex::encode("/o"), "value": hex::encode(3u32.to_le_bytes())}])
+        );
+    }
+
+    #[tokio::test]
+    async fn recover_tip_resumes_from_the_highest_stored_hash() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let mut adapter = open_adapter(&args);
+        for height in [0, 1, 2, 4] {
+            adapter.put(args.hash_key_format.key(height), [height as u8; 32]).unwrap();
+        }
+        let state = test_state(args, adapter);
+        let tip = || async {
+            let runtime = state.runtime.lock().await;
+            let mut context = runtime.context.lock().unwrap();
+            context.db.get(TIP_HEIGHT_KEY.as_bytes()).unwrap()
+        };
+
+        // The run from start_block ends at 2; the hash stored for 4 is past a gap
+        state.recover_tip().await.unwrap();
+        assert_eq!(tip().await, Some(2u32.to_le_bytes().to_vec()));
+
+        // A well-formed tip is left alone
+        state.runtime.lock().await.context.lock().unwrap().db.write_tip_height(7).unwrap();
+        state.recover_tip().await.unwrap();
+        assert_eq!(tip().await, Some(7u32.to_le_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn bootstrap_snapshot_resumes_an_empty_store_at_the_snapshot_tip() {
+        let source_dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let mut source = open_adapter(&test_args(&source_dir, "http://127.0.0.1:0", &[]));
+        source.put(b"/key", b"value").unwrap();
+        source.write_tip_height(42).unwrap();
+        let path = source_dir.path().join("snapshot.bin");
+        snapshot::export_snapshot(&source.db, &mut std::fs::File::create(&path).unwrap()).unwrap();
+
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let mut adapter = open_adapter(&args);
+        assert_eq!(bootstrap_snapshot(&adapter, &path).unwrap(), Some(2));
+        assert_eq!(adapter.get(b"/key").unwrap(), Some(b"value".to_vec()));
+        let state = test_state(args, adapter.clone());
+        assert_eq!(state.query_height().await.unwrap(), 42);
+
+        // Once the store has a tip, a second bootstrap leaves it alone
+        adapter.put(b"/key", b"newer").unwrap();
+        assert_eq!(bootstrap_snapshot(&adapter, &path).unwrap(), None);
+        assert_eq!(adapter.get(b"/key").unwrap(), Some(b"newer".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn reorg_checks_are_skipped_below_the_trusted_height() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--trusted-height=6"]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 10;
+            for height in 0..=10 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+            }
+            // The node has replaced block 5
+            node.hashes.insert(5, vec![0xee; 32]);
+        }
+        let state = test_state(args, adapter);
+
+        for height in 0..6 {
+            assert_eq!(state.best_height(height).await.unwrap(), height);
+        }
+        assert!(node.lock().unwrap().calls.is_empty());
+
+        // From the trusted height on, the check runs and finds the replaced block
+        assert_eq!(state.best_height(6).await.unwrap(), 5);
+        assert!(node.lock().unwrap().calls["getblockhash"] > 0);
+    }
+
+    #[test]
+    fn compact_on_start_reclaims_overwritten_versions() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let mut adapter = open_adapter(&args);
+        // Each pass lands in its own SST file, all but the last fully shadowed
+        for pass in 0..4u8 {
+            for index in 0..1000u32 {
+                adapter.put(index.to_be_bytes(), [pass; 256]).unwrap();
+            }
+            adapter.db.flush().unwrap();
+        }
+        assert_eq!(compact_on_start(&args, &adapter).unwrap(), None);
+
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--compact-on-start"]);
+        let (before, after) = compact_on_start(&args, &adapter).unwrap().unwrap();
+        assert!(after < before / 2, "{} -> {}", before, after);
+        assert_eq!(adapter.get(7u32.to_be_bytes()).unwrap(), Some(vec![3; 256]));
+    }
+
+    #[tokio::test]
+    async fn a_node_tip_below_the_indexed_tip_waits_or_rolls_back() {
+        for (mode, expected) in [("wait", 6), ("rollback", 2)] {
+            let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+            let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+            let on_node_behind = format!("--on-node-behind={}", mode);
+            let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[&on_node_behind]);
+            let mut adapter = open_adapter(&args);
+            {
+                // Six blocks are indexed, but the node only has three, forked at height 2
+                let mut node = node.lock().unwrap();
+                node.tip = 2;
+                for height in 0..6u32 {
+                    adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+                }
+                for height in 0..2u32 {
+                    node.hashes.insert(height, vec![height as u8; 32]);
+                }
+                node.hashes.insert(2, vec![0xee; 32]);
+            }
+            let state = test_state(args, adapter);
+            assert_eq!(state.best_height(6).await.unwrap(), expected, "{}", mode);
+        }
+
+        // A node with nothing in common rolls back to genesis rather than underflowing
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--on-node-behind=rollback"]);
+        let mut adapter = open_adapter(&args);
+        for height in 0..6u32 {
+            adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+        }
+        node.lock().unwrap().hashes.insert(0, vec![0xee; 32]);
+        let state = test_state(args, adapter);
+        assert_eq!(state.best_height(6).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn renamed_rpc_methods_are_sent_in_place_of_the_core_names() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(
+            &dir,
+            &serve_fake_node(node.clone()).await,
+            &[
+                "--rpc-method-getblockcount=chain_height",
+                "--rpc-method-getblockhash=chain_hash",
+                "--rpc-method-getblock=getblockbyhash",
+            ],
+        );
+        let block = vec![1; 80];
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 1;
+            for (alias, method) in [("chain_height", "getblockcount"), ("chain_hash", "getblockhash"), ("getblockbyhash", "getblock")] {
+                node.aliases.insert(alias.to_string(), method.to_string());
+            }
+            let blockhash = header_blockhash(&block).unwrap();
+            node.hashes.insert(1, blockhash.clone());
+            node.blocks.insert(blockhash, block.clone());
+        }
+        let state = test_state(args.clone(), open_adapter(&args));
+
+        assert_eq!(state.fetch_blockcount().await.unwrap(), 1);
+        assert_eq!(state.fetch_blockhash(1).await.unwrap(), header_blockhash(&block).unwrap());
+        assert_eq!(state.pull_block(1).await.unwrap(), block);
+        let calls: Vec<String> = node.lock().unwrap().calls.keys().cloned().collect();
+        assert_eq!(calls, vec!["chain_hash", "chain_height", "getblockbyhash"]);
+    }
+
+    #[tokio::test]
+    async fn probe_reports_what_the_node_advertises() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let url = serve_fake_node(node.clone()).await;
+        let genesis = vec![0; 80];
+        {
+            let mut node = node.lock().unwrap();
+            let blockhash = header_blockhash(&genesis).unwrap();
+            node.hashes.insert(0, blockhash.clone());
+            node.blocks.insert(blockhash, genesis.clone());
+            node.chain_info = serde_json::json!({
+                "chain": "regtest", "blocks": 0, "headers": 0, "pruned": true, "pruneheight": 100
+            });
+            node.zmq_notifications = serde_json::json!([
+                {"type": "pubrawblock", "address": "tcp://127.0.0.1:28332"}
+            ]);
+            node.batch = true;
+            node.rest = true;
+        }
+        let args = test_args(&dir, &url, &[]);
+        let report = test_state(args.clone(), open_adapter(&args)).probe().await.unwrap();
+        assert!(report.passed(), "{}", report);
+        assert!(report.methods.iter().all(|(_, present)| *present), "{}", report);
+        assert!(report.batch && report.rest, "{}", report);
+        assert_eq!(
+            report.zmq,
+            vec![("pubrawblock".to_string(), "tcp://127.0.0.1:28332".to_string())]
+        );
+        assert_eq!(report.suggestions.len(), 1, "{}", report);
+        assert!(report.suggestions[0].contains("--start-block 100"), "{}", report);
+        assert!(report.to_string().contains("chain: regtest"), "{}", report);
+
+        // A node without the optional interfaces, lacking a renamed method this configuration
+        // calls and the mempool methods it does not
+        {
+            let mut node = node.lock().unwrap();
+            node.chain_info = Value::Null;
+            node.batch = false;
+            node.rest = false;
+            for method in ["getblockchaininfo", "getzmqnotifications", "getrawmempool", "getblockbyhash"] {
+                node.unknown.insert(method.to_string());
+            }
+        }
+        let args = test_args(&dir, &url, &["--rpc-method-getblock=getblockbyhash"]);
+        let report = test_state(args.clone(), open_adapter(&args)).probe().await.unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.missing, vec!["getblockbyhash"]);
+        let absent: Vec<&str> = report
+            .methods
+            .iter()
+            .filter(|(_, present)| !present)
+            .map(|(method, _)| method.as_str())
+            .collect();
+        assert_eq!(absent, vec!["getblockbyhash", "getrawmempool"]);
+        assert!(!report.batch && !report.rest && report.zmq.is_empty(), "{}", report);
+        assert_eq!(report.suggestions.len(), 1, "{}", report);
+        assert!(report.suggestions[0].contains("--rpc-method-getblock"), "{}", report);
+        assert!(report.to_string().ends_with("probe: FAIL"));
+    }
+
+    #[test]
+    fn assert_passes_a_matching_golden_and_diffs_a_mismatched_one() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let adapter = open_adapter(&args);
+        let codec = KeyCodec::new();
+        let actual = assert_block(&args, adapter.clone(), 3, vec![0; 80]).unwrap();
+        // The run's writes are not left behind in the store
+        assert!(adapter.db.iterator(rocksdb::IteratorMode::Start).next().is_none());
+
+        let golden = dir.path().join("golden.jsonl");
+        let mut file = std::fs::File::create(&golden).unwrap();
+        snapshot::write_jsonl(&actual, &mut file, &codec).unwrap();
+        let mut out = vec![];
+        assert_eq!(compare_golden(3, &actual, &golden, &mut out, &codec).unwrap(), actual.len() as u64);
+        assert!(out.is_empty());
+
+        // A golden file expecting another value for one key and an extra key
+        let mut expected = actual.clone();
+        let (key, value) = expected.iter_mut().next().unwrap();
+        let changed = hex::encode(key);
+        value.push(0xff);
+        expected.insert(b"/missing".to_vec(), b"value".to_vec());
+        let mut file = std::fs::File::create(&golden).unwrap();
+        snapshot::write_jsonl(&expected, &mut file, &codec).unwrap();
+        let error = compare_golden(3, &actual, &golden, &mut out, &codec).unwrap_err();
+        assert!(error.to_string().contains("0 unexpected, 1 missing and 1 differing pairs"), "{}", error);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", out);
+        assert!(lines.iter().any(|line| line.starts_with(&format!("~ {}", changed))), "{}", out);
+        assert!(lines.iter().any(|line| line.starts_with("- ")), "{}", out);
+    }
+
+    // Traps in every block, and cannot be instantiated again once the height is past 0, as it is
+    // for the refresh after a trap
+    const UNREFRESHABLE: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (memory (export "memory") 1)
+      (func $init
+        (call $load (i32.const 1000))
+        (if (i32.load (i32.const 1000))
+          (then (unreachable))))
+      (start $init)
+      (func (export "_start") (unreachable)))"#;
+
+    #[tokio::test]
+    async fn a_failed_refresh_stops_the_block_with_both_errors() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        // Quarantining the trapped block is what replaces the instance
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--quarantine-bad-blocks"]);
+        std::fs::write(&args.indexer, UNREFRESHABLE).unwrap();
+        let adapter = open_adapter(&args);
+        let state = test_state(args, adapter.clone());
+
+        let error = format!("{:#}", state.index_block(1, vec![0; 80]).await.unwrap_err());
+        assert!(error.starts_with("block 1 failed with "), "{}", error);
+        assert!(error.contains("the instance could not be recreated: Failed to instantiate module"), "{}", error);
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 0);
+    }
+
+    #[actix_web::test]
+    async fn a_reorg_is_logged_with_its_ancestor_and_hashes() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let url = serve_fake_node(node.clone()).await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let reorg_log = dir.path().join("reorgs.jsonl");
+        let reorg_log_arg = format!("--reorg-log={}", reorg_log.display());
+        let args_until = |exit_at: &str| test_args(&dir, &url, &[&reorg_log_arg, exit_at]);
+        // Block `height` of the original chain, or of the branch replacing it from height 2
+        let block = |height: u32, branch: bool| vec![height as u8 + if branch { 0x80 } else { 0 }; 80];
+        let serve = |node: &mut FakeNode, height: u32, branch: bool| {
+            let blockhash = header_blockhash(&block(height, branch)).unwrap();
+            node.hashes.insert(height, blockhash.clone());
+            node.blocks.insert(blockhash, block(height, branch));
+        };
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 3;
+            for height in 0..=3 {
+                serve(&mut node, height, false);
+            }
+        }
+        let args = args_until("--exit-at=4");
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let adapter = open_adapter(&args);
+        test_state(args, adapter.clone()).run().await.unwrap();
+
+        // The node moves to the branch, one block longer, before the indexer runs again
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 4;
+            for height in 2..=4 {
+                serve(&mut node, height, true);
+            }
+        }
+        let args = args_until("--exit-at=5");
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        test_state(args.clone(), adapter.clone()).run().await.unwrap();
+        assert_eq!(query_height(adapter.db.clone(), 0).await.unwrap(), 5);
+
+        let expected = serde_json::json!({
+            "old_tip": 3,
+            "new_common_ancestor": 1,
+            "old_hash": hex::encode(header_blockhash(&block(2, false)).unwrap()),
+            "new_hash": hex::encode(header_blockhash(&block(2, true)).unwrap()),
+        });
+        let body = call_jsonrpc(&args, adapter.clone(), "metashrew_reorgs", serde_json::json!([])).await;
+        let events = body["result"].as_array().unwrap();
+        assert_eq!(events.len(), 1, "{}", body);
+        let mut event = events[0].clone();
+        assert!(event["timestamp"].as_u64().unwrap() > 0);
+        event.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(event, expected);
+
+        let lines = std::fs::read_to_string(&reorg_log).unwrap();
+        let logged: Vec<Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(logged, vec![events[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn reorg_resolution_compares_hashes_and_downloads_one_block() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 10;
+            for height in 0..=10u32 {
+                adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+                // The node has replaced blocks 8 to 10
+                let block = vec![height as u8 + if height >= 8 { 0x80 } else { 0 }; 80];
+                let blockhash = if height >= 8 { header_blockhash(&block).unwrap() } else { vec![height as u8; 32] };
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash, block);
+            }
+        }
+        let state = test_state(args, adapter);
+
+        assert_eq!(state.best_height(11).await.unwrap(), 8);
+        {
+            let node = node.lock().unwrap();
+            assert!(node.calls["getblockhash"] >= 3);
+            assert_eq!(node.calls.get("getblock"), None);
+            assert_eq!(node.calls.get("getblockheader"), None);
+        }
+
+        // Only the block at the fork point is pulled
+        assert_eq!(state.pull_block(8).await.unwrap(), vec![0x88; 80]);
+        assert_eq!(node.lock().unwrap().calls["getblock"], 1);
+    }
+
+    #[tokio::test]
+    async fn reorgs_are_clamped_at_the_start_anchor_and_fail_past_it() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 5;
+            for height in 0..=5 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+            }
+            // Blocks 3 to 5 are indexed from --start-block 3
+            for height in 3..=5 {
+                adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+            }
+        }
+        let mut state = test_state(args.clone(), adapter);
+        state.start_block = 3;
+        state.store_start_anchor().await.unwrap();
+        assert_eq!(state.get_blockhash(2).await, Some(vec![2; 32]));
+        assert_eq!(state.get_blockhash(1).await, None);
+        // A stored anchor is kept
+        node.lock().unwrap().hashes.insert(2, vec![0xee; 32]);
+        state.store_start_anchor().await.unwrap();
+        assert_eq!(state.get_blockhash(2).await, Some(vec![2; 32]));
+        node.lock().unwrap().hashes.insert(2, vec![2; 32]);
+
+        // Each reorg replaces the blocks from `fork` with a branch of its own
+        let reorg_from = |fork: u32| {
+            let mut node = node.lock().unwrap();
+            for height in fork..=5 {
+                node.hashes.insert(height, vec![height as u8 + fork as u8 * 0x10; 32]);
+            }
+        };
+        reorg_from(4);
+        assert_eq!(state.best_height(6).await.unwrap(), 4);
+        // A reorg of the start block rewinds to it, not below
+        reorg_from(3);
+        assert_eq!(state.best_height(6).await.unwrap(), 3);
+        assert_eq!(state.common_ancestor(5).await.unwrap(), Some(2));
+
+        // Once the anchor itself is replaced, the start is on an orphaned chain
+        reorg_from(2);
+        let error = state.best_height(6).await.unwrap_err();
+        assert!(error.is::<OrphanedStart>(), "{}", error);
+        assert!(error.to_string().contains("block 2, the anchor for --start-block 3"), "{}", error);
+        assert!(state.common_ancestor(5).await.unwrap_err().is::<OrphanedStart>());
+    }
+
+    #[tokio::test]
+    async fn best_height_skips_the_walk_while_the_tip_is_unchanged() {
+        let node = Arc::new(std::sync::Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &[]);
+        let mut adapter = open_adapter(&args);
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 5;
+            for height in 0..=7 {
+                node.hashes.insert(height, vec![height as u8; 32]);
+                adapter.put(args.hash_key_format.key(height), vec![height as u8; 32]).unwrap();
+            }
+        }
+        let state = test_state(args, adapter);
+        // (getblockcount, getblockhash) calls since the last time this was asked
+        let calls = || {
+            let mut node = node.lock().unwrap();
+            let count = |method: &str| node.calls.get(method).copied().unwrap_or(0);
+            let counts = (count("getblockcount"), count("getblockhash"));
+            node.calls.clear();
+            counts
+        };
+
+        // The first check walks, the idle ones after it only confirm the tip hash
+        assert_eq!(state.best_height(6).await.unwrap(), 6);
+        assert_eq!(calls(), (1, 1));
+        for _ in 0..3 {
+            assert_eq!(state.best_height(6).await.unwrap(), 6);
+        }
+        assert_eq!(calls(), (3, 3));
+        assert_eq!(*state.checked_tip.lock().unwrap(), Some((5, vec![5; 32])));
+
+        // A replaced tip at the same height is caught by its hash
+        node.lock().unwrap().hashes.insert(5, vec![0xee; 32]);
+        assert_eq!(state.best_height(6).await.unwrap(), 5);
+        assert_eq!(calls(), (1, 3));
+        node.lock().unwrap().hashes.insert(5, vec![5; 32]);
+
+        // Once the count moves the walk runs without fetching the new tip's hash first
+        node.lock().unwrap().tip = 7;
+        assert_eq!(state.best_height(6).await.unwrap(), 6);
+        assert_eq!(calls(), (1, 1));
+        assert_eq!(*state.checked_tip.lock().unwrap(), None);
+    }
+}
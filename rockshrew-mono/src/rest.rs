@@ -0,0 +1,296 @@
+use actix_web::{error, get, web, HttpResponse, Responder, Result as ActixResult};
+use anyhow::Result;
+use metashrew_runtime::MetashrewRuntime;
+use rockshrew_runtime::RocksDBRuntimeAdapter;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::{current_height, AppState};
+
+// Logical keys resolved per chunk of a /scan response
+const SCAN_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct ScanQuery {
+    // Hex-encoded key prefix
+    prefix: String,
+    // Defaults to the latest indexed block
+    height: Option<u32>,
+}
+
+// Resolves one page of keys to their values at `height`, as one JSON line per non-empty value
+fn scan_page(
+    db: &RocksDBRuntimeAdapter,
+    prefix: &[u8],
+    after: Option<&[u8]>,
+    height: u32,
+) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let page = db.scan_prefix_page(prefix, after, SCAN_PAGE_SIZE)?;
+    let context = Arc::new(std::sync::Mutex::new(
+        metashrew_runtime::MetashrewRuntimeContext::new(db.clone(), height, vec![]),
+    ));
+    let mut chunk: Vec<u8> = vec![];
+    for key in &page.keys {
+        let value = MetashrewRuntime::<RocksDBRuntimeAdapter>::db_value_at_block(
+            context.clone(),
+            key,
+            height,
+        )?;
+        if value.is_empty() {
+            continue;
+        }
+        serde_json::to_writer(
+            &mut chunk,
+            &serde_json::json!({
+                "key": format!("0x{}", hex::encode(key)),
+                "value": format!("0x{}", hex::encode(&value)),
+            }),
+        )?;
+        chunk.push(b'\n');
+    }
+    Ok((chunk, page.next))
+}
+
+// Streams every key under a prefix with its value as of a height, as newline-delimited JSON.
+// Each page of SCAN_PAGE_SIZE keys is read and sent as its own chunk, so neither side holds
+// the whole result.
+#[get("/scan")]
+pub async fn handle_scan(query: web::Query<ScanQuery>, state: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    let prefix = hex::decode(query.prefix.trim_start_matches("0x")).map_err(error::ErrorBadRequest)?;
+    let height = query
+        .height
+        .unwrap_or_else(|| current_height().saturating_sub(1));
+    state.check_history_depth(height).map_err(error::ErrorBadRequest)?;
+    let db = state.runtime.lock().await.context.lock().unwrap().db.clone();
+    // None once the last page has been sent; otherwise the cursor to resume after
+    let cursor: Option<Option<Vec<u8>>> = Some(None);
+    let body = futures_util::stream::unfold(cursor, move |cursor| {
+        let db = db.clone();
+        let prefix = prefix.clone();
+        async move {
+            let after = cursor?;
+            match scan_page(&db, &prefix, after.as_deref(), height) {
+                Ok((chunk, next)) => Some((
+                    Ok::<_, actix_web::Error>(web::Bytes::from(chunk)),
+                    next.map(Some),
+                )),
+                Err(e) => {
+                    log::warn!("scan of prefix {} failed: {}", hex::encode(&prefix), e);
+                    Some((Err(error::ErrorInternalServerError(e.to_string())), None))
+                }
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body))
+}
+
+impl AppState {
+    // limit_response for the REST routes, where an overflow is a 413 rather than an error object
+    fn limit_rest_response(&self, result: Vec<u8>) -> ActixResult<Vec<u8>> {
+        self.limit_response(result)
+            .map_err(|e| error::ErrorPayloadTooLarge(e.message))
+    }
+}
+
+#[derive(Deserialize)]
+struct RestKeyQuery {
+    // Defaults to the latest indexed block
+    height: Option<u32>,
+}
+
+// The value of one indexer key as of a height, as metashrew_view's indexers would read it;
+// a key with no value there is a 404
+#[get("/v1/key/{key}")]
+pub async fn handle_rest_key(
+    path: web::Path<String>,
+    query: web::Query<RestKeyQuery>,
+    state: web::Data<AppState>,
+) -> ActixResult<HttpResponse> {
+    let key = hex::decode(path.trim_start_matches("0x")).map_err(error::ErrorBadRequest)?;
+    let height = query
+        .height
+        .unwrap_or_else(|| current_height().saturating_sub(1));
+    state.check_history_depth(height).map_err(error::ErrorBadRequest)?;
+    let db = state.runtime.lock().await.context.lock().unwrap().db.clone();
+    let context = Arc::new(std::sync::Mutex::new(
+        metashrew_runtime::MetashrewRuntimeContext::new(db, height, vec![]),
+    ));
+    let value = MetashrewRuntime::<RocksDBRuntimeAdapter>::db_value_at_block(context, &key, height)
+        .map_err(error::ErrorInternalServerError)?;
+    if value.is_empty() {
+        return Err(error::ErrorNotFound(format!(
+            "no value for key 0x{} at height {}",
+            hex::encode(&key),
+            height
+        )));
+    }
+    let value = state.limit_rest_response(value)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "key": format!("0x{}", hex::encode(&key)),
+        "height": height,
+        "value": format!("0x{}", hex::encode(value)),
+    })))
+}
+
+#[get("/v1/height")]
+pub async fn handle_rest_height() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "height": current_height() }))
+}
+
+#[derive(Deserialize)]
+struct RestViewQuery {
+    // Hex-encoded view input, empty when omitted
+    #[serde(default)]
+    input: String,
+    // A block height, "latest" or "pending", as metashrew_view takes it; defaults to "latest"
+    height: Option<String>,
+}
+
+#[get("/v1/view/{name}")]
+pub async fn handle_rest_view(
+    path: web::Path<String>,
+    query: web::Query<RestViewQuery>,
+    state: web::Data<AppState>,
+) -> ActixResult<HttpResponse> {
+    let name = path.into_inner();
+    let input = hex::decode(query.input.trim_start_matches("0x")).map_err(error::ErrorBadRequest)?;
+    let result = match query.height.as_deref().unwrap_or("latest") {
+        "pending" => match state.mempool {
+            Some(ref mempool) => mempool.lock().await.view(name, &input),
+            None => return Err(error::ErrorNotFound("mempool indexing is not enabled")),
+        },
+        "latest" => state.runtime.lock().await.view(name, &input, current_height()),
+        height => {
+            let height: u32 = height.parse().map_err(|_| {
+                error::ErrorBadRequest("height must be a number, 'latest' or 'pending'")
+            })?;
+            state.check_history_depth(height).map_err(error::ErrorBadRequest)?;
+            state.runtime.lock().await.view(name, &input, height)
+        }
+    };
+    let result = state.limit_rest_response(result.map_err(error::ErrorInternalServerError)?)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "result": format!("0x{}", hex::encode(result)),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{open_adapter, test_app_state, test_args, CURRENT_HEIGHT, GREETER};
+    use crate::set_current_height;
+    use actix_web::body::MessageBody;
+    use actix_web::{test, App};
+    use metashrew_runtime::{BatchLike, KeyValueStoreLike, MetashrewRuntimeContext};
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    #[actix_web::test]
+    async fn rest_routes_answer_like_the_json_rpc_methods() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--rest"]);
+        std::fs::write(&args.indexer, GREETER).unwrap();
+        let mut adapter = open_adapter(&args);
+        let context = Arc::new(std::sync::Mutex::new(MetashrewRuntimeContext::new(
+            adapter.clone(),
+            3,
+            vec![],
+        )));
+        let mut batch = <RocksDBRuntimeAdapter as KeyValueStoreLike>::Batch::default();
+        MetashrewRuntime::<RocksDBRuntimeAdapter>::db_append_annotated(
+            context,
+            &mut batch,
+            &b"/balance".to_vec(),
+            &vec![0x2a],
+            3,
+        )
+        .unwrap();
+        adapter.write(batch).unwrap();
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_app_state(&args, runtime)))
+                .service(handle_rest_key)
+                .service(handle_rest_height)
+                .service(handle_rest_view),
+        )
+        .await;
+        set_current_height(5);
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let body: Value = test::call_and_read_body_json(&app, get("/v1/height")).await;
+        assert_eq!(body["height"], 5);
+
+        let key = format!("/v1/key/0x{}", hex::encode("/balance"));
+        let body: Value = test::call_and_read_body_json(&app, get(&key)).await;
+        assert_eq!((body["height"].as_u64(), body["value"].as_str()), (Some(4), Some("0x2a")));
+        let response = test::call_service(&app, get(&format!("{}?height=2", key))).await;
+        assert_eq!(response.status(), 404);
+        let missing = format!("/v1/key/{}", hex::encode("/nothing"));
+        assert_eq!(test::call_service(&app, get(&missing)).await.status(), 404);
+        assert_eq!(test::call_service(&app, get("/v1/key/zz")).await.status(), 400);
+
+        let body: Value = test::call_and_read_body_json(&app, get("/v1/view/greet?input=0x01")).await;
+        assert_eq!(body["result"], format!("0x{}", hex::encode("hi")));
+        let response = test::call_service(&app, get("/v1/view/greet?height=pending")).await;
+        assert_eq!(response.status(), 404);
+        let response = test::call_service(&app, get("/v1/view/greet?height=soon")).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn scan_streams_each_page_as_its_own_chunk() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let mut adapter = open_adapter(&args);
+        let context = Arc::new(std::sync::Mutex::new(MetashrewRuntimeContext::new(
+            adapter.clone(),
+            1,
+            vec![],
+        )));
+        let mut batch = <RocksDBRuntimeAdapter as KeyValueStoreLike>::Batch::default();
+        let count = SCAN_PAGE_SIZE * 2 + 500;
+        for n in 0..count {
+            MetashrewRuntime::<RocksDBRuntimeAdapter>::db_append_annotated(
+                context.clone(),
+                &mut batch,
+                &format!("/utxo/{:05}", n).into_bytes(),
+                &vec![1],
+                1,
+            )
+            .unwrap();
+        }
+        adapter.write(batch).unwrap();
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), adapter).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_app_state(&args, runtime)))
+                .service(handle_scan),
+        )
+        .await;
+        set_current_height(2);
+
+        let uri = format!("/scan?prefix=0x{}", hex::encode("/utxo/"));
+        let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(response.status(), 200);
+        let mut body = std::pin::pin!(response.into_body());
+        let mut chunks: Vec<web::Bytes> = vec![];
+        while let Some(chunk) = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+            chunks.push(chunk.unwrap());
+        }
+        let lines: Vec<usize> = chunks.iter().map(|chunk| chunk.split(|b| *b == b'\n').count() - 1).collect();
+        assert_eq!(lines, vec![SCAN_PAGE_SIZE, SCAN_PAGE_SIZE, 500]);
+        let first: Value = serde_json::from_slice(chunks[0].split(|b| *b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(first["key"], format!("0x{}", hex::encode("/utxo/00000")));
+        assert_eq!(first["value"], "0x01");
+
+        // Nothing was written under the prefix as of block 0
+        let uri = format!("{}&height=0", uri);
+        let body = test::call_and_read_body(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert!(body.is_empty());
+    }
+}
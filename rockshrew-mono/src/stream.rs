@@ -0,0 +1,286 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// Wire format for --block-stream. After connecting, the client sends the height it wants to
+// start from as a little-endian u32. The server then writes one frame per block, in height
+// order: the 4 magic bytes, the little-endian u32 height, the little-endian u32 block length
+// and the raw serialized block.
+pub const FRAME_MAGIC: &[u8; 4] = b"MSBF";
+
+// Comfortably above the 4MB consensus limit, so a corrupt length can't trigger a huge allocation
+const MAX_FRAME_LEN: u32 = 32 * 1024 * 1024;
+
+/// Yields serialized blocks to the indexer in height order.
+pub trait BlockSource {
+    async fn next_block(&mut self) -> Result<(u32, Vec<u8>)>;
+}
+
+pub struct StreamBlockSource {
+    reader: BufReader<TcpStream>,
+    next_height: u32,
+}
+
+impl StreamBlockSource {
+    pub async fn connect(addr: &str, start_height: u32) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&start_height.to_le_bytes()).await?;
+        Ok(Self {
+            reader: BufReader::new(stream),
+            next_height: start_height,
+        })
+    }
+}
+
+impl BlockSource for StreamBlockSource {
+    async fn next_block(&mut self) -> Result<(u32, Vec<u8>)> {
+        let mut magic = [0u8; 4];
+        self.reader.read_exact(&mut magic).await?;
+        if &magic != FRAME_MAGIC {
+            return Err(anyhow!("bad block frame magic {:02x?}", magic));
+        }
+        let height = self.reader.read_u32_le().await?;
+        if height != self.next_height {
+            return Err(anyhow!(
+                "block stream sent height {} but {} was expected",
+                height,
+                self.next_height
+            ));
+        }
+        let len = self.reader.read_u32_le().await?;
+        if len > MAX_FRAME_LEN {
+            return Err(anyhow!("block frame for height {} is {} bytes", height, len));
+        }
+        let mut block = vec![0u8; len as usize];
+        self.reader.read_exact(&mut block).await?;
+        self.next_height += 1;
+        Ok((height, block))
+    }
+}
+
+// Optional file in a --block-dir mapping heights to block hashes, one "height blockhash" pair
+// per line; # starts a comment
+pub const BLOCK_DIR_INDEX: &str = "index";
+
+/// Reads blocks from files in a directory, named `<height>.blk`, or `<blockhash>.blk` when the
+/// directory has an index. Blocks named by hash must hash to their name.
+pub struct DirBlockSource {
+    dir: PathBuf,
+    index: Option<BTreeMap<u32, String>>,
+    next_height: u32,
+}
+
+impl DirBlockSource {
+    pub async fn open(dir: PathBuf, start_height: u32) -> Result<Self> {
+        let index_path = dir.join(BLOCK_DIR_INDEX);
+        let index = if tokio::fs::try_exists(&index_path).await? {
+            let mut index = BTreeMap::new();
+            let text = tokio::fs::read_to_string(&index_path).await?;
+            for (number, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let (height, blockhash) = line
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| anyhow!("{}:{}: expected \"height blockhash\"", index_path.display(), number + 1))?;
+                let height: u32 = height
+                    .parse()
+                    .with_context(|| format!("{}:{}: bad height", index_path.display(), number + 1))?;
+                index.insert(height, blockhash.trim().to_lowercase());
+            }
+            Some(index)
+        } else {
+            None
+        };
+        Ok(Self {
+            dir,
+            index,
+            next_height: start_height,
+        })
+    }
+
+    fn path(&self, height: u32) -> Option<PathBuf> {
+        match self.index {
+            Some(ref index) => index
+                .get(&height)
+                .map(|blockhash| self.dir.join(format!("{}.blk", blockhash))),
+            None => Some(self.dir.join(format!("{}.blk", height))),
+        }
+    }
+
+    /// Whether the archive has a block for the next height.
+    pub async fn has_next(&self) -> Result<bool> {
+        Ok(match self.path(self.next_height) {
+            Some(path) => tokio::fs::try_exists(path).await?,
+            None => false,
+        })
+    }
+}
+
+impl BlockSource for DirBlockSource {
+    async fn next_block(&mut self) -> Result<(u32, Vec<u8>)> {
+        let height = self.next_height;
+        let path = self
+            .path(height)
+            .ok_or_else(|| anyhow!("block directory index has no entry for height {}", height))?;
+        let block = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read block {} from {}", height, path.display()))?;
+        if let Some(expected) = self.index.as_ref().and_then(|index| index.get(&height)) {
+            let blockhash = hex::encode(crate::header_blockhash(&block)?);
+            if &blockhash != expected {
+                return Err(anyhow!(
+                    "{} hashes to {}, not the indexed {}",
+                    path.display(),
+                    blockhash,
+                    expected
+                ));
+            }
+        }
+        self.next_height += 1;
+        Ok((height, block))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header_blockhash;
+    use crate::tests::{open_adapter, test_args, test_state, CURRENT_HEIGHT, RECORD_HEIGHT};
+    use metashrew_runtime::{db_make_length_key, db_make_list_key, KeyValueStoreLike};
+    use tokio::net::TcpListener;
+
+    fn frame(height: u32, block: &[u8]) -> Vec<u8> {
+        let mut frame = FRAME_MAGIC.to_vec();
+        frame.extend(height.to_le_bytes());
+        frame.extend((block.len() as u32).to_le_bytes());
+        frame.extend(block);
+        frame
+    }
+
+    // Answers one connection with the frames for `heights`, after checking the requested start
+    async fn serve_frames(start: u32, heights: Vec<u32>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(stream.read_u32_le().await.unwrap(), start);
+            for height in heights {
+                let mut block = vec![0u8; 80];
+                block[0] = height as u8;
+                stream.write_all(&frame(height, &block)).await.unwrap();
+            }
+            // Held open so the client sees the frames rather than a closed stream
+            let _ = stream.read_u8().await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn stream_source_rejects_a_frame_out_of_order() {
+        let mut source = StreamBlockSource::connect(&serve_frames(3, vec![3, 5]).await, 3)
+            .await
+            .unwrap();
+        let (height, block) = source.next_block().await.unwrap();
+        assert_eq!((height, block[0], block.len()), (3, 3, 80));
+        let error = source.next_block().await.unwrap_err();
+        assert_eq!(error.to_string(), "block stream sent height 5 but 4 was expected");
+    }
+
+    #[tokio::test]
+    async fn streamed_blocks_are_indexed_in_order() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &["--exit-at=6", "--no-reorg-tracking"]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.run_stream(serve_frames(3, vec![3, 4, 5]).await, 3).await.unwrap();
+
+        let runtime = state.runtime.lock().await;
+        let db = &mut runtime.context.lock().unwrap().db;
+        let key = b"/o".to_vec();
+        assert_eq!(db.get(db_make_length_key(&key).unwrap()).unwrap(), Some(3u32.to_le_bytes().to_vec()));
+        let indexed: Vec<u32> = (0..3)
+            .map(|index| {
+                let entry = db.get(db_make_list_key(&key, index).unwrap()).unwrap().unwrap();
+                u32::from_le_bytes(entry[..4].try_into().unwrap())
+            })
+            .collect();
+        assert_eq!(indexed, vec![3, 4, 5]);
+    }
+
+    // Reads back the heights RECORD_HEIGHT flushed, in the order they were indexed
+    fn indexed_heights(db: &mut impl KeyValueStoreLike) -> Vec<u32> {
+        let key = b"/o".to_vec();
+        let length = match db.get(db_make_length_key(&key).unwrap()).unwrap() {
+            Some(length) => u32::from_le_bytes(length[..4].try_into().unwrap()),
+            None => 0,
+        };
+        (0..length)
+            .map(|index| {
+                let entry = db.get(db_make_list_key(&key, index).unwrap()).unwrap().unwrap();
+                u32::from_le_bytes(entry[..4].try_into().unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn block_dir_is_indexed_in_height_order() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let blocks = dir.path().join("blocks");
+        std::fs::create_dir(&blocks).unwrap();
+        // Written out of order, and with a gap after 4
+        for height in [4u32, 2, 3, 6] {
+            std::fs::write(blocks.join(format!("{}.blk", height)), vec![height as u8; 80]).unwrap();
+        }
+        let block_dir = format!("--block-dir={}", blocks.display());
+
+        let args = test_args(&dir, "http://127.0.0.1:0", &[&block_dir]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        state.run_dir(blocks.clone(), 2).await.unwrap();
+        {
+            let runtime = state.runtime.lock().await;
+            let db = &mut runtime.context.lock().unwrap().db;
+            assert_eq!(indexed_heights(db), vec![2, 3, 4]);
+            assert_eq!(db.get(args.hash_key_format.key(3)).unwrap(), header_blockhash(&[3; 80]).ok());
+        }
+
+        let erroring = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&erroring, "http://127.0.0.1:0", &[&block_dir, "--on-missing-block=error"]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let state = test_state(args.clone(), open_adapter(&args));
+        let error = state.run_dir(blocks.clone(), 3).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("{} has no block 5", blocks.display()));
+        let runtime = state.runtime.lock().await;
+        assert_eq!(indexed_heights(&mut runtime.context.lock().unwrap().db), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn block_dir_index_names_blocks_by_hash_and_checks_them() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let hash = |first: u8| hex::encode(header_blockhash(&[first; 80]).unwrap());
+        std::fs::write(dir.path().join(format!("{}.blk", hash(0))), [0; 80]).unwrap();
+        // Stored under the hash of a different block
+        std::fs::write(dir.path().join(format!("{}.blk", hash(9))), [1; 80]).unwrap();
+        std::fs::write(
+            dir.path().join(BLOCK_DIR_INDEX),
+            format!("# height blockhash\n0 {}\n1 {}\n", hash(0), hash(9).to_uppercase()),
+        )
+        .unwrap();
+
+        let mut source = DirBlockSource::open(dir.path().to_path_buf(), 0).await.unwrap();
+        assert!(source.has_next().await.unwrap());
+        assert_eq!(source.next_block().await.unwrap(), (0, vec![0; 80]));
+        assert!(source.has_next().await.unwrap());
+        let error = source.next_block().await.unwrap_err();
+        assert!(error.to_string().contains(&format!("hashes to {}", hash(1))), "{}", error);
+
+        let source = DirBlockSource::open(dir.path().to_path_buf(), 2).await.unwrap();
+        assert!(!source.has_next().await.unwrap());
+    }
+}
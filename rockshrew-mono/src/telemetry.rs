@@ -0,0 +1,141 @@
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Flushes buffered spans to the collector when dropped.
+pub struct TracingGuard {
+    provider: TracerProvider,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            log::warn!("failed to flush OTLP spans: {}", e);
+        }
+    }
+}
+
+/// Installs a global subscriber that batches `tracing` spans to an OTLP/gRPC collector.
+///
+/// Without this no subscriber is registered, so the per-block spans are disabled at the
+/// callsite and cost a single branch each.
+pub fn init_otlp(endpoint: &str) -> Result<TracingGuard> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "rockshrew-mono",
+        )]))
+        .build();
+    subscriber(&provider).try_init()?;
+    Ok(TracingGuard { provider })
+}
+
+// Turns `tracing` spans into OpenTelemetry spans handed to `provider`'s exporter
+fn subscriber(provider: &TracerProvider) -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("rockshrew-mono")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        open_adapter, serve_fake_node, test_args, test_state, FakeNode, CURRENT_HEIGHT, RECORD_HEIGHT,
+    };
+    use futures_util::future::BoxFuture;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn each_block_exports_fetch_execute_and_commit_spans() {
+        let _height = CURRENT_HEIGHT.lock().await;
+        let node = Arc::new(Mutex::new(FakeNode::default()));
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, &serve_fake_node(node.clone()).await, &["--exit-at=2"]);
+        std::fs::write(&args.indexer, RECORD_HEIGHT).unwrap();
+        let mut blockhashes = vec![];
+        {
+            let mut node = node.lock().unwrap();
+            node.tip = 2;
+            for height in 0..=2u32 {
+                let block = vec![height as u8; 80];
+                let blockhash = crate::header_blockhash(&block).unwrap();
+                node.hashes.insert(height, blockhash.clone());
+                node.blocks.insert(blockhash.clone(), block);
+                blockhashes.push(hex::encode(blockhash));
+            }
+        }
+        let exporter = MemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        {
+            // Held only on this thread, which the test's runtime runs every task on
+            let _subscriber = tracing::subscriber::set_default(subscriber(&provider));
+            test_state(args.clone(), open_adapter(&args)).run().await.unwrap();
+        }
+
+        let spans: Vec<(String, BTreeMap<String, String>)> = exporter
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|span| {
+                let attributes = span
+                    .attributes
+                    .iter()
+                    .filter(|kv| ["height", "blockhash"].contains(&kv.key.as_str()))
+                    .map(|kv| {
+                        let value = match kv.value {
+                            Value::String(ref s) => s.to_string(),
+                            ref other => other.to_string(),
+                        };
+                        (kv.key.to_string(), value)
+                    })
+                    .collect();
+                (span.name.to_string(), attributes)
+            })
+            .collect();
+        for (height, hash) in blockhashes.iter().enumerate().take(2) {
+            let attributes = |blockhash: bool| {
+                let mut attributes = BTreeMap::from([("height".to_string(), height.to_string())]);
+                if blockhash {
+                    attributes.insert("blockhash".to_string(), hash.clone());
+                }
+                attributes
+            };
+            for (name, blockhash) in [("fetch", true), ("execute", true), ("commit", false)] {
+                assert!(
+                    spans.contains(&(name.to_string(), attributes(blockhash))),
+                    "no {} span for block {} in {:?}",
+                    name,
+                    height,
+                    spans
+                );
+            }
+        }
+        assert!(!spans.iter().any(|(_, attributes)| attributes.get("height") == Some(&"2".to_string())));
+    }
+}
@@ -0,0 +1,160 @@
+use metashrew_runtime::MetashrewRuntime;
+use rockshrew_runtime::RocksDBRuntimeAdapter;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use super::Metrics;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum WatchdogAction {
+    /// Log the stall and abort the process, for a supervisor to restart
+    Abort,
+    /// Abandon the stuck step and restart the sync loop from the stored tip, aborting if the
+    /// restarted loop makes no progress either
+    Recover,
+}
+
+// The sync loop's last report to the --loop-watchdog-secs watchdog
+struct Beat {
+    at: Instant,
+    stage: String,
+    // Reports made so far; the watchdog's own resets do not count
+    count: u64,
+}
+
+pub struct Liveness(std::sync::Mutex<Beat>);
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self(std::sync::Mutex::new(Beat {
+            at: Instant::now(),
+            stage: String::from("starting"),
+            count: 0,
+        }))
+    }
+
+    pub fn beat(&self, stage: String) {
+        let mut beat = self.0.lock().unwrap();
+        beat.at = Instant::now();
+        beat.stage = stage;
+        beat.count += 1;
+    }
+}
+
+// Describes where a stalled sync loop stopped and which locks are taken, the closest thing to a
+// stack trace available for an async task
+fn stall_report(
+    stalled: Duration,
+    stage: &str,
+    runtime: &Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>,
+    metrics: &Metrics,
+) -> String {
+    let locks = match runtime.try_lock() {
+        Err(_) => String::from("runtime lock: held\n  store context: unknown while the runtime lock is held"),
+        Ok(runtime) => format!(
+            "runtime lock: free\n  store context: {}",
+            match runtime.context.try_lock() {
+                Ok(_) => "free",
+                Err(std::sync::TryLockError::WouldBlock) => "held",
+                Err(std::sync::TryLockError::Poisoned(_)) => "poisoned",
+            }
+        ),
+    };
+    format!(
+        "sync loop stalled: no progress for {}s\n  stage: {}\n  {}\n  node tip: {}\n  last commit: {:.0}s ago",
+        stalled.as_secs(),
+        stage,
+        locks,
+        metrics.node_tip.load(Ordering::Relaxed),
+        metrics.seconds_since_last_block()
+    )
+}
+
+// Checks the sync loop's liveness once a second from a thread of its own, so a wedged async
+// runtime cannot keep it from firing. Under WatchdogAction::Recover a stall sends a restart
+// request on `restart`; a second stall with no report since the restart aborts regardless.
+// The watchdog stops once `restart`'s receiver is gone along with the loop it restarts.
+pub fn spawn_loop_watchdog(
+    liveness: Arc<Liveness>,
+    limit: Duration,
+    action: WatchdogAction,
+    runtime: Arc<Mutex<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
+    metrics: Arc<Metrics>,
+    restart: tokio::sync::mpsc::UnboundedSender<()>,
+) {
+    std::thread::spawn(move || {
+        let mut restarted_at: Option<u64> = None;
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            if restart.is_closed() {
+                return;
+            }
+            let (stalled, stage, count) = {
+                let beat = liveness.0.lock().unwrap();
+                (beat.at.elapsed(), beat.stage.clone(), beat.count)
+            };
+            if stalled < limit {
+                continue;
+            }
+            log::error!("{}", stall_report(stalled, &stage, &runtime, &metrics));
+            if action == WatchdogAction::Abort || restarted_at == Some(count) {
+                if restarted_at == Some(count) {
+                    log::error!("restarting the sync loop did not restore progress");
+                }
+                log::logger().flush();
+                std::process::abort();
+            }
+            restarted_at = Some(count);
+            {
+                let mut beat = liveness.0.lock().unwrap();
+                beat.at = Instant::now();
+                beat.stage = String::from("restarting after a stall");
+            }
+            let _ = restart.send(());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{open_adapter, test_args};
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn watchdog_fires_while_a_block_is_wedged() {
+        let dir = tempdir::TempDir::new("rockshrew-mono").unwrap();
+        let args = test_args(&dir, "http://127.0.0.1:0", &[]);
+        let runtime = MetashrewRuntime::load(PathBuf::from(&args.indexer), open_adapter(&args)).unwrap();
+        let runtime = Arc::new(Mutex::new(runtime));
+        let metrics = Arc::new(Metrics::new(String::from("test")));
+        let liveness = Arc::new(Liveness::new());
+        let (restart_tx, mut restart_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+        spawn_loop_watchdog(
+            liveness.clone(),
+            Duration::from_millis(1500),
+            WatchdogAction::Recover,
+            runtime.clone(),
+            metrics.clone(),
+            restart_tx,
+        );
+
+        // A block that holds the runtime lock well past the limit
+        liveness.beat(String::from("executing block 7"));
+        let wedged = runtime.lock().await;
+        let fired = tokio::time::timeout(Duration::from_secs(5), restart_rx.recv()).await;
+        // Stops the watchdog before the unchanged count makes a second stall abort
+        drop(restart_rx);
+        assert_eq!(fired, Ok(Some(())));
+        assert_eq!(liveness.0.lock().unwrap().stage, "restarting after a stall");
+        let report = stall_report(Duration::from_secs(2), "executing block 7", &runtime, &metrics);
+        assert!(report.contains("stage: executing block 7"), "{}", report);
+        assert!(report.contains("runtime lock: held"), "{}", report);
+        drop(wedged);
+
+        let report = stall_report(Duration::from_secs(2), "executing block 7", &runtime, &metrics);
+        assert!(report.contains("runtime lock: free\n  store context: free"), "{}", report);
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Maps the leading table-id byte of a key to a human-readable name for tooling output.
+///
+/// This is purely a display layer: keys on disk stay raw bytes and nothing in the indexing
+/// path consults it.
+#[derive(Clone, Debug, Default)]
+pub struct KeyCodec {
+    names: HashMap<u8, String>,
+}
+
+impl KeyCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, table: u8, name: String) {
+        self.names.insert(table, name);
+    }
+
+    /// Parses a comma-separated list of `<hex byte>=<name>` pairs, e.g. `01=utxo,02=balance`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut codec = Self::new();
+        for entry in spec.split(',').filter(|entry| !entry.is_empty()) {
+            let (table, name) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("key codec entry {:?} must look like 01=name", entry))?;
+            let table = u8::from_str_radix(table.trim_start_matches("0x"), 16)
+                .map_err(|_| anyhow!("key codec table id {:?} is not a hex byte", table))?;
+            codec.register(table, name.to_string());
+        }
+        Ok(codec)
+    }
+
+    pub fn name(&self, key: &[u8]) -> Option<&str> {
+        key.first()
+            .and_then(|table| self.names.get(table))
+            .map(|name| name.as_str())
+    }
+
+    /// Renders `key` as `<name>:<hex of the rest>` when its table is registered, otherwise as hex.
+    pub fn display(&self, key: &[u8]) -> String {
+        match self.name(key) {
+            Some(name) => format!("{}:{}", name, hex::encode(&key[1..])),
+            None => hex::encode(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_registers_each_table() {
+        let codec = KeyCodec::parse("01=utxo,0x02=balance").unwrap();
+        assert_eq!(codec.name(&[1, 9]), Some("utxo"));
+        assert_eq!(codec.name(&[2]), Some("balance"));
+        assert_eq!(codec.name(&[3]), None);
+        assert_eq!(codec.name(&[]), None);
+        assert!(KeyCodec::parse("").unwrap().name(&[1]).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_entries() {
+        assert!(KeyCodec::parse("utxo").is_err());
+        assert!(KeyCodec::parse("zz=utxo").is_err());
+        assert!(KeyCodec::parse("100=utxo").is_err());
+    }
+
+    #[test]
+    fn display_names_registered_tables() {
+        let codec = KeyCodec::parse("01=utxo").unwrap();
+        assert_eq!(codec.display(&[1, 0xab, 0xcd]), "utxo:abcd");
+        assert_eq!(codec.display(&[2, 0xab]), "02ab");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{to_labeled_key, RocksDBRuntimeAdapter};
+use anyhow::{anyhow, Result};
+use rocksdb::WriteBatch;
+use std::str::FromStr;
+
+// Each logical key keeps its versions as a list: the key followed by a little-endian u32 index
+// maps to the value with the height that wrote it appended, and the key followed by u32::MAX
+// holds the list length. Downsampling rewrites the retained versions to the front of the list,
+// deletes the rest and shrinks the length, all in one write per key.
+
+/// Keeps every version written in the last `recent` blocks and, below that, only the last version
+/// written in each bucket of `every` heights.
+///
+/// A query for a height inside a downsampled range sees the value as of the end of the previous
+/// bucket until it reaches the height of the version that was kept, so history below the cutoff
+/// is only exact at the last height of each bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryResolution {
+    pub recent: u32,
+    pub every: u32,
+}
+
+impl FromStr for HistoryResolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (recent, every) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected <recent>:<every>, found {:?}", s))?;
+        let resolution = Self {
+            recent: recent.parse()?,
+            every: every.parse()?,
+        };
+        if resolution.every == 0 {
+            return Err(anyhow!("bucket size must be at least 1"));
+        }
+        Ok(resolution)
+    }
+}
+
+impl HistoryResolution {
+    // Returns the list positions of `heights` (oldest first) that survive at `tip`
+    pub fn retained(&self, heights: &[u32], tip: u32) -> Vec<usize> {
+        let cutoff = tip.saturating_sub(self.recent);
+        (0..heights.len())
+            .filter(|&i| {
+                heights[i] >= cutoff
+                    || i + 1 == heights.len()
+                    || heights[i + 1] >= cutoff
+                    || heights[i + 1] / self.every != heights[i] / self.every
+            })
+            .collect()
+    }
+}
+
+// Each height's update list lives under the bare little-endian height, which is also where a
+// 4-byte logical key keeps its list, so a 4-byte key at or below the tip is an update list (or
+// has collided with one) while one above it can only be a logical key so far
+fn is_update_list(key: &[u8], tip: u32) -> bool {
+    key.len() == 4 && u32::from_le_bytes(key.try_into().unwrap()) <= tip
+}
+
+fn list_key(key: &[u8], index: u32) -> Vec<u8> {
+    let mut result = to_labeled_key(&key.to_vec());
+    result.extend(index.to_le_bytes());
+    result
+}
+
+/// Applies `resolution` to the versions of `key` as of `tip`, returning how many were dropped.
+///
+/// Callers must keep the indexer from appending to the same key while this runs.
+pub fn downsample_key(
+    adapter: &RocksDBRuntimeAdapter,
+    key: &[u8],
+    resolution: HistoryResolution,
+    tip: u32,
+) -> Result<u32> {
+    let length = match adapter.db.get(list_key(key, u32::MAX))? {
+        Some(bytes) => u32::from_le_bytes(bytes.as_slice().try_into()?),
+        None => return Ok(0),
+    };
+    let mut entries: Vec<Vec<u8>> = Vec::with_capacity(length as usize);
+    for index in 0..length {
+        match adapter.db.get(list_key(key, index))? {
+            Some(value) if value.len() >= 4 => entries.push(value),
+            _ => return Err(anyhow!("version {} of {} is missing or unannotated", index, hex::encode(key))),
+        }
+    }
+    let heights: Vec<u32> = entries
+        .iter()
+        .map(|value| u32::from_le_bytes(value[value.len() - 4..].try_into().unwrap()))
+        .collect();
+    let retained = resolution.retained(&heights, tip);
+    if retained.len() == entries.len() {
+        return Ok(0);
+    }
+    let mut batch = WriteBatch::default();
+    for (index, position) in retained.iter().enumerate() {
+        if index != *position {
+            batch.put(list_key(key, index as u32), &entries[*position]);
+        }
+    }
+    for index in retained.len()..entries.len() {
+        batch.delete(list_key(key, index as u32));
+    }
+    batch.put(list_key(key, u32::MAX), (retained.len() as u32).to_le_bytes());
+    adapter.db.write(batch)?;
+    Ok((entries.len() - retained.len()) as u32)
+}
+
+/// Downsamples up to `limit` keys following `after`, returning the number of versions dropped and
+/// the cursor for the next page, None once the keyspace is exhausted.
+///
+/// Per-height update lists and `/__INTERNAL/` bookkeeping hold unannotated values and are left
+/// alone. An update list is a 4-byte key no higher than `tip` read as a little-endian height;
+/// 4-byte logical keys above the tip are downsampled like any other, until the chain reaches
+/// the height they spell.
+pub fn downsample_page(
+    adapter: &RocksDBRuntimeAdapter,
+    after: Option<&[u8]>,
+    limit: usize,
+    resolution: HistoryResolution,
+    tip: u32,
+) -> Result<(u64, Option<Vec<u8>>)> {
+    let page = adapter.scan_prefix_page(&[], after, limit)?;
+    let mut dropped: u64 = 0;
+    for key in &page.keys {
+        if is_update_list(key, tip) || key.starts_with(b"/__INTERNAL/") {
+            continue;
+        }
+        dropped += downsample_key(adapter, key, resolution, tip)? as u64;
+    }
+    Ok((dropped, page.next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &tempdir::TempDir) -> RocksDBRuntimeAdapter {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        RocksDBRuntimeAdapter::open(dir.path().to_string_lossy().to_string(), opts).unwrap()
+    }
+
+    // Writes one annotated version of `key` per height, oldest first
+    fn write_versions(adapter: &RocksDBRuntimeAdapter, key: &[u8], heights: &[u32]) {
+        for (index, height) in heights.iter().enumerate() {
+            let mut value = vec![index as u8];
+            value.extend(height.to_le_bytes());
+            adapter.db.put(list_key(key, index as u32), value).unwrap();
+        }
+        adapter
+            .db
+            .put(list_key(key, u32::MAX), (heights.len() as u32).to_le_bytes())
+            .unwrap();
+    }
+
+    fn versions(adapter: &RocksDBRuntimeAdapter, key: &[u8]) -> Vec<u32> {
+        let length = u32::from_le_bytes(
+            adapter.db.get(list_key(key, u32::MAX)).unwrap().unwrap().as_slice().try_into().unwrap(),
+        );
+        (0..length)
+            .map(|index| {
+                let value = adapter.db.get(list_key(key, index)).unwrap().unwrap();
+                u32::from_le_bytes(value[value.len() - 4..].try_into().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn downsample_page_skips_update_lists_only() {
+        let dir = tempdir::TempDir::new("rockshrew-history").unwrap();
+        let adapter = open(&dir);
+        let heights = [1, 5, 9, 12, 18, 25, 31, 35];
+        write_versions(&adapter, b"key", &heights);
+        // A logical key spelling a height far above the tip
+        write_versions(&adapter, &[0, 0, 0, 1], &heights);
+        // The update list for height 9, whose entries are bare keys
+        let update_list = 9u32.to_le_bytes();
+        for (index, key) in [&b"key"[..], b"other", b"third"].iter().enumerate() {
+            adapter.db.put(list_key(&update_list, index as u32), key).unwrap();
+        }
+        adapter.db.put(list_key(&update_list, u32::MAX), 3u32.to_le_bytes()).unwrap();
+
+        let resolution = HistoryResolution { recent: 10, every: 10 };
+        let (dropped, next) = downsample_page(&adapter, None, 10, resolution, 35).unwrap();
+        assert_eq!((dropped, next), (6, None));
+        assert_eq!(versions(&adapter, b"key"), vec![9, 18, 25, 31, 35]);
+        assert_eq!(versions(&adapter, &[0, 0, 0, 1]), vec![9, 18, 25, 31, 35]);
+        assert_eq!(adapter.db.get(list_key(&update_list, 2)).unwrap(), Some(b"third".to_vec()));
+    }
+
+    #[test]
+    fn from_str_requires_a_bucket() {
+        assert_eq!(
+            "100:10".parse::<HistoryResolution>().unwrap(),
+            HistoryResolution { recent: 100, every: 10 }
+        );
+        assert!("100".parse::<HistoryResolution>().is_err());
+        assert!("100:0".parse::<HistoryResolution>().is_err());
+    }
+
+    #[test]
+    fn retained_keeps_recent_versions_and_the_last_per_bucket() {
+        let resolution = HistoryResolution { recent: 10, every: 10 };
+        let heights = [1, 5, 9, 12, 18, 25, 31, 35];
+        // Cutoff is 35 - 10 = 25: 9 and 18 close their buckets, 25 onwards is recent
+        assert_eq!(resolution.retained(&heights, 35), vec![2, 4, 5, 6, 7]);
+        // The version the first recent height reads from survives even mid-bucket
+        assert_eq!(resolution.retained(&[1, 5, 30], 35), vec![1, 2]);
+        assert_eq!(resolution.retained(&heights, 5), (0..heights.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn downsample_key_compacts_the_list() {
+        let dir = tempdir::TempDir::new("rockshrew-history").unwrap();
+        let adapter = open(&dir);
+        write_versions(&adapter, b"key", &[1, 5, 9, 12, 18, 25, 31, 35]);
+        let resolution = HistoryResolution { recent: 10, every: 10 };
+        assert_eq!(downsample_key(&adapter, b"key", resolution, 35).unwrap(), 3);
+        assert_eq!(versions(&adapter, b"key"), vec![9, 18, 25, 31, 35]);
+        assert!(adapter.db.get(list_key(b"key", 5)).unwrap().is_none());
+        assert_eq!(downsample_key(&adapter, b"key", resolution, 35).unwrap(), 0);
+        assert_eq!(downsample_key(&adapter, b"missing", resolution, 35).unwrap(), 0);
+    }
+}
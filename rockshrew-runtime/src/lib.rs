@@ -1,17 +1,30 @@
-use anyhow::Result;
-use metashrew_runtime::{BatchLike, KeyValueStoreLike};
-use rocksdb::{DB, Options, WriteBatch, WriteBatchIterator};
+use anyhow::{anyhow, Result};
+use metashrew_runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+use rocksdb::{Direction, IteratorMode, DB, Options, WriteBatch, WriteBatchIterator, WriteOptions};
+use std::path::Path;
 use std::sync::{Arc};
 
-const TIP_HEIGHT_KEY: &'static str = "/__INTERNAL/tip-height";
+pub mod codec;
+pub mod history;
+pub mod snapshot;
+pub mod wal;
+
+use wal::CommitLog;
+
+pub const TIP_HEIGHT_KEY: &str = "/__INTERNAL/tip-height";
 
 #[derive(Clone)]
 pub struct RocksDBRuntimeAdapter {
     pub db: Arc<DB>,
     pub height: u32,
+    // The tip-height key is only written when the new tip is a multiple of this
+    pub commit_tip_every: u32,
+    // When set, each batch is logged here before it is committed; see `set_wal_dir`
+    pub wal: Option<CommitLog>,
 }
 
 static mut _LABEL: Option<String> = None;
+static mut _CHAIN_ID: Option<u8> = None;
 
 const TIMEOUT: u64 = 1500;
 
@@ -21,6 +34,32 @@ pub fn wait_timeout() {
     thread::sleep(time::Duration::from_millis(TIMEOUT));
 }
 
+/// Canonicalizes a `--label` so equivalent spellings share one namespace.
+///
+/// Labels are UTF-8 and used byte-for-byte as the key prefix, so anything that would make that
+/// prefix ambiguous is rejected: an empty label, surrounding whitespace, control characters, and
+/// the `://` separator itself, which would nest one label's keys inside another's. With
+/// `lowercase` the label is also lowercased, making `MyLabel` and `mylabel` the same namespace.
+pub fn normalize_label(label: &str, lowercase: bool) -> Result<String> {
+    if label.is_empty() {
+        return Err(anyhow!("label must not be empty"));
+    }
+    if label.trim() != label {
+        return Err(anyhow!("label {:?} has leading or trailing whitespace", label));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(anyhow!("label {:?} contains a control character", label));
+    }
+    if label.contains("://") {
+        return Err(anyhow!("label {:?} must not contain \"://\"", label));
+    }
+    Ok(if lowercase {
+        label.to_lowercase()
+    } else {
+        label.to_string()
+    })
+}
+
 pub fn set_label(s: String) -> () {
     unsafe {
         _LABEL = Some(s + "://");
@@ -37,10 +76,25 @@ pub fn has_label() -> bool {
     unsafe { _LABEL.is_some() }
 }
 
+/// Puts every key, internal ones included, behind this byte, ahead of any label, so indexers for
+/// different chains sharing one store never see each other's keys.
+pub fn set_chain_id(chain_id: u8) {
+    unsafe {
+        _CHAIN_ID = Some(chain_id);
+    }
+}
+
+pub fn get_chain_id() -> Option<u8> {
+    unsafe { _CHAIN_ID }
+}
+
 pub fn to_labeled_key(key: &Vec<u8>) -> Vec<u8> {
-    if has_label() {
+    if has_label() || get_chain_id().is_some() {
         let mut result: Vec<u8> = vec![];
-        result.extend(get_label().as_str().as_bytes());
+        result.extend(get_chain_id());
+        if has_label() {
+            result.extend(get_label().as_str().as_bytes());
+        }
         result.extend(key);
         result
     } else {
@@ -63,6 +117,12 @@ pub async fn query_height(db: Arc<DB>, start_block: u32) -> Result<u32> {
     Ok(u32::from_le_bytes(bytes_ref.try_into().unwrap()))
 }
 
+/// One page of `RocksDBRuntimeAdapter::scan_prefix_page`.
+pub struct ScanPage {
+    pub keys: Vec<Vec<u8>>,
+    pub next: Option<Vec<u8>>,
+}
+
 impl RocksDBRuntimeAdapter {
     pub fn open_secondary(
         primary_path: String,
@@ -72,7 +132,9 @@ impl RocksDBRuntimeAdapter {
         let db = rocksdb::DB::open_as_secondary(&opts, &primary_path, &secondary_path)?;
         Ok(RocksDBRuntimeAdapter {
             db: Arc::new(db),
-            height: 0
+            height: 0,
+            commit_tip_every: 1,
+            wal: None,
         })
     }
     pub fn open(path: String, opts: Options) -> Result<RocksDBRuntimeAdapter> {
@@ -80,6 +142,8 @@ impl RocksDBRuntimeAdapter {
         Ok(RocksDBRuntimeAdapter {
             db: Arc::new(db),
             height: 0,
+            commit_tip_every: 1,
+            wal: None,
         })
     }
 
@@ -90,14 +154,149 @@ impl RocksDBRuntimeAdapter {
         self.height = height;
     }
 
+    pub fn set_commit_tip_every(&mut self, n: u32) {
+        self.commit_tip_every = std::cmp::max(n, 1);
+    }
+
+    /// Logs every batch, tip included, to a write-ahead log in `dir` and fsyncs it before the
+    /// batch is committed, then commits with a synced write and clears the log. Call
+    /// `replay_wal` on startup to finish a commit the process died in the middle of.
+    ///
+    /// A log that cannot be written fails the write with `AdapterError::Wal` before anything
+    /// is committed. One that cannot be cleared afterwards fails it too, though the batch is in
+    /// the store by then and replaying it rewrites the same values.
+    pub fn set_wal_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        self.wal = Some(CommitLog::open(dir)?);
+        Ok(())
+    }
+
+    /// Commits the batch left in the write-ahead log by an interrupted write, returning whether
+    /// there was one. Replaying a batch that did reach the store rewrites the same values.
+    pub fn replay_wal(&self) -> Result<bool> {
+        let wal = match self.wal {
+            Some(ref wal) => wal,
+            None => return Ok(false),
+        };
+        let data = match wal.pending()? {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        let mut opts = WriteOptions::default();
+        opts.set_sync(true);
+        self.db.write_opt(WriteBatch::from_data(&data), &opts)?;
+        wal.clear()?;
+        Ok(true)
+    }
+
+    /// Compacts the whole keyspace, returning the total size of the SST files before and after.
+    pub fn compact(&self) -> Result<(u64, u64)> {
+        let sst_size = || -> Result<u64> {
+            Ok(self
+                .db
+                .property_int_value("rocksdb.total-sst-files-size")?
+                .unwrap_or(0))
+        };
+        let before = sst_size()?;
+        self.db.compact_range::<&[u8], &[u8]>(None, None);
+        Ok((before, sst_size()?))
+    }
+
+    /// Persists the tip-height key immediately, regardless of `commit_tip_every`.
+    ///
+    /// This bypasses the write-ahead log set with `set_wal_dir`. The tip is a single put, which
+    /// RocksDB applies atomically on its own, so there is nothing for a replay to finish.
+    pub fn write_tip_height(&self, height: u32) -> Result<(), rocksdb::Error> {
+        self.db.put(
+            to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec()),
+            height.to_le_bytes(),
+        )
+    }
+
+    /// Returns up to `limit` logical keys under `prefix` following `after`, relative to the
+    /// active label, plus the cursor to pass as `after` for the next page, None once the prefix
+    /// is exhausted. Keys are found and ordered through their length entries (the key followed
+    /// by u32::MAX), so only one page is held at a time and a key that extends another can come
+    /// before it.
+    pub fn scan_prefix_page(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScanPage, rocksdb::Error> {
+        let length_suffix = u32::MAX.to_le_bytes();
+        let labeled_prefix = to_labeled_key(&prefix.to_vec());
+        let label_len = labeled_prefix.len() - prefix.len();
+        let start = match after {
+            Some(key) => {
+                let mut start = to_labeled_key(&key.to_vec());
+                start.extend(length_suffix);
+                start
+            }
+            None => labeled_prefix.clone(),
+        };
+        let mut keys: Vec<Vec<u8>> = vec![];
+        for item in self.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            let (raw_key, _) = item?;
+            if !raw_key.starts_with(&labeled_prefix) {
+                return Ok(ScanPage { keys, next: None });
+            }
+            if raw_key.len() < labeled_prefix.len() + 4
+                || !raw_key.ends_with(&length_suffix)
+                || (after.is_some() && raw_key[..] == start[..])
+            {
+                continue;
+            }
+            if keys.len() >= limit {
+                let next = keys.last().cloned();
+                return Ok(ScanPage { keys, next });
+            }
+            keys.push(raw_key[label_len..raw_key.len() - 4].to_vec());
+        }
+        Ok(ScanPage { keys, next: None })
+    }
+
     pub fn clone(&self) -> Self {
         RocksDBRuntimeAdapter {
             db: self.db.clone(),
             height: self.height,
+            commit_tip_every: self.commit_tip_every,
+            wal: self.wal.clone(),
+        }
+    }
+}
+
+/// Why a `RocksDBRuntimeAdapter` operation failed.
+#[derive(Debug)]
+pub enum AdapterError {
+    Db(rocksdb::Error),
+    /// The write-ahead log set with `set_wal_dir` could not be written or cleared.
+    Wal(std::io::Error),
+}
+
+impl std::fmt::Display for AdapterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdapterError::Db(e) => write!(f, "database error: {}", e),
+            AdapterError::Wal(e) => write!(f, "write-ahead log error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AdapterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AdapterError::Db(e) => Some(e),
+            AdapterError::Wal(e) => Some(e),
         }
     }
 }
 
+impl From<rocksdb::Error> for AdapterError {
+    fn from(e: rocksdb::Error) -> Self {
+        AdapterError::Db(e)
+    }
+}
+
 pub struct RocksDBBatch(pub WriteBatch);
 
 impl BatchLike for RocksDBBatch {
@@ -123,28 +322,202 @@ impl<'a> WriteBatchIterator for RocksDBBatchCloner<'a> {
 
 impl KeyValueStoreLike for RocksDBRuntimeAdapter {
     type Batch = RocksDBBatch;
-    type Error = rocksdb::Error;
+    type Error = AdapterError;
 
     fn write(&mut self, batch: RocksDBBatch) -> Result<(), Self::Error> {
         let key_bytes: Vec<u8> = TIP_HEIGHT_KEY.as_bytes().to_vec();
         let height_bytes: Vec<u8> = (self.height + 1).to_le_bytes().to_vec();
         
         let mut final_batch = WriteBatch::default();
-        final_batch.put(&to_labeled_key(&key_bytes), &height_bytes);
+        if (self.height + 1).is_multiple_of(self.commit_tip_every) {
+            final_batch.put(to_labeled_key(&key_bytes), height_bytes);
+        }
         batch.0.iterate(&mut RocksDBBatchCloner(&mut final_batch));
         
-        self.db.write(final_batch)
+        match self.wal {
+            Some(ref wal) => {
+                wal.stage(final_batch.data()).map_err(AdapterError::Wal)?;
+                let mut opts = WriteOptions::default();
+                opts.set_sync(true);
+                self.db.write_opt(final_batch, &opts)?;
+                wal.clear().map_err(AdapterError::Wal)
+            }
+            None => Ok(self.db.write(final_batch)?),
+        }
     }
 
     fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.db.get(to_labeled_key(&key.as_ref().to_vec())).map(|opt| opt.map(|v| v.to_vec()))
+        Ok(self.db.get(to_labeled_key(&key.as_ref().to_vec()))?)
     }
 
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
-        self.db.delete(to_labeled_key(&key.as_ref().to_vec()))
+        Ok(self.db.delete(to_labeled_key(&key.as_ref().to_vec()))?)
     }
 
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), Self::Error> {
-        self.db.put(to_labeled_key(&key.as_ref().to_vec()), value)
+        Ok(self.db.put(to_labeled_key(&key.as_ref().to_vec()), value)?)
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let labeled_prefix = to_labeled_key(&prefix.as_ref().to_vec());
+        let label_len = labeled_prefix.len() - prefix.as_ref().len();
+        let mut result: KeyValuePairs = vec![];
+        for item in self
+            .db
+            .iterator(IteratorMode::From(&labeled_prefix, Direction::Forward))
+        {
+            let (key, value) = item?;
+            if !key.starts_with(&labeled_prefix) {
+                break;
+            }
+            result.push((key[label_len..].to_vec(), value.to_vec()));
+        }
+        Ok(result)
+    }
+
+    fn scan_prefix_keys<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.scan_prefix_page(prefix.as_ref(), after, limit)?.keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &tempdir::TempDir) -> RocksDBRuntimeAdapter {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        RocksDBRuntimeAdapter::open(dir.path().to_string_lossy().to_string(), opts).unwrap()
+    }
+
+    fn length_key(key: &[u8]) -> Vec<u8> {
+        let mut result = key.to_vec();
+        result.extend(u32::MAX.to_le_bytes());
+        result
+    }
+
+    #[test]
+    fn delete_if_only_deletes_the_expected_value() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        adapter.put(b"key", b"value").unwrap();
+        assert!(!adapter.delete_if(b"key", b"other").unwrap());
+        assert_eq!(adapter.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(adapter.delete_if(b"key", b"value").unwrap());
+        assert_eq!(adapter.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn normalize_label_rejects_ambiguous_prefixes() {
+        assert_eq!(normalize_label("MyLabel", false).unwrap(), "MyLabel");
+        assert_eq!(normalize_label("MyLabel", true).unwrap(), "mylabel");
+        for label in ["", " padded", "padded\t", "tab\u{7}bed", "nested://label"] {
+            assert!(normalize_label(label, false).is_err(), "{:?}", label);
+        }
+    }
+
+    #[test]
+    fn unwritable_wal_fails_the_write_without_committing() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        let wal_dir = dir.path().join("wal");
+        adapter.set_wal_dir(&wal_dir).unwrap();
+        let mut batch = RocksDBBatch::default();
+        batch.put(b"key", b"value");
+        adapter.write(batch).unwrap();
+        assert_eq!(adapter.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+        std::fs::remove_dir_all(&wal_dir).unwrap();
+        let mut batch = RocksDBBatch::default();
+        batch.put(b"other", b"value");
+        assert!(matches!(adapter.write(batch), Err(AdapterError::Wal(_))));
+        assert_eq!(adapter.get(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn replay_wal_commits_a_batch_left_by_a_crash() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let wal_dir = dir.path().join("wal");
+        {
+            // The process dies after logging the batch but before committing it
+            let mut adapter = open(&dir);
+            adapter.set_wal_dir(&wal_dir).unwrap();
+            let mut batch = RocksDBBatch::default();
+            batch.put(b"key", b"value");
+            CommitLog::open(&wal_dir).unwrap().stage(batch.0.data()).unwrap();
+            assert_eq!(adapter.get(b"key").unwrap(), None);
+        }
+
+        let mut adapter = open(&dir);
+        adapter.set_wal_dir(&wal_dir).unwrap();
+        assert!(adapter.replay_wal().unwrap());
+        assert_eq!(adapter.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(adapter.wal.as_ref().unwrap().pending().unwrap(), None);
+        assert!(!adapter.replay_wal().unwrap());
+    }
+
+    #[test]
+    fn scan_prefix_page_walks_keys_by_length_entry() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let adapter = open(&dir);
+        for key in [&b"a"[..], b"ab", b"b", b"c"] {
+            adapter.db.put(length_key(key), 1u32.to_le_bytes()).unwrap();
+            adapter.db.put([key, &0u32.to_le_bytes()[..]].concat(), b"v").unwrap();
+        }
+
+        let first = adapter.scan_prefix_page(&[], None, 2).unwrap();
+        assert_eq!(first.keys, vec![b"ab".to_vec(), b"a".to_vec()]);
+        assert_eq!(first.next, Some(b"a".to_vec()));
+        let second = adapter.scan_prefix_page(&[], first.next.as_deref(), 2).unwrap();
+        assert_eq!(second.keys, vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(second.next, None);
+
+        let prefixed = adapter.scan_prefix_page(b"a", None, 10).unwrap();
+        assert_eq!(prefixed.keys, vec![b"ab".to_vec(), b"a".to_vec()]);
+        assert_eq!(prefixed.next, None);
+    }
+
+    #[test]
+    fn scan_prefix_keys_stops_at_the_limit() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        for key in [&b"ka"[..], b"kb", b"kc", b"l"] {
+            adapter.db.put(length_key(key), 1u32.to_le_bytes()).unwrap();
+        }
+        assert_eq!(adapter.scan_prefix_keys(b"k", None, 2).unwrap(), vec![b"ka".to_vec(), b"kb".to_vec()]);
+        assert_eq!(adapter.scan_prefix_keys(b"k", Some(b"kb"), 2).unwrap(), vec![b"kc".to_vec()]);
+    }
+
+    #[test]
+    fn commit_tip_every_advances_the_tip_only_every_n_blocks() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let tip = |adapter: &mut RocksDBRuntimeAdapter| {
+            let tip = adapter.get(TIP_HEIGHT_KEY.as_bytes()).unwrap();
+            tip.map(|tip| u32::from_le_bytes(tip.try_into().unwrap()))
+        };
+        {
+            let mut adapter = open(&dir);
+            adapter.set_commit_tip_every(3);
+            let mut tips = vec![];
+            for height in 0..5u32 {
+                adapter.set_height(height);
+                let mut batch = RocksDBBatch::default();
+                batch.put(height.to_le_bytes(), b"data");
+                adapter.write(batch).unwrap();
+                assert_eq!(adapter.get(height.to_le_bytes()).unwrap(), Some(b"data".to_vec()));
+                tips.push(tip(&mut adapter));
+            }
+            assert_eq!(tips, vec![None, None, Some(3), Some(3), Some(3)]);
+        }
+
+        // A restart resumes at most N-1 blocks behind the data, re-indexing 3 and 4
+        let mut adapter = open(&dir);
+        assert_eq!(tip(&mut adapter), Some(3));
+        assert_eq!(adapter.get(4u32.to_le_bytes()).unwrap(), Some(b"data".to_vec()));
     }
 }
@@ -0,0 +1,668 @@
+use crate::codec::KeyCodec;
+use crate::{to_labeled_key, TIP_HEIGHT_KEY};
+use anyhow::{anyhow, Result};
+use rocksdb::{Direction, IteratorMode, Snapshot, WriteBatch, DB};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A snapshot starts with SNAPSHOT_MAGIC and a little-endian u32 format version, followed in
+// version 1 by a flat sequence of entries, each a little-endian u32 key length, the key, a
+// little-endian u32 value length and the value. Keys are stored relative to the active label
+// so a snapshot can be restored under a different one.
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"MSSN";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+fn write_header<W: Write>(out: &mut W) -> Result<()> {
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+// Returns the format version after checking the magic
+fn read_header<R: Read>(input: &mut R) -> Result<u32> {
+    let mut header = [0u8; 8];
+    input
+        .read_exact(&mut header)
+        .map_err(|e| anyhow!("snapshot header unreadable: {}", e))?;
+    if &header[..4] != SNAPSHOT_MAGIC {
+        return Err(anyhow!(
+            "not a snapshot: expected magic {:?}, found {:02x?}",
+            std::str::from_utf8(SNAPSHOT_MAGIC).unwrap(),
+            &header[..4]
+        ));
+    }
+    Ok(u32::from_le_bytes(header[4..].try_into()?))
+}
+
+fn write_chunk<W: Write>(out: &mut W, bytes: &[u8]) -> Result<()> {
+    out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_chunk<R: Read>(input: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    match input.read_exact(&mut len_bytes) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut bytes = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+// The chain id and label ahead of every key in the active namespace
+fn label_prefix() -> Vec<u8> {
+    to_labeled_key(&vec![])
+}
+
+/// Writes every key/value pair in the active label's namespace, returning the number of pairs.
+/// Reads go through a RocksDB snapshot, so the export is consistent even while blocks commit.
+pub fn export_snapshot<W: Write>(db: &DB, out: &mut W) -> Result<u64> {
+    let prefix = label_prefix();
+    let view = db.snapshot();
+    let mut count: u64 = 0;
+    write_header(out)?;
+    for item in view.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        write_chunk(out, &key[prefix.len()..])?;
+        write_chunk(out, &value)?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Streams every key/value pair in the active label's namespace as one
+/// `{"key":"<hex>","value":"<hex>"}` line per pair, returning the number of pairs. Keys whose
+/// table is registered in `codec` also get a `"table"` field with its name.
+pub fn export_jsonl<W: Write>(db: &DB, out: &mut W, codec: &KeyCodec) -> Result<u64> {
+    let prefix = label_prefix();
+    let mut count: u64 = 0;
+    for item in db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        write_jsonl_line(out, &key[prefix.len()..], &value, codec)?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+fn write_jsonl_line<W: Write>(out: &mut W, key: &[u8], value: &[u8], codec: &KeyCodec) -> Result<()> {
+    let mut line = serde_json::json!({
+        "key": hex::encode(key),
+        "value": hex::encode(value),
+    });
+    if let Some(name) = codec.name(key) {
+        line["table"] = serde_json::Value::String(name.to_string());
+    }
+    serde_json::to_writer(&mut *out, &line)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Writes `pairs` in the `export_jsonl` format, returning the number of pairs.
+pub fn write_jsonl<W: Write>(pairs: &BTreeMap<Vec<u8>, Vec<u8>>, out: &mut W, codec: &KeyCodec) -> Result<u64> {
+    for (key, value) in pairs {
+        write_jsonl_line(out, key, value, codec)?;
+    }
+    out.flush()?;
+    Ok(pairs.len() as u64)
+}
+
+/// Reads pairs in the `export_jsonl` format. Blank lines and the `"table"` field are ignored.
+pub fn read_jsonl<R: BufRead>(input: R) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut pairs = BTreeMap::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        let field = |name: &str| -> Result<Vec<u8>> {
+            let text = parsed[name]
+                .as_str()
+                .ok_or_else(|| anyhow!("line {}: missing \"{}\"", number + 1, name))?;
+            hex::decode(text).map_err(|e| anyhow!("line {}: bad {} hex: {}", number + 1, name, e))
+        };
+        pairs.insert(field("key")?, field("value")?);
+    }
+    Ok(pairs)
+}
+
+#[cfg(feature = "parquet")]
+pub const PARQUET_ROW_GROUP_SIZE: usize = 65536;
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str =
+    "message metashrew { REQUIRED BINARY key; REQUIRED BINARY value; OPTIONAL BINARY table (UTF8); }";
+
+/// Writes every pair under `key_prefix` to a Parquet file with `key` and `value` columns,
+/// relative to the active label, plus a nullable `table` column named through `codec`.
+/// Rows are flushed in groups of `PARQUET_ROW_GROUP_SIZE` so memory stays bounded. Returns
+/// the number of rows written.
+#[cfg(feature = "parquet")]
+pub fn export_parquet<W: Write + Send>(
+    db: &DB,
+    out: W,
+    key_prefix: &[u8],
+    codec: &KeyCodec,
+) -> Result<u64> {
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let mut writer =
+        SerializedFileWriter::new(out, schema, Arc::new(WriterProperties::builder().build()))?;
+    let label = label_prefix();
+    let prefix = to_labeled_key(&key_prefix.to_vec());
+    let mut rows: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(PARQUET_ROW_GROUP_SIZE);
+    let mut count: u64 = 0;
+    for item in db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        rows.push((key[label.len()..].to_vec(), value.to_vec()));
+        if rows.len() == PARQUET_ROW_GROUP_SIZE {
+            write_row_group(&mut writer, &rows, codec)?;
+            count += rows.len() as u64;
+            rows.clear();
+        }
+    }
+    if !rows.is_empty() {
+        write_row_group(&mut writer, &rows, codec)?;
+        count += rows.len() as u64;
+    }
+    writer.close()?;
+    Ok(count)
+}
+
+#[cfg(feature = "parquet")]
+fn write_row_group<W: Write + Send>(
+    writer: &mut parquet::file::writer::SerializedFileWriter<W>,
+    rows: &[(Vec<u8>, Vec<u8>)],
+    codec: &KeyCodec,
+) -> Result<()> {
+    use parquet::data_type::{ByteArray, ByteArrayType};
+
+    let keys: Vec<ByteArray> = rows.iter().map(|(key, _)| key.clone().into()).collect();
+    let values: Vec<ByteArray> = rows.iter().map(|(_, value)| value.clone().into()).collect();
+    let names: Vec<Option<&str>> = rows.iter().map(|(key, _)| codec.name(key)).collect();
+    let tables: Vec<ByteArray> = names.iter().flatten().map(|name| (*name).into()).collect();
+    let levels: Vec<i16> = names.iter().map(|name| name.is_some() as i16).collect();
+
+    let mut group = writer.next_row_group()?;
+    let columns: [(&[ByteArray], Option<&[i16]>); 3] =
+        [(&keys, None), (&values, None), (&tables, Some(&levels))];
+    for (data, levels) in columns {
+        let mut column = group
+            .next_column()?
+            .ok_or_else(|| anyhow!("parquet schema has fewer columns than expected"))?;
+        column.typed::<ByteArrayType>().write_batch(data, levels, None)?;
+        column.close()?;
+    }
+    group.close()?;
+    Ok(())
+}
+
+/// Writes up to `limit` pairs under `key_prefix` as `<key> <hex value>` lines, rendering keys
+/// through `codec`, and returns the number written.
+pub fn dump<W: Write>(
+    db: &DB,
+    out: &mut W,
+    key_prefix: &[u8],
+    limit: Option<u64>,
+    codec: &KeyCodec,
+) -> Result<u64> {
+    let label = label_prefix();
+    let prefix = to_labeled_key(&key_prefix.to_vec());
+    let mut count: u64 = 0;
+    for item in db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        if limit.is_some_and(|limit| count >= limit) {
+            break;
+        }
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        writeln!(out, "{} {}", codec.display(&key[label.len()..]), hex::encode(&value))?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TableStats {
+    pub count: u64,
+    pub total_value_bytes: u64,
+}
+
+impl TableStats {
+    pub fn avg_value_bytes(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total_value_bytes as f64 / self.count as f64
+        }
+    }
+}
+
+/// Groups the active label's keys by their leading table-id byte, iterating the store rather
+/// than loading it. The empty key, if present, is grouped under `None`.
+pub fn table_stats(db: &DB) -> Result<BTreeMap<Option<u8>, TableStats>> {
+    let prefix = label_prefix();
+    let mut stats: BTreeMap<Option<u8>, TableStats> = BTreeMap::new();
+    for item in db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        let entry = stats.entry(key.get(prefix.len()).copied()).or_default();
+        entry.count += 1;
+        entry.total_value_bytes += value.len() as u64;
+    }
+    Ok(stats)
+}
+
+// Pairs under `label`, in key order, with the label prefix stripped
+fn label_entries<'a>(
+    view: &'a Snapshot<'a>,
+    label: &str,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+    let prefix = (label.to_string() + "://").into_bytes();
+    let strip = prefix.len();
+    view.iterator(IteratorMode::From(&prefix, Direction::Forward))
+        .map(|item| item.map_err(anyhow::Error::from))
+        .take_while(move |item| item.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)))
+        .map(move |item| item.map(|(key, value)| (key[strip..].to_vec(), value.to_vec())))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LabelDiff {
+    pub only_a: u64,
+    pub only_b: u64,
+    pub changed: u64,
+    pub same: u64,
+}
+
+impl LabelDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_a == 0 && self.only_b == 0 && self.changed == 0
+    }
+}
+
+/// Compares the keyspaces of labels `a` and `b` by walking both in key order through one
+/// RocksDB snapshot, so neither is loaded into memory. With `detail`, every differing key is
+/// written there as `- <key> <a value>`, `+ <key> <b value>` or `~ <key> <a value> <b value>`,
+/// with keys rendered through `codec`.
+pub fn diff_labels<W: Write>(
+    db: &DB,
+    a: &str,
+    b: &str,
+    detail: Option<&mut W>,
+    codec: &KeyCodec,
+) -> Result<LabelDiff> {
+    let view = db.snapshot();
+    diff_entries(label_entries(&view, a), label_entries(&view, b), detail, codec)
+}
+
+/// Compares the pairs a block wrote against a golden set, reporting them as `diff_labels`
+/// does with the golden set on the `-` side.
+pub fn diff_pairs<W: Write>(
+    expected: &BTreeMap<Vec<u8>, Vec<u8>>,
+    actual: &BTreeMap<Vec<u8>, Vec<u8>>,
+    detail: Option<&mut W>,
+    codec: &KeyCodec,
+) -> Result<LabelDiff> {
+    let entries = |pairs: &BTreeMap<Vec<u8>, Vec<u8>>| {
+        pairs.clone().into_iter().map(Ok).collect::<Vec<Result<_>>>().into_iter()
+    };
+    diff_entries(entries(expected), entries(actual), detail, codec)
+}
+
+// Walks two key-ordered entry streams side by side
+fn diff_entries<W: Write>(
+    mut left_entries: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    mut right_entries: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    mut detail: Option<&mut W>,
+    codec: &KeyCodec,
+) -> Result<LabelDiff> {
+    let mut left = left_entries.next().transpose()?;
+    let mut right = right_entries.next().transpose()?;
+    let mut diff = LabelDiff::default();
+    loop {
+        let order = match (&left, &right) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((left_key, _)), Some((right_key, _))) => left_key.cmp(right_key),
+        };
+        match order {
+            Ordering::Less => {
+                let (key, value) = left.take().unwrap();
+                diff.only_a += 1;
+                if let Some(out) = detail.as_mut() {
+                    writeln!(out, "- {} {}", codec.display(&key), hex::encode(&value))?;
+                }
+                left = left_entries.next().transpose()?;
+            }
+            Ordering::Greater => {
+                let (key, value) = right.take().unwrap();
+                diff.only_b += 1;
+                if let Some(out) = detail.as_mut() {
+                    writeln!(out, "+ {} {}", codec.display(&key), hex::encode(&value))?;
+                }
+                right = right_entries.next().transpose()?;
+            }
+            Ordering::Equal => {
+                let (key, left_value) = left.take().unwrap();
+                let (_, right_value) = right.take().unwrap();
+                if left_value == right_value {
+                    diff.same += 1;
+                } else {
+                    diff.changed += 1;
+                    if let Some(out) = detail.as_mut() {
+                        writeln!(
+                            out,
+                            "~ {} {} {}",
+                            codec.display(&key),
+                            hex::encode(&left_value),
+                            hex::encode(&right_value)
+                        )?;
+                    }
+                }
+                left = left_entries.next().transpose()?;
+                right = right_entries.next().transpose()?;
+            }
+        }
+    }
+    if let Some(out) = detail {
+        out.flush()?;
+    }
+    Ok(diff)
+}
+
+/// Number of pairs `import_snapshot` applies per RocksDB write.
+pub const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Applies a snapshot to the active label's namespace, returning the number of pairs restored.
+///
+/// The file is read incrementally and written in batches of `IMPORT_BATCH_SIZE`, so memory use
+/// does not grow with the snapshot. The tip height is held back and written last, so an
+/// interrupted import still leaves `is_empty` true and can simply be rerun. The header is
+/// checked first, so a file that is not a snapshot or has an unknown version writes nothing.
+pub fn import_snapshot<R: Read>(db: &DB, input: &mut R) -> Result<u64> {
+    match read_header(input)? {
+        1 => import_entries_v1(input, |batch| Ok(db.write(batch)?)),
+        version => Err(anyhow!(
+            "snapshot format version {} is not supported (this build reads up to {})",
+            version,
+            SNAPSHOT_VERSION
+        )),
+    }
+}
+
+// Hands the pairs to `apply` in batches of at most IMPORT_BATCH_SIZE
+fn import_entries_v1<R: Read>(
+    input: &mut R,
+    mut apply: impl FnMut(WriteBatch) -> Result<()>,
+) -> Result<u64> {
+    let mut batch = WriteBatch::default();
+    let mut count: u64 = 0;
+    let mut tip: Option<Vec<u8>> = None;
+    while let Some(key) = read_chunk(input)? {
+        let value = read_chunk(input)?
+            .ok_or_else(|| anyhow!("snapshot truncated after {} entries", count))?;
+        if key == TIP_HEIGHT_KEY.as_bytes() {
+            tip = Some(value);
+        } else {
+            batch.put(to_labeled_key(&key), value);
+        }
+        count += 1;
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            apply(std::mem::take(&mut batch))?;
+        }
+    }
+    if let Some(value) = tip {
+        batch.put(to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec()), value);
+    }
+    apply(batch)?;
+    Ok(count)
+}
+
+/// Returns true if the store has not yet recorded a tip height for the active label.
+pub fn is_empty(db: &DB) -> Result<bool> {
+    Ok(db
+        .get(to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec()))?
+        .is_none())
+}
+
+/// Exports a snapshot into `dir` as `snapshot-<height>-<unix seconds>.bin`, then deletes all but
+/// the `keep` most recent snapshots there. Returns the path written.
+pub fn dump_snapshot(db: &DB, dir: &Path, height: u32, keep: usize) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let name = format!("snapshot-{:010}-{}.bin", height, timestamp);
+    let path = dir.join(&name);
+    // Write under a temporary name so a crash never leaves a truncated file that looks complete
+    let partial = dir.join(format!(".{}.partial", name));
+    {
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        export_snapshot(db, &mut writer)?;
+    }
+    std::fs::rename(&partial, &path)?;
+
+    let mut existing: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("snapshot-") && name.ends_with(".bin"))
+        })
+        .collect();
+    existing.sort();
+    let excess = existing.len().saturating_sub(std::cmp::max(keep, 1));
+    for old in &existing[..excess] {
+        std::fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RocksDBRuntimeAdapter;
+    use metashrew_runtime::KeyValueStoreLike;
+    use rocksdb::Options;
+
+    fn open(dir: &tempdir::TempDir) -> RocksDBRuntimeAdapter {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        RocksDBRuntimeAdapter::open(dir.path().to_string_lossy().to_string(), opts).unwrap()
+    }
+
+    #[test]
+    fn jsonl_export_decodes_back_to_the_stored_pairs() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        let mut pairs = BTreeMap::new();
+        for (key, value) in [(&b"\x01a"[..], &b"1"[..]), (b"\x02b", b""), (b"plain", b"\xff\x00")] {
+            adapter.put(key, value).unwrap();
+            pairs.insert(key.to_vec(), value.to_vec());
+        }
+        let mut codec = KeyCodec::new();
+        codec.register(1, "balances".to_string());
+
+        let mut out = vec![];
+        assert_eq!(export_jsonl(&adapter.db, &mut out, &codec).unwrap(), 3);
+        let first: serde_json::Value =
+            serde_json::from_slice(out.split(|byte| *byte == b'\n').next().unwrap()).unwrap();
+        assert_eq!(first["table"], "balances");
+        assert_eq!(read_jsonl(&out[..]).unwrap(), pairs);
+    }
+
+    #[test]
+    fn table_stats_group_keys_by_their_leading_byte() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        for (key, value) in [(&b"\x01a"[..], &b"12"[..]), (b"\x01b", b"3456"), (b"\x02a", b"")] {
+            adapter.put(key, value).unwrap();
+        }
+        adapter.put(b"", b"x").unwrap();
+
+        let stats = table_stats(&adapter.db).unwrap();
+        let summary: Vec<(Option<u8>, u64, u64)> = stats
+            .iter()
+            .map(|(table, stats)| (*table, stats.count, stats.total_value_bytes))
+            .collect();
+        assert_eq!(summary, vec![(None, 1, 1), (Some(1), 2, 6), (Some(2), 1, 0)]);
+        assert_eq!(stats[&Some(1)].avg_value_bytes(), 3.0);
+        assert_eq!(stats[&Some(2)].avg_value_bytes(), 0.0);
+    }
+
+    #[test]
+    fn diff_labels_enumerates_exactly_the_differing_keys() {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let adapter = open(&dir);
+        for (key, value) in [
+            (&b"a://same"[..], &b"1"[..]),
+            (b"a://changed", b"2"),
+            (b"a://gone", b"3"),
+            (b"b://same", b"1"),
+            (b"b://changed", b"4"),
+            (b"b://new", b"5"),
+            // Neither label's keyspace, though it shares a prefix with one
+            (b"ab://gone", b"6"),
+        ] {
+            adapter.db.put(key, value).unwrap();
+        }
+
+        let mut detail = vec![];
+        let diff = diff_labels(&adapter.db, "a", "b", Some(&mut detail), &KeyCodec::new()).unwrap();
+        assert_eq!((diff.only_a, diff.only_b, diff.changed, diff.same), (1, 1, 1, 1));
+        let lines: Vec<char> = String::from_utf8(detail)
+            .unwrap()
+            .lines()
+            .map(|line| line.chars().next().unwrap())
+            .collect();
+        assert_eq!(lines, vec!['~', '-', '+']);
+        let same = diff_labels::<Vec<u8>>(&adapter.db, "a", "a", None, &KeyCodec::new()).unwrap();
+        assert!(same.is_empty());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_export_reads_back_the_table_prefix() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::RowAccessor;
+
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        for (key, value) in [(&b"\x01a"[..], &b"1"[..]), (b"\x01b", b"22"), (b"\x02a", b"3")] {
+            adapter.put(key, value).unwrap();
+        }
+        let mut codec = KeyCodec::new();
+        codec.register(1, "balances".to_string());
+        let path = dir.path().join("balances.parquet");
+        let rows = export_parquet(&adapter.db, File::create(&path).unwrap(), b"\x01", &codec).unwrap();
+        assert_eq!(rows, 2);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let read: Vec<(Vec<u8>, Vec<u8>, String)> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                (
+                    row.get_bytes(0).unwrap().data().to_vec(),
+                    row.get_bytes(1).unwrap().data().to_vec(),
+                    row.get_string(2).unwrap().clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            read,
+            vec![
+                (b"\x01a".to_vec(), b"1".to_vec(), "balances".to_string()),
+                (b"\x01b".to_vec(), b"22".to_vec(), "balances".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_check_the_magic_and_version_first() {
+        let source_dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut source = open(&source_dir);
+        source.put(b"key", b"value").unwrap();
+        let mut exported = vec![];
+        export_snapshot(&source.db, &mut exported).unwrap();
+        assert_eq!(&exported[..8], b"MSSN\x01\x00\x00\x00");
+
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut adapter = open(&dir);
+        let mut unknown = exported.clone();
+        unknown[4] = 2;
+        assert_eq!(
+            import_snapshot(&adapter.db, &mut &unknown[..]).unwrap_err().to_string(),
+            "snapshot format version 2 is not supported (this build reads up to 1)"
+        );
+        let mut tampered = exported.clone();
+        tampered[0] = b'X';
+        let error = import_snapshot(&adapter.db, &mut &tampered[..]).unwrap_err().to_string();
+        assert!(error.starts_with("not a snapshot"), "{}", error);
+        assert!(import_snapshot(&adapter.db, &mut &exported[..3]).is_err());
+        assert_eq!(adapter.get(b"key").unwrap(), None);
+
+        assert_eq!(import_snapshot(&adapter.db, &mut &exported[..]).unwrap(), 1);
+        assert_eq!(adapter.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn large_imports_apply_in_bounded_batches_with_the_tip_last() {
+        let source_dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut source = open(&source_dir);
+        let pairs = 2 * IMPORT_BATCH_SIZE + 500;
+        for index in 0..pairs as u32 {
+            source.put(index.to_be_bytes(), index.to_le_bytes()).unwrap();
+        }
+        source.write_tip_height(42).unwrap();
+        let mut exported = vec![];
+        assert_eq!(export_snapshot(&source.db, &mut exported).unwrap(), pairs as u64 + 1);
+
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let adapter = open(&dir);
+        let mut input = &exported[8..];
+        let mut sizes = vec![];
+        let tip_key = to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec());
+        let count = import_entries_v1(&mut input, |batch| {
+            // The tip only lands once every other pair has been written
+            assert_eq!(adapter.db.get(&tip_key).unwrap(), None);
+            sizes.push(batch.len());
+            Ok(adapter.db.write(batch)?)
+        })
+        .unwrap();
+        assert_eq!(count, pairs as u64 + 1);
+        assert_eq!(sizes, vec![IMPORT_BATCH_SIZE, IMPORT_BATCH_SIZE, 501]);
+        let mut restored = vec![];
+        export_snapshot(&adapter.db, &mut restored).unwrap();
+        assert!(restored == exported);
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// The log holds at most one commit: the serialized WriteBatch, tip included, of the block being
+// written. It is written under a temporary name and renamed into place, so COMMIT_LOG either
+// does not exist or holds a whole batch.
+
+pub const COMMIT_LOG: &str = "commit.wal";
+
+/// A single-entry write-ahead log in a directory of its own.
+#[derive(Clone)]
+pub struct CommitLog {
+    pub dir: PathBuf,
+}
+
+impl CommitLog {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(COMMIT_LOG)
+    }
+
+    /// Durably records `data` as the pending commit, replacing any earlier one.
+    pub fn stage(&self, data: &[u8]) -> std::io::Result<()> {
+        let staging = self.dir.join(String::from(COMMIT_LOG) + ".tmp");
+        let mut file = File::create(&staging)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&staging, self.path())?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Drops the pending commit once the store has it.
+    pub fn clear(&self) -> std::io::Result<()> {
+        match fs::remove_file(self.path()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Returns the commit left behind by a write that did not finish, if any.
+    pub fn pending(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path()) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
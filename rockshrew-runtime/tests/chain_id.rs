@@ -0,0 +1,76 @@
+// The chain id is process-wide, so this runs in its own test binary rather than alongside the
+// library's tests, which would see their keys move under it.
+
+use metashrew_runtime::{db_make_length_key, KeyValueStoreLike, MetashrewRuntime};
+use rockshrew_runtime::{set_chain_id, RocksDBRuntimeAdapter, TIP_HEIGHT_KEY};
+use rocksdb::{IteratorMode, Options};
+
+// Flushes ["k", <first byte of the block>]
+const FIRST_BYTE: &str = r#"(module
+  (import "env" "__load_input" (func $load (param i32)))
+  (import "env" "__flush" (func $flush (param i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01\00")
+  (func (export "_start")
+    (call $load (i32.const 1000))
+    (i32.store8 (i32.const 105) (i32.load8_u (i32.const 1004)))
+    (call $flush (i32.const 100))))"#;
+
+const HEIGHT_TO_HASH: &str = "/__INTERNAL/height-to-hash/";
+
+#[test]
+fn chain_ids_isolate_every_keyspace_in_a_shared_store() {
+    let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    let adapter =
+        RocksDBRuntimeAdapter::open(dir.path().join("db").to_string_lossy().to_string(), opts)
+            .unwrap();
+    let indexer = dir.path().join("indexer.wat");
+    std::fs::write(&indexer, FIRST_BYTE).unwrap();
+    let mut runtimes = [
+        MetashrewRuntime::load(indexer.clone(), adapter.clone()).unwrap(),
+        MetashrewRuntime::load(indexer, adapter.clone()).unwrap(),
+    ];
+
+    // The two chains take turns on the same heights, with different blocks and hashes; chain 1
+    // gets one block further
+    for height in 0..3u32 {
+        for (chain, runtime) in runtimes.iter_mut().enumerate() {
+            let chain_id = chain as u8 + 1;
+            if chain_id == 2 && height == 2 {
+                continue;
+            }
+            set_chain_id(chain_id);
+            let mut context = runtime.context.lock().unwrap();
+            context.db.set_height(height);
+            context.db.put(format!("{}{}", HEIGHT_TO_HASH, height), [chain_id; 32]).unwrap();
+            context.begin_block(height, vec![chain_id * 10 + height as u8; 80]);
+            drop(context);
+            runtime.execute().unwrap();
+        }
+    }
+
+    for (chain_id, blocks) in [(1u8, 3u32), (2, 2)] {
+        set_chain_id(chain_id);
+        let mut db = adapter.clone();
+        assert_eq!(db.get(TIP_HEIGHT_KEY).unwrap(), Some(blocks.to_le_bytes().to_vec()));
+        for height in 0..blocks {
+            let hash = db.get(format!("{}{}", HEIGHT_TO_HASH, height)).unwrap();
+            assert_eq!(hash, Some(vec![chain_id; 32]));
+        }
+        let length = db.get(db_make_length_key(&b"k".to_vec()).unwrap()).unwrap().unwrap();
+        assert_eq!(u32::from_le_bytes(length[..4].try_into().unwrap()), blocks);
+    }
+
+    // Nothing, internal keys included, was written outside the two chains' prefixes
+    let mut chains = std::collections::BTreeSet::new();
+    for item in adapter.db.iterator(IteratorMode::Start) {
+        let (key, _) = item.unwrap();
+        assert!(key[0] == 1 || key[0] == 2, "{:?} has no chain id", key);
+        if key[1..] == *TIP_HEIGHT_KEY.as_bytes() {
+            chains.insert(key[0]);
+        }
+    }
+    assert_eq!(chains.into_iter().collect::<Vec<u8>>(), vec![1, 2]);
+}
@@ -6,8 +6,11 @@ use anyhow;
 use clap::{Parser};
 use lazy_static::lazy_static;
 use log::{debug, info};
-use rockshrew_runtime::{query_height, set_label, RocksDBRuntimeAdapter};
-use metashrew_runtime::MetashrewRuntime;
+use rockshrew_runtime::{
+    get_chain_id, get_label, has_label, normalize_label, query_height, set_chain_id, set_label,
+    to_labeled_key, RocksDBRuntimeAdapter,
+};
+use metashrew_runtime::{MetashrewRuntime, ReadOnlyAdapter};
 use rocksdb::Options;
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -70,6 +73,14 @@ struct RockshrewViewArgs {
     #[arg(long, env = "ROCKS_LABEL")]
     label: Option<String>,
 
+    /// Lowercase the label so differently-cased spellings share a namespace
+    #[arg(long, env = "ROCKS_LABEL_LOWERCASE")]
+    lowercase_label: bool,
+
+    /// Chain id byte the indexer prefixed every key with, ahead of any label
+    #[arg(long, env = "ROCKS_CHAIN_ID")]
+    chain_id: Option<u8>,
+
     /// Path to the primary RocksDB database directory
     #[arg(long, env = "ROCKS_DB_PATH", default_value = "rocksdb_data")]
     db_path: String,
@@ -125,7 +136,8 @@ struct Context {
     hash: [u8; 32],
     #[allow(dead_code)]
     program: Vec<u8>,
-    runtime: MetashrewRuntime<RocksDBRuntimeAdapter>,
+    // Views are served through a read-only wrapper so no request can mutate the store
+    runtime: MetashrewRuntime<ReadOnlyAdapter<RocksDBRuntimeAdapter>>,
 }
 
 static mut _HEIGHT: u32 = 0;
@@ -156,7 +168,7 @@ async fn jsonrpc_call(
     debug!("{}", serde_json::to_string(&body).unwrap());
 
     // Ensure we're caught up with primary before processing request
-    if let Err(e) = synchronized_catch_up(&context.runtime.context.lock().unwrap().db.db).await {
+    if let Err(e) = synchronized_catch_up(&context.runtime.context.lock().unwrap().db.inner.db).await {
         log::warn!("Failed to catch up with primary before request: {}", e);
         // Continue processing despite catch-up failure
     }
@@ -209,12 +221,12 @@ async fn jsonrpc_call(
 
         let height: u32 = match &body.params[2] {
             serde_json::Value::String(s) if s == "latest" => {
-                fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
             }
             serde_json::Value::Number(n) => {
                 let h = n.as_u64().unwrap_or(0) as u32;
                 if h > height() {
-                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
                 } else {
                     h
                 }
@@ -222,7 +234,7 @@ async fn jsonrpc_call(
             serde_json::Value::String(s) => match s.parse::<u32>() {
                 Ok(h) => {
                     if h > height() {
-                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
                     } else {
                         h
                     }
@@ -283,13 +295,29 @@ async fn jsonrpc_call(
             }
         }
     } else if body.method == "metashrew_height" {
-        let height = fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?;
+        let height = fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?;
         let result = JsonRpcResult {
             id: body.id,
             result: height.to_string(),
             jsonrpc: "2.0".to_string(),
         };
         Ok(HttpResponse::Ok().json(result))
+    } else if body.method == "metashrew_info" {
+        let db = context.runtime.context.lock().unwrap().db.inner.clone();
+        let height = fetch_and_set_height(&db).await?;
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "id": body.id,
+            "result": {
+                "label": has_label().then(|| get_label().trim_end_matches("://")),
+                "chain_id": get_chain_id(),
+                "internal_prefix": "/__INTERNAL/",
+                "key_prefix": format!("0x{}", hex::encode(to_labeled_key(&vec![]))),
+                "indexer_hash": format!("0x{}", hex::encode(context.runtime.indexer_hash)),
+                "version": env!("CARGO_PKG_VERSION"),
+                "height": height,
+            },
+            "jsonrpc": "2.0",
+        })))
     } else if body.method == "metashrew_preview" {
         // Ensure we have required params
         if body.params.len() < 4 {
@@ -356,12 +384,12 @@ async fn jsonrpc_call(
 
         let height: u32 = match &body.params[3] {
             serde_json::Value::String(s) if s == "latest" => {
-                fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
             }
             serde_json::Value::Number(n) => {
                 let h = n.as_u64().unwrap_or(0) as u32;
                 if h > height() {
-                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
                 } else {
                     h
                 }
@@ -369,7 +397,7 @@ async fn jsonrpc_call(
             serde_json::Value::String(s) => match s.parse::<u32>() {
                 Ok(h) => {
                     if h > height() {
-                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
+                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db.inner).await?
                     } else {
                         h
                     }
@@ -468,8 +496,11 @@ async fn main() -> std::io::Result<()> {
     // Parse command line arguments (falls back to env vars via #[arg(env)])
     let args = RockshrewViewArgs::parse();
 
-    if let Some(label) = args.label {
-        set_label(label);
+    if let Some(chain_id) = args.chain_id {
+        set_chain_id(chain_id);
+    }
+    if let Some(ref label) = args.label {
+        set_label(normalize_label(label, args.lowercase_label).expect("Invalid label"));
     }
 
     let program = File::open(&args.indexer).expect("Failed to open program file");
@@ -551,12 +582,14 @@ async fn main() -> std::io::Result<()> {
                 program: bytes.clone(),
                 runtime: MetashrewRuntime::load(
                     args.indexer.clone(),
-                    RocksDBRuntimeAdapter::open_secondary(
-                        args.db_path.clone(),
-                        args.secondary_path.clone(),
-                        opts.clone(),
-                    )
-                    .unwrap(),
+                    ReadOnlyAdapter::new(
+                        RocksDBRuntimeAdapter::open_secondary(
+                            args.db_path.clone(),
+                            args.secondary_path.clone(),
+                            opts.clone(),
+                        )
+                        .unwrap(),
+                    ),
                 )
                 .unwrap(),
             }))
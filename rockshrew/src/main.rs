@@ -4,11 +4,12 @@ use env_logger;
 use hex;
 use itertools::Itertools;
 use log::debug;
-use rockshrew_runtime::{query_height, set_label, RocksDBRuntimeAdapter};
+use rockshrew_runtime::{normalize_label, query_height, set_chain_id, set_label, RocksDBRuntimeAdapter};
 use metashrew_runtime::KeyValueStoreLike;
 use metashrew_runtime::MetashrewRuntime;
 use rocksdb::{Options};
 use reqwest::{Response, Url};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::{Number, Value};
@@ -33,8 +34,26 @@ struct Args {
     auth: Option<String>,
     #[arg(long)]
     label: Option<String>,
+    /// Lowercase --label so differently-cased spellings share a namespace
+    #[arg(long)]
+    lowercase_label: bool,
+    /// Prefix every key, internal ones included, with this chain id byte ahead of any label
+    #[arg(long)]
+    chain_id: Option<u8>,
     #[arg(long)]
     exit_at: Option<u32>,
+    /// Fail on any node response that has unknown fields or a result of the wrong type
+    #[arg(long)]
+    strict_json: bool,
+    /// JSON-RPC method that returns the node's block count, for nodes that rename it
+    #[arg(long, default_value = "getblockcount")]
+    rpc_method_getblockcount: String,
+    /// JSON-RPC method that returns the hash of the block at a height
+    #[arg(long, default_value = "getblockhash")]
+    rpc_method_getblockhash: String,
+    /// JSON-RPC method that returns a raw block, given its hash and verbosity 0
+    #[arg(long, default_value = "getblock")]
+    rpc_method_getblock: String,
 }
 
 const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
@@ -63,6 +82,39 @@ pub struct BlockCountResponse {
     pub error: Option<Value>,
 }
 
+// The shape --strict-json holds responses to: nothing but these fields, and a result of the
+// type the method returns. `jsonrpc` is echoed by nodes answering JSON-RPC 2.0 requests.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictJsonRpcResponse<T> {
+    pub id: u32,
+    pub jsonrpc: Option<String>,
+    pub result: Option<T>,
+    pub error: Option<Value>,
+}
+
+fn strict_result<T: DeserializeOwned>(method: &str, body: &[u8]) -> Result<T> {
+    let response: StrictJsonRpcResponse<T> = serde_json::from_slice(body)
+        .map_err(|e| anyhow!("unexpected {} response from node: {}", method, e))?;
+    if let Some(error) = response.error {
+        return Err(anyhow!("{} failed on node: {}", method, error));
+    }
+    response
+        .result
+        .ok_or_else(|| anyhow!("missing result from {} response", method))
+}
+
+// Hex results must decode, and a block hash must be 32 bytes
+fn check_hex_result(method: &str, result: &str) -> Result<()> {
+    if !result.len().is_multiple_of(2) || !result.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("{} returned a non-hex result", method));
+    }
+    if method == "getblockhash" && result.len() != 64 {
+        return Err(anyhow!("getblockhash returned {} hex digits, not 64", result.len()));
+    }
+    Ok(())
+}
+
 pub struct MetashrewRocksDBSync {
     runtime: MetashrewRuntime<RocksDBRuntimeAdapter>,
     args: Args,
@@ -115,6 +167,30 @@ impl MetashrewRocksDBSync {
         Ok(response.unwrap())
     }
 
+    async fn count_result(&self, response: Response) -> Result<u32> {
+        if self.args.strict_json {
+            return strict_result("getblockcount", &response.bytes().await?);
+        }
+        response
+            .json::<BlockCountResponse>()
+            .await?
+            .result
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))
+    }
+
+    async fn hex_result(&self, method: &str, response: Response) -> Result<String> {
+        if self.args.strict_json {
+            let result: String = strict_result(method, &response.bytes().await?)?;
+            check_hex_result(method, &result)?;
+            return Ok(result);
+        }
+        response
+            .json::<JsonRpcResponse>()
+            .await?
+            .result
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))
+    }
+
     async fn fetch_blockcount(&self) -> Result<u32> {
         let response = self
             .post(serde_json::to_string(
@@ -124,18 +200,13 @@ impl MetashrewRocksDBSync {
                         .as_secs()
                         .try_into()?,
                     jsonrpc: String::from("2.0"),
-                    method: String::from("getblockcount"),
+                    method: self.args.rpc_method_getblockcount.clone(),
                     params: vec![],
                 }),
             )?)
             .await?;
 
-        Ok(response
-            .json::<BlockCountResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?)
+        self.count_result(response).await
     }
 
     pub async fn poll_connection<'a>(&'a self) -> Arc<rocksdb::DB> {
@@ -156,17 +227,12 @@ impl MetashrewRocksDBSync {
                         .as_secs()
                         .try_into()?,
                     jsonrpc: String::from("2.0"),
-                    method: String::from("getblockcount"),
+                    method: self.args.rpc_method_getblockcount.clone(),
                     params: vec![],
                 }),
             )?)
             .await?;
-        let tip = response
-            .json::<BlockCountResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?;
+        let tip = self.count_result(response).await?;
         if best >= tip - std::cmp::min(6, tip) {
             loop {
                 if best == 0 {
@@ -234,17 +300,12 @@ impl MetashrewRocksDBSync {
                         .as_secs()
                         .try_into()?,
                     jsonrpc: String::from("2.0"),
-                    method: String::from("getblockhash"),
+                    method: self.args.rpc_method_getblockhash.clone(),
                     params: vec![block_number],
                 }),
             )?)
             .await?;
-        let blockhash = response
-            .json::<JsonRpcResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?;
+        let blockhash = self.hex_result("getblockhash", response).await?;
         Ok(hex::decode(&blockhash)?)
     }
 
@@ -293,28 +354,23 @@ impl MetashrewRocksDBSync {
             &blockhash,
         )
         .unwrap();
-        Ok(hex::decode(
-            self.post(serde_json::to_string(
+        let response = self
+            .post(serde_json::to_string(
                 &(JsonRpcRequest::<Value> {
                     id: (<u64 as TryInto<i32>>::try_into(
                         SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
                     )? + 1)
                         .try_into()?,
                     jsonrpc: String::from("2.0"),
-                    method: String::from("getblock"),
+                    method: self.args.rpc_method_getblock.clone(),
                     params: vec![
                         Value::String(hex::encode(&blockhash)),
                         Value::Number(Number::from(0)),
                     ],
                 }),
             )?)
-            .await?
-            .json::<JsonRpcResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?,
-        )?)
+            .await?;
+        Ok(hex::decode(self.hex_result("getblock", response).await?)?)
     }
 
     async fn run(&mut self) -> Result<()> {
@@ -332,14 +388,24 @@ impl MetashrewRocksDBSync {
                 Ok(v) => v,
                 Err(_) => i,
             };
-            self.runtime.context.lock().unwrap().block = self.pull_block(best).await.unwrap();
-            self.runtime.context.lock().unwrap().height = best;
+            let block = self.pull_block(best).await.unwrap();
+            self.runtime.context.lock().unwrap().begin_block(best, block);
             self.runtime.context.lock().unwrap().db.set_height(best);
-            if let Err(_) = self.runtime.run() {
-                debug!("respawn cache");
-                self.runtime.refresh_memory()?;
+            if let Err(e) = self.runtime.run() {
+                if e.is_deterministic() {
+                    return Err(anyhow!("block {} failed with {}", best, e));
+                }
+                log::warn!("block {} failed with {}, respawning cache", best, e);
+                self.runtime.refresh_memory().map_err(|r| {
+                    anyhow!(
+                        "block {} failed with {} and the instance could not be recreated: {:#}",
+                        best,
+                        e,
+                        r
+                    )
+                })?;
                 if let Err(e) = self.runtime.run() {
-                    panic!("runtime run failed after retry: {}", e);
+                    return Err(anyhow!("block {} failed again after a retry with {}", best, e));
                 }
             }
             i = i + 1;
@@ -355,8 +421,11 @@ impl MetashrewRocksDBSync {
 async fn main() {
     env_logger::init();
     let args = Args::parse();
+    if let Some(chain_id) = args.chain_id {
+        set_chain_id(chain_id);
+    }
     if let Some(ref label) = args.label {
-        set_label(label.clone());
+        set_label(normalize_label(label, args.lowercase_label).unwrap());
     }
     let start_block = args.start_block.unwrap_or_else(|| 0);
     let indexer: PathBuf = args.indexer.clone().into();
@@ -391,3 +460,31 @@ async fn main() {
     };
     sync.run().await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_json_rejects_what_lenient_parsing_tolerates() {
+        let extra_field = br#"{"id":1,"result":5,"error":null,"extra":1}"#;
+        assert!(strict_result::<u32>("getblockcount", extra_field).is_err());
+        let lenient: BlockCountResponse = serde_json::from_slice(extra_field).unwrap();
+        assert_eq!(lenient.result, Some(5));
+
+        let wrong_type = br#"{"id":1,"result":"5","error":null}"#;
+        assert!(strict_result::<u32>("getblockcount", wrong_type).is_err());
+        let echoed = br#"{"id":1,"jsonrpc":"2.0","result":5,"error":null}"#;
+        assert_eq!(strict_result::<u32>("getblockcount", echoed).unwrap(), 5);
+
+        let non_hex = br#"{"id":1,"result":"zz","error":null}"#;
+        let result: String = strict_result("getblock", non_hex).unwrap();
+        assert!(check_hex_result("getblock", &result).is_err());
+        let lenient: JsonRpcResponse = serde_json::from_slice(non_hex).unwrap();
+        assert_eq!(lenient.result.as_deref(), Some("zz"));
+
+        assert!(check_hex_result("getblockhash", "abcd").is_err());
+        assert!(check_hex_result("getblockhash", &"ab".repeat(32)).is_ok());
+        assert!(check_hex_result("getblock", "abcd").is_ok());
+    }
+}
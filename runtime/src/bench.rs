@@ -0,0 +1,147 @@
+use crate::runtime::{
+    db_annotate_value, db_make_length_key, BatchLike, KeyValueStoreLike, MetashrewRuntime,
+    MetashrewRuntimeContext,
+};
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shape of the synthetic workload driven by `run_write_bench`.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub blocks: u32,
+    pub writes_per_block: u32,
+    pub value_size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub blocks: u32,
+    pub writes: u64,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl BenchReport {
+    pub fn blocks_per_sec(&self) -> f64 {
+        self.blocks as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn writes_per_sec(&self) -> f64 {
+        self.writes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(((sorted.len() - 1) as f64) * p).round() as usize]
+}
+
+/// Commits `config.blocks` synthetic blocks to `store` through the same append path `__flush`
+/// uses, timing each block's commit. Half of each block's writes update keys from the previous
+/// block so the value lists grow the way they do under a real indexer.
+pub fn run_write_bench<T>(store: T, config: &BenchConfig) -> Result<BenchReport>
+where
+    T: KeyValueStoreLike + Clone + Sync + Send + 'static,
+{
+    let context = Arc::new(Mutex::new(MetashrewRuntimeContext::new(store, 0, vec![])));
+    let value = vec![0xab; config.value_size];
+    let mut latencies: Vec<Duration> = Vec::with_capacity(config.blocks as usize);
+    let started = Instant::now();
+
+    for height in 0..config.blocks {
+        let block_started = Instant::now();
+        let mut batch = T::Batch::default();
+        MetashrewRuntime::<T>::db_create_empty_update_list(&mut batch, height)?;
+        let update_key = height.to_le_bytes().to_vec();
+        // Lengths bumped so far in this block's batch, as __flush tracks them
+        let mut pending: HashMap<Vec<u8>, u32> = HashMap::new();
+        pending.insert(db_make_length_key(&update_key)?, 0);
+        for i in 0..config.writes_per_block {
+            // Even writes update the key the previous block wrote at the next, odd index
+            let key = if i % 2 == 0 && height > 0 {
+                format!("/bench/{}/{}", height - 1, i + 1)
+            } else {
+                format!("/bench/{}/{}", height, i)
+            }
+            .into_bytes();
+            MetashrewRuntime::<T>::db_append_pending(
+                context.clone(),
+                &mut batch,
+                &mut pending,
+                &key,
+                &db_annotate_value(&value, height)?,
+            )?;
+            MetashrewRuntime::<T>::db_append_pending(
+                context.clone(),
+                &mut batch,
+                &mut pending,
+                &update_key,
+                &key,
+            )?;
+        }
+        {
+            let mut guard = context.lock().map_err(|e| anyhow!("Mutex lock error: {}", e))?;
+            guard.height = height;
+            guard
+                .db
+                .write(batch)
+                .map_err(|e| anyhow!("Database error: {:?}", e))?;
+        }
+        latencies.push(block_started.elapsed());
+    }
+
+    let elapsed = started.elapsed();
+    latencies.sort();
+    Ok(BenchReport {
+        blocks: config.blocks,
+        writes: config.blocks as u64 * config.writes_per_block as u64,
+        elapsed,
+        p50: percentile(&latencies, 0.50),
+        p99: percentile(&latencies, 0.99),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+
+    #[test]
+    fn write_bench_commits_every_block() {
+        let mut store = MemStoreAdapter::new();
+        let config = BenchConfig {
+            blocks: 10,
+            writes_per_block: 4,
+            value_size: 8,
+        };
+        let report = run_write_bench(store.clone(), &config).unwrap();
+        assert_eq!((report.blocks, report.writes), (10, 40));
+        assert!(report.p50 <= report.p99 && report.p99 <= report.elapsed);
+        assert!(report.blocks_per_sec() > 0.0 && report.writes_per_sec() > 0.0);
+
+        let length = |store: &mut MemStoreAdapter, key: &str| {
+            store.get(db_make_length_key(&key.as_bytes().to_vec()).unwrap()).unwrap()
+        };
+        // Block 9's even writes gave block 8's odd keys a second version
+        assert_eq!(length(&mut store, "/bench/8/1"), Some(2u32.to_le_bytes().to_vec()));
+        assert_eq!(length(&mut store, "/bench/8/3"), Some(2u32.to_le_bytes().to_vec()));
+        assert_eq!(length(&mut store, "/bench/9/1"), Some(1u32.to_le_bytes().to_vec()));
+        assert_eq!(length(&mut store, "/bench/9/0"), None);
+        assert_eq!(length(&mut store, "/bench/0/0"), Some(1u32.to_le_bytes().to_vec()));
+        let updated = db_make_length_key(&9u32.to_le_bytes().to_vec()).unwrap();
+        assert_eq!(store.get(updated).unwrap(), Some(4u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn percentile_picks_the_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 0.50), Duration::from_millis(51));
+        assert_eq!(percentile(&sorted, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 0.99), Duration::ZERO);
+    }
+}
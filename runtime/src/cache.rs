@@ -0,0 +1,212 @@
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+// Cached lookups, including misses, plus their recency order. `generation` advances on every
+// eviction so a lookup that raced a mutation does not cache what it read.
+#[derive(Default)]
+struct LruCache {
+    entries: HashMap<Vec<u8>, (Option<Vec<u8>>, u64)>,
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    generation: u64,
+}
+
+impl LruCache {
+    fn get(&mut self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let tick = self.tick;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, key.to_vec());
+        self.tick += 1;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, capacity: usize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+        self.tick += 1;
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.generation += 1;
+    }
+}
+
+/// Serves repeated `get`s of hot keys from an in-process LRU cache in front of the wrapped
+/// store. Clones share the cache.
+///
+/// Every mutation made through the adapter, including each key of a written batch, evicts the
+/// affected entries once it reaches the store. Writes made to the store by other handles or
+/// processes are not seen, so wrap the only writer or a store that does not change underneath.
+#[derive(Clone)]
+pub struct CachingAdapter<T: KeyValueStoreLike + Clone> {
+    pub inner: T,
+    capacity: usize,
+    cache: Arc<Mutex<LruCache>>,
+}
+
+impl<T: KeyValueStoreLike + Clone> CachingAdapter<T> {
+    pub fn new(inner: T, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: Arc::new(Mutex::new(LruCache::default())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Wraps the store's own batch, remembering which keys it touches so they can be evicted.
+pub struct CachingBatch<B: BatchLike> {
+    pub inner: B,
+    keys: Vec<Vec<u8>>,
+}
+
+impl<B: BatchLike> BatchLike for CachingBatch<B> {
+    fn default() -> Self {
+        Self {
+            inner: B::default(),
+            keys: vec![],
+        }
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.keys.push(key.as_ref().to_vec());
+        self.inner.put(key, value);
+    }
+}
+
+impl<T: KeyValueStoreLike + Clone> KeyValueStoreLike for CachingAdapter<T> {
+    type Error = T::Error;
+    type Batch = CachingBatch<T::Batch>;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        let result = self.inner.write(batch.inner);
+        let mut cache = self.cache.lock().unwrap();
+        for key in batch.keys.iter() {
+            cache.invalidate(key);
+        }
+        result
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if self.capacity == 0 {
+            return self.inner.get(key);
+        }
+        let generation = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(key.as_ref()) {
+                return Ok(value);
+            }
+            cache.generation
+        };
+        let value = self.inner.get(key.as_ref())?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.insert(key.as_ref().to_vec(), value.clone(), self.capacity);
+        }
+        Ok(value)
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        let result = self.inner.delete(key.as_ref());
+        self.cache.lock().unwrap().invalidate(key.as_ref());
+        result
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let result = self.inner.put(key.as_ref(), value);
+        self.cache.lock().unwrap().invalidate(key.as_ref());
+        result
+    }
+
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let result = self.inner.delete_if(key.as_ref(), expected);
+        self.cache.lock().unwrap().invalidate(key.as_ref());
+        result
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn scan_prefix_keys<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner.scan_prefix_keys(prefix, after, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{MemStoreAdapter, MemStoreBatch};
+
+    #[test]
+    fn hits_skip_the_store_and_writes_invalidate() {
+        let mut inner = MemStoreAdapter::new();
+        inner.put(b"counter", b"1").unwrap();
+        let mut store = CachingAdapter::new(inner.clone(), 2);
+        assert_eq!(store.get(b"counter").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"missing").unwrap(), None);
+
+        // Changed behind the cache's back, so only a store read would see the new values
+        inner.put(b"counter", b"2").unwrap();
+        inner.put(b"missing", b"found").unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"missing").unwrap(), None);
+
+        store.put(b"counter", b"3").unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), Some(b"3".to_vec()));
+        let mut batch = CachingBatch::<MemStoreBatch>::default();
+        batch.put(b"other", b"value");
+        batch.put(b"missing", b"batched");
+        store.write(batch).unwrap();
+        assert_eq!(store.get(b"missing").unwrap(), Some(b"batched".to_vec()));
+        store.delete(b"counter").unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), None);
+
+        // The least recently used entry makes room once the capacity is reached
+        assert_eq!(store.len(), 2);
+        store.get(b"other").unwrap();
+        assert_eq!(store.len(), 2);
+        inner.put(b"missing", b"evicted").unwrap();
+        assert_eq!(store.get(b"missing").unwrap(), Some(b"evicted".to_vec()));
+    }
+}
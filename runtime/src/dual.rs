@@ -0,0 +1,162 @@
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+
+/// Mirrors every mutation into a second store while serving reads from the primary only.
+///
+/// This is meant for zero-downtime migrations between backends: run the indexer with the new
+/// store as `secondary` until it has caught up, then cut over to it as the only store.
+#[derive(Clone)]
+pub struct DualWriteAdapter<A: KeyValueStoreLike + Clone, B: KeyValueStoreLike + Clone> {
+    pub primary: A,
+    pub secondary: B,
+}
+
+impl<A: KeyValueStoreLike + Clone, B: KeyValueStoreLike + Clone> DualWriteAdapter<A, B> {
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+pub struct DualWriteBatch<A: BatchLike, B: BatchLike>(pub A, pub B);
+
+impl<A: BatchLike, B: BatchLike> BatchLike for DualWriteBatch<A, B> {
+    fn default() -> Self {
+        Self(A::default(), B::default())
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.0.put(key.as_ref(), value.as_ref());
+        self.1.put(key.as_ref(), value.as_ref());
+    }
+}
+
+/// Identifies which side of a `DualWriteAdapter` rejected an operation.
+#[derive(Debug)]
+pub enum DualWriteError<A: std::fmt::Debug, B: std::fmt::Debug> {
+    Primary(A),
+    Secondary(B),
+}
+
+impl<A: std::fmt::Debug, B: std::fmt::Debug> std::fmt::Display for DualWriteError<A, B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DualWriteError::Primary(e) => write!(f, "primary store error: {:?}", e),
+            DualWriteError::Secondary(e) => write!(f, "secondary store error: {:?}", e),
+        }
+    }
+}
+
+impl<A: KeyValueStoreLike + Clone, B: KeyValueStoreLike + Clone> KeyValueStoreLike
+    for DualWriteAdapter<A, B>
+{
+    type Error = DualWriteError<A::Error, B::Error>;
+    type Batch = DualWriteBatch<A::Batch, B::Batch>;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        self.primary.write(batch.0).map_err(DualWriteError::Primary)?;
+        self.secondary
+            .write(batch.1)
+            .map_err(DualWriteError::Secondary)
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.primary.get(key).map_err(DualWriteError::Primary)
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.primary
+            .delete(key.as_ref())
+            .map_err(DualWriteError::Primary)?;
+        self.secondary
+            .delete(key.as_ref())
+            .map_err(DualWriteError::Secondary)
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.primary
+            .put(key.as_ref(), value.as_ref())
+            .map_err(DualWriteError::Primary)?;
+        self.secondary
+            .put(key.as_ref(), value.as_ref())
+            .map_err(DualWriteError::Secondary)
+    }
+
+    // The primary decides; a deletion it makes is mirrored unconditionally, like delete
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if !self
+            .primary
+            .delete_if(key.as_ref(), expected)
+            .map_err(DualWriteError::Primary)?
+        {
+            return Ok(false);
+        }
+        self.secondary
+            .delete(key.as_ref())
+            .map_err(DualWriteError::Secondary)?;
+        Ok(true)
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        self.primary
+            .scan_prefix(prefix)
+            .map_err(DualWriteError::Primary)
+    }
+
+    fn scan_prefix_keys<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.primary
+            .scan_prefix_keys(prefix, after, limit)
+            .map_err(DualWriteError::Primary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+    use crate::readonly::{ReadOnlyAdapter, ReadOnlyError};
+
+    #[test]
+    fn both_stores_receive_identical_writes() {
+        let (primary, secondary) = (MemStoreAdapter::new(), MemStoreAdapter::new());
+        let mut dual = DualWriteAdapter::new(primary.clone(), secondary.clone());
+        let mut batch = <DualWriteAdapter<MemStoreAdapter, MemStoreAdapter> as KeyValueStoreLike>::Batch::default();
+        batch.put(b"a", b"1");
+        batch.put(b"b", b"2");
+        dual.write(batch).unwrap();
+        dual.put(b"c", b"3").unwrap();
+        dual.delete(b"a").unwrap();
+        assert!(!dual.delete_if(b"b", b"9").unwrap());
+        assert!(dual.delete_if(b"c", b"3").unwrap());
+        assert_eq!(*primary.map.lock().unwrap(), *secondary.map.lock().unwrap());
+        assert_eq!(primary.len(), 1);
+
+        // Reads never touch the secondary
+        secondary.clone().put(b"only-secondary", b"x").unwrap();
+        assert_eq!(dual.get(b"only-secondary").unwrap(), None);
+        assert_eq!(dual.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn a_failed_secondary_write_is_surfaced() {
+        let primary = MemStoreAdapter::new();
+        let mut dual = DualWriteAdapter::new(primary.clone(), ReadOnlyAdapter::new(MemStoreAdapter::new()));
+        assert!(matches!(
+            dual.put(b"a", b"1"),
+            Err(DualWriteError::Secondary(ReadOnlyError::WriteRejected))
+        ));
+        // The primary had already taken the write
+        assert_eq!(primary.len(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::mem::MemStoreAdapter;
+use crate::runtime::{KeyValueStoreLike, MetashrewRuntime, ORPHANS_PREFIX};
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::{sha256d, Hash};
+use std::path::PathBuf;
+
+/// A scripted stand-in for the node: the canonical chain as raw blocks indexed by height.
+/// Replacing its blocks from some height onward simulates a reorg.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedChain {
+    pub blocks: Vec<Vec<u8>>,
+}
+
+impl ScriptedChain {
+    pub fn new(blocks: Vec<Vec<u8>>) -> Self {
+        Self { blocks }
+    }
+
+    /// Number of blocks on the chain, which is also the first height it does not have.
+    pub fn len(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn push(&mut self, block: Vec<u8>) {
+        self.blocks.push(block);
+    }
+
+    /// Drops every block from `height` up and appends `blocks` in their place.
+    pub fn reorg(&mut self, height: u32, blocks: Vec<Vec<u8>>) {
+        self.blocks.truncate(height as usize);
+        self.blocks.extend(blocks);
+    }
+
+    // Blocks in a script need not be well-formed, so hash the whole block rather than a header
+    pub fn blockhash(&self, height: u32) -> Option<Vec<u8>> {
+        self.blocks
+            .get(height as usize)
+            .map(|block| sha256d::Hash::hash(block).to_byte_array().to_vec())
+    }
+}
+
+/// Drives an indexer over a `ScriptedChain` the way the daemons' sync loops do, remembering
+/// the hash of every block it indexed. When the chain's hash at a height stops matching, the
+/// runtime's state is rolled back to that height before the new branch is indexed.
+pub struct Harness<T: KeyValueStoreLike + Clone + Send + Sync + 'static> {
+    pub runtime: MetashrewRuntime<T>,
+    hashes: Vec<Vec<u8>>,
+    pub reorgs: u32,
+}
+
+impl Harness<MemStoreAdapter> {
+    /// Loads the fixture indexer at `indexer` against a fresh in-memory store.
+    pub fn new(indexer: PathBuf) -> Result<Self> {
+        Self::with_store(indexer, MemStoreAdapter::new())
+    }
+}
+
+impl<T: KeyValueStoreLike + Clone + Send + Sync + 'static> Harness<T> {
+    pub fn with_store(indexer: PathBuf, store: T) -> Result<Self> {
+        Ok(Self {
+            runtime: MetashrewRuntime::load(indexer, store)?,
+            hashes: vec![],
+            reorgs: 0,
+        })
+    }
+
+    /// Number of blocks indexed on the current branch.
+    pub fn height(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+
+    /// Indexes `chain` through its tip, first rolling back to the fork point if the chain no
+    /// longer contains every block indexed so far. Returns the number of blocks indexed.
+    pub fn sync(&mut self, chain: &ScriptedChain) -> Result<u32> {
+        let fork = (0..self.height())
+            .find(|height| chain.blockhash(*height).as_ref() != Some(&self.hashes[*height as usize]))
+            .unwrap_or(self.height());
+        if fork < self.height() {
+            debug!("scripted reorg at block {}", fork);
+            if self.runtime.keep_orphans {
+                let mut context = self.runtime.context.lock().unwrap();
+                for height in fork..self.height() {
+                    context
+                        .db
+                        .put(
+                            format!("{}{}/blockhash", ORPHANS_PREFIX, height).into_bytes(),
+                            &self.hashes[height as usize],
+                        )
+                        .map_err(|e| anyhow!("Database error: {:?}", e))?;
+                }
+            }
+            self.hashes.truncate(fork as usize);
+            self.reorgs += 1;
+        }
+        for height in fork..chain.len() {
+            {
+                let mut context = self.runtime.context.lock().unwrap();
+                context.begin_block(height, chain.blocks[height as usize].clone());
+            }
+            self.runtime
+                .run()
+                .map_err(|e| anyhow!("block {} failed with {}", height, e))?;
+            self.hashes.push(chain.blockhash(height).unwrap());
+        }
+        Ok(chain.len().saturating_sub(fork))
+    }
+
+    /// The value the indexer last wrote to `key` on the current branch, empty if none.
+    pub fn value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        MetashrewRuntime::<T>::db_value_at_block(
+            self.runtime.context.clone(),
+            &key.to_vec(),
+            self.height().saturating_sub(1),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flushes ["k", <first byte of the block>]
+    const FIRST_BYTE: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01\00")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store8 (i32.const 105) (i32.load8_u (i32.const 1004)))
+        (call $flush (i32.const 100))))"#;
+
+    #[test]
+    fn sync_rolls_back_to_the_fork_and_indexes_the_new_branch() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let indexer = dir.path().join("indexer.wat");
+        std::fs::write(&indexer, FIRST_BYTE).unwrap();
+        let mut harness = Harness::new(indexer).unwrap();
+        let mut chain = ScriptedChain::new(vec![vec![1; 80], vec![2; 80], vec![3; 80]]);
+        assert_eq!(harness.sync(&chain).unwrap(), 3);
+        assert_eq!(harness.value(b"k").unwrap(), vec![3]);
+        assert_eq!(harness.sync(&chain).unwrap(), 0);
+
+        chain.reorg(1, vec![vec![5; 80], vec![6; 80], vec![7; 80]]);
+        assert_eq!(harness.sync(&chain).unwrap(), 3);
+        assert_eq!((harness.height(), harness.reorgs), (4, 1));
+        assert_eq!(harness.value(b"k").unwrap(), vec![7]);
+
+        // A branch shorter than the one indexed rolls back past its tip
+        chain.reorg(2, vec![]);
+        assert_eq!(harness.sync(&chain).unwrap(), 0);
+        assert_eq!((harness.height(), harness.reorgs), (2, 2));
+        assert_eq!(harness.value(b"k").unwrap(), vec![5]);
+    }
+}
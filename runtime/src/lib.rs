@@ -1,8 +1,24 @@
 #[macro_use]
 extern crate log;
 
+pub mod bench;
+pub mod cache;
+pub mod dual;
+#[cfg(feature = "harness")]
+pub mod harness;
+pub mod mem;
+pub mod overlay;
+pub mod readonly;
 #[allow(renamed_and_removed_lints)]
 pub mod proto;
 pub mod runtime;
+pub mod sandbox;
+pub mod tiered;
 
+pub use cache::*;
+pub use dual::*;
+pub use mem::*;
+pub use overlay::*;
+pub use readonly::*;
 pub use runtime::*;
+pub use tiered::*;
@@ -0,0 +1,111 @@
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+/// An in-process store backed by an ordered map, for benchmarks and tooling that should run
+/// without a database. Clones share the same map.
+#[derive(Clone, Default)]
+pub struct MemStoreAdapter {
+    pub map: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemStoreAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct MemStoreBatch(pub KeyValuePairs);
+
+impl BatchLike for MemStoreBatch {
+    fn default() -> Self {
+        Self(vec![])
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.0.push((key.as_ref().to_vec(), value.as_ref().to_vec()));
+    }
+}
+
+impl KeyValueStoreLike for MemStoreAdapter {
+    type Error = Infallible;
+    type Batch = MemStoreBatch;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        self.map.lock().unwrap().extend(batch.0);
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.map.lock().unwrap().get(key.as_ref()).cloned())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.map.lock().unwrap().remove(key.as_ref());
+        Ok(())
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.map
+            .lock()
+            .unwrap()
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut map = self.map.lock().unwrap();
+        if map.get(key.as_ref()).map(|value| value.as_slice()) != Some(expected.as_ref()) {
+            return Ok(false);
+        }
+        map.remove(key.as_ref());
+        Ok(true)
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let prefix = prefix.as_ref();
+        Ok(self
+            .map
+            .lock()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_if_only_deletes_the_expected_value() {
+        let mut store = MemStoreAdapter::new();
+        store.put(b"key", b"value").unwrap();
+        assert!(!store.delete_if(b"key", b"other").unwrap());
+        assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(!store.delete_if(b"missing", b"value").unwrap());
+
+        assert!(store.delete_if(b"key", b"value").unwrap());
+        assert_eq!(store.get(b"key").unwrap(), None);
+        assert!(!store.delete_if(b"key", b"value").unwrap());
+    }
+}
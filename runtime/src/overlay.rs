@@ -0,0 +1,101 @@
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+use std::collections::BTreeMap;
+
+/// Layers a scratch namespace on top of a base store.
+///
+/// Reads check the namespace first and fall back to the base, so an indexer run against the
+/// overlay sees the base's state, while every write lands under `prefix` and leaves the base's
+/// own keys untouched. Writes are applied key by key rather than atomically, which is fine for
+/// state that is thrown away with `clear` and rebuilt.
+#[derive(Clone)]
+pub struct OverlayAdapter<T: KeyValueStoreLike + Clone> {
+    pub base: T,
+    pub prefix: Vec<u8>,
+}
+
+impl<T: KeyValueStoreLike + Clone> OverlayAdapter<T> {
+    pub fn new(base: T, prefix: Vec<u8>) -> Self {
+        Self { base, prefix }
+    }
+
+    fn namespaced(&self, key: &[u8]) -> Vec<u8> {
+        let mut result = self.prefix.clone();
+        result.extend(key);
+        result
+    }
+
+    /// Deletes everything written to the overlay, returning the number of keys removed.
+    pub fn clear(&mut self) -> Result<u64, T::Error> {
+        let pairs = self.base.scan_prefix(&self.prefix)?;
+        for (key, _) in &pairs {
+            self.base.delete(key)?;
+        }
+        Ok(pairs.len() as u64)
+    }
+}
+
+pub struct OverlayBatch(pub KeyValuePairs);
+
+impl BatchLike for OverlayBatch {
+    fn default() -> Self {
+        Self(vec![])
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.0.push((key.as_ref().to_vec(), value.as_ref().to_vec()));
+    }
+}
+
+impl<T: KeyValueStoreLike + Clone> KeyValueStoreLike for OverlayAdapter<T> {
+    type Error = T::Error;
+    type Batch = OverlayBatch;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        for (key, value) in batch.0 {
+            self.base.put(self.namespaced(&key), value)?;
+        }
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.base.get(self.namespaced(key.as_ref()))? {
+            Some(value) => Ok(Some(value)),
+            None => self.base.get(key),
+        }
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.base.delete(self.namespaced(key.as_ref()))
+    }
+
+    // Like delete, only ever removes the overlay's copy, never the base's
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.base.delete_if(self.namespaced(key.as_ref()), expected)
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.base.put(self.namespaced(key.as_ref()), value)
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let mut merged: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        for (key, value) in self.base.scan_prefix(prefix.as_ref())? {
+            if !key.starts_with(&self.prefix) {
+                merged.insert(key, value);
+            }
+        }
+        let namespaced_prefix = self.namespaced(prefix.as_ref());
+        for (key, value) in self.base.scan_prefix(&namespaced_prefix)? {
+            merged.insert(key[self.prefix.len()..].to_vec(), value);
+        }
+        Ok(merged.into_iter().collect())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
+
+/// Serves reads from the wrapped store and rejects every mutation, so a query server cannot
+/// change state even if a view function tries to write.
+#[derive(Clone)]
+pub struct ReadOnlyAdapter<T: KeyValueStoreLike + Clone> {
+    pub inner: T,
+}
+
+impl<T: KeyValueStoreLike + Clone> ReadOnlyAdapter<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+/// Batches are accepted so the runtime can build them, but can never be written.
+pub struct ReadOnlyBatch;
+
+impl BatchLike for ReadOnlyBatch {
+    fn default() -> Self {
+        Self
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, _key: K, _value: V) {}
+}
+
+#[derive(Debug)]
+pub enum ReadOnlyError<E: std::fmt::Debug> {
+    WriteRejected,
+    Store(E),
+}
+
+impl<E: std::fmt::Debug> std::fmt::Display for ReadOnlyError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadOnlyError::WriteRejected => write!(f, "write rejected by read-only store"),
+            ReadOnlyError::Store(e) => write!(f, "store error: {:?}", e),
+        }
+    }
+}
+
+impl<T: KeyValueStoreLike + Clone> KeyValueStoreLike for ReadOnlyAdapter<T> {
+    type Error = ReadOnlyError<T::Error>;
+    type Batch = ReadOnlyBatch;
+
+    fn write(&mut self, _batch: Self::Batch) -> Result<(), Self::Error> {
+        Err(ReadOnlyError::WriteRejected)
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get(key).map_err(ReadOnlyError::Store)
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, _key: K) -> Result<(), Self::Error> {
+        Err(ReadOnlyError::WriteRejected)
+    }
+
+    fn put<K, V>(&mut self, _key: K, _value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        Err(ReadOnlyError::WriteRejected)
+    }
+
+    fn delete_if<K, V>(&mut self, _key: K, _expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        Err(ReadOnlyError::WriteRejected)
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        self.inner.scan_prefix(prefix).map_err(ReadOnlyError::Store)
+    }
+
+    fn scan_prefix_keys<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner
+            .scan_prefix_keys(prefix, after, limit)
+            .map_err(ReadOnlyError::Store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+
+    #[test]
+    fn writes_are_rejected_while_reads_pass_through() {
+        let mut inner = MemStoreAdapter::new();
+        inner.put(b"key", b"value").unwrap();
+        let mut store = ReadOnlyAdapter::new(inner.clone());
+        assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.scan_prefix(b"k").unwrap(), vec![(b"key".to_vec(), b"value".to_vec())]);
+
+        let mut batch = ReadOnlyBatch::default();
+        batch.put(b"other", b"value");
+        assert!(matches!(store.write(batch), Err(ReadOnlyError::WriteRejected)));
+        assert!(matches!(store.put(b"key", b"changed"), Err(ReadOnlyError::WriteRejected)));
+        assert!(matches!(store.delete(b"key"), Err(ReadOnlyError::WriteRejected)));
+        assert!(matches!(store.delete_if(b"key", b"value"), Err(ReadOnlyError::WriteRejected)));
+        assert_eq!(inner.len(), 1);
+        assert_eq!(inner.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+}
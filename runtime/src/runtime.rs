@@ -2,16 +2,113 @@ use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
 //use rlp;
 use protobuf::Message;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
-use wasmtime::{Caller, Linker, Store, StoreLimits, StoreLimitsBuilder};
+use std::time::Duration;
+use wasmtime::{Caller, Linker, ResourceLimiter, Store, StoreLimits, StoreLimitsBuilder};
 
 fn lock_err<T>(err: std::sync::PoisonError<T>) -> anyhow::Error {
     anyhow!("Mutex lock error: {}", err)
 }
 
 
+/// A host-side predicate on transactions. With one set, the block parser imports only expose
+/// the transactions that match it, so an indexer interested in a narrow slice of the chain
+/// never sees the rest.
+#[cfg(feature = "block-parser")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxFilter {
+    /// Transactions with at least one OP_RETURN output
+    OpReturn,
+    /// Transactions with at least one Taproot (P2TR) output
+    Taproot,
+}
+
+#[cfg(feature = "block-parser")]
+impl std::str::FromStr for TxFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "op-return" => Ok(TxFilter::OpReturn),
+            "taproot" => Ok(TxFilter::Taproot),
+            _ => Err(anyhow!("unknown transaction filter {:?}, expected op-return or taproot", s)),
+        }
+    }
+}
+
+#[cfg(feature = "block-parser")]
+impl TxFilter {
+    pub fn matches(&self, tx: &bitcoin::Transaction) -> bool {
+        tx.output.iter().any(|output| match self {
+            TxFilter::OpReturn => output.script_pubkey.is_op_return(),
+            TxFilter::Taproot => output.script_pubkey.is_p2tr(),
+        })
+    }
+}
+
+// Splits a serialized block into each transaction's consensus bytes and its txid, keeping only
+// the transactions `filter` matches
+#[cfg(feature = "block-parser")]
+fn parse_block_txs(block: &[u8], filter: Option<TxFilter>) -> Result<KeyValuePairs> {
+    use bitcoin::hashes::Hash;
+    let block: bitcoin::Block = bitcoin::consensus::deserialize(block)?;
+    Ok(block
+        .txdata
+        .iter()
+        .filter(|tx| filter.is_none_or(|filter| filter.matches(tx)))
+        .map(|tx| {
+            (
+                bitcoin::consensus::serialize(tx),
+                tx.txid().to_byte_array().to_vec(),
+            )
+        })
+        .collect())
+}
+
+// Epoch deadline for stores with no block timeout running; far enough ahead to never be hit
+const NO_DEADLINE: u64 = u64::MAX / 2;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+// Granularity preallocation touches guest memory at, the smallest page size of common hosts
+const HOST_PAGE_SIZE: usize = 4096;
+
+// An engine loaded with a block timeout counts epochs so `execute` can interrupt a block that
+// overruns it; every store starts with a deadline it will never reach
+fn new_store(engine: &wasmtime::Engine) -> Store<State> {
+    let mut store = Store::<State>::new(engine, State::new());
+    store.set_epoch_deadline(NO_DEADLINE);
+    store
+}
+
+// Bumps the engine's epoch every `tick` until dropped, so one thread serves every block a
+// runtime loaded with a block timeout runs
+struct EpochTicker {
+    tick: Duration,
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
+impl EpochTicker {
+    // Ticks run at a tenth of the timeout, so a block is interrupted at most 10% late
+    fn spawn(engine: wasmtime::Engine, timeout: Duration) -> Self {
+        let tick = std::cmp::max(timeout / 10, Duration::from_millis(1));
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(tick) {
+                engine.increment_epoch();
+            }
+        });
+        Self { tick, _stop: stop }
+    }
+
+    // Ticks that cover at least `timeout`, counting the one already under way as none
+    fn deadline(&self, timeout: Duration) -> u64 {
+        (timeout.as_nanos() / self.tick.as_nanos()) as u64 + 1
+    }
+}
+
 fn try_into_vec<const N: usize>(bytes: [u8; N]) -> Result<Vec<u8>> {
     Vec::<u8>::try_from(bytes).map_err(|e| anyhow!("Failed to convert bytes to Vec: {:?}", e))
 }
@@ -19,13 +116,29 @@ fn try_into_vec<const N: usize>(bytes: [u8; N]) -> Result<Vec<u8>> {
 use crate::proto::metashrew::KeyValueFlush;
 
 type SerBlock = Vec<u8>;
+
+/// Guest export the host calls as `__alloc(size: i32) -> i32` to reserve memory for bytes it
+/// hands back from an import.
+pub const ALLOCATOR_EXPORT: &str = "__alloc";
+
+/// Guest export, of any kind, through which an indexer opts into being fed blocks in descending
+/// height order; see `supports_reverse`.
+pub const REVERSE_EXPORT: &str = "__reverse";
+
+/// Namespace `handle_reorg` copies reverted values into when `keep_orphans` is set, as
+/// `<prefix><height>/data/<key>`. The orphaned block's hash goes under `<prefix><height>/blockhash`.
+pub const ORPHANS_PREFIX: &str = "/__INTERNAL/orphans/";
+
+// Host imports that return bytes by allocating them through `ALLOCATOR_EXPORT`
+const ALLOCATING_IMPORTS: &[&str] = &["__input_alloc"];
+pub type KeyValuePairs = Vec<(Vec<u8>, Vec<u8>)>;
 pub trait BatchLike {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V);
     fn default() -> Self;
 }
 pub trait KeyValueStoreLike {
     type Error: std::fmt::Debug;
-    type Batch: BatchLike;
+    type Batch: BatchLike + Send;
     fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error>;
     fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error>;
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error>;
@@ -33,6 +146,44 @@ pub trait KeyValueStoreLike {
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>;
+    /// Returns every stored key/value pair whose key starts with `prefix`, in ascending key order.
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error>;
+    /// Returns up to `limit` logical keys under `prefix` that follow `after`, found and ordered
+    /// through their length entries (the key followed by u32::MAX). The default filters
+    /// `scan_prefix`; stores that can stop iterating early override it.
+    fn scan_prefix_keys<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let prefix = prefix.as_ref();
+        let length_suffix = u32::MAX.to_le_bytes();
+        let start = after.map(|key| [key, &length_suffix[..]].concat());
+        Ok(self
+            .scan_prefix(prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.len() >= prefix.len() + 4 && key.ends_with(&length_suffix))
+            .skip_while(|key| start.as_ref().is_some_and(|start| key <= start))
+            .take(limit)
+            .map(|key| key[..key.len() - 4].to_vec())
+            .collect())
+    }
+    /// Deletes `key` only if it currently holds `expected`, returning whether it was deleted.
+    /// The default reads and deletes in two steps, so it is only safe with a single writer;
+    /// stores shared between writers override it with an atomic compare-and-delete.
+    fn delete_if<K, V>(&mut self, key: K, expected: V) -> Result<bool, Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self.get(key.as_ref())?.as_deref() != Some(expected.as_ref()) {
+            return Ok(false);
+        }
+        self.delete(key)?;
+        Ok(true)
+    }
 }
 
 //const TIP_KEY: &[u8] = b"T";
@@ -41,6 +192,112 @@ pub trait KeyValueStoreLike {
 pub struct State {
     limits: StoreLimits,
     had_failure: bool,
+    memory_limit_hit: bool,
+    // Pending results for each `__scan_start` handle issued during the current call
+    scans: Vec<VecDeque<Vec<u8>>>,
+    // Store reads made through `__get`/`__get_len`/`__scan_start` during the current block, and
+    // the cap on them
+    reads: u32,
+    max_reads: Option<u32>,
+    read_budget_hit: bool,
+    // Prefixes every key `__flush` writes must start with, unrestricted when None
+    write_prefixes: Option<Vec<Vec<u8>>>,
+    write_denied: bool,
+    // Cap on the key and value bytes the current block's `__flush` calls may write between them
+    max_write_bytes: Option<u64>,
+    write_budget_hit: bool,
+    flushed_bytes: u64,
+    // Key/value pairs committed by the current call's `__flush` calls
+    flushed_pairs: u64,
+    // Pairs the current call's `__flush` calls handed over, kept only when `capture_flush` is set
+    capture_flush: bool,
+    staged: Option<KeyValuePairs>,
+    // Consensus bytes and txid of each transaction in the current block, parsed on first use
+    #[cfg(feature = "block-parser")]
+    block_txs: Option<KeyValuePairs>,
+    // Transactions `block_txs` keeps, every one when None
+    #[cfg(feature = "block-parser")]
+    tx_filter: Option<TxFilter>,
+}
+
+/// Why a call to `MetashrewRuntime::run` failed.
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// The guest trapped; this is deterministic for a given block and module.
+    Trap(anyhow::Error),
+    /// The guest ran out of fuel.
+    FuelExhausted(anyhow::Error),
+    /// A memory or table could not grow within the store limits.
+    MemoryLimit(anyhow::Error),
+    /// The guest was interrupted after running longer than `block_timeout`.
+    Timeout(anyhow::Error),
+    /// The guest made more store reads than `max_reads_per_block` allows.
+    ReadBudget(anyhow::Error),
+    /// The guest flushed a key outside `write_prefixes`.
+    WriteNotAllowed(anyhow::Error),
+    /// The guest flushed more bytes than `max_write_bytes_per_block` allows.
+    WriteBudget(anyhow::Error),
+    /// A host import reported a failure, or the guest returned without flushing.
+    HostError(anyhow::Error),
+    /// A failure outside the guest, such as a poisoned lock or a store error.
+    Internal(anyhow::Error),
+}
+
+impl RuntimeError {
+    /// Whether rerunning the same block is expected to fail the same way.
+    pub fn is_deterministic(&self) -> bool {
+        matches!(
+            self,
+            RuntimeError::Trap(_)
+                | RuntimeError::FuelExhausted(_)
+                | RuntimeError::ReadBudget(_)
+                | RuntimeError::WriteNotAllowed(_)
+                | RuntimeError::WriteBudget(_)
+        )
+    }
+
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            RuntimeError::Trap(e)
+            | RuntimeError::FuelExhausted(e)
+            | RuntimeError::MemoryLimit(e)
+            | RuntimeError::ReadBudget(e)
+            | RuntimeError::WriteNotAllowed(e)
+            | RuntimeError::WriteBudget(e)
+            | RuntimeError::Timeout(e)
+            | RuntimeError::HostError(e)
+            | RuntimeError::Internal(e) => e,
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            RuntimeError::Trap(_) => "trap",
+            RuntimeError::FuelExhausted(_) => "fuel exhausted",
+            RuntimeError::MemoryLimit(_) => "memory limit",
+            RuntimeError::ReadBudget(_) => "read budget exceeded",
+            RuntimeError::WriteNotAllowed(_) => "write not allowed",
+            RuntimeError::WriteBudget(_) => "write budget exceeded",
+            RuntimeError::Timeout(_) => "block timeout",
+            RuntimeError::HostError(_) => "host error",
+            RuntimeError::Internal(_) => "internal error",
+        };
+        write!(f, "{}: {:#}", kind, self.inner())
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.inner().as_ref())
+    }
+}
+
+impl From<anyhow::Error> for RuntimeError {
+    fn from(err: anyhow::Error) -> Self {
+        RuntimeError::Internal(err)
+    }
 }
 
 pub struct MetashrewRuntimeContext<T: KeyValueStoreLike + Clone> {
@@ -48,6 +305,10 @@ pub struct MetashrewRuntimeContext<T: KeyValueStoreLike + Clone> {
     pub height: u32,
     pub block: SerBlock,
     pub state: u32,
+    // Writes handed over by `__flush`, committed by `execute` once the block succeeds
+    batch: Option<T::Batch>,
+    // List lengths `batch` has already bumped, carried from one `__flush` to the next
+    pending: HashMap<Vec<u8>, u32>,
 }
 
 impl<T: KeyValueStoreLike + Clone> Clone for MetashrewRuntimeContext<T> {
@@ -57,19 +318,41 @@ impl<T: KeyValueStoreLike + Clone> Clone for MetashrewRuntimeContext<T> {
             height: self.height,
             block: self.block.clone(),
             state: self.state,
+            batch: None,
+            pending: HashMap::new(),
         };
     }
 }
 
 impl<T: KeyValueStoreLike + Clone> MetashrewRuntimeContext<T> {
-    fn new(db: T, height: u32, block: SerBlock) -> Self {
+    pub fn new(db: T, height: u32, block: SerBlock) -> Self {
         return Self {
             db: db,
             height: height,
             block: block,
             state: 0,
+            batch: None,
+            pending: HashMap::new(),
         };
     }
+
+    /// Starts processing `block` at `height`, discarding the flush flag and any writes left
+    /// staged by a block that did not finish.
+    pub fn begin_block(&mut self, height: u32, block: SerBlock) {
+        self.height = height;
+        self.block = block;
+        self.state = 0;
+        self.batch = None;
+        self.pending.clear();
+    }
+
+    /// Finishes the current block, returning the writes its `__flush` staged, or an empty batch
+    /// if it never flushed.
+    pub fn end_block(&mut self) -> T::Batch {
+        self.state = 0;
+        self.pending.clear();
+        self.batch.take().unwrap_or_else(T::Batch::default)
+    }
 }
 
 pub struct MetashrewRuntime<T: KeyValueStoreLike + Clone + 'static> {
@@ -79,6 +362,40 @@ pub struct MetashrewRuntime<T: KeyValueStoreLike + Clone + 'static> {
     pub module: wasmtime::Module,
     pub linker: wasmtime::Linker<State>,
     pub instance: wasmtime::Instance,
+    // Cap on `__get`/`__get_len`/`__scan_start` calls per `run`, unlimited when None
+    pub max_reads_per_block: Option<u32>,
+    // Prefixes every key the indexer flushes must start with, any key when None
+    pub write_prefixes: Option<Vec<Vec<u8>>>,
+    // Cap on the key and value bytes the indexer may flush per `run`, unlimited when None
+    pub max_write_bytes_per_block: Option<u64>,
+    // Transactions the block parser imports expose, every one when None
+    #[cfg(feature = "block-parser")]
+    pub tx_filter: Option<TxFilter>,
+    // Wall-clock limit on guest execution per `run`, unlimited when None; only a runtime from
+    // `load_with_timeout` can enforce one
+    pub block_timeout: Option<Duration>,
+    // Drives the epoch `block_timeout` is measured in, None when loaded without a timeout
+    ticker: Option<EpochTicker>,
+    // SHA-256 of the active module's bytes
+    pub indexer_hash: [u8; 32],
+    // Copy values reverted by a reorg under ORPHANS_PREFIX instead of only discarding them
+    pub keep_orphans: bool,
+    // Keep the pairs each `__flush` hands over, for `staged_pairs`
+    pub capture_flush: bool,
+    // Consecutive blocks that flush no pairs `execute` may leave unwritten before committing
+    // them together; see `flush_empty_blocks`
+    pub coalesce_empty_blocks: Option<u32>,
+    // Heights of the empty blocks executed since the last write, oldest first
+    empty_blocks: Vec<u32>,
+    // Bytes of guest memory every new instance is grown to and touched; see set_prealloc_memory
+    prealloc_memory: Option<u64>,
+    // Set while the last `execute` has not returned successfully; blocks `reload`
+    block_pending: bool,
+    // Set when `handle_reorg` staged orphan copies for the current block, which must then be
+    // written even if the block flushes nothing
+    orphans_staged: bool,
+    // Carries the height of the last block `execute` committed; see `tip_updates`
+    tip: tokio::sync::watch::Sender<u32>,
 }
 
 impl State {
@@ -90,10 +407,82 @@ impl State {
                 .instances(usize::MAX)
                 .build(),
             had_failure: false,
+            memory_limit_hit: false,
+            scans: vec![],
+            reads: 0,
+            max_reads: None,
+            read_budget_hit: false,
+            write_prefixes: None,
+            write_denied: false,
+            max_write_bytes: None,
+            write_budget_hit: false,
+            flushed_bytes: 0,
+            flushed_pairs: 0,
+            capture_flush: false,
+            staged: None,
+            #[cfg(feature = "block-parser")]
+            block_txs: None,
+            #[cfg(feature = "block-parser")]
+            tx_filter: None,
+        }
+    }
+
+    // Returns the first key in a flushed key/value list that starts with no allowed prefix
+    fn denied_write<'a>(&self, list: &'a [Vec<u8>]) -> Option<&'a Vec<u8>> {
+        let prefixes = self.write_prefixes.as_ref()?;
+        list.iter()
+            .step_by(2)
+            .find(|key| !prefixes.iter().any(|prefix| key.starts_with(prefix)))
+    }
+
+    // Counts one store read, trapping the guest once the per-block budget is used up
+    fn charge_read(&mut self) -> Result<()> {
+        self.reads += 1;
+        match self.max_reads {
+            Some(max) if self.reads > max => {
+                self.read_budget_hit = true;
+                Err(anyhow!("indexer exceeded its budget of {} store reads per block", max))
+            }
+            _ => Ok(()),
         }
     }
 }
 
+impl ResourceLimiter for State {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if !allowed {
+            self.memory_limit_hit = true;
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(&mut self, current: u32, desired: u32, maximum: Option<u32>) -> Result<bool> {
+        let allowed = self.limits.table_growing(current, desired, maximum)?;
+        if !allowed {
+            self.memory_limit_hit = true;
+        }
+        Ok(allowed)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
 pub fn db_make_list_key(v: &Vec<u8>, index: u32) -> Result<Vec<u8>> {
     let mut entry = v.clone();
     let index_bits = try_into_vec(index.to_le_bytes())?;
@@ -141,6 +530,110 @@ pub fn db_annotate_value(v: &Vec<u8>, block_height: u32) -> Result<Vec<u8>> {
     Ok(entry)
 }
 
+pub fn indexer_hash(module: &[u8]) -> [u8; 32] {
+    use bitcoin::hashes::Hash;
+    bitcoin::hashes::sha256::Hash::hash(module).to_byte_array()
+}
+
+/// Checks that the module exports everything the host calls into, naming each missing export
+/// so a bad build fails at load time instead of trapping on the first block.
+pub fn check_exports(module: &wasmtime::Module) -> Result<()> {
+    let mut missing: Vec<&str> = vec![];
+    match module.get_export("_start") {
+        Some(wasmtime::ExternType::Func(ty)) if ty.params().len() == 0 && ty.results().len() == 0 => {}
+        _ => missing.push("_start() entrypoint"),
+    }
+    match module.get_export("memory") {
+        Some(wasmtime::ExternType::Memory(_)) => {}
+        _ => missing.push("memory"),
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "module is missing required exports: {}",
+            missing.join(", ")
+        ));
+    }
+    check_allocator(module)
+}
+
+/// Whether the module exports `REVERSE_EXPORT`, declaring that its state is meaningful when
+/// blocks arrive from the tip downward. Forward sync never checks this.
+pub fn supports_reverse(module: &wasmtime::Module) -> bool {
+    module.get_export(REVERSE_EXPORT).is_some()
+}
+
+/// Fails if the module imports a byte-returning host function without exporting a suitable
+/// allocator, so the ABI mismatch surfaces at load time rather than mid-block.
+pub fn check_allocator(module: &wasmtime::Module) -> Result<()> {
+    let import = match module
+        .imports()
+        .find(|import| import.module() == "env" && ALLOCATING_IMPORTS.contains(&import.name()))
+    {
+        Some(import) => import,
+        None => return Ok(()),
+    };
+    match module.get_export(ALLOCATOR_EXPORT) {
+        Some(wasmtime::ExternType::Func(ty))
+            if ty.params().eq([wasmtime::ValType::I32])
+                && ty.results().eq([wasmtime::ValType::I32]) =>
+        {
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "module imports {} but does not export {}(size: i32) -> i32",
+            import.name(),
+            ALLOCATOR_EXPORT
+        )),
+    }
+}
+
+// Writes each host page of the guest memory back with the byte it already holds, so the pages
+// are resident before the guest runs without changing what it sees
+fn prealloc_memory(
+    instance: &wasmtime::Instance,
+    store: &mut wasmtime::Store<State>,
+    bytes: Option<u64>,
+) -> Result<()> {
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None => return Ok(()),
+    };
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| anyhow!("module does not export memory"))?;
+    let pages = bytes.div_ceil(WASM_PAGE_SIZE);
+    let current = memory.size(&*store);
+    if pages > current {
+        memory
+            .grow(&mut *store, pages - current)
+            .with_context(|| format!("Failed to grow guest memory to {} bytes", bytes))?;
+    }
+    let data = memory.data_mut(&mut *store);
+    for offset in (0..data.len()).step_by(HOST_PAGE_SIZE) {
+        data[offset] = std::hint::black_box(data[offset]);
+    }
+    Ok(())
+}
+
+/// Copies `bytes` into an ArrayBuffer allocated through the guest's allocator, returning a
+/// pointer to the data with its length in the 4 bytes before it.
+pub fn write_alloc(caller: &mut Caller<'_, State>, bytes: &[u8]) -> Result<i32> {
+    let alloc = caller
+        .get_export(ALLOCATOR_EXPORT)
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| anyhow!("module does not export {}", ALLOCATOR_EXPORT))?
+        .typed::<i32, i32>(&*caller)?;
+    let mut data = u32_to_vec(bytes.len() as u32)?;
+    data.extend(bytes);
+    let ptr = alloc.call(&mut *caller, data.len() as i32)?;
+    let mem = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| anyhow!("module does not export memory"))?;
+    mem.write(&mut *caller, ptr as usize, &data)?;
+    Ok(ptr + 4)
+}
+
 pub fn to_signed_or_trap<'a, T: TryInto<i32>>(_caller: &mut Caller<'_, State>, v: T) -> i32 {
     return match <T as TryInto<i32>>::try_into(v) {
         Ok(v) => v,
@@ -166,28 +659,45 @@ where
     T: Clone,
 {
     pub fn load(indexer: PathBuf, store: T) -> Result<Self> {
-        let engine = wasmtime::Engine::default();
-        let module = wasmtime::Module::from_file(&engine, indexer.into_os_string())
-            .context("Failed to load WASM module")?;
+        Self::load_with_timeout(indexer, store, None)
+    }
+
+    /// Like `load`, but with `block_timeout` set and the engine built to enforce it. Epoch
+    /// interruption costs a check in every guest loop and call, so `load` leaves it off.
+    pub fn load_with_timeout(
+        indexer: PathBuf,
+        store: T,
+        block_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(block_timeout.is_some());
+        let engine = wasmtime::Engine::new(&config)?;
+        let bytes = std::fs::read(&indexer).context("Failed to load WASM module")?;
+        let module = wasmtime::Module::new(&engine, &bytes).context("Failed to load WASM module")?;
+        check_exports(&module)?;
         let mut linker = Linker::<State>::new(&engine);
-        let mut wasmstore = Store::<State>::new(&engine, State::new());
+        let mut wasmstore = new_store(&engine);
         let context = Arc::<Mutex<MetashrewRuntimeContext<T>>>::new(Mutex::<
             MetashrewRuntimeContext<T>,
         >::new(
             MetashrewRuntimeContext::<T>::new(store, 0, vec![]),
         ));
         {
-            wasmstore.limiter(|state| &mut state.limits)
+            wasmstore.limiter(|state| state)
         }
         {
             Self::setup_linker(context.clone(), &mut linker)
                 .context("Failed to setup basic linker")?;
             Self::setup_linker_indexer(context.clone(), &mut linker)
                 .context("Failed to setup indexer linker")?;
+            #[cfg(feature = "block-parser")]
+            Self::setup_linker_block(context.clone(), &mut linker)
+                .context("Failed to setup block parser linker")?;
             linker.define_unknown_imports_as_traps(&module)?;
         }
         let instance = linker.instantiate(&mut wasmstore, &module)
             .context("Failed to instantiate WASM module")?;
+        let ticker = block_timeout.map(|timeout| EpochTicker::spawn(engine.clone(), timeout));
         Ok(MetashrewRuntime {
             wasmstore,
             engine,
@@ -195,9 +705,33 @@ where
             linker,
             context,
             instance,
+            max_reads_per_block: None,
+            write_prefixes: None,
+            max_write_bytes_per_block: None,
+            #[cfg(feature = "block-parser")]
+            tx_filter: None,
+            block_timeout,
+            ticker,
+            indexer_hash: indexer_hash(&bytes),
+            keep_orphans: false,
+            capture_flush: false,
+            coalesce_empty_blocks: None,
+            empty_blocks: vec![],
+            prealloc_memory: None,
+            block_pending: false,
+            orphans_staged: false,
+            tip: tokio::sync::watch::channel(0).0,
         })
     }
 
+    /// Subscribes to the height of each block committed through `run` or `execute`, sent once
+    /// its writes are in the store. The value is 0 until the first block commits, and a
+    /// height indexed again after a reorg is sent again. Empty blocks held back by
+    /// `coalesce_empty_blocks` are only sent once they are written.
+    pub fn tip_updates(&self) -> tokio::sync::watch::Receiver<u32> {
+        self.tip.subscribe()
+    }
+
     pub fn preview(
         &self,
         block: &Vec<u8>,
@@ -212,11 +746,15 @@ where
         };
 
         let mut linker = Linker::<State>::new(&self.engine);
-        let mut wasmstore = Store::<State>::new(&self.engine, State::new());
+        let mut wasmstore = new_store(&self.engine);
         let context = Arc::<Mutex<MetashrewRuntimeContext<T>>>::new(Mutex::new(preview_context));
 
         {
-            wasmstore.limiter(|state| &mut state.limits);
+            wasmstore.limiter(|state| state);
+            #[cfg(feature = "block-parser")]
+            {
+                wasmstore.data_mut().tx_filter = self.tx_filter;
+            }
         }
 
         {
@@ -224,6 +762,9 @@ where
                 .context("Failed to setup basic linker")?;
             Self::setup_linker_indexer(context.clone(), &mut linker)
                 .context("Failed to setup indexer linker")?;
+            #[cfg(feature = "block-parser")]
+            Self::setup_linker_block(context.clone(), &mut linker)
+                .context("Failed to setup block parser linker")?;
             linker.define_unknown_imports_as_traps(&self.module)?;
         }
 
@@ -236,20 +777,25 @@ where
             
         match start.call(&mut wasmstore, ()) {
             Ok(_) => {
-                let context_guard = context.lock().map_err(lock_err)?;
+                let mut context_guard = context.lock().map_err(lock_err)?;
                 if context_guard.state != 1 && !wasmstore.data().had_failure {
                     return Err(anyhow!("indexer exited unexpectedly during preview"));
                 }
+                let batch = context_guard.end_block();
+                context_guard
+                    .db
+                    .write(batch)
+                    .map_err(|e| anyhow!("Database error: {:?}", e))?;
             }
             Err(e) => return Err(e).context("Error executing _start in preview"),
         }
 
         // Now set up for view call
         let mut view_linker = Linker::<State>::new(&self.engine);
-        let mut view_store = Store::<State>::new(&self.engine, State::new());
+        let mut view_store = new_store(&self.engine);
 
         {
-            view_store.limiter(|state| &mut state.limits);
+            view_store.limiter(|state| state);
         }
 
         {
@@ -283,7 +829,7 @@ where
     }
     pub fn view(&self, symbol: String, input: &Vec<u8>, height: u32) -> Result<Vec<u8>> {
         let mut linker = Linker::<State>::new(&self.engine);
-        let mut wasmstore = Store::<State>::new(&self.engine, State::new());
+        let mut wasmstore = new_store(&self.engine);
         
         let context = {
             let guard = self.context.lock().map_err(lock_err)?;
@@ -297,7 +843,7 @@ where
         }
         
         {
-            wasmstore.limiter(|state| &mut state.limits)
+            wasmstore.limiter(|state| state)
         }
         
         {
@@ -327,15 +873,66 @@ where
             result,
         ))
     }
+    /// Swaps in the indexer at `indexer` for every later block, returning false without
+    /// touching anything if its hash matches the active module. The new module is compiled,
+    /// checked for the required exports and instantiated before it replaces the old one, so a
+    /// bad build leaves the current indexer running.
+    ///
+    /// Refused while a block that failed is still waiting to be retried, so no block is ever
+    /// indexed partly by one module and partly by another.
+    pub fn reload(&mut self, indexer: PathBuf) -> Result<bool> {
+        if self.block_pending {
+            let height = self.context.lock().map_err(lock_err)?.height;
+            return Err(anyhow!(
+                "block {} has not committed; refusing to swap the indexer mid-block",
+                height
+            ));
+        }
+        let bytes = std::fs::read(&indexer).context("Failed to load WASM module")?;
+        let hash = indexer_hash(&bytes);
+        if hash == self.indexer_hash {
+            return Ok(false);
+        }
+        let module =
+            wasmtime::Module::new(&self.engine, &bytes).context("Failed to load WASM module")?;
+        check_exports(&module)?;
+        self.linker.define_unknown_imports_as_traps(&module)?;
+        let mut wasmstore = new_store(&self.engine);
+        wasmstore.limiter(|state| state);
+        let instance = self
+            .linker
+            .instantiate(&mut wasmstore, &module)
+            .context("Failed to instantiate WASM module")?;
+        prealloc_memory(&instance, &mut wasmstore, self.prealloc_memory)?;
+        self.module = module;
+        self.instance = instance;
+        self.wasmstore = wasmstore;
+        self.indexer_hash = hash;
+        Ok(true)
+    }
+
     pub fn refresh_memory(&mut self) -> Result<()> {
-        let mut wasmstore = Store::<State>::new(&self.engine, State::new());
-        wasmstore.limiter(|state| &mut state.limits);
+        let mut wasmstore = new_store(&self.engine);
+        wasmstore.limiter(|state| state);
         self.instance = self
             .linker
             .instantiate(&mut wasmstore, &self.module)
             .context("Failed to instantiate module during memory refresh")?;
         self.wasmstore = wasmstore;
-        Ok(())
+        prealloc_memory(&self.instance, &mut self.wasmstore, self.prealloc_memory)
+    }
+
+    /// Grows the guest's linear memory to at least `bytes` and touches every page of it, now and
+    /// for each instance `refresh_memory` or `reload` creates later, so the first block on a
+    /// fresh instance does not stall faulting in pages. None stops preallocating later instances.
+    ///
+    /// This only pays off for guest allocators that claim the memory already present when they
+    /// initialize, as AssemblyScript's does; ones that only use pages they grow themselves, like
+    /// Rust's default wasm allocator, grow past the preallocated memory instead. Fails if the
+    /// module declares a maximum below `bytes`.
+    pub fn set_prealloc_memory(&mut self, bytes: Option<u64>) -> Result<()> {
+        self.prealloc_memory = bytes;
+        prealloc_memory(&self.instance, &mut self.wasmstore, bytes)
     }
 
     pub fn db_create_empty_update_list(batch: &mut T::Batch, height: u32) -> Result<()> {
@@ -346,23 +943,176 @@ where
         batch.put(&key, &value_vec);
         Ok(())
     }
-    pub fn run(&mut self) -> Result<(), anyhow::Error> {
-        self.context.lock().map_err(lock_err)?.state = 0;
+    /// Number of key/value pairs committed by the last `run` or `execute`.
+    pub fn flushed_pairs(&self) -> u64 {
+        self.wasmstore.data().flushed_pairs
+    }
+
+    /// Commits the empty blocks `coalesce_empty_blocks` is holding back, if any, along with the
+    /// tip the store writes for the height it was last set to. Call it before the tip is written
+    /// any other way, and whenever the indexer catches up, so the stored tip does not lag.
+    pub fn flush_empty_blocks(&mut self) -> Result<()> {
+        if self.empty_blocks.is_empty() {
+            return Ok(());
+        }
+        let mut batch = T::Batch::default();
+        for &empty in &self.empty_blocks {
+            Self::db_create_empty_update_list(&mut batch, empty)?;
+        }
+        self.context
+            .lock()
+            .map_err(lock_err)?
+            .db
+            .write(batch)
+            .map_err(|e| anyhow!("Database error: {:?}", e))?;
+        if let Some(&last) = self.empty_blocks.last() {
+            self.tip.send_replace(last);
+        }
+        self.empty_blocks.clear();
+        Ok(())
+    }
+
+    /// The key/value pairs the last `run` or `execute` flushed, as the indexer passed them and
+    /// before any were rejected, when `capture_flush` is set. None if it never flushed.
+    pub fn staged_pairs(&self) -> Option<&KeyValuePairs> {
+        self.wasmstore.data().staged.as_ref()
+    }
+
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.handle_reorg()?;
+        self.execute()
+    }
+
+    /// Runs `_start` against the current block and height without checking for a reorg first.
+    ///
+    /// Callers are responsible for the ordering of the heights they execute: `run` rolls back
+    /// any later height it finds already indexed, this does not.
+    pub fn execute(&mut self) -> Result<(), RuntimeError> {
+        let deadline = match (self.block_timeout, &self.ticker) {
+            (Some(timeout), Some(ticker)) => Some(ticker.deadline(timeout)),
+            (Some(_), None) => {
+                return Err(RuntimeError::Internal(anyhow!(
+                    "block_timeout is only enforced on a runtime from load_with_timeout"
+                )))
+            }
+            (None, _) => None,
+        };
+        self.block_pending = true;
+        {
+            // A height executed again, after a reorg, replaces any uncommitted empty block at or
+            // above it
+            let height = self.context.lock().map_err(lock_err)?.height;
+            self.empty_blocks.retain(|&empty| empty < height);
+        }
+        {
+            let state = self.wasmstore.data_mut();
+            state.had_failure = false;
+            state.memory_limit_hit = false;
+            state.scans.clear();
+            state.reads = 0;
+            state.read_budget_hit = false;
+            state.max_reads = self.max_reads_per_block;
+            state.write_prefixes = self.write_prefixes.clone();
+            state.write_denied = false;
+            state.max_write_bytes = self.max_write_bytes_per_block;
+            state.write_budget_hit = false;
+            state.flushed_bytes = 0;
+            state.flushed_pairs = 0;
+            state.capture_flush = self.capture_flush;
+            state.staged = None;
+            #[cfg(feature = "block-parser")]
+            {
+                state.block_txs = None;
+                state.tx_filter = self.tx_filter;
+            }
+        }
         let start = self
             .instance
             .get_typed_func::<(), ()>(&mut self.wasmstore, "_start")
             .context("Failed to get _start function")?;
-        
-        self.handle_reorg()?;
-        
-        match start.call(&mut self.wasmstore, ()) {
+
+        // Once the ticker has bumped the epoch past the deadline the guest traps at its next
+        // epoch check. Host imports are not interrupted.
+        if let Some(deadline) = deadline {
+            self.wasmstore.set_epoch_deadline(deadline);
+        }
+        let result = start.call(&mut self.wasmstore, ());
+        if deadline.is_some() {
+            self.wasmstore.set_epoch_deadline(NO_DEADLINE);
+        }
+
+        match result {
             Ok(_) => {
-                if self.context.lock().map_err(lock_err)?.state != 1 && !self.wasmstore.data().had_failure {
-                    return Err(anyhow!("indexer exited unexpectedly"));
+                let mut context = self.context.lock().map_err(lock_err)?;
+                let flushed = context.state == 1;
+                let batch = context.end_block();
+                let orphans_staged = std::mem::take(&mut self.orphans_staged);
+                if self.wasmstore.data().had_failure {
+                    return Err(RuntimeError::HostError(anyhow!(
+                        "host import reported a failure"
+                    )));
+                }
+                if !flushed {
+                    return Err(RuntimeError::HostError(anyhow!("indexer exited unexpectedly")));
+                }
+                let height = context.height;
+                let pairs = self.wasmstore.data().flushed_pairs;
+                if pairs == 0
+                    && !orphans_staged
+                    && self
+                        .coalesce_empty_blocks
+                        .is_some_and(|max| (self.empty_blocks.len() as u32) + 1 < max)
+                {
+                    drop(context);
+                    // Its tip is published once `flush_empty_blocks` or a later commit writes it
+                    self.empty_blocks.push(height);
+                    self.block_pending = false;
+                    return Ok(());
+                }
+                // The held-back blocks' batches held nothing but their empty update lists
+                let mut batch = batch;
+                for &empty in &self.empty_blocks {
+                    Self::db_create_empty_update_list(&mut batch, empty)?;
                 }
+                let _span = tracing::info_span!("commit", height, pairs).entered();
+                context
+                    .db
+                    .write(batch)
+                    .map_err(|e| RuntimeError::Internal(anyhow!("Database error: {:?}", e)))?;
+                drop(context);
+                self.empty_blocks.clear();
+                self.block_pending = false;
+                self.tip.send_replace(height);
                 Ok(())
             }
-            Err(e) => Err(e).context("Error calling _start function"),
+            Err(e) => {
+                self.orphans_staged = false;
+                if let Ok(mut context) = self.context.lock() {
+                    context.end_block();
+                }
+                let state = self.wasmstore.data();
+                let trap = e.downcast_ref::<wasmtime::Trap>().copied();
+                let e = e.context("Error calling _start function");
+                Err(if state.memory_limit_hit {
+                    RuntimeError::MemoryLimit(e)
+                } else if state.read_budget_hit {
+                    RuntimeError::ReadBudget(e)
+                } else if state.write_denied {
+                    RuntimeError::WriteNotAllowed(e)
+                } else if state.write_budget_hit {
+                    RuntimeError::WriteBudget(e)
+                } else if trap == Some(wasmtime::Trap::Interrupt) {
+                    RuntimeError::Timeout(e)
+                } else if trap == Some(wasmtime::Trap::OutOfFuel) {
+                    RuntimeError::FuelExhausted(e)
+                } else if state.had_failure {
+                    RuntimeError::HostError(e)
+                } else if trap.is_some() {
+                    RuntimeError::Trap(e)
+                } else {
+                    RuntimeError::Internal(e)
+                })
+            }
         }
     }
 
@@ -414,7 +1164,7 @@ where
     ) -> Result<HashSet<Vec<u8>>> {
         let key = u32_to_vec(height)?;
         let updated_key = db_make_updated_key(&key);
-        let length = Self::db_length_at_key(context.clone(), &updated_key)? as i32;
+        let length = Self::db_length_at_key(context.clone(), &db_make_length_key(&updated_key)?)? as i32;
         let mut i: i32 = 0;
         let mut set: HashSet<Vec<u8>> = HashSet::<Vec<u8>>::new();
         
@@ -475,6 +1225,70 @@ where
         Ok(vec![])
     }
 
+    /// Returns the height of the last block that wrote `key`, None if none ever has. Versions
+    /// are appended in height order, so this is the height annotated on the newest one.
+    pub fn db_last_modified(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        key: &Vec<u8>,
+    ) -> Result<Option<u32>> {
+        let length = Self::db_length_at_key(context.clone(), &db_make_length_key(key)?)?;
+        if length == 0 {
+            return Ok(None);
+        }
+        let value = context
+            .lock()
+            .map_err(lock_err)?
+            .db
+            .get(db_make_list_key(key, length - 1)?)
+            .map_err(|e| anyhow!("Database error: {:?}", e))?
+            .ok_or_else(|| anyhow!("Missing value for key at index {}", length - 1))?;
+        if value.len() < 4 {
+            return Err(anyhow!("Invalid value length: {}", value.len()));
+        }
+        let bytes: [u8; 4] = value[(value.len() - 4)..]
+            .try_into()
+            .map_err(|e| anyhow!("Invalid value height bytes: {:?}", e))?;
+        Ok(Some(u32::from_le_bytes(bytes)))
+    }
+
+    /// Resolves up to `max_results` keys under `prefix` to their values as of `height`, in key
+    /// order. Each entry is encoded as a little-endian u32 key length, the key, then the value.
+    pub fn db_scan_prefix_at_block(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        prefix: &Vec<u8>,
+        height: u32,
+        max_results: u32,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut entries: Vec<Vec<u8>> = vec![];
+        let mut after: Option<Vec<u8>> = None;
+
+        // Keys with no value at `height` are skipped, so keep paging until enough resolve
+        while (entries.len() as u32) < max_results {
+            let wanted = (max_results - entries.len() as u32) as usize;
+            let keys = context
+                .lock()
+                .map_err(lock_err)?
+                .db
+                .scan_prefix_keys(prefix, after.as_deref(), wanted)
+                .map_err(|e| anyhow!("Database error: {:?}", e))?;
+            for key in &keys {
+                let value = Self::db_value_at_block(context.clone(), key, height)?;
+                if value.is_empty() {
+                    continue;
+                }
+                let mut entry = u32_to_vec(key.len() as u32)?;
+                entry.extend(key);
+                entry.extend(value);
+                entries.push(entry);
+            }
+            if keys.len() < wanted {
+                break;
+            }
+            after = keys.last().cloned();
+        }
+        Ok(entries)
+    }
+
     pub fn db_updated_keys_for_block_range(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         from: u32,
@@ -489,12 +1303,59 @@ where
         Ok(result)
     }
 
+    /// Copies the last value `key` was given in each block from `from_block` up, the entries
+    /// `db_rollback_key` is about to delete, to `ORPHANS_PREFIX<height>/data/<key>`. A later
+    /// reorg across the same height overwrites what an earlier one kept.
+    pub fn db_preserve_orphaned(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        key: &Vec<u8>,
+        from_block: u32,
+    ) -> Result<()> {
+        let length = Self::db_length_at_key(context.clone(), &db_make_length_key(key)?)?;
+        let mut preserved: HashSet<u32> = HashSet::new();
+        let mut index = length as i32 - 1;
+        while index >= 0 {
+            let list_key = db_make_list_key(key, index as u32)?;
+            let mut value = match context
+                .lock()
+                .map_err(lock_err)?
+                .db
+                .get(&list_key)
+                .map_err(|e| anyhow!("Database error: {:?}", e))?
+            {
+                Some(value) if value.len() >= 4 => value,
+                _ => break,
+            };
+            let height_bytes: [u8; 4] = value
+                .split_off(value.len() - 4)
+                .try_into()
+                .map_err(|e| anyhow!("Invalid value height bytes: {:?}", e))?;
+            let value_height = u32::from_le_bytes(height_bytes);
+            if value_height < from_block {
+                break;
+            }
+            // Entries are visited newest first, so the first one seen for a block is its last write
+            if preserved.insert(value_height) {
+                let mut orphan_key = format!("{}{}/data/", ORPHANS_PREFIX, value_height).into_bytes();
+                orphan_key.extend(key);
+                context
+                    .lock()
+                    .map_err(lock_err)?
+                    .batch
+                    .get_or_insert_with(T::Batch::default)
+                    .put(&orphan_key, &value);
+            }
+            index -= 1;
+        }
+        Ok(())
+    }
+
     pub fn db_rollback_key(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         key: &Vec<u8>,
         to_block: u32,
     ) -> Result<()> {
-        let length = Self::db_length_at_key(context.clone(), key)?;
+        let length = Self::db_length_at_key(context.clone(), &db_make_length_key(key)?)?;
         let mut index = length as i32 - 1;
         let mut end_length = length as i32;
         
@@ -558,7 +1419,7 @@ where
             return Ok(());
         }
         
-        let new_length_bits = u32_to_vec(length + 1)?;
+        let new_length_bits = u32_to_vec(length)?;
         guard.db
             .put(&length_key, &new_length_bits)
             .map_err(|e| anyhow!("Failed to update length: {:?}", e))?;
@@ -580,9 +1441,27 @@ where
             self.refresh_memory()?;
         }
         
+        // The copies are staged in the block's batch, so they land in the same write as the
+        // block replacing the orphaned ones
+        if self.keep_orphans {
+            for key in &set {
+                Self::db_preserve_orphaned(context.clone(), key, height)?;
+            }
+            self.orphans_staged = context.lock().map_err(lock_err)?.batch.is_some();
+        }
         for key in &set {
             Self::db_rollback_key(context.clone(), key, height)?;
         }
+        // Forget the orphaned blocks' update lists so later blocks do not see them as a reorg
+        for orphaned in height..latest {
+            let length_key = db_make_length_key(&db_make_updated_key(&u32_to_vec(orphaned)?))?;
+            context
+                .lock()
+                .map_err(lock_err)?
+                .db
+                .delete(&length_key)
+                .map_err(|e| anyhow!("Database delete error: {:?}", e))?;
+        }
         
         Ok(())
     }
@@ -593,6 +1472,7 @@ where
     ) -> Result<()> {
         let context_ref_len = context.clone();
         let context_ref_input = context.clone();
+        let context_ref_input_alloc = context.clone();
         
         linker
             .func_wrap(
@@ -658,6 +1538,34 @@ where
             )
             .map_err(|e| anyhow!("Failed to wrap __load_input: {:?}", e))?;
 
+        linker
+            .func_wrap(
+                "env",
+                "__input_alloc",
+                move |mut caller: Caller<'_, State>| -> i32 {
+                    let input = match context_ref_input_alloc.lock() {
+                        Ok(ctx) => {
+                            let mut input = ctx.height.to_le_bytes().to_vec();
+                            input.extend(&ctx.block);
+                            input
+                        }
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return 0;
+                        }
+                    };
+
+                    match write_alloc(&mut caller, &input) {
+                        Ok(ptr) => ptr,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            0
+                        }
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __input_alloc: {:?}", e))?;
+
         linker
             .func_wrap(
                 "env",
@@ -715,6 +1623,26 @@ where
         
         Ok(())
     }
+    // Like `db_append`, but takes lengths already bumped earlier in the same batch from
+    // `pending`, since a batch is not visible to reads until it is written
+    pub(crate) fn db_append_pending(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        batch: &mut T::Batch,
+        pending: &mut HashMap<Vec<u8>, u32>,
+        key: &Vec<u8>,
+        value: &Vec<u8>,
+    ) -> Result<()> {
+        let length_key = db_make_length_key(key)?;
+        let length = match pending.get(&length_key) {
+            Some(length) => *length,
+            None => Self::db_length_at_key(context.clone(), &length_key)?,
+        };
+        batch.put(&db_make_list_key(key, length)?, value);
+        batch.put(&length_key, &u32_to_vec(length + 1)?);
+        pending.insert(length_key, length + 1);
+        Ok(())
+    }
+
     pub fn db_append(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         batch: &mut T::Batch,
@@ -840,6 +1768,125 @@ where
 
         Ok(())
     }
+    // Parses the current block into State::block_txs on the first call per run
+    #[cfg(feature = "block-parser")]
+    fn block_txs<'a>(
+        context: &Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        caller: &'a mut Caller<'_, State>,
+    ) -> Result<&'a KeyValuePairs> {
+        if caller.data().block_txs.is_none() {
+            let block = context.lock().map_err(lock_err)?.block.clone();
+            let filter = caller.data().tx_filter;
+            caller.data_mut().block_txs = Some(parse_block_txs(&block, filter)?);
+        }
+        Ok(caller.data().block_txs.as_ref().unwrap())
+    }
+
+    #[cfg(feature = "block-parser")]
+    fn block_tx(
+        context: &Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        caller: &mut Caller<'_, State>,
+        index: i32,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let txs = Self::block_txs(context, caller)?;
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| txs.get(index))
+            .cloned()
+            .ok_or_else(|| anyhow!("transaction index {} out of range", index))
+    }
+
+    #[cfg(feature = "block-parser")]
+    pub fn setup_linker_block(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        linker: &mut Linker<State>,
+    ) -> Result<()> {
+        let context_count = context.clone();
+        let context_len = context.clone();
+        let context_tx = context.clone();
+        let context_txid = context.clone();
+
+        linker
+            .func_wrap(
+                "env",
+                "__block_tx_count",
+                move |mut caller: Caller<'_, State>| -> i32 {
+                    match Self::block_txs(&context_count, &mut caller) {
+                        Ok(txs) => txs.len() as i32,
+                        Err(_) => i32::MAX,
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __block_tx_count: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__block_tx_len",
+                move |mut caller: Caller<'_, State>, index: i32| -> i32 {
+                    match Self::block_tx(&context_len, &mut caller, index) {
+                        Ok((tx, _)) => tx.len() as i32,
+                        Err(_) => i32::MAX,
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __block_tx_len: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__block_tx",
+                move |mut caller: Caller<'_, State>, index: i32, out: i32| {
+                    let tx = match Self::block_tx(&context_tx, &mut caller, index) {
+                        Ok((tx, _)) => tx,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    let mem = match caller.get_export("memory").and_then(|export| export.into_memory()) {
+                        Some(memory) => memory,
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    if mem.write(&mut caller, out as usize, &tx).is_err() {
+                        caller.data_mut().had_failure = true;
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __block_tx: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__block_txid",
+                move |mut caller: Caller<'_, State>, index: i32, out: i32| {
+                    let txid = match Self::block_tx(&context_txid, &mut caller, index) {
+                        Ok((_, txid)) => txid,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    let mem = match caller.get_export("memory").and_then(|export| export.into_memory()) {
+                        Some(memory) => memory,
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    if mem.write(&mut caller, out as usize, &txid).is_err() {
+                        caller.data_mut().had_failure = true;
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __block_txid: {:?}", e))?;
+
+        Ok(())
+    }
+
     pub fn setup_linker_indexer(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         linker: &mut Linker<State>,
@@ -847,17 +1894,18 @@ where
         let context_ref = context.clone();
         let context_get = context.clone();
         let context_get_len = context.clone();
+        let context_scan = context.clone();
         
         linker
             .func_wrap(
                 "env",
                 "__flush",
-                move |mut caller: Caller<'_, State>, encoded: i32| {
+                move |mut caller: Caller<'_, State>, encoded: i32| -> Result<()> {
                     let height = match context_ref.clone().lock() {
                         Ok(ctx) => ctx.height,
                         Err(_) => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
                     };
 
@@ -866,12 +1914,12 @@ where
                             Some(memory) => memory,
                             None => {
                                 caller.data_mut().had_failure = true;
-                                return;
+                                return Ok(());
                             }
                         },
                         None => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
                     };
 
@@ -880,49 +1928,97 @@ where
                         Ok(v) => v,
                         Err(_) => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
                     };
 
-                    let mut batch = T::Batch::default();
-                    if let Err(_) = Self::db_create_empty_update_list(&mut batch, height as u32) {
-                        caller.data_mut().had_failure = true;
-                        return;
-                    }
-
                     let decoded = match KeyValueFlush::parse_from_bytes(&encoded_vec) {
                         Ok(d) => d,
                         Err(_) => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
+                        }
+                    };
+                    if caller.data().capture_flush {
+                        caller
+                            .data_mut()
+                            .staged
+                            .get_or_insert_with(Vec::new)
+                            .extend(decoded.list.iter().cloned().tuples());
+                    }
+                    if let Some(key) = caller.data().denied_write(&decoded.list) {
+                        let key = hex::encode(key);
+                        caller.data_mut().write_denied = true;
+                        return Err(anyhow!("indexer wrote {} outside its allowed write prefixes", key));
+                    }
+                    let written: u64 = caller.data().flushed_bytes
+                        + decoded.list.iter().map(|item| item.len() as u64).sum::<u64>();
+                    if let Some(max) = caller.data().max_write_bytes {
+                        if written > max {
+                            caller.data_mut().write_budget_hit = true;
+                            return Err(anyhow!(
+                                "block {} flushed {} bytes, over the budget of {} bytes per block",
+                                height,
+                                written,
+                                max
+                            ));
+                        }
+                    }
+                    caller.data_mut().flushed_bytes = written;
+
+                    // A later `__flush` in the same block appends to the batch and list lengths
+                    // the earlier ones left, so both sets of writes are committed together
+                    let staged = match context_ref.clone().lock() {
+                        Ok(mut ctx) => ctx
+                            .batch
+                            .take()
+                            .map(|batch| (batch, std::mem::take(&mut ctx.pending))),
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return Ok(());
+                        }
+                    };
+                    let (mut batch, mut pending) = match staged {
+                        Some(staged) => staged,
+                        None => {
+                            let mut batch = T::Batch::default();
+                            if Self::db_create_empty_update_list(&mut batch, height).is_err() {
+                                caller.data_mut().had_failure = true;
+                                return Ok(());
+                            }
+                            let mut pending: HashMap<Vec<u8>, u32> = HashMap::new();
+                            match u32_to_vec(height).and_then(|update_key| db_make_length_key(&update_key)) {
+                                Ok(length_key) => {
+                                    pending.insert(length_key, 0);
+                                }
+                                Err(_) => {
+                                    caller.data_mut().had_failure = true;
+                                    return Ok(());
+                                }
+                            }
+                            (batch, pending)
                         }
                     };
 
                     for (k, v) in decoded.list.iter().tuples() {
-                        let k_owned = <Vec<u8> as Clone>::clone(k);
-                        let v_owned = <Vec<u8> as Clone>::clone(v);
-
-                        if let Err(_) = Self::db_append_annotated(
-                            context_ref.clone(),
-                            &mut batch,
-                            &k_owned,
-                            &v_owned,
-                            height as u32,
-                        ) {
+                        let appended = db_annotate_value(v, height).and_then(|entry| {
+                            Self::db_append_pending(context_ref.clone(), &mut batch, &mut pending, k, &entry)
+                        });
+                        if appended.is_err() {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
 
                         match u32_to_vec(height) {
                             Ok(update_key) => {
-                                if let Err(_) = Self::db_append(context_ref.clone(), &mut batch, &update_key, &k_owned) {
+                                if Self::db_append_pending(context_ref.clone(), &mut batch, &mut pending, &update_key, k).is_err() {
                                     caller.data_mut().had_failure = true;
-                                    return;
+                                    return Ok(());
                                 }
                             }
                             Err(_) => {
                                 caller.data_mut().had_failure = true;
-                                return;
+                                return Ok(());
                             }
                         }
                     }
@@ -933,19 +2029,18 @@ where
                         height
                     );
 
+                    caller.data_mut().flushed_pairs += (decoded.list.len() / 2) as u64;
                     match context_ref.clone().lock() {
                         Ok(mut ctx) => {
                             ctx.state = 1;
-                            if let Err(_) = ctx.db.write(batch) {
-                                caller.data_mut().had_failure = true;
-                                return;
-                            }
+                            ctx.batch = Some(batch);
+                            ctx.pending = pending;
                         }
                         Err(_) => {
                             caller.data_mut().had_failure = true;
-                            return;
                         }
                     }
+                    Ok(())
                 },
             )
             .map_err(|e| anyhow!("Failed to wrap __flush: {:?}", e))?;
@@ -954,18 +2049,19 @@ where
             .func_wrap(
                 "env",
                 "__get",
-                move |mut caller: Caller<'_, State>, key: i32, value: i32| {
+                move |mut caller: Caller<'_, State>, key: i32, value: i32| -> Result<()> {
+                    caller.data_mut().charge_read()?;
                     let mem = match caller.get_export("memory") {
                         Some(export) => match export.into_memory() {
                             Some(memory) => memory,
                             None => {
                                 caller.data_mut().had_failure = true;
-                                return;
+                                return Ok(());
                             }
                         },
                         None => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
                     };
 
@@ -975,7 +2071,7 @@ where
                         Ok(ctx) => ctx.height,
                         Err(_) => {
                             caller.data_mut().had_failure = true;
-                            return;
+                            return Ok(());
                         }
                     };
 
@@ -1006,6 +2102,7 @@ where
                             }
                         }
                     };
+                    Ok(())
                 },
             )
             .map_err(|e| anyhow!("Failed to wrap __get: {:?}", e))?;
@@ -1014,23 +2111,24 @@ where
             .func_wrap(
                 "env",
                 "__get_len",
-                move |mut caller: Caller<'_, State>, key: i32| -> i32 {
+                move |mut caller: Caller<'_, State>, key: i32| -> Result<i32> {
+                    caller.data_mut().charge_read()?;
                     let mem = match caller.get_export("memory") {
                         Some(export) => match export.into_memory() {
                             Some(memory) => memory,
-                            None => return i32::MAX,
+                            None => return Ok(i32::MAX),
                         },
-                        None => return i32::MAX,
+                        None => return Ok(i32::MAX),
                     };
 
                     let data = mem.data(&caller);
                     let key_vec_result = try_read_arraybuffer_as_vec(data, key);
                     let height = match context_get_len.clone().lock() {
                         Ok(ctx) => ctx.height,
-                        Err(_) => return i32::MAX,
+                        Err(_) => return Ok(i32::MAX),
                     };
 
-                    match key_vec_result {
+                    Ok(match key_vec_result {
                         Ok(key_vec) => {
                             match Self::db_value_at_block(context_get_len.clone(), &key_vec, height) {
                                 Ok(value) => value.len() as i32,
@@ -1038,11 +2136,631 @@ where
                             }
                         }
                         Err(_) => i32::MAX,
-                    }
+                    })
                 },
             )
             .map_err(|e| anyhow!("Failed to wrap __get_len: {:?}", e))?;
 
+        linker
+            .func_wrap(
+                "env",
+                "__scan_start",
+                move |mut caller: Caller<'_, State>, prefix: i32, max_results: i32| -> Result<i32> {
+                    caller.data_mut().charge_read()?;
+                    let mem = match caller.get_export("memory") {
+                        Some(export) => match export.into_memory() {
+                            Some(memory) => memory,
+                            None => return Ok(i32::MAX),
+                        },
+                        None => return Ok(i32::MAX),
+                    };
+
+                    let data = mem.data(&caller);
+                    let prefix_vec = match try_read_arraybuffer_as_vec(data, prefix) {
+                        Ok(v) => v,
+                        Err(_) => return Ok(i32::MAX),
+                    };
+                    let height = match context_scan.clone().lock() {
+                        Ok(ctx) => ctx.height,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return Ok(i32::MAX);
+                        }
+                    };
+
+                    Ok(match Self::db_scan_prefix_at_block(
+                        context_scan.clone(),
+                        &prefix_vec,
+                        height,
+                        std::cmp::max(max_results, 0) as u32,
+                    ) {
+                        Ok(entries) => {
+                            let scans = &mut caller.data_mut().scans;
+                            scans.push(entries.into());
+                            (scans.len() - 1) as i32
+                        }
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            i32::MAX
+                        }
+                    })
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __scan_start: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__scan_next_len",
+                |caller: Caller<'_, State>, handle: i32| -> i32 {
+                    match caller.data().scans.get(handle as usize) {
+                        Some(scan) => scan.front().map(|entry| entry.len() as i32).unwrap_or(0),
+                        None => i32::MAX,
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __scan_next_len: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__scan_next",
+                |mut caller: Caller<'_, State>, handle: i32, value: i32| {
+                    let mem = match caller.get_export("memory") {
+                        Some(export) => match export.into_memory() {
+                            Some(memory) => memory,
+                            None => {
+                                caller.data_mut().had_failure = true;
+                                return;
+                            }
+                        },
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+
+                    let entry = match caller.data_mut().scans.get_mut(handle as usize) {
+                        Some(scan) => scan.pop_front(),
+                        None => None,
+                    };
+                    match entry {
+                        Some(entry) => {
+                            if mem.write(&mut caller, value as usize, entry.as_slice()).is_err() {
+                                caller.data_mut().had_failure = true;
+                            }
+                        }
+                        None => {
+                            caller.data_mut().had_failure = true;
+                        }
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __scan_next: {:?}", e))?;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+
+    // Height 0 flushes ["k", "a"] then ["k", "b"], height 1 flushes ["k", "a"] and traps, heights
+    // from 10 that are not a multiple of 4 flush nothing, and any other height flushes ["j", "b"]
+    const INDEXER: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01a")
+      (data (i32.const 196) "\06\00\00\00\0a\01k\0a\01b")
+      (data (i32.const 296) "\06\00\00\00\0a\01j\0a\01b")
+      (data (i32.const 396) "\00\00\00\00")
+      (func (export "_start") (local $height i32)
+        (call $load (i32.const 1000))
+        (local.set $height (i32.load (i32.const 1000)))
+        (if (i32.eqz (local.get $height))
+          (then
+            (call $flush (i32.const 100))
+            (call $flush (i32.const 200))
+            (return)))
+        (if (i32.eq (local.get $height) (i32.const 1))
+          (then
+            (call $flush (i32.const 100))
+            (unreachable)))
+        (if (i32.and
+              (i32.ge_u (local.get $height) (i32.const 10))
+              (i32.ne (i32.rem_u (local.get $height) (i32.const 4)) (i32.const 0)))
+          (then
+            (call $flush (i32.const 400))
+            (return)))
+        (call $flush (i32.const 300))))"#;
+
+    // Never returns from `_start`
+    const STALL: &str = r#"(module
+      (memory (export "memory") 1)
+      (func (export "_start") (loop $spin (br $spin))))"#;
+
+    // Flushes ["k", <first byte of the block>], or nothing when that byte is 0
+    const FIRST_BYTE: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01\00")
+      (data (i32.const 196) "\00\00\00\00")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (if (i32.eqz (i32.load8_u (i32.const 1004)))
+          (then
+            (call $flush (i32.const 200))
+            (return)))
+        (i32.store8 (i32.const 105) (i32.load8_u (i32.const 1004)))
+        (call $flush (i32.const 100))))"#;
+
+    // Starts two scans of "k" for up to two keys each, then flushes nothing
+    const SCAN: &str = r#"(module
+      (import "env" "__scan_start" (func $scan (param i32 i32) (result i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\01\00\00\00k")
+      (data (i32.const 196) "\00\00\00\00")
+      (func (export "_start")
+        (drop (call $scan (i32.const 100) (i32.const 2)))
+        (drop (call $scan (i32.const 100) (i32.const 2)))
+        (call $flush (i32.const 200))))"#;
+
+    // Returns without flushing, except that a first block byte of 1 grows memory past its maximum
+    // and then traps
+    const NO_FLUSH: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (memory (export "memory") 1 1)
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (if (i32.eq (i32.load8_u (i32.const 1004)) (i32.const 1))
+          (then
+            (drop (memory.grow (i32.const 1)))
+            (unreachable)))))"#;
+
+    // FIRST_BYTE, reading its input through `__input_alloc` into memory from its own allocator
+    const ALLOC_INPUT: &str = r#"(module
+      (import "env" "__input_alloc" (func $input (result i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01\00")
+      (func (export "__alloc") (param $size i32) (result i32) (i32.const 2000))
+      (func (export "_start")
+        (i32.store8 (i32.const 105) (i32.load8_u (i32.add (call $input) (i32.const 4))))
+        (call $flush (i32.const 100))))"#;
+
+    fn load_wat(
+        wat: &str,
+        store: MemStoreAdapter,
+        block_timeout: Option<Duration>,
+    ) -> MetashrewRuntime<MemStoreAdapter> {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        std::fs::write(&path, wat).unwrap();
+        MetashrewRuntime::load_with_timeout(path, store, block_timeout).unwrap()
+    }
+
+    fn load(store: MemStoreAdapter) -> MetashrewRuntime<MemStoreAdapter> {
+        load_wat(INDEXER, store, None)
+    }
+
+    fn execute_at(runtime: &mut MetashrewRuntime<MemStoreAdapter>, height: u32) -> Result<(), RuntimeError> {
+        runtime.context.lock().unwrap().begin_block(height, vec![0; 80]);
+        runtime.execute()
+    }
+
+    fn list(store: &MemStoreAdapter, key: &[u8]) -> Vec<Vec<u8>> {
+        let map = store.map.lock().unwrap();
+        let key = key.to_vec();
+        let length = match map.get(&db_make_length_key(&key).unwrap()) {
+            Some(length) => u32::from_le_bytes(length.as_slice().try_into().unwrap()),
+            None => return vec![],
+        };
+        (0..length)
+            .map(|i| map[&db_make_list_key(&key, i).unwrap()].clone())
+            .collect()
+    }
+
+    fn annotated(value: &[u8], height: u32) -> Vec<u8> {
+        db_annotate_value(&value.to_vec(), height).unwrap()
+    }
+
+    // Stores `key`'s versions, oldest first, in the list layout `__flush` writes
+    fn write_list(store: &MemStoreAdapter, key: &[u8], versions: &[(&[u8], u32)]) {
+        let mut map = store.map.lock().unwrap();
+        let key = key.to_vec();
+        for (index, (value, height)) in versions.iter().enumerate() {
+            map.insert(db_make_list_key(&key, index as u32).unwrap(), annotated(value, *height));
+        }
+        map.insert(db_make_length_key(&key).unwrap(), (versions.len() as u32).to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn last_modified_is_the_height_of_the_newest_write() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load_wat(FIRST_BYTE, store, None);
+        let key = b"k".to_vec();
+        assert_eq!(MetashrewRuntime::db_last_modified(runtime.context.clone(), &key).unwrap(), None);
+        // Block 13 flushes nothing, so the key last changed at 12
+        for (height, first_byte) in [(5, 1), (12, 2), (13, 0)] {
+            runtime.context.lock().unwrap().begin_block(height, vec![first_byte; 80]);
+            runtime.execute().unwrap();
+        }
+        assert_eq!(MetashrewRuntime::db_last_modified(runtime.context.clone(), &key).unwrap(), Some(12));
+        assert_eq!(
+            MetashrewRuntime::db_last_modified(runtime.context.clone(), &b"j".to_vec()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn byte_returning_imports_write_through_the_guest_allocator() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load_wat(ALLOC_INPUT, store.clone(), None);
+        runtime.context.lock().unwrap().begin_block(0, vec![7; 80]);
+        runtime.execute().unwrap();
+        assert_eq!(list(&store, b"k"), vec![annotated(&[7], 0)]);
+
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        std::fs::write(&path, ALLOC_INPUT.replace("(func (export \"__alloc\")", "(func")).unwrap();
+        let error = MetashrewRuntime::load(path, MemStoreAdapter::new()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "module imports __input_alloc but does not export __alloc(size: i32) -> i32"
+        );
+    }
+
+    // Flushes ["n", <transaction count>] and ["k", <txid of the last transaction>], both read
+    // through the block parser imports
+    #[cfg(feature = "block-parser")]
+    const BLOCK_TXS: &str = r#"(module
+      (import "env" "__block_tx_count" (func $count (result i32)))
+      (import "env" "__block_txid" (func $txid (param i32 i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\2b\00\00\00\0a\01n\0a\01\00\0a\01k\0a\20")
+      (func (export "_start") (local $count i32)
+        (local.set $count (call $count))
+        (i32.store8 (i32.const 105) (local.get $count))
+        (call $txid (i32.sub (local.get $count) (i32.const 1)) (i32.const 111))
+        (call $flush (i32.const 100))))"#;
+
+    #[cfg(feature = "block-parser")]
+    #[test]
+    fn block_parser_reports_the_transactions_to_the_guest() {
+        use bitcoin::hashes::Hash;
+        let mut block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        for lock_time in 1..3 {
+            let mut tx = block.txdata[0].clone();
+            tx.lock_time = bitcoin::absolute::LockTime::from_consensus(lock_time);
+            block.txdata.push(tx);
+        }
+        let store = MemStoreAdapter::new();
+        let mut runtime = load_wat(BLOCK_TXS, store.clone(), None);
+        runtime.context.lock().unwrap().begin_block(0, bitcoin::consensus::serialize(&block));
+        runtime.execute().unwrap();
+        assert_eq!(list(&store, b"n"), vec![annotated(&[3], 0)]);
+        let txid = block.txdata[2].txid().to_byte_array();
+        assert_eq!(list(&store, b"k"), vec![annotated(&txid, 0)]);
+
+        // An unparseable block reports i32::MAX transactions, and reading one past the end fails
+        runtime.context.lock().unwrap().begin_block(1, vec![0; 80]);
+        assert!(runtime.execute().is_err());
+    }
+
+    #[cfg(feature = "block-parser")]
+    #[test]
+    fn tx_filter_shows_the_guest_only_matching_transactions() {
+        use bitcoin::hashes::Hash;
+        let mut block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        // After the coinbase: OP_RETURN, Taproot, OP_RETURN
+        let op_return = bitcoin::ScriptBuf::from_bytes(vec![0x6a, 0x01, 0x01]);
+        let taproot = bitcoin::ScriptBuf::from_bytes([&[0x51, 0x20][..], &[7; 32]].concat());
+        for (lock_time, script) in [(1, &op_return), (2, &taproot), (3, &op_return)] {
+            let mut tx = block.txdata[0].clone();
+            tx.lock_time = bitcoin::absolute::LockTime::from_consensus(lock_time);
+            tx.output[0].script_pubkey = script.clone();
+            block.txdata.push(tx);
+        }
+        let txid = |index: usize| block.txdata[index].txid().to_byte_array();
+
+        for (filter, count, last, height) in [(TxFilter::OpReturn, 2, 3, 0), (TxFilter::Taproot, 1, 2, 1)] {
+            let store = MemStoreAdapter::new();
+            let mut runtime = load_wat(BLOCK_TXS, store.clone(), None);
+            runtime.tx_filter = Some(filter);
+            runtime.context.lock().unwrap().begin_block(height, bitcoin::consensus::serialize(&block));
+            runtime.execute().unwrap();
+            assert_eq!(list(&store, b"n"), vec![annotated(&[count], height)]);
+            assert_eq!(list(&store, b"k"), vec![annotated(&txid(last), height)]);
+        }
+    }
+
+    #[test]
+    fn reload_swaps_the_indexer_only_between_blocks() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load(store.clone());
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("first_byte.wat");
+        std::fs::write(&path, FIRST_BYTE).unwrap();
+        let block = |runtime: &mut MetashrewRuntime<MemStoreAdapter>, height: u32, byte: u8| {
+            runtime.context.lock().unwrap().begin_block(height, vec![byte; 80]);
+            runtime.run()
+        };
+
+        // Block 1 traps and is still waiting to be retried
+        assert!(block(&mut runtime, 1, 9).is_err());
+        assert_eq!(
+            runtime.reload(path.clone()).unwrap_err().to_string(),
+            "block 1 has not committed; refusing to swap the indexer mid-block"
+        );
+        block(&mut runtime, 2, 9).unwrap();
+        assert_eq!(list(&store, b"j"), vec![annotated(b"b", 2)]);
+
+        let broken = dir.path().join("broken.wat");
+        std::fs::write(&broken, r#"(module (memory (export "memory") 1))"#).unwrap();
+        assert!(runtime.reload(broken).is_err());
+        assert!(runtime.reload(path.clone()).unwrap());
+        assert!(!runtime.reload(path).unwrap());
+        block(&mut runtime, 3, 9).unwrap();
+        assert_eq!(list(&store, b"k").last(), Some(&annotated(&[9], 3)));
+        assert_eq!(list(&store, b"j").len(), 1);
+    }
+
+    #[test]
+    fn load_names_every_missing_export() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        let load_error = |wat: &str| {
+            std::fs::write(&path, wat).unwrap();
+            MetashrewRuntime::load(path.clone(), MemStoreAdapter::new()).err().unwrap().to_string()
+        };
+        assert_eq!(
+            load_error(r#"(module (memory (export "memory") 1) (func (export "start")))"#),
+            "module is missing required exports: _start() entrypoint"
+        );
+        assert_eq!(
+            load_error(r#"(module (memory 1) (func (export "_start") (param i32)))"#),
+            "module is missing required exports: _start() entrypoint, memory"
+        );
+    }
+
+    #[test]
+    fn second_flush_appends_to_the_first() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load(store.clone());
+        execute_at(&mut runtime, 0).unwrap();
+        assert_eq!(runtime.flushed_pairs(), 2);
+        assert_eq!(list(&store, b"k"), vec![annotated(b"a", 0), annotated(b"b", 0)]);
+        assert_eq!(list(&store, &0u32.to_le_bytes()), vec![b"k".to_vec(), b"k".to_vec()]);
+    }
+
+    #[test]
+    fn failed_block_leaks_nothing_into_the_next() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load(store.clone());
+        assert!(matches!(execute_at(&mut runtime, 1), Err(RuntimeError::Trap(_))));
+        execute_at(&mut runtime, 2).unwrap();
+        assert_eq!(runtime.flushed_pairs(), 1);
+        assert!(list(&store, b"k").is_empty());
+        assert!(list(&store, &1u32.to_le_bytes()).is_empty());
+        assert_eq!(list(&store, b"j"), vec![annotated(b"b", 2)]);
+        assert_eq!(list(&store, &2u32.to_le_bytes()), vec![b"j".to_vec()]);
+        assert_eq!(runtime.context.lock().unwrap().state, 0);
+    }
+
+    #[test]
+    fn tip_updates_observe_each_committed_height_in_order() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load_wat(FIRST_BYTE, store.clone(), None);
+        let mut tip = runtime.tip_updates();
+        assert!(!tip.has_changed().unwrap());
+
+        let mut observed = vec![];
+        for height in 0..4u32 {
+            runtime.context.lock().unwrap().begin_block(height, vec![height as u8 + 1]);
+            runtime.run().unwrap();
+            assert!(tip.has_changed().unwrap());
+            observed.push(*tip.borrow_and_update());
+            // Sent after the batch is written, so the block's value is already readable
+            assert_eq!(list(&store, b"k").len(), height as usize + 1);
+        }
+        assert_eq!(observed, vec![0, 1, 2, 3]);
+
+        // A block that fails is not announced
+        let mut runtime = load(MemStoreAdapter::new());
+        let mut tip = runtime.tip_updates();
+        execute_at(&mut runtime, 0).unwrap();
+        assert_eq!(*tip.borrow_and_update(), 0);
+        assert!(execute_at(&mut runtime, 1).is_err());
+        assert!(!tip.has_changed().unwrap());
+    }
+
+    #[test]
+    fn coalesced_blocks_publish_their_tip_once_written() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load(store.clone());
+        runtime.coalesce_empty_blocks = Some(3);
+        let tip = runtime.tip_updates();
+        let resume_at = |runtime: &MetashrewRuntime<MemStoreAdapter>| {
+            MetashrewRuntime::check_latest_block_for_reorg(runtime.context.clone(), 10).unwrap()
+        };
+
+        execute_at(&mut runtime, 10).unwrap();
+        execute_at(&mut runtime, 11).unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (0, 10));
+        execute_at(&mut runtime, 12).unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (12, 13));
+
+        // The third empty block in a row commits the run
+        execute_at(&mut runtime, 13).unwrap();
+        execute_at(&mut runtime, 14).unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (12, 13));
+        execute_at(&mut runtime, 15).unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (15, 16));
+
+        // A restart before the flush resumes from the held block rather than skipping it
+        execute_at(&mut runtime, 16).unwrap();
+        execute_at(&mut runtime, 17).unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (16, 17));
+        runtime.flush_empty_blocks().unwrap();
+        assert_eq!((*tip.borrow(), resume_at(&runtime)), (17, 18));
+        assert!(list(&store, &17u32.to_le_bytes()).is_empty());
+    }
+
+    #[test]
+    fn block_timeout_interrupts_each_stalled_block() {
+        let timeout = Duration::from_millis(200);
+        let mut runtime = load_wat(STALL, MemStoreAdapter::new(), Some(timeout));
+        for height in 0..2 {
+            let started = std::time::Instant::now();
+            assert!(matches!(execute_at(&mut runtime, height), Err(RuntimeError::Timeout(_))));
+            let elapsed = started.elapsed();
+            assert!(elapsed >= timeout && elapsed < timeout * 5, "{:?}", elapsed);
+        }
+    }
+
+    #[test]
+    fn block_timeout_needs_a_runtime_loaded_with_one() {
+        let mut runtime = load(MemStoreAdapter::new());
+        runtime.block_timeout = Some(Duration::from_millis(200));
+        assert!(matches!(execute_at(&mut runtime, 0), Err(RuntimeError::Internal(_))));
+        runtime.block_timeout = None;
+        execute_at(&mut runtime, 0).unwrap();
+    }
+
+    #[test]
+    fn reorg_keeps_orphaned_values_apart_from_the_canonical_ones() {
+        for keep_orphans in [false, true] {
+            let store = MemStoreAdapter::new();
+            let mut runtime = load_wat(FIRST_BYTE, store.clone(), None);
+            runtime.keep_orphans = keep_orphans;
+            for (height, byte) in [(0, 1), (1, 2), (2, 3)] {
+                runtime.context.lock().unwrap().begin_block(height, vec![byte]);
+                runtime.run().unwrap();
+            }
+            // Height 1 arrives again on another fork, reverting 1 and 2
+            runtime.context.lock().unwrap().begin_block(1, vec![9]);
+            runtime.run().unwrap();
+
+            assert_eq!(list(&store, b"k"), vec![annotated(&[1], 0), annotated(&[9], 1)]);
+            assert!(list(&store, &2u32.to_le_bytes()).is_empty());
+            let orphans = store.map.lock().unwrap();
+            let orphan = |height: u32| {
+                let key = format!("{}{}/data/k", ORPHANS_PREFIX, height);
+                orphans.get(key.as_bytes()).cloned()
+            };
+            if keep_orphans {
+                assert_eq!((orphan(0), orphan(1), orphan(2)), (None, Some(vec![2]), Some(vec![3])));
+            } else {
+                assert_eq!((orphan(1), orphan(2)), (None, None));
+            }
+        }
+    }
+
+    #[test]
+    fn orphans_are_written_with_an_empty_replacement_block() {
+        let store = MemStoreAdapter::new();
+        let mut runtime = load_wat(FIRST_BYTE, store.clone(), None);
+        runtime.keep_orphans = true;
+        runtime.coalesce_empty_blocks = Some(3);
+        for (height, byte) in [(0, 1), (1, 2), (1, 0)] {
+            runtime.context.lock().unwrap().begin_block(height, vec![byte]);
+            runtime.run().unwrap();
+        }
+        // The replacement flushed nothing, but the block is not held back with its orphan copy
+        let key = format!("{}1/data/k", ORPHANS_PREFIX);
+        assert_eq!(store.map.lock().unwrap().get(key.as_bytes()).cloned(), Some(vec![2]));
+        assert_eq!(*runtime.tip_updates().borrow(), 1);
+    }
+
+    #[test]
+    fn each_failure_maps_to_its_variant() {
+        let mut runtime = load(MemStoreAdapter::new());
+        let failure = execute_at(&mut runtime, 1).unwrap_err();
+        assert!(matches!(failure, RuntimeError::Trap(_)) && failure.to_string().starts_with("trap: "));
+
+        runtime.write_prefixes = Some(vec![b"j".to_vec()]);
+        assert!(matches!(execute_at(&mut runtime, 0), Err(RuntimeError::WriteNotAllowed(_))));
+        runtime.write_prefixes = None;
+        runtime.max_write_bytes_per_block = Some(1);
+        assert!(matches!(execute_at(&mut runtime, 0), Err(RuntimeError::WriteBudget(_))));
+
+        let mut runtime = load_wat(NO_FLUSH, MemStoreAdapter::new(), None);
+        let run = |runtime: &mut MetashrewRuntime<MemStoreAdapter>, byte: u8| {
+            runtime.context.lock().unwrap().begin_block(0, vec![byte]);
+            runtime.execute()
+        };
+        assert!(matches!(run(&mut runtime, 0), Err(RuntimeError::HostError(_))));
+        assert!(matches!(run(&mut runtime, 1), Err(RuntimeError::MemoryLimit(_))));
+    }
+
+    #[test]
+    fn is_deterministic_per_variant() {
+        let deterministic = [
+            RuntimeError::Trap(anyhow!("")),
+            RuntimeError::FuelExhausted(anyhow!("")),
+            RuntimeError::ReadBudget(anyhow!("")),
+            RuntimeError::WriteNotAllowed(anyhow!("")),
+            RuntimeError::WriteBudget(anyhow!("")),
+        ];
+        let transient = [
+            RuntimeError::MemoryLimit(anyhow!("")),
+            RuntimeError::Timeout(anyhow!("")),
+            RuntimeError::HostError(anyhow!("")),
+            RuntimeError::Internal(anyhow!("")),
+        ];
+        assert!(deterministic.iter().all(RuntimeError::is_deterministic));
+        assert!(!transient.iter().any(RuntimeError::is_deterministic));
+    }
+
+    #[test]
+    fn begin_block_discards_staged_writes() {
+        let mut context = MetashrewRuntimeContext::new(MemStoreAdapter::new(), 0, vec![]);
+        let mut batch = <MemStoreAdapter as KeyValueStoreLike>::Batch::default();
+        batch.put(b"k", b"v");
+        context.batch = Some(batch);
+        context.pending.insert(b"k".to_vec(), 1);
+        context.state = 1;
+        context.begin_block(1, vec![1]);
+        assert_eq!((context.height, context.block.clone(), context.state), (1, vec![1], 0));
+        assert!(context.batch.is_none());
+        assert!(context.pending.is_empty());
+        assert!(context.end_block().0.is_empty());
+    }
+
+    #[test]
+    fn scan_pages_past_keys_with_no_value_yet() {
+        let store = MemStoreAdapter::new();
+        write_list(&store, b"ka", &[(b"1", 0)]);
+        write_list(&store, b"kb", &[(b"2", 5)]);
+        write_list(&store, b"kc", &[(b"3", 0), (b"4", 5)]);
+        write_list(&store, b"kd", &[(b"5", 1)]);
+        write_list(&store, b"l", &[(b"6", 0)]);
+        let context = Arc::new(Mutex::new(MetashrewRuntimeContext::new(store, 0, vec![])));
+        let scan = |height: u32, max_results: u32| {
+            MetashrewRuntime::db_scan_prefix_at_block(context.clone(), &b"k".to_vec(), height, max_results)
+                .unwrap()
+        };
+        let entry = |key: &[u8], value: &[u8]| [&(key.len() as u32).to_le_bytes()[..], key, value].concat();
+
+        // kb has no value at height 1, so the second page supplies kd
+        assert_eq!(scan(1, 3), vec![entry(b"ka", b"1"), entry(b"kc", b"3"), entry(b"kd", b"5")]);
+        assert_eq!(scan(5, 2), vec![entry(b"ka", b"1"), entry(b"kb", b"2")]);
+        assert_eq!(scan(5, 10).len(), 4);
+        assert!(scan(5, 0).is_empty());
+    }
+
+    #[test]
+    fn each_scan_counts_against_the_read_budget() {
+        let mut runtime = load_wat(SCAN, MemStoreAdapter::new(), None);
+        runtime.max_reads_per_block = Some(1);
+        assert!(matches!(execute_at(&mut runtime, 0), Err(RuntimeError::ReadBudget(_))));
+        runtime.max_reads_per_block = Some(2);
+        execute_at(&mut runtime, 0).unwrap();
+    }
+}
@@ -0,0 +1,555 @@
+use crate::mem::MemStoreBatch;
+use crate::runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike, MetashrewRuntime, RuntimeError};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Every message is one frame: a tag byte, a little-endian u32 payload length and the payload.
+// Byte strings inside payloads are a little-endian u32 length followed by the bytes.
+//
+// Supervisor to worker:
+/// Height as a little-endian u32, then the raw block.
+pub const FRAME_BLOCK: u8 = 1;
+/// Answer to `FRAME_GET`: a 0 byte for a missing key, else a 1 byte and the value.
+pub const FRAME_VALUE: u8 = 2;
+/// Answer to `FRAME_SCAN`: each pair as its key then its value.
+pub const FRAME_PAIRS: u8 = 3;
+// Worker to supervisor, while it runs a block:
+/// A store read of the key in the payload.
+pub const FRAME_GET: u8 = 16;
+/// A prefix scan of the prefix in the payload.
+pub const FRAME_SCAN: u8 = 17;
+/// The block ran: the flushed pair count as a little-endian u64, then each staged write as its
+/// key, a 0 byte for a delete or a 1 byte and the value.
+pub const FRAME_DONE: u8 = 18;
+/// The block failed: the `RuntimeError` kind as a byte, then the message.
+pub const FRAME_FAILED: u8 = 19;
+
+// Reject frames past this before allocating for them, since the worker is not trusted
+const MAX_FRAME_LEN: u32 = 1 << 30;
+// Runs of one block, counting the first, before a worker that keeps dying is given up on
+const SANDBOX_ATTEMPTS: u32 = 3;
+
+pub fn write_frame<W: Write + ?Sized>(out: &mut W, tag: u8, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len()).context("frame too large")?;
+    out.write_all(&[tag])?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(payload)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads one frame, or `None` if the stream ended cleanly before it.
+pub fn read_frame<R: Read + ?Sized>(input: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut tag = [0u8; 1];
+    if input.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_LEN));
+    }
+    let mut payload = vec![0u8; len as usize];
+    input.read_exact(&mut payload)?;
+    Ok(Some((tag[0], payload)))
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(payload: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if payload.len() < len {
+        return Err(anyhow!("truncated frame payload"));
+    }
+    let (head, rest) = payload.split_at(len);
+    *payload = rest;
+    Ok(head)
+}
+
+fn take_bytes(payload: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = u32::from_le_bytes(take(payload, 4)?.try_into()?);
+    Ok(take(payload, len as usize)?.to_vec())
+}
+
+fn encode_error(err: &RuntimeError) -> Vec<u8> {
+    let kind = match err {
+        RuntimeError::Trap(_) => 0,
+        RuntimeError::FuelExhausted(_) => 1,
+        RuntimeError::MemoryLimit(_) => 2,
+        RuntimeError::Timeout(_) => 3,
+        RuntimeError::ReadBudget(_) => 4,
+        RuntimeError::HostError(_) => 5,
+        RuntimeError::Internal(_) => 6,
+        RuntimeError::WriteNotAllowed(_) => 7,
+        RuntimeError::WriteBudget(_) => 8,
+    };
+    let mut payload = vec![kind];
+    payload.extend_from_slice(format!("{:#}", err.inner()).as_bytes());
+    payload
+}
+
+fn decode_error(payload: &[u8]) -> RuntimeError {
+    let message = anyhow!("{}", String::from_utf8_lossy(payload.get(1..).unwrap_or_default()));
+    match payload.first() {
+        Some(0) => RuntimeError::Trap(message),
+        Some(1) => RuntimeError::FuelExhausted(message),
+        Some(2) => RuntimeError::MemoryLimit(message),
+        Some(3) => RuntimeError::Timeout(message),
+        Some(4) => RuntimeError::ReadBudget(message),
+        Some(5) => RuntimeError::HostError(message),
+        Some(7) => RuntimeError::WriteNotAllowed(message),
+        Some(8) => RuntimeError::WriteBudget(message),
+        _ => RuntimeError::Internal(message),
+    }
+}
+
+// Writes a worker has staged for the current block, `None` marking a delete
+type Staged = Arc<Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>>;
+
+struct WorkerPipe {
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+}
+
+impl WorkerPipe {
+    fn request(&mut self, tag: u8, payload: &[u8], reply: u8) -> Result<Vec<u8>> {
+        write_frame(&mut self.output, tag, payload)?;
+        match read_frame(&mut self.input)? {
+            Some((tag, payload)) if tag == reply => Ok(payload),
+            Some((tag, _)) => Err(anyhow!("expected frame {} from the supervisor, got {}", reply, tag)),
+            None => Err(anyhow!("supervisor closed the sandbox pipe")),
+        }
+    }
+}
+
+/// The store a sandboxed indexer sees: reads are answered by the supervisor over the pipe and
+/// writes are staged in the worker until the block is done. Clones share the pipe and staging.
+#[derive(Clone)]
+pub struct SandboxStore {
+    pipe: Arc<Mutex<WorkerPipe>>,
+    staged: Staged,
+}
+
+impl KeyValueStoreLike for SandboxStore {
+    type Error = anyhow::Error;
+    type Batch = MemStoreBatch;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        let mut staged = self.staged.lock().unwrap();
+        for (key, value) in batch.0 {
+            staged.insert(key, Some(value));
+        }
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.staged.lock().unwrap().get(key.as_ref()) {
+            return Ok(value.clone());
+        }
+        let payload = self
+            .pipe
+            .lock()
+            .unwrap()
+            .request(FRAME_GET, key.as_ref(), FRAME_VALUE)?;
+        Ok(match payload.split_first() {
+            Some((1, value)) => Some(value.to_vec()),
+            _ => None,
+        })
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.staged.lock().unwrap().insert(key.as_ref().to_vec(), None);
+        Ok(())
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.staged
+            .lock()
+            .unwrap()
+            .insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+        Ok(())
+    }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let prefix = prefix.as_ref();
+        let payload = self
+            .pipe
+            .lock()
+            .unwrap()
+            .request(FRAME_SCAN, prefix, FRAME_PAIRS)?;
+        let mut pairs = BTreeMap::new();
+        let mut rest = payload.as_slice();
+        while !rest.is_empty() {
+            let key = take_bytes(&mut rest)?;
+            pairs.insert(key, take_bytes(&mut rest)?);
+        }
+        for (key, value) in self.staged.lock().unwrap().range(prefix.to_vec()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            match value {
+                Some(value) => pairs.insert(key.clone(), value.clone()),
+                None => pairs.remove(key),
+            };
+        }
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// The worker side of the sandbox: loads the indexer against a `SandboxStore` and runs each
+/// block the supervisor sends, answering with the staged writes or the failure.
+pub struct SandboxWorker {
+    pub runtime: MetashrewRuntime<SandboxStore>,
+    pipe: Arc<Mutex<WorkerPipe>>,
+    staged: Staged,
+}
+
+impl SandboxWorker {
+    pub fn new(
+        indexer: PathBuf,
+        input: impl Read + Send + 'static,
+        output: impl Write + Send + 'static,
+        block_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let pipe = Arc::new(Mutex::new(WorkerPipe {
+            input: Box::new(input),
+            output: Box::new(output),
+        }));
+        let staged = Arc::new(Mutex::new(BTreeMap::new()));
+        let store = SandboxStore {
+            pipe: pipe.clone(),
+            staged: staged.clone(),
+        };
+        Ok(Self {
+            runtime: MetashrewRuntime::load_with_timeout(indexer, store, block_timeout)?,
+            pipe,
+            staged,
+        })
+    }
+
+    /// Serves blocks until the supervisor closes the pipe.
+    pub fn serve(&mut self) -> Result<()> {
+        loop {
+            let frame = read_frame(&mut self.pipe.lock().unwrap().input)?;
+            let payload = match frame {
+                Some((FRAME_BLOCK, payload)) => payload,
+                Some((tag, _)) => return Err(anyhow!("expected a block frame, got {}", tag)),
+                None => return Ok(()),
+            };
+            let mut rest = payload.as_slice();
+            let height = u32::from_le_bytes(take(&mut rest, 4)?.try_into()?);
+            {
+                let mut context = self.runtime.context.lock().unwrap();
+                context.begin_block(height, rest.to_vec());
+            }
+            let result = self.runtime.execute();
+            let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+            let (tag, reply) = match result {
+                Ok(()) => {
+                    let mut reply = self.runtime.flushed_pairs().to_le_bytes().to_vec();
+                    for (key, value) in staged.iter() {
+                        put_bytes(&mut reply, key);
+                        match value {
+                            Some(value) => {
+                                reply.push(1);
+                                put_bytes(&mut reply, value);
+                            }
+                            None => reply.push(0),
+                        }
+                    }
+                    (FRAME_DONE, reply)
+                }
+                Err(e) => {
+                    self.runtime.refresh_memory()?;
+                    (FRAME_FAILED, encode_error(&e))
+                }
+            };
+            write_frame(&mut self.pipe.lock().unwrap().output, tag, &reply)?;
+        }
+    }
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Worker {
+    fn stop(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// How a block run ended when the worker itself held up
+enum Outcome {
+    Done(u64),
+    Failed(RuntimeError),
+    Store(anyhow::Error),
+}
+
+/// The supervisor side of the sandbox: runs blocks in a worker process spawned from `spawn`,
+/// serving its reads from and applying its writes to the real store. A worker that dies or
+/// breaks the protocol mid-block is replaced and the block is rerun from scratch; nothing it
+/// staged reaches the store before it reports the block done.
+pub struct Sandbox {
+    spawn: Box<dyn FnMut() -> Command + Send>,
+    worker: Option<Worker>,
+    /// Number of workers replaced after dying.
+    pub restarts: u32,
+    /// Number of key/value pairs flushed by the last block that ran.
+    pub flushed_pairs: u64,
+}
+
+impl Sandbox {
+    /// `spawn` builds the command that starts a worker; its stdin and stdout are the pipe.
+    pub fn new(spawn: impl FnMut() -> Command + Send + 'static) -> Self {
+        Self {
+            spawn: Box::new(spawn),
+            worker: None,
+            restarts: 0,
+            flushed_pairs: 0,
+        }
+    }
+
+    fn worker(&mut self) -> Result<&mut Worker> {
+        if self.worker.is_none() {
+            let mut child = (self.spawn)()
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .context("Failed to spawn sandbox worker")?;
+            let stdin = child.stdin.take().ok_or_else(|| anyhow!("worker has no stdin"))?;
+            let stdout = child.stdout.take().ok_or_else(|| anyhow!("worker has no stdout"))?;
+            self.worker = Some(Worker { child, stdin, stdout });
+        }
+        Ok(self.worker.as_mut().unwrap())
+    }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.stop();
+        }
+    }
+
+    /// Runs `block` at `height` in the worker and writes what it flushed to `db`.
+    pub fn execute<T: KeyValueStoreLike>(
+        &mut self,
+        db: &mut T,
+        height: u32,
+        block: &[u8],
+    ) -> Result<(), RuntimeError> {
+        let mut attempt = 1;
+        loop {
+            let outcome = self
+                .worker()
+                .and_then(|worker| Self::run_block(worker, db, height, block));
+            match outcome {
+                Ok(Outcome::Done(pairs)) => {
+                    self.flushed_pairs = pairs;
+                    return Ok(());
+                }
+                Ok(Outcome::Failed(e)) => return Err(e),
+                Ok(Outcome::Store(e)) => {
+                    self.stop();
+                    return Err(RuntimeError::Internal(e));
+                }
+                Err(e) => {
+                    self.stop();
+                    if attempt >= SANDBOX_ATTEMPTS {
+                        return Err(RuntimeError::Internal(e.context(format!(
+                            "sandbox worker failed {} times on block {}",
+                            attempt, height
+                        ))));
+                    }
+                    warn!("sandbox worker failed on block {}: {:#}, restarting", height, e);
+                    self.restarts += 1;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn run_block<T: KeyValueStoreLike>(
+        worker: &mut Worker,
+        db: &mut T,
+        height: u32,
+        block: &[u8],
+    ) -> Result<Outcome> {
+        let mut payload = height.to_le_bytes().to_vec();
+        payload.extend_from_slice(block);
+        write_frame(&mut worker.stdin, FRAME_BLOCK, &payload)?;
+        loop {
+            let (tag, payload) = read_frame(&mut worker.stdout)?
+                .ok_or_else(|| anyhow!("sandbox worker exited"))?;
+            match tag {
+                FRAME_GET => {
+                    let reply = match db.get(&payload) {
+                        Ok(Some(value)) => [&[1u8][..], &value].concat(),
+                        Ok(None) => vec![0],
+                        Err(e) => return Ok(Outcome::Store(anyhow!("Database error: {:?}", e))),
+                    };
+                    write_frame(&mut worker.stdin, FRAME_VALUE, &reply)?;
+                }
+                FRAME_SCAN => {
+                    let pairs = match db.scan_prefix(&payload) {
+                        Ok(pairs) => pairs,
+                        Err(e) => return Ok(Outcome::Store(anyhow!("Database error: {:?}", e))),
+                    };
+                    let mut reply = vec![];
+                    for (key, value) in pairs.iter() {
+                        put_bytes(&mut reply, key);
+                        put_bytes(&mut reply, value);
+                    }
+                    write_frame(&mut worker.stdin, FRAME_PAIRS, &reply)?;
+                }
+                FRAME_DONE => {
+                    let mut rest = payload.as_slice();
+                    let pairs = u64::from_le_bytes(take(&mut rest, 8)?.try_into()?);
+                    let mut batch = T::Batch::default();
+                    let mut deletes = vec![];
+                    while !rest.is_empty() {
+                        let key = take_bytes(&mut rest)?;
+                        match take(&mut rest, 1)? {
+                            [0] => deletes.push(key),
+                            _ => batch.put(key, take_bytes(&mut rest)?),
+                        }
+                    }
+                    let written = db.write(batch).and_then(|_| {
+                        deletes.iter().try_for_each(|key| db.delete(key))
+                    });
+                    return Ok(match written {
+                        Ok(()) => Outcome::Done(pairs),
+                        Err(e) => Outcome::Store(anyhow!("Database error: {:?}", e)),
+                    });
+                }
+                FRAME_FAILED => return Ok(Outcome::Failed(decode_error(&payload))),
+                tag => return Err(anyhow!("unexpected frame {} from sandbox worker", tag)),
+            }
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+    use crate::runtime::{db_annotate_value, db_make_length_key, db_make_list_key};
+    use std::os::unix::io::FromRawFd;
+
+    // Flushes ["k", <first byte of the block>]
+    const FIRST_BYTE: &str = r#"(module
+      (import "env" "__load_input" (func $load (param i32)))
+      (import "env" "__flush" (func $flush (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 96) "\06\00\00\00\0a\01k\0a\01\00")
+      (func (export "_start")
+        (call $load (i32.const 1000))
+        (i32.store8 (i32.const 105) (i32.load8_u (i32.const 1004)))
+        (call $flush (i32.const 100))))"#;
+
+    // Passes the supervisor's frames through, except that the first worker to be sent block 1
+    // leaves `marker` behind and aborts
+    struct AbortAtOne {
+        input: std::io::Stdin,
+        marker: PathBuf,
+        buffered: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for AbortAtOne {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.buffered.position() as usize == self.buffered.get_ref().len() {
+                let frame = read_frame(&mut self.input).map_err(std::io::Error::other)?;
+                let (tag, payload) = match frame {
+                    Some(frame) => frame,
+                    None => return Ok(0),
+                };
+                let at_one = tag == FRAME_BLOCK && payload.starts_with(&1u32.to_le_bytes());
+                if at_one && !self.marker.exists() {
+                    std::fs::write(&self.marker, b"").unwrap();
+                    std::process::abort();
+                }
+                let mut frame = vec![];
+                write_frame(&mut frame, tag, &payload).map_err(std::io::Error::other)?;
+                self.buffered = std::io::Cursor::new(frame);
+            }
+            self.buffered.read(buf)
+        }
+    }
+
+    // The worker process, spawned by the test below as this test binary with its protocol
+    // output on fd 3, since the test harness prints to stdout
+    #[test]
+    #[ignore]
+    fn sandbox_worker() {
+        let paths = (std::env::var_os("SANDBOX_INDEXER"), std::env::var_os("SANDBOX_MARKER"));
+        let (indexer, marker) = match paths {
+            (Some(indexer), Some(marker)) => (PathBuf::from(indexer), PathBuf::from(marker)),
+            _ => return,
+        };
+        let input = AbortAtOne {
+            input: std::io::stdin(),
+            marker,
+            buffered: std::io::Cursor::new(vec![]),
+        };
+        let output = unsafe { std::fs::File::from_raw_fd(3) };
+        SandboxWorker::new(indexer, input, output, None).unwrap().serve().unwrap();
+    }
+
+    #[test]
+    fn a_crashed_worker_is_replaced_and_the_block_rerun() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let indexer = dir.path().join("indexer.wat");
+        std::fs::write(&indexer, FIRST_BYTE).unwrap();
+        let marker = dir.path().join("crashed");
+        let exe = std::env::current_exe().unwrap();
+        let (worker_indexer, worker_marker) = (indexer.clone(), marker.clone());
+        let mut sandbox = Sandbox::new(move || {
+            let mut command = Command::new("sh");
+            command
+                .args(["-c", r#"exec "$0" "$@" 3>&1 >/dev/null"#])
+                .arg(&exe)
+                .args(["--exact", "sandbox::tests::sandbox_worker", "--ignored"])
+                .env("SANDBOX_INDEXER", &worker_indexer)
+                .env("SANDBOX_MARKER", &worker_marker);
+            command
+        });
+        let mut store = MemStoreAdapter::new();
+        for height in 0..3u32 {
+            sandbox.execute(&mut store, height, &[height as u8 + 1; 80]).unwrap();
+        }
+
+        assert!(marker.exists());
+        assert_eq!(sandbox.restarts, 1);
+        let key = b"k".to_vec();
+        assert_eq!(
+            store.get(db_make_length_key(&key).unwrap()).unwrap(),
+            Some(3u32.to_le_bytes().to_vec())
+        );
+        for height in 0..3u32 {
+            assert_eq!(
+                store.get(db_make_list_key(&key, height).unwrap()).unwrap(),
+                Some(db_annotate_value(&vec![height as u8 + 1], height).unwrap())
+            );
+        }
+    }
+}
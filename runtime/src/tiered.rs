@@ -0,0 +1,221 @@
+use crate::runtime::{KeyValuePairs, KeyValueStoreLike};
+use std::collections::BTreeMap;
+
+// Versions live under the key followed by a little-endian u32 index, each value carrying the
+// height that wrote it in its last four bytes, with the list length under index u32::MAX.
+// Eviction only ever moves superseded versions, so the length and the latest version of every
+// key stay hot and a read at the tip never has to reach the cold tier.
+const LENGTH_SUFFIX: [u8; 4] = u32::MAX.to_le_bytes();
+
+// Keys `evict` takes from the hot tier per scan, so a pass never holds the whole keyspace
+const EVICT_PAGE_SIZE: usize = 1000;
+
+/// Keeps recent data in a fast `hot` store and older history in a larger, slower `cold` one.
+///
+/// Reads try the hot tier first and fall through to the cold tier on a miss, copying what they
+/// find back into the hot tier when `promote` is set. Every mutation goes to the hot tier only;
+/// `evict` is what moves data down, and is meant to be run periodically alongside the indexer.
+/// Deletes remove the key from both tiers.
+#[derive(Clone)]
+pub struct TieredAdapter<H: KeyValueStoreLike + Clone, C: KeyValueStoreLike + Clone> {
+    pub hot: H,
+    pub cold: C,
+    pub promote: bool,
+}
+
+/// Identifies which tier of a `TieredAdapter` rejected an operation.
+#[derive(Debug)]
+pub enum TieredError<H: std::fmt::Debug, C: std::fmt::Debug> {
+    Hot(H),
+    Cold(C),
+}
+
+impl<H: std::fmt::Debug, C: std::fmt::Debug> std::fmt::Display for TieredError<H, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TieredError::Hot(e) => write!(f, "hot tier error: {:?}", e),
+            TieredError::Cold(e) => write!(f, "cold tier error: {:?}", e),
+        }
+    }
+}
+
+impl<H: KeyValueStoreLike + Clone, C: KeyValueStoreLike + Clone> TieredAdapter<H, C> {
+    pub fn new(hot: H, cold: C, promote: bool) -> Self {
+        Self { hot, cold, promote }
+    }
+
+    /// Moves every superseded version written below `height` from the hot tier to the cold
+    /// one, returning how many were moved.
+    ///
+    /// Per-height update lists (4-byte keys) and `/__INTERNAL/` bookkeeping hold unannotated
+    /// values and stay hot. Each version is copied before it is deleted, so an interrupted pass
+    /// leaves at worst a copy in both tiers, and running it again finishes the move.
+    pub fn evict(&mut self, height: u32) -> Result<u64, TieredError<H::Error, C::Error>> {
+        let mut moved: u64 = 0;
+        let mut after: Option<Vec<u8>> = None;
+        loop {
+            let keys = self
+                .hot
+                .scan_prefix_keys([], after.as_deref(), EVICT_PAGE_SIZE)
+                .map_err(TieredError::Hot)?;
+            for key in &keys {
+                if key.len() == 4 || key.starts_with(b"/__INTERNAL/") {
+                    continue;
+                }
+                moved += self.evict_key(key, height)?;
+            }
+            if keys.len() < EVICT_PAGE_SIZE {
+                break;
+            }
+            after = keys.last().cloned();
+        }
+        Ok(moved)
+    }
+
+    // Moves the superseded versions of one key written below `height` to the cold tier
+    fn evict_key(
+        &mut self,
+        key: &[u8],
+        height: u32,
+    ) -> Result<u64, TieredError<H::Error, C::Error>> {
+        let mut length_key = key.to_vec();
+        length_key.extend(LENGTH_SUFFIX);
+        let length = match self.hot.get(&length_key).map_err(TieredError::Hot)? {
+            Some(length) if length.len() == 4 => {
+                u32::from_le_bytes(length.as_slice().try_into().unwrap())
+            }
+            _ => return Ok(0),
+        };
+        let mut moved: u64 = 0;
+        for index in 0..length.saturating_sub(1) {
+            let mut entry_key = key.to_vec();
+            entry_key.extend(index.to_le_bytes());
+            let value = match self.hot.get(&entry_key).map_err(TieredError::Hot)? {
+                Some(value) if value.len() >= 4 => value,
+                _ => continue,
+            };
+            let written = u32::from_le_bytes(value[value.len() - 4..].try_into().unwrap());
+            if written >= height {
+                continue;
+            }
+            self.cold
+                .put(&entry_key, &value)
+                .map_err(TieredError::Cold)?;
+            self.hot.delete(&entry_key).map_err(TieredError::Hot)?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+}
+
+impl<H: KeyValueStoreLike + Clone, C: KeyValueStoreLike + Clone> KeyValueStoreLike
+    for TieredAdapter<H, C>
+{
+    type Error = TieredError<H::Error, C::Error>;
+    type Batch = H::Batch;
+
+    fn write(&mut self, batch: Self::Batch) -> Result<(), Self::Error> {
+        self.hot.write(batch).map_err(TieredError::Hot)
+    }
+
+    fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.hot.get(key.as_ref()).map_err(TieredError::Hot)? {
+            return Ok(Some(value));
+        }
+        let value = self.cold.get(key.as_ref()).map_err(TieredError::Cold)?;
+        if let (true, Some(value)) = (self.promote, value.as_ref()) {
+            self.hot
+                .put(key.as_ref(), value)
+                .map_err(TieredError::Hot)?;
+        }
+        Ok(value)
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.hot.delete(key.as_ref()).map_err(TieredError::Hot)?;
+        self.cold.delete(key.as_ref()).map_err(TieredError::Cold)
+    }
+
+    fn put<K, V>(&mut self, key: K, value: V) -> Result<(), Self::Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.hot.put(key, value).map_err(TieredError::Hot)
+    }
+
+    // A promoted key can be in both tiers; the hot copy is the one a get would return
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let mut merged: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        merged.extend(
+            self.cold
+                .scan_prefix(prefix.as_ref())
+                .map_err(TieredError::Cold)?,
+        );
+        merged.extend(
+            self.hot
+                .scan_prefix(prefix.as_ref())
+                .map_err(TieredError::Hot)?,
+        );
+        Ok(merged.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStoreAdapter;
+
+    fn entry_key(key: &[u8], index: u32) -> Vec<u8> {
+        [key, &index.to_le_bytes()[..]].concat()
+    }
+
+    // Stores one version of `key` per height, oldest first, each annotated with its height
+    fn write_versions(store: &mut MemStoreAdapter, key: &[u8], heights: &[u32]) {
+        for (index, height) in heights.iter().enumerate() {
+            let value = [&[index as u8][..], &height.to_le_bytes()[..]].concat();
+            store.put(entry_key(key, index as u32), value).unwrap();
+        }
+        store.put(entry_key(key, u32::MAX), (heights.len() as u32).to_le_bytes()).unwrap();
+    }
+
+    #[test]
+    fn reads_fall_through_and_promote() {
+        let mut cold = MemStoreAdapter::new();
+        cold.put(b"k", b"cold").unwrap();
+        for promote in [false, true] {
+            let mut tiered = TieredAdapter::new(MemStoreAdapter::new(), cold.clone(), promote);
+            assert_eq!(tiered.get(b"k").unwrap(), Some(b"cold".to_vec()));
+            assert_eq!(tiered.hot.get(b"k").unwrap().is_some(), promote);
+            tiered.put(b"k", b"hot").unwrap();
+            assert_eq!(tiered.get(b"k").unwrap(), Some(b"hot".to_vec()));
+            assert_eq!(tiered.scan_prefix(b"k").unwrap(), vec![(b"k".to_vec(), b"hot".to_vec())]);
+        }
+        let mut tiered = TieredAdapter::new(MemStoreAdapter::new(), cold, false);
+        tiered.delete(b"k").unwrap();
+        assert_eq!(tiered.get(b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn evict_moves_superseded_versions_below_the_height() {
+        let mut tiered = TieredAdapter::new(MemStoreAdapter::new(), MemStoreAdapter::new(), false);
+        // More keys than one page, so eviction has to continue past the first
+        for n in 0..EVICT_PAGE_SIZE as u32 + 1 {
+            write_versions(&mut tiered.hot, format!("key-{}", n).as_bytes(), &[1, 5, 9]);
+        }
+        write_versions(&mut tiered.hot, &7u32.to_le_bytes(), &[1, 2]);
+        write_versions(&mut tiered.hot, b"/__INTERNAL/k", &[1, 2]);
+
+        assert_eq!(tiered.evict(6).unwrap(), 2 * (EVICT_PAGE_SIZE as u64 + 1));
+        assert_eq!(tiered.evict(6).unwrap(), 0);
+        let last = format!("key-{}", EVICT_PAGE_SIZE);
+        for index in [0, 1] {
+            assert!(tiered.hot.get(entry_key(last.as_bytes(), index)).unwrap().is_none());
+            assert!(tiered.cold.get(entry_key(last.as_bytes(), index)).unwrap().is_some());
+            assert!(tiered.get(entry_key(last.as_bytes(), index)).unwrap().is_some());
+        }
+        assert!(tiered.hot.get(entry_key(last.as_bytes(), 2)).unwrap().is_some());
+        assert!(tiered.hot.get(entry_key(&7u32.to_le_bytes(), 0)).unwrap().is_some());
+        assert!(tiered.hot.get(entry_key(b"/__INTERNAL/k", 0)).unwrap().is_some());
+    }
+}